@@ -0,0 +1,482 @@
+use anyhow::{Context as _, anyhow};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+use qemu_plugin::InlineOp;
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use qemu_plugin::Scoreboard;
+use qemu_plugin::{
+    Args, HasCallbacks, Info, PluginArgs, PluginId, Register, Result, TranslationBlock, register,
+};
+#[cfg(not(feature = "plugin-api-v0"))]
+use qemu_plugin::{qemu_plugin_end_code, qemu_plugin_start_code};
+#[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+use std::cell::Cell;
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU8, Ordering},
+    },
+};
+
+/// The size of the coverage map AFL uses unless told otherwise
+const DEFAULT_MAP_SIZE: usize = 1 << 16;
+
+/// The environment variable AFL passes the shared memory ID of the coverage map in
+const SHM_ENV_VAR: &str = "__AFL_SHM_ID";
+
+/// The environment variable AFL++ passes the size of the coverage map in
+const MAP_SIZE_ENV_VAR: &str = "AFL_MAP_SIZE";
+
+#[derive(PluginArgs, Clone, Debug)]
+struct Options {
+    /// A file to use as the coverage map instead of the shared memory AFL passes in
+    /// `__AFL_SHM_ID`, for testing without a fuzzer. The file is created if it does not
+    /// exist and resized to the size of the map
+    map: Option<PathBuf>,
+    /// The size of the coverage map in bytes, which must be a power of two. Defaults to
+    /// `AFL_MAP_SIZE` if it is set, or 65536
+    map_size: Option<usize>,
+    #[cfg(not(feature = "plugin-api-v0"))]
+    /// Instrument all code, rather than only the code of the binary being fuzzed
+    all: bool,
+}
+
+#[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+thread_local! {
+    /// The location of the last block executed by the vCPU running on this thread, shifted
+    /// right by one. In user mode, each vCPU runs on its own thread.
+    static PREV_LOC: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The AFL coverage map, which counts the executions of each edge between two blocks
+struct Map {
+    area: *mut AtomicU8,
+    len: usize,
+    /// Whether the map is AFL's shared memory, rather than a mapped file
+    #[cfg(unix)]
+    shm: bool,
+}
+
+// SAFETY: The map is only accessed through atomics, and lives until the plugin is dropped
+unsafe impl Send for Map {}
+// SAFETY: The map is only accessed through atomics, and lives until the plugin is dropped
+unsafe impl Sync for Map {}
+
+impl Map {
+    #[cfg(unix)]
+    /// Attach to the map AFL shares through `__AFL_SHM_ID`, or map `path` if it is given
+    fn open(path: Option<&PathBuf>, len: usize) -> anyhow::Result<Self> {
+        if let Some(path) = path {
+            return Self::file(path, len);
+        }
+
+        let id = std::env::var(SHM_ENV_VAR)
+            .map_err(|_| {
+                anyhow!("{SHM_ENV_VAR} is not set, run under AFL or give a map file with map=")
+            })?
+            .parse::<libc::c_int>()
+            .with_context(|| format!("Invalid {SHM_ENV_VAR}"))?;
+
+        Self::shared(id, len)
+    }
+
+    #[cfg(unix)]
+    /// Map the file at `path` as the map, creating it if it does not exist
+    fn file(path: &Path, len: usize) -> anyhow::Result<Self> {
+        use std::{fs::OpenOptions, os::fd::AsRawFd};
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open coverage map {}", path.display()))?;
+        file.set_len(len as u64)?;
+
+        let area = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+
+        if area == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to map {}", path.display()));
+        }
+
+        Ok(Self {
+            area: area.cast(),
+            len,
+            shm: false,
+        })
+    }
+
+    #[cfg(unix)]
+    /// Attach to the shared memory segment `id` as the map
+    fn shared(id: libc::c_int, len: usize) -> anyhow::Result<Self> {
+        // NOTE: The map is indexed up to `len`, so a smaller segment would be written past
+        // its end
+        let mut info = unsafe { std::mem::zeroed::<libc::shmid_ds>() };
+
+        if unsafe { libc::shmctl(id, libc::IPC_STAT, &mut info) } == -1 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to query shared memory {id}"));
+        }
+
+        if info.shm_segsz < len {
+            return Err(anyhow!(
+                "Shared memory {id} is {} bytes, smaller than the coverage map size {len}",
+                info.shm_segsz
+            ));
+        }
+
+        let area = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+
+        if area as isize == -1 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to attach to shared memory {id}"));
+        }
+
+        Ok(Self {
+            area: area.cast(),
+            len,
+            shm: true,
+        })
+    }
+
+    #[cfg(not(unix))]
+    /// Attach to the map AFL shares through `__AFL_SHM_ID`, or map `path` if it is given
+    fn open(_path: Option<&PathBuf>, _len: usize) -> anyhow::Result<Self> {
+        Err(anyhow!(
+            "AFL coverage maps are only supported on Unix hosts"
+        ))
+    }
+
+    /// Count an execution of the edge at `index`. Concurrent updates from several vCPUs
+    /// may be lost, as they are in AFL's own instrumentation, but the map is never torn.
+    fn hit(&self, index: u64) {
+        let index = index as usize & (self.len - 1);
+        // SAFETY: The index is masked to the length of the map, which is a power of two
+        let count = unsafe { &*self.area.add(index) };
+        count.store(
+            count.load(Ordering::Relaxed).wrapping_add(1),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The location of a block at `pc` in the map, as AFL's QEMU mode computes it
+    fn location(&self, pc: u64) -> u64 {
+        ((pc >> 4) ^ (pc << 8)) & (self.len as u64 - 1)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe {
+            if self.shm {
+                libc::shmdt(self.area.cast());
+            } else {
+                libc::munmap(self.area.cast(), self.len);
+            }
+        }
+    }
+}
+
+/// The size of the coverage map, from the `map_size` argument or the environment
+fn map_size(options: &Options) -> anyhow::Result<usize> {
+    let size = match options.map_size {
+        Some(size) => size,
+        None => std::env::var(MAP_SIZE_ENV_VAR)
+            .ok()
+            .map(|size| size.parse::<usize>())
+            .transpose()
+            .with_context(|| format!("Invalid {MAP_SIZE_ENV_VAR}"))?
+            .unwrap_or(DEFAULT_MAP_SIZE),
+    };
+
+    if !size.is_power_of_two() {
+        return Err(anyhow!("Coverage map size {size} is not a power of two"));
+    }
+
+    Ok(size)
+}
+
+#[derive(Default)]
+struct AflCov {
+    map: Option<Arc<Map>>,
+    /// The code which is instrumented, or `None` to instrument all code
+    code: Option<Range<u64>>,
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// The location of the last block each vCPU executed, shifted right by one
+    prev_loc: Option<&'static Scoreboard<'static, u64>>,
+}
+
+impl Register for AflCov {
+    fn register(&mut self, _id: PluginId, args: &Args, _info: &Info) -> Result<()> {
+        let options = Options::parse(args)?;
+        let map = Map::open(options.map.as_ref(), map_size(&options)?)?;
+
+        self.map = Some(Arc::new(map));
+
+        #[cfg(not(feature = "plugin-api-v0"))]
+        if !options.all
+            && let (Some(start), Some(end)) = (qemu_plugin_start_code(), qemu_plugin_end_code())
+        {
+            self.code = Some(start..end);
+        }
+
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        {
+            // NOTE: The scoreboard is intentionally leaked, because translated code
+            // updates it until the plugin is unloaded
+            self.prev_loc = Some(Box::leak(Box::default()));
+        }
+
+        Ok(())
+    }
+}
+
+#[qemu_plugin::callbacks]
+impl HasCallbacks for AflCov {
+    fn on_translation_block_translate(
+        &mut self,
+        _id: PluginId,
+        tb: TranslationBlock,
+    ) -> Result<()> {
+        let Some(map) = &self.map else {
+            return Ok(());
+        };
+
+        let pc = tb.vaddr();
+
+        if self.code.as_ref().is_some_and(|code| !code.contains(&pc)) {
+            return Ok(());
+        }
+
+        let cur_loc = map.location(pc);
+        let map = map.clone();
+
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        let prev_loc = self
+            .prev_loc
+            .ok_or_else(|| anyhow!("No previous location scoreboard"))?
+            .field(|loc| loc);
+
+        // NOTE: QEMU runs the callbacks and inline operations of a block in the order they
+        // were registered, so the previous location is stored after the callback reads it
+        #[cfg(not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2"
+        )))]
+        {
+            tb.register_execute_callback(move |vcpu_index| {
+                map.hit(cur_loc ^ prev_loc.get(vcpu_index));
+            });
+            tb.register_inline(InlineOp::store(prev_loc, cur_loc >> 1));
+        }
+
+        #[cfg(feature = "plugin-api-v2")]
+        tb.register_execute_callback(move |vcpu_index| {
+            map.hit(cur_loc ^ prev_loc.get(vcpu_index));
+            prev_loc.set(vcpu_index, cur_loc >> 1);
+        });
+
+        #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+        tb.register_execute_callback(move |_| {
+            PREV_LOC.with(|prev_loc| {
+                map.hit(cur_loc ^ prev_loc.get());
+                prev_loc.set(cur_loc >> 1);
+            });
+        });
+
+        Ok(())
+    }
+}
+
+register!(AflCov::default());
+
+#[cfg(test)]
+mod tests {
+    use qemu_plugin_mock::{MockQemu, MockQemuBuilder, MockTranslationBlock, TempFile};
+
+    const MAP_SIZE: usize = 1 << 16;
+    const START: u64 = 0x400000;
+    const END: u64 = 0x402000;
+
+    /// A mock host emulating a binary whose code is at `START..END`, with the coverage
+    /// map in `map`
+    fn afl_cov(map: &TempFile) -> MockQemuBuilder {
+        MockQemu::user("x86_64")
+            .plugin(super::AflCov::default())
+            .binary("/nonexistent/guest", START, END, START)
+            .arg(map.arg("map"))
+            .arg(format!("map_size={MAP_SIZE}"))
+    }
+
+    /// The location of a block at `pc`, as AFL's QEMU mode computes it
+    fn location(pc: u64) -> usize {
+        (((pc >> 4) ^ (pc << 8)) as usize) & (MAP_SIZE - 1)
+    }
+
+    /// The index of the edge from the block at `prev` to the block at `cur`
+    fn edge(prev: Option<u64>, cur: u64) -> usize {
+        location(cur) ^ prev.map_or(0, |prev| location(prev) >> 1)
+    }
+
+    /// The non-zero counts in the coverage map
+    fn counts(map: &TempFile) -> Vec<(usize, u8)> {
+        let data = map.read().unwrap();
+        assert_eq!(data.len(), MAP_SIZE);
+
+        let mut counts = data
+            .into_iter()
+            .enumerate()
+            .filter(|(_, count)| *count != 0)
+            .collect::<Vec<_>>();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// The expected counts in the coverage map, from the edges and their counts
+    fn expected(edges: impl IntoIterator<Item = (usize, u8)>) -> Vec<(usize, u8)> {
+        let mut counts = Vec::<(usize, u8)>::new();
+
+        for (edge, count) in edges {
+            match counts.iter_mut().find(|(index, _)| *index == edge) {
+                Some((_, total)) => *total += count,
+                None => counts.push((edge, count)),
+            }
+        }
+
+        counts.sort_unstable();
+        counts
+    }
+
+    /// A block of one instruction
+    fn block(vaddr: u64) -> MockTranslationBlock {
+        MockTranslationBlock::nops(vaddr, 1)
+    }
+
+    #[test]
+    fn edges_between_blocks_are_counted() {
+        let map = TempFile::new("edges");
+        let mut qemu = afl_cov(&map).start().unwrap();
+
+        let (a, b) = (0x400100, 0x400230);
+        let (ta, tb) = (qemu.translate(&block(a)), qemu.translate(&block(b)));
+
+        for translation in [&ta, &tb, &ta, &tb] {
+            qemu.execute(translation, 0).unwrap();
+        }
+
+        assert_eq!(
+            counts(&map),
+            expected([
+                (edge(None, a), 1),
+                (edge(Some(a), b), 2),
+                (edge(Some(b), a), 1),
+            ])
+        );
+    }
+
+    #[cfg(not(feature = "plugin-api-v0"))]
+    #[test]
+    fn only_the_binary_is_instrumented() {
+        let map = TempFile::new("filter");
+        let mut qemu = afl_cov(&map).start().unwrap();
+
+        let (a, library) = (0x400100, 0x7f0000001000);
+        for vaddr in [a, library, a] {
+            let translation = qemu.translate(&block(vaddr));
+            qemu.execute(&translation, 0).unwrap();
+        }
+
+        assert_eq!(
+            counts(&map),
+            expected([(edge(None, a), 1), (edge(Some(a), a), 1)])
+        );
+    }
+
+    #[cfg(not(feature = "plugin-api-v0"))]
+    #[test]
+    fn all_instruments_every_block() {
+        let map = TempFile::new("all");
+        let mut qemu = afl_cov(&map).arg("all=on").start().unwrap();
+
+        let (a, library) = (0x400100, 0x7f0000001000);
+        for vaddr in [a, library, a] {
+            let translation = qemu.translate(&block(vaddr));
+            qemu.execute(&translation, 0).unwrap();
+        }
+
+        assert_eq!(
+            counts(&map),
+            expected([
+                (edge(None, a), 1),
+                (edge(Some(a), library), 1),
+                (edge(Some(library), a), 1),
+            ])
+        );
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    #[test]
+    fn each_vcpu_has_its_own_previous_location() {
+        let map = TempFile::new("vcpus");
+        let mut qemu = afl_cov(&map).start().unwrap();
+        qemu.vcpu_init(1);
+
+        let (a, b) = (0x400100, 0x400230);
+        let (ta, tb) = (qemu.translate(&block(a)), qemu.translate(&block(b)));
+        qemu.execute(&ta, 0).unwrap();
+        qemu.execute(&tb, 1).unwrap();
+        qemu.execute(&tb, 0).unwrap();
+
+        assert_eq!(
+            counts(&map),
+            expected([
+                (edge(None, a), 1),
+                (edge(None, b), 1),
+                (edge(Some(a), b), 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn map_size_must_be_a_power_of_two() {
+        let map = TempFile::new("size");
+
+        assert!(
+            MockQemu::user("x86_64")
+                .plugin(super::AflCov::default())
+                .arg(map.arg("map"))
+                .arg("map_size=1000")
+                .install()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn small_shared_memory_is_rejected() {
+        let id = unsafe { libc::shmget(libc::IPC_PRIVATE, 4096, libc::IPC_CREAT | 0o600) };
+        assert_ne!(id, -1);
+
+        let result = super::Map::shared(id, MAP_SIZE);
+        unsafe { libc::shmctl(id, libc::IPC_RMID, std::ptr::null_mut()) };
+
+        assert!(result.is_err());
+    }
+}
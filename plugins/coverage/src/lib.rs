@@ -0,0 +1,662 @@
+use anyhow::{Context as _, anyhow};
+use qemu_plugin::{
+    Args, Counters, FromArgValue, HasCallbacks, Info, InlineOp, PluginArgs, PluginId, Register,
+    Result, Symbolizer, TranslationBlock, qemu_plugin_outs, qemu_plugin_register_atexit_cb,
+    register,
+};
+#[cfg(not(feature = "plugin-api-v0"))]
+use qemu_plugin::{
+    ObjectLayout, qemu_plugin_end_code, qemu_plugin_entry_code, qemu_plugin_path_to_binary,
+    qemu_plugin_start_code,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+#[derive(FromArgValue, Clone, Copy, Debug, PartialEq, Eq)]
+enum DrcovVersion {
+    /// Module table version 2, which every drcov reader supports
+    V2,
+    /// Module table version 5, which adds the containing module, file offset and
+    /// preferred base of each module
+    V3,
+}
+
+#[derive(PluginArgs, Clone, Debug)]
+struct Options {
+    /// The file to write drcov coverage to
+    #[arg(default = PathBuf::from("coverage.drcov"))]
+    output: PathBuf,
+    /// The version of the drcov format to write
+    #[arg(default = DrcovVersion::V2)]
+    drcov: DrcovVersion,
+    /// Write the number of times each block was executed to this file. Counting
+    /// executions is slightly slower than marking blocks as executed, so it is only done
+    /// when this is given
+    hits: Option<PathBuf>,
+    /// Write line coverage in lcov format to this file, using the DWARF line tables of
+    /// the binary. Like the other outputs, it only covers the binary being emulated, and
+    /// not the shared objects it loads
+    lcov: Option<PathBuf>,
+}
+
+/// The binary coverage is collected for, as it is loaded in the guest
+#[derive(Clone, Debug)]
+struct Module {
+    path: PathBuf,
+    /// The address the first segment of the binary is loaded at
+    base: u64,
+    /// The address the last segment of the binary ends at
+    end: u64,
+    /// The address of the entry point
+    entry: u64,
+    /// The address the first segment is loaded at in the file
+    preferred_base: u64,
+    /// The loaded addresses of the code of the binary, which is instrumented
+    code: Range<u64>,
+}
+
+impl Module {
+    #[cfg(not(feature = "plugin-api-v0"))]
+    /// The binary being emulated, located from the bounds QEMU reports for its code and
+    /// the segments in its file
+    fn main() -> Result<Self> {
+        let path = qemu_plugin_path_to_binary()?
+            .ok_or_else(|| anyhow!("coverage only supports user mode emulation"))?;
+        let start = qemu_plugin_start_code().unwrap_or_default();
+        let end = qemu_plugin_end_code().unwrap_or_default();
+
+        let mut module = Self {
+            path,
+            base: start,
+            end,
+            entry: qemu_plugin_entry_code().unwrap_or_default(),
+            preferred_base: start,
+            code: start..end,
+        };
+
+        // NOTE: QEMU only reports the bounds of the executable segments, so the rest of the
+        // binary is located relative to them. If the file does not exist, the code is
+        // treated as the whole binary.
+        if module.path.is_file() {
+            let layout = ObjectLayout::read(&module.path)
+                .with_context(|| format!("Failed to parse {}", module.path.display()))?;
+
+            if layout.code.is_some() {
+                let bias = layout.bias_from_code(start);
+                module.preferred_base = layout.base;
+                module.base = layout.base.wrapping_add(bias);
+                module.end = layout.end.wrapping_add(bias);
+            }
+        }
+
+        Ok(module)
+    }
+
+    #[cfg(feature = "plugin-api-v0")]
+    /// The binary being emulated, which plugin API version 0 can not locate
+    fn main() -> Result<Self> {
+        Err(anyhow!("coverage requires plugin API version 1 or later").into())
+    }
+
+    /// The file name of the binary
+    fn name(&self) -> String {
+        self.path.file_name().map_or_else(
+            || self.path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        )
+    }
+}
+
+/// A block which was executed
+#[derive(Clone, Copy, Debug)]
+struct Executed {
+    vaddr: u64,
+    /// The size of the block in bytes
+    len: u64,
+    /// The number of times the block was executed, or 1 if executions were not counted
+    hits: u64,
+}
+
+/// A flag for each instrumented block, which translated code sets inline when the block
+/// is executed. Flags are not tied to a translation, so a block translated again after a
+/// flush of the translation block cache keeps its coverage.
+#[derive(Default)]
+struct Blocks {
+    flags: Counters,
+    /// The address and size in bytes of the block each flag belongs to
+    blocks: Vec<(u64, u64)>,
+    /// The flag of each instrumented block, by address and size in bytes
+    slots: HashMap<(u64, u64), usize>,
+}
+
+impl Blocks {
+    /// The operation marking a block as executed. Blocks translated more than once share
+    /// a flag.
+    fn set(&mut self, vaddr: u64, len: u64, count: bool) -> InlineOp {
+        let next = self.blocks.len();
+        let slot = *self.slots.entry((vaddr, len)).or_insert(next);
+
+        if slot == next {
+            self.blocks.push((vaddr, len));
+        }
+
+        let flag = self.flags.counter(slot);
+
+        #[cfg(not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2"
+        )))]
+        if !count {
+            return InlineOp::store(flag, 1);
+        }
+
+        // NOTE: Plugin API versions before 3 can not store inline, so they always count
+        #[cfg(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2"
+        ))]
+        let _ = count;
+
+        InlineOp::add(flag, 1)
+    }
+
+    /// The blocks which were executed, sorted by address
+    fn executed(&self, count: bool) -> Vec<Executed> {
+        let mut executed = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, &(vaddr, len))| {
+                let hits = self.flags.sum(slot);
+                (hits > 0).then_some(Executed {
+                    vaddr,
+                    len,
+                    hits: if count { hits } else { 1 },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        executed.sort_unstable_by_key(|block| (block.vaddr, block.len));
+        executed
+    }
+}
+
+/// Write a drcov file with a single module. Both versions share the binary block table,
+/// which holds the offset of each block from the base of its module, its size and the
+/// index of its module.
+fn write_drcov(
+    out: &mut impl Write,
+    version: DrcovVersion,
+    module: &Module,
+    executed: &[Executed],
+) -> std::io::Result<()> {
+    let path = module.path.display();
+
+    match version {
+        DrcovVersion::V2 => {
+            writeln!(out, "DRCOV VERSION: 2")?;
+            writeln!(out, "DRCOV FLAVOR: drcov")?;
+            writeln!(out, "Module Table: version 2, count 1")?;
+            writeln!(
+                out,
+                "Columns: id, base, end, entry, checksum, timestamp, path"
+            )?;
+            writeln!(
+                out,
+                " 0, {:#018x}, {:#018x}, {:#018x}, 0x00000000, 0x00000000, {path}",
+                module.base, module.end, module.entry
+            )?;
+        }
+        DrcovVersion::V3 => {
+            writeln!(out, "DRCOV VERSION: 3")?;
+            writeln!(out, "DRCOV FLAVOR: drcov")?;
+            writeln!(out, "Module Table: version 5, count 1")?;
+            writeln!(
+                out,
+                "Columns: id, containing_id, start, end, entry, offset, preferred_base, path"
+            )?;
+            writeln!(
+                out,
+                " 0, 0, {:#018x}, {:#018x}, {:#018x}, {:#018x}, {:#018x}, {path}",
+                module.base, module.end, module.entry, 0, module.preferred_base
+            )?;
+        }
+    }
+
+    writeln!(out, "BB Table: {} bbs", executed.len())?;
+
+    for block in executed {
+        let start = u32::try_from(block.vaddr - module.base).unwrap_or(u32::MAX);
+        let size = u16::try_from(block.len).unwrap_or(u16::MAX);
+
+        out.write_all(&start.to_le_bytes())?;
+        out.write_all(&size.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Write one `<module>+<offset> <size> <hits>` line for each executed block
+fn write_hits(out: &mut impl Write, module: &Module, executed: &[Executed]) -> std::io::Result<()> {
+    let name = module.name();
+
+    for block in executed {
+        writeln!(
+            out,
+            "{name}+{:#x} {} {}",
+            block.vaddr - module.base,
+            block.len,
+            block.hits
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The highest hit count of the blocks overlapping `range`. Blocks are sorted by
+/// address, and none is longer than `max_len`.
+fn hits(executed: &[Executed], max_len: u64, range: Range<u64>) -> u64 {
+    let first =
+        executed.partition_point(|block| block.vaddr.saturating_add(max_len) <= range.start);
+
+    executed[first..]
+        .iter()
+        .take_while(|block| block.vaddr < range.end)
+        .filter(|block| block.vaddr + block.len > range.start)
+        .map(|block| block.hits)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Write line coverage in lcov format. Every line with code in the binary's line tables
+/// is listed, with the hit count of the most executed block covering it.
+fn write_lcov(
+    out: &mut impl Write,
+    symbolizer: &Symbolizer,
+    module: &Module,
+    executed: &[Executed],
+) -> std::io::Result<()> {
+    let max_len = executed
+        .iter()
+        .map(|block| block.len)
+        .max()
+        .unwrap_or_default();
+    let mut files = BTreeMap::<String, BTreeMap<u32, u64>>::new();
+
+    for (range, location) in symbolizer.lines(module.code.clone()) {
+        let Some(line) = location.line else {
+            continue;
+        };
+
+        let count = hits(executed, max_len, range);
+        let entry = files
+            .entry(location.file.display().to_string())
+            .or_default()
+            .entry(line)
+            .or_default();
+        *entry = (*entry).max(count);
+    }
+
+    for (file, lines) in files {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{file}")?;
+
+        for (line, count) in &lines {
+            writeln!(out, "DA:{line},{count}")?;
+        }
+
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(
+            out,
+            "LH:{}",
+            lines.values().filter(|count| **count > 0).count()
+        )?;
+        writeln!(out, "end_of_record")?;
+    }
+
+    Ok(())
+}
+
+/// Create `path` and write to it with `write`
+fn write_file<F>(path: &Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+{
+    let mut out = BufWriter::new(
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+    );
+    write(&mut out)
+        .and_then(|_| out.flush())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Write every output requested by `options`. The symbolizer is given if line coverage
+/// is written.
+fn write(
+    options: &Options,
+    module: &Module,
+    symbolizer: Option<&Symbolizer>,
+    blocks: &Blocks,
+) -> anyhow::Result<()> {
+    let executed = blocks.executed(options.hits.is_some());
+
+    write_file(&options.output, |out| {
+        write_drcov(out, options.drcov, module, &executed)
+    })?;
+
+    if let Some(path) = &options.hits {
+        write_file(path, |out| write_hits(out, module, &executed))?;
+    }
+
+    if let (Some(path), Some(symbolizer)) = (&options.lcov, symbolizer) {
+        write_file(path, |out| write_lcov(out, symbolizer, module, &executed))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct Coverage {
+    module: Option<Module>,
+    /// Whether executions are counted rather than only marked
+    count: bool,
+    /// Shared with the exit callback, which writes the coverage
+    blocks: Arc<Mutex<Blocks>>,
+}
+
+impl Register for Coverage {
+    fn register(&mut self, id: PluginId, args: &Args, info: &Info) -> Result<()> {
+        let options = Options::parse(args)?;
+
+        if info.system.is_some() {
+            return Err(anyhow!("coverage only supports user mode emulation").into());
+        }
+
+        let module = Module::main()?;
+        self.module = Some(module.clone());
+        self.count = options.hits.is_some();
+
+        // NOTE: The symbolizer only holds the binary being emulated, whose line tables
+        // are read when QEMU exits
+        let symbolizer = options
+            .lcov
+            .is_some()
+            .then(|| Symbolizer::from_info(info))
+            .transpose()?;
+        let blocks = self.blocks.clone();
+
+        qemu_plugin_register_atexit_cb(id, move |_| {
+            let blocks = blocks.lock().unwrap_or_else(PoisonError::into_inner);
+
+            if let Err(e) = write(&options, &module, symbolizer.as_ref(), &blocks) {
+                let _ = qemu_plugin_outs(format!("Failed to write coverage: {e:#}\n"));
+            }
+        })
+    }
+}
+
+#[qemu_plugin::callbacks]
+impl HasCallbacks for Coverage {
+    fn on_translation_block_translate(
+        &mut self,
+        _id: PluginId,
+        tb: TranslationBlock,
+    ) -> Result<()> {
+        let vaddr = tb.vaddr();
+
+        if !self
+            .module
+            .as_ref()
+            .is_some_and(|module| module.code.contains(&vaddr))
+        {
+            return Ok(());
+        }
+
+        let len = tb
+            .instructions()
+            .last()
+            .map_or(0, |insn| insn.vaddr() + insn.size() as u64 - vaddr);
+
+        let op = self
+            .blocks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set(vaddr, len, self.count);
+        tb.register_inline(op);
+
+        Ok(())
+    }
+}
+
+register!(Coverage::default());
+
+#[cfg(all(test, not(feature = "plugin-api-v0")))]
+mod tests {
+    use object::{Object as _, ObjectSegment as _, ObjectSymbol as _, SegmentFlags, elf::PF_X};
+    use qemu_plugin_mock::{
+        MockInstruction, MockQemu, MockQemuBuilder, MockTranslationBlock, TempFile,
+    };
+    use std::fs;
+
+    const BASE: u64 = 0x400000;
+    const END: u64 = 0x402000;
+    const ENTRY: u64 = 0x400100;
+
+    /// A mock host emulating a binary which can not be read, so its code is treated as
+    /// the whole binary
+    fn coverage(output: &TempFile) -> MockQemuBuilder {
+        MockQemu::user("x86_64")
+            .plugin(super::Coverage::default())
+            .binary("/nonexistent/guest", BASE, END, ENTRY)
+            .arg(output.arg("output"))
+    }
+
+    /// A block of five instructions, 5 bytes long in total
+    fn block(vaddr: u64) -> MockTranslationBlock {
+        MockTranslationBlock::nops(vaddr, 5)
+    }
+
+    /// Read a drcov file, returning its header lines and the offset and size of each
+    /// block in its block table
+    fn drcov(output: &TempFile) -> (Vec<String>, Vec<(u32, u16)>) {
+        let data = output.read().unwrap();
+        let marker = b" bbs\n";
+        let end = data
+            .windows(marker.len())
+            .position(|window| window == marker)
+            .unwrap()
+            + marker.len();
+
+        let header = String::from_utf8_lossy(&data[..end])
+            .lines()
+            .map(str::to_string)
+            .collect();
+        let blocks = data[end..]
+            .chunks_exact(8)
+            .map(|entry| {
+                assert_eq!(entry[6..], [0, 0], "every block is in module 0");
+                (
+                    u32::from_le_bytes(entry[..4].try_into().unwrap()),
+                    u16::from_le_bytes(entry[4..6].try_into().unwrap()),
+                )
+            })
+            .collect();
+
+        (header, blocks)
+    }
+
+    #[test]
+    fn drcov_v2_lists_executed_blocks() {
+        let output = TempFile::new("v2.drcov");
+        let mut qemu = coverage(&output).start().unwrap();
+
+        let first = qemu.translate(&block(0x400100));
+        let _unexecuted = qemu.translate(&block(0x400200));
+        let outside = qemu.translate(&block(0x500000));
+        qemu.execute(&first, 0).unwrap();
+        qemu.execute(&outside, 0).unwrap();
+        qemu.exit();
+
+        let (header, blocks) = drcov(&output);
+        assert_eq!(
+            header,
+            [
+                "DRCOV VERSION: 2",
+                "DRCOV FLAVOR: drcov",
+                "Module Table: version 2, count 1",
+                "Columns: id, base, end, entry, checksum, timestamp, path",
+                " 0, 0x0000000000400000, 0x0000000000402000, 0x0000000000400100, 0x00000000, \
+                 0x00000000, /nonexistent/guest",
+                "BB Table: 1 bbs",
+            ]
+        );
+        assert_eq!(blocks, [(0x100, 5)]);
+    }
+
+    #[test]
+    fn drcov_v3_lists_executed_blocks() {
+        let output = TempFile::new("v3.drcov");
+        let mut qemu = coverage(&output).arg("drcov=v3").start().unwrap();
+
+        for vaddr in [0x401000, 0x400100] {
+            let translation = qemu.translate(&block(vaddr));
+            qemu.execute(&translation, 0).unwrap();
+        }
+        qemu.exit();
+
+        let (header, blocks) = drcov(&output);
+        assert_eq!(
+            header,
+            [
+                "DRCOV VERSION: 3",
+                "DRCOV FLAVOR: drcov",
+                "Module Table: version 5, count 1",
+                "Columns: id, containing_id, start, end, entry, offset, preferred_base, path",
+                " 0, 0, 0x0000000000400000, 0x0000000000402000, 0x0000000000400100, \
+                 0x0000000000000000, 0x0000000000400000, /nonexistent/guest",
+                "BB Table: 2 bbs",
+            ]
+        );
+        assert_eq!(blocks, [(0x100, 5), (0x1000, 5)]);
+    }
+
+    #[test]
+    fn hits_count_executions_on_every_vcpu() {
+        let (output, hits) = (TempFile::new("hits.drcov"), TempFile::new("hits.txt"));
+        let mut qemu = coverage(&output).arg(hits.arg("hits")).start().unwrap();
+        qemu.vcpu_init(1);
+
+        let first = qemu.translate(&block(0x400100));
+        let second = qemu.translate(&block(0x400200));
+        for vcpu in [0, 1, 1] {
+            qemu.execute(&first, vcpu).unwrap();
+        }
+        qemu.execute(&second, 0).unwrap();
+        qemu.exit();
+
+        assert_eq!(
+            hits.read_to_string().unwrap(),
+            "guest+0x100 5 3\nguest+0x200 5 1\n"
+        );
+        assert_eq!(drcov(&output).1, [(0x100, 5), (0x200, 5)]);
+    }
+
+    #[test]
+    fn coverage_survives_flush() {
+        let (output, hits) = (TempFile::new("flush.drcov"), TempFile::new("flush.txt"));
+        let mut qemu = coverage(&output).arg(hits.arg("hits")).start().unwrap();
+
+        let translation = qemu.translate(&block(0x400100));
+        qemu.execute(&translation, 0).unwrap();
+        qemu.flush();
+
+        // The block is translated again, and counted with the same flag
+        let translation = qemu.translate(&block(0x400100));
+        qemu.execute(&translation, 0).unwrap();
+        qemu.execute(&translation, 0).unwrap();
+        qemu.exit();
+
+        assert_eq!(hits.read_to_string().unwrap(), "guest+0x100 5 3\n");
+        assert_eq!(drcov(&output).1, [(0x100, 5)]);
+    }
+
+    #[test]
+    fn lcov_reports_executed_lines() {
+        // NOTE: The test binary has line tables, so it stands in for the guest binary,
+        // loaded at its file addresses
+        let exe = std::env::current_exe().unwrap();
+        let data = fs::read(&exe).unwrap();
+        let file = object::File::parse(&*data).unwrap();
+        let code = file
+            .segments()
+            .find(|segment| {
+                matches!(segment.flags(), SegmentFlags::Elf { p_flags } if p_flags & PF_X != 0)
+            })
+            .unwrap();
+        let function = file
+            .symbols()
+            .find(|symbol| symbol.name().is_ok_and(|name| name.contains("write_hits")))
+            .unwrap();
+
+        let (output, lcov) = (TempFile::new("lcov.drcov"), TempFile::new("lcov.info"));
+        let mut qemu = MockQemu::user("x86_64")
+            .plugin(super::Coverage::default())
+            .binary(
+                exe.to_str().unwrap(),
+                code.address(),
+                code.address() + code.size(),
+                code.address(),
+            )
+            .arg(output.arg("output"))
+            .arg(lcov.arg("lcov"))
+            .start()
+            .unwrap();
+
+        let vaddr = function.address();
+        let translation = qemu.translate(&MockTranslationBlock::new(vaddr).instruction(
+            MockInstruction::new(vaddr, vec![0x90; function.size().min(16) as usize]),
+        ));
+        qemu.execute(&translation, 0).unwrap();
+        qemu.exit();
+
+        let lcov = lcov.read_to_string().unwrap();
+        let record = lcov
+            .split("end_of_record\n")
+            .find(|record| record.contains("coverage/src/lib.rs\n"))
+            .unwrap();
+
+        assert!(record.starts_with("TN:\nSF:"));
+        assert!(
+            record
+                .lines()
+                .filter_map(|line| line.strip_prefix("DA:"))
+                .any(|line| line.ends_with(",1"))
+        );
+        assert!(!record.contains("LH:0\n"));
+    }
+}
+
+#[cfg(all(test, feature = "plugin-api-v0"))]
+mod tests {
+    use qemu_plugin_mock::MockQemu;
+
+    #[test]
+    fn plugin_api_v0_is_rejected() {
+        assert!(
+            MockQemu::user("x86_64")
+                .plugin(super::Coverage::default())
+                .binary("/nonexistent/guest", 0x400000, 0x402000, 0x400100)
+                .install()
+                .is_err()
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use qemu_plugin::{
-    HasCallbacks, PluginId, Register, Result, TranslationBlock, VCPUIndex, register,
+    Callbacks, HasCallbacks, PluginId, Register, Result, TranslationBlock, VCPUIndex, register,
 };
 
 #[derive(Default)]
@@ -8,6 +8,10 @@ struct ICount;
 impl Register for ICount {}
 
 impl HasCallbacks for ICount {
+    fn callbacks(&self) -> Callbacks {
+        Callbacks::VCPU_INIT | Callbacks::TRANSLATION_BLOCK_TRANSLATE
+    }
+
     fn on_vcpu_init(&mut self, _id: PluginId, _vcpu_id: VCPUIndex) -> Result<()> {
         Ok(())
     }
@@ -0,0 +1,1096 @@
+use anyhow::{Context as _, anyhow};
+use qemu_plugin::{
+    Args, Counter, Counters, HasCallbacks, Info, InlineOp, PluginArgs, PluginId, Register, Result,
+    TranslationBlock, qemu_plugin_outs, qemu_plugin_register_atexit_cb, register,
+};
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use qemu_plugin::{CallStack, Frame, VCPUIndex};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+use qemu_plugin::{PluginCondition, Scoreboard};
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use std::sync::RwLock;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// The name functions are attributed to when QEMU knows no symbol for a block
+const UNKNOWN: &str = "[unknown]";
+
+#[derive(PluginArgs, Clone, Debug)]
+struct Options {
+    /// The number of functions and blocks to list in the report
+    #[arg(default = 20)]
+    top: usize,
+    /// The file to write the report to. The report is written to the QEMU log if not given
+    report: Option<PathBuf>,
+    /// Write folded stacks for flamegraphs to this file. Stacks are followed with a shadow
+    /// call stack, which runs a callback on every block executed. With plugin API
+    /// version 1 or earlier, or on targets whose calls can not be decoded, each stack is
+    /// the single function a block belongs to.
+    folded: Option<PathBuf>,
+    /// Write a pprof profile to this file
+    pprof: Option<PathBuf>,
+    /// Sample the executing block once every this many instructions instead of counting
+    /// every execution of every block. Requires plugin API version 3 or later
+    sample_every: Option<u64>,
+}
+
+/// A block which has been instrumented
+#[derive(Clone, Debug)]
+struct Block {
+    vaddr: u64,
+    /// The number of instructions in the block
+    insns: u64,
+    /// The function the block belongs to
+    function: Option<String>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The blocks executed on each call stack, for folded stacks
+#[derive(Default)]
+struct Stacks {
+    /// The function each call instruction belongs to, by its address
+    callers: HashMap<u64, String>,
+    /// Shared with the callbacks of the blocks, which count the stacks they run on
+    counts: Arc<StackCounts>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The number of executions or samples of each block on each call stack, counted
+/// separately for each vCPU so vCPUs do not contend for a lock, and merged when QEMU exits
+#[derive(Default)]
+struct StackCounts {
+    vcpus: RwLock<Vec<Mutex<VcpuStacks>>>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl StackCounts {
+    /// Add `count` executions or samples of the block counted by `slot` on `frames`, the
+    /// call stack of `vcpu_index`
+    fn add(&self, vcpu_index: VCPUIndex, frames: &[Frame], slot: usize, count: u64) {
+        let index = vcpu_index as usize;
+
+        if let Some(stacks) = self
+            .vcpus
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(index)
+        {
+            stacks
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .add(frames, slot, count);
+            return;
+        }
+
+        let mut vcpus = self.vcpus.write().unwrap_or_else(PoisonError::into_inner);
+
+        if vcpus.len() <= index {
+            vcpus.resize_with(index + 1, Default::default);
+        }
+
+        vcpus[index]
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .add(frames, slot, count);
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The blocks executed on each call stack of one vCPU
+#[derive(Default)]
+struct VcpuStacks {
+    /// The index of each stack, by the call sites of its frames, outermost first
+    indices: HashMap<Vec<u64>, usize>,
+    /// The call sites of each stack, and the number of executions or samples of each
+    /// block on it by counter
+    stacks: Vec<(Vec<u64>, HashMap<usize, u64>)>,
+    /// The index of the stack the vCPU last ran a block on
+    last: Option<usize>,
+    /// The call sites of the stack being added, kept to avoid an allocation for every
+    /// stack already seen
+    sites: Vec<u64>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl VcpuStacks {
+    /// Add `count` executions or samples of the block counted by `slot` on `frames`
+    fn add(&mut self, frames: &[Frame], slot: usize, count: u64) {
+        let sites = frames.iter().map(|frame| frame.call_site);
+
+        // NOTE: Consecutive blocks usually run on the same stack, which is recognized
+        // without hashing it
+        let index = match self.last {
+            Some(last) if self.stacks[last].0.iter().copied().eq(sites.clone()) => last,
+            _ => {
+                self.sites.clear();
+                self.sites.extend(sites);
+
+                match self.indices.get(self.sites.as_slice()) {
+                    Some(index) => *index,
+                    None => {
+                        let index = self.stacks.len();
+                        self.indices.insert(self.sites.clone(), index);
+                        self.stacks.push((self.sites.clone(), HashMap::new()));
+                        index
+                    }
+                }
+            }
+        };
+
+        self.last = Some(index);
+        *self.stacks[index].1.entry(slot).or_default() += count;
+    }
+}
+
+#[derive(Default)]
+struct Blocks {
+    /// A counter for each profiled block. Counters are not tied to a translation, so a
+    /// block translated again after a flush of the translation block cache keeps its
+    /// count.
+    counters: Counters,
+    /// The block each counter belongs to
+    blocks: Vec<Block>,
+    /// The counter of each instrumented block, by address and number of instructions
+    slots: HashMap<(u64, u64), usize>,
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// The call stacks blocks ran on, if they are followed
+    stacks: Option<Stacks>,
+}
+
+impl Blocks {
+    /// The index and counter for a block. Blocks translated more than once share a
+    /// counter.
+    fn slot(&mut self, block: Block) -> (usize, Counter) {
+        let next = self.blocks.len();
+        let slot = *self.slots.entry((block.vaddr, block.insns)).or_insert(next);
+
+        if slot == next {
+            self.blocks.push(block);
+        }
+
+        (slot, self.counters.counter(slot))
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// The instructions executed on each folded stack, if stacks were followed
+    fn folded(&self, sampled: Option<u64>) -> Option<BTreeMap<String, u64>> {
+        let stacks = self.stacks.as_ref()?;
+        let mut folded = BTreeMap::new();
+
+        let vcpus = stacks
+            .counts
+            .vcpus
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        for vcpu in vcpus.iter() {
+            let vcpu = vcpu.lock().unwrap_or_else(PoisonError::into_inner);
+
+            for (sites, counts) in &vcpu.stacks {
+                let callers = sites
+                    .iter()
+                    .map(|site| stacks.callers.get(site).map_or(UNKNOWN, String::as_str))
+                    .map(frame)
+                    .collect::<Vec<_>>();
+
+                for (slot, count) in counts {
+                    let block = &self.blocks[*slot];
+                    let instructions = count * sampled.unwrap_or(block.insns);
+                    let function = frame(block.function.as_deref().unwrap_or(UNKNOWN));
+                    let stack = callers
+                        .iter()
+                        .cloned()
+                        .chain([function])
+                        .collect::<Vec<_>>()
+                        .join(";");
+                    *folded.entry(stack).or_default() += instructions;
+                }
+            }
+        }
+
+        Some(folded)
+    }
+
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    /// The instructions executed on each folded stack, if stacks were followed
+    fn folded(&self, _sampled: Option<u64>) -> Option<BTreeMap<String, u64>> {
+        None
+    }
+}
+
+/// The name of a function as a frame of a folded stack, in which semicolons separate
+/// frames and the last space separates the count
+fn frame(name: &str) -> String {
+    name.replace([';', ' '], "_")
+}
+
+/// A block or function with its cost
+#[derive(Clone, Debug)]
+struct Cost {
+    name: String,
+    /// The number of executions of the block, or samples taken in it
+    count: u64,
+    /// The number of instructions executed in the block
+    instructions: u64,
+}
+
+/// The profile of a run, computed from the block counters when QEMU exits
+struct Profile {
+    /// The number of instructions between samples, if counts are samples rather than
+    /// executions
+    sampled: Option<u64>,
+    blocks: Vec<(Block, Cost)>,
+    functions: Vec<Cost>,
+    /// The instructions executed on each call stack, if stacks were followed
+    stacks: Option<BTreeMap<String, u64>>,
+}
+
+impl Profile {
+    fn new(blocks: &Blocks, sampled: Option<u64>) -> Self {
+        let mut functions = HashMap::<&str, Cost>::new();
+
+        let mut costs = blocks
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, block)| {
+                let count = blocks.counters.sum(slot);
+
+                (count > 0).then(|| {
+                    let instructions = match sampled {
+                        Some(period) => count * period,
+                        None => count * block.insns,
+                    };
+                    let function = block.function.as_deref().unwrap_or(UNKNOWN);
+                    let cost = functions.entry(function).or_insert_with(|| Cost {
+                        name: function.to_string(),
+                        count: 0,
+                        instructions: 0,
+                    });
+                    cost.count += count;
+                    cost.instructions += instructions;
+
+                    (
+                        block.clone(),
+                        Cost {
+                            name: format!("{:#x} ({function})", block.vaddr),
+                            count,
+                            instructions,
+                        },
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut functions = functions.into_values().collect::<Vec<_>>();
+
+        // NOTE: Ties are broken by address for blocks and by name for functions, so the
+        // output is deterministic
+        costs.sort_by(|(a, ac), (b, bc)| {
+            bc.instructions
+                .cmp(&ac.instructions)
+                .then(a.vaddr.cmp(&b.vaddr))
+        });
+        functions.sort_by(|a, b| {
+            b.instructions
+                .cmp(&a.instructions)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+
+        Self {
+            sampled,
+            blocks: costs,
+            functions,
+            stacks: blocks.folded(sampled),
+        }
+    }
+
+    /// Write the `top` most expensive functions and blocks as a table
+    fn write_report(&self, out: &mut impl Write, top: usize) -> std::io::Result<()> {
+        let total = self
+            .functions
+            .iter()
+            .map(|cost| cost.instructions)
+            .sum::<u64>()
+            .max(1);
+        let count = if self.sampled.is_some() {
+            "samples"
+        } else {
+            "executions"
+        };
+
+        for (title, costs) in [
+            (
+                "functions",
+                self.functions.iter().take(top).collect::<Vec<_>>(),
+            ),
+            (
+                "blocks",
+                self.blocks.iter().map(|(_, cost)| cost).take(top).collect(),
+            ),
+        ] {
+            writeln!(out, "Top {} {title} by instructions executed:", costs.len())?;
+            writeln!(out, "{:>16} {:>8} {:>12}  name", "instructions", "%", count)?;
+
+            for cost in costs {
+                writeln!(
+                    out,
+                    "{:>16} {:>7.2}% {:>12}  {}",
+                    cost.instructions,
+                    cost.instructions as f64 * 100.0 / total as f64,
+                    cost.count,
+                    cost.name
+                )?;
+            }
+
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write one `<frame>;<frame>;... <instructions>` line per call stack, in the folded
+    /// stack format read by `flamegraph.pl` and `inferno`. If stacks were not followed,
+    /// each stack is a single function.
+    fn write_folded(&self, out: &mut impl Write) -> std::io::Result<()> {
+        match &self.stacks {
+            Some(stacks) => {
+                for (stack, instructions) in stacks {
+                    writeln!(out, "{stack} {instructions}")?;
+                }
+            }
+            None => {
+                for cost in &self.functions {
+                    writeln!(out, "{} {}", frame(&cost.name), cost.instructions)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the profile in the pprof protobuf format, with one location per block and
+    /// one function per symbol
+    fn write_pprof(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let mut strings = Strings::default();
+        let mut profile = Message::default();
+        let count = if self.sampled.is_some() {
+            "samples"
+        } else {
+            "executions"
+        };
+
+        for kind in [count, "instructions"] {
+            let mut value_type = Message::default();
+            value_type.int64(1, strings.index(kind));
+            value_type.int64(2, strings.index("count"));
+            profile.message(1, &value_type);
+        }
+
+        let mut functions = HashMap::<&str, u64>::new();
+
+        for (index, (block, cost)) in self.blocks.iter().enumerate() {
+            let location_id = index as u64 + 1;
+            let function = block.function.as_deref().unwrap_or(UNKNOWN);
+            let next = functions.len() as u64 + 1;
+            let function_id = *functions.entry(function).or_insert_with(|| {
+                let mut message = Message::default();
+                message.uint64(1, next);
+                message.int64(2, strings.index(function));
+                message.int64(3, strings.index(function));
+                profile.message(5, &message);
+                next
+            });
+
+            let mut line = Message::default();
+            line.uint64(1, function_id);
+
+            let mut location = Message::default();
+            location.uint64(1, location_id);
+            location.uint64(3, block.vaddr);
+            location.message(4, &line);
+            profile.message(4, &location);
+
+            let mut sample = Message::default();
+            sample.packed(1, [location_id]);
+            sample.packed(2, [cost.count, cost.instructions]);
+            profile.message(2, &sample);
+        }
+
+        if let Some(period) = self.sampled {
+            let mut period_type = Message::default();
+            period_type.int64(1, strings.index("instructions"));
+            period_type.int64(2, strings.index("count"));
+            profile.message(11, &period_type);
+            profile.int64(12, period);
+        }
+
+        for string in &strings.strings {
+            profile.bytes(6, string.as_bytes());
+        }
+
+        out.write_all(&profile.0)
+    }
+}
+
+/// The string table of a pprof profile, which always starts with the empty string
+struct Strings {
+    strings: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Self {
+            strings: vec![String::new()],
+            indices: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl Strings {
+    /// The index of `string`, adding it to the table if needed
+    fn index(&mut self, string: &str) -> u64 {
+        if let Some(index) = self.indices.get(string) {
+            return *index;
+        }
+
+        let index = self.strings.len() as u64;
+        self.strings.push(string.to_string());
+        self.indices.insert(string.to_string(), index);
+        index
+    }
+}
+
+/// An encoded protobuf message. Only the wire types used by pprof are supported.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint(field << 3 | wire_type);
+    }
+
+    fn uint64(&mut self, field: u64, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    /// Non-negative `int64` fields are encoded the same way as `uint64` fields
+    fn int64(&mut self, field: u64, value: u64) {
+        self.uint64(field, value);
+    }
+
+    fn bytes(&mut self, field: u64, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn message(&mut self, field: u64, value: &Message) {
+        self.bytes(field, &value.0);
+    }
+
+    fn packed(&mut self, field: u64, values: impl IntoIterator<Item = u64>) {
+        let mut packed = Message::default();
+        values.into_iter().for_each(|value| packed.varint(value));
+        self.bytes(field, &packed.0);
+    }
+}
+
+/// Create `path` and write to it with `write`
+fn write_file<F>(path: &Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+{
+    let mut out = BufWriter::new(
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+    );
+    write(&mut out)
+        .and_then(|_| out.flush())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Write every output requested by `options`
+fn write(options: &Options, blocks: &Blocks) -> anyhow::Result<()> {
+    let profile = Profile::new(blocks, options.sample_every);
+
+    match &options.report {
+        Some(path) => write_file(path, |out| profile.write_report(out, options.top))?,
+        None => {
+            let mut report = Vec::new();
+            profile.write_report(&mut report, options.top)?;
+            qemu_plugin_outs(String::from_utf8_lossy(&report))?;
+        }
+    }
+
+    if let Some(path) = &options.folded {
+        write_file(path, |out| profile.write_folded(out))?;
+    }
+
+    if let Some(path) = &options.pprof {
+        write_file(path, |out| profile.write_pprof(out))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct Profiler {
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2"
+    )))]
+    /// The number of instructions between samples, if sampling
+    sample_every: Option<u64>,
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2"
+    )))]
+    /// The number of instructions each vCPU has executed since it was last sampled
+    since_sample: Option<&'static Scoreboard<'static, u64>>,
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// The shadow call stack of each vCPU, if folded stacks are written
+    stack: Option<CallStack>,
+    /// Shared with the exit callback, which writes the profile
+    blocks: Arc<Mutex<Blocks>>,
+}
+
+impl Register for Profiler {
+    fn register(&mut self, id: PluginId, args: &Args, info: &Info) -> Result<()> {
+        let options = Options::parse(args)?;
+
+        if let Some(period) = options.sample_every {
+            if period == 0 {
+                return Err(anyhow!("sample_every must be at least 1").into());
+            }
+
+            #[cfg(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2"
+            ))]
+            return Err(anyhow!("sample_every requires plugin API version 3 or later").into());
+
+            #[cfg(not(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2"
+            )))]
+            {
+                // NOTE: The scoreboard is intentionally leaked, because translated code
+                // updates it until the plugin is unloaded
+                self.since_sample = Some(Box::leak(Box::default()));
+                self.sample_every = Some(period);
+            }
+        }
+
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        if options.folded.is_some() {
+            // NOTE: Targets whose calls can not be decoded fall back to single frames
+            self.stack = CallStack::from_info(info).ok();
+
+            if self.stack.is_some() {
+                self.blocks
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .stacks = Some(Stacks::default());
+            }
+        }
+        #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+        let _ = info;
+
+        let blocks = self.blocks.clone();
+
+        qemu_plugin_register_atexit_cb(id, move |_| {
+            let blocks = blocks.lock().unwrap_or_else(PoisonError::into_inner);
+
+            if let Err(e) = write(&options, &blocks) {
+                let _ = qemu_plugin_outs(format!("Failed to write profile: {e:#}\n"));
+            }
+        })
+    }
+}
+
+#[qemu_plugin::callbacks]
+impl HasCallbacks for Profiler {
+    fn on_translation_block_translate(
+        &mut self,
+        _id: PluginId,
+        tb: TranslationBlock,
+    ) -> Result<()> {
+        let insns = tb.size() as u64;
+
+        #[cfg(not(feature = "plugin-api-v0"))]
+        let function = tb
+            .instructions()
+            .next()
+            .map(|insn| insn.symbol())
+            .transpose()?
+            .flatten();
+        #[cfg(feature = "plugin-api-v0")]
+        let function = None;
+
+        // NOTE: The function a call is made from is that of the call instruction, which
+        // may differ from that of the first instruction of its block
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        let call = match self.stack.as_ref().and_then(|stack| stack.instrument(&tb)) {
+            Some(branch) if branch.is_call() => match tb.instructions().last() {
+                Some(insn) => Some((insn.vaddr(), insn.symbol()?)),
+                None => None,
+            },
+            _ => None,
+        };
+
+        let mut blocks = self.blocks.lock().unwrap_or_else(PoisonError::into_inner);
+        let (index, slot) = blocks.slot(Block {
+            vaddr: tb.vaddr(),
+            insns,
+            function,
+        });
+
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        if let (Some(stacks), Some((site, function))) = (&mut blocks.stacks, call) {
+            stacks
+                .callers
+                .insert(site, function.unwrap_or_else(|| UNKNOWN.to_string()));
+        }
+
+        // NOTE: The callbacks count the stacks they run on without locking the blocks
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        let stack = self
+            .stack
+            .clone()
+            .zip(blocks.stacks.as_ref().map(|stacks| stacks.counts.clone()));
+
+        drop(blocks);
+
+        #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+        let _ = index;
+
+        #[cfg(not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2"
+        )))]
+        if let (Some(period), Some(since_sample)) = (self.sample_every, self.since_sample) {
+            let since_sample = since_sample.field(|count| count);
+
+            // NOTE: QEMU runs the callbacks and inline operations of a block in the order
+            // they were registered, so the callback sees the count including this block.
+            // The remainder is kept so the sampling period does not drift.
+            tb.register_inline(InlineOp::add(since_sample, insns));
+            tb.register_conditional_execute_callback(
+                move |vcpu_index| {
+                    let count = since_sample.get(vcpu_index);
+                    slot.add(vcpu_index, count / period);
+                    since_sample.set(vcpu_index, count % period);
+
+                    if let Some((stack, counts)) = &stack {
+                        stack.with_frames(vcpu_index, |frames| {
+                            counts.add(vcpu_index, frames, index, count / period);
+                        });
+                    }
+                },
+                PluginCondition::QEMU_PLUGIN_COND_GE,
+                since_sample.entry(),
+                period,
+            );
+
+            return Ok(());
+        }
+
+        tb.register_inline(InlineOp::add(slot, 1));
+
+        // NOTE: Callbacks on the block run before those on its final instruction, so the
+        // stack is that of the function the block belongs to
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        if let Some((stack, counts)) = stack {
+            tb.register_execute_callback(move |vcpu_index| {
+                stack.with_frames(vcpu_index, |frames| {
+                    counts.add(vcpu_index, frames, index, 1);
+                });
+            });
+        }
+
+        Ok(())
+    }
+}
+
+register!(Profiler::default());
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    use qemu_plugin_mock::MockInstruction;
+    use qemu_plugin_mock::{MockQemu, MockQemuBuilder, MockTranslationBlock, TempFile};
+    use std::collections::HashMap;
+
+    /// A mock host emulating `target`, with the plugin configured by `args`
+    fn profile<I, S>(target: &str, args: I) -> MockQemuBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        MockQemu::user(target)
+            .plugin(super::Profiler::default())
+            .args(args)
+    }
+
+    /// A block at `vaddr` in `function` made of `insns` one byte instructions
+    fn block(vaddr: u64, function: &str, insns: u64) -> MockTranslationBlock {
+        MockTranslationBlock::nops(vaddr, insns).symbol(function)
+    }
+
+    /// Run three blocks in three functions, costing 2, 12 and 5 instructions
+    fn run(qemu: &mut MockQemu) {
+        let main = qemu.translate(&block(0x1000, "main", 2));
+        let f = qemu.translate(&block(0x2000, "f", 3));
+        let g = qemu.translate(&block(0x3000, "g", 1));
+
+        qemu.execute(&main, 0).unwrap();
+        (0..4).for_each(|_| qemu.execute(&f, 0).unwrap());
+        (0..5).for_each(|_| qemu.execute(&g, 0).unwrap());
+    }
+
+    /// The instructions and name of each row of each table of a report
+    fn tables(report: &str) -> Vec<Vec<(u64, String)>> {
+        report
+            .split("\n\n")
+            .filter(|table| !table.trim().is_empty())
+            .map(|table| {
+                table
+                    .lines()
+                    .skip(2)
+                    .map(|row| {
+                        let fields = row.split_whitespace().collect::<Vec<_>>();
+                        (fields[0].parse().unwrap(), fields[3..].join(" "))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn report_lists_the_most_expensive_first() {
+        let report = TempFile::new("report");
+        let mut qemu = profile("x86_64", ["top=2".to_string(), report.arg("report")])
+            .start()
+            .unwrap();
+
+        run(&mut qemu);
+        qemu.exit();
+
+        let report = report.read_to_string().unwrap();
+        assert!(report.contains("Top 2 blocks by instructions executed:\n"));
+
+        let tables = tables(&report);
+        let blocks = tables[1]
+            .iter()
+            .map(|(instructions, name)| (*instructions, name.split(' ').next().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [(12, "0x2000"), (5, "0x3000")]);
+
+        #[cfg(not(feature = "plugin-api-v0"))]
+        assert_eq!(
+            tables,
+            [
+                vec![(12, "f".to_string()), (5, "g".to_string())],
+                vec![
+                    (12, "0x2000 (f)".to_string()),
+                    (5, "0x3000 (g)".to_string())
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn report_breaks_ties_by_address() {
+        let report = TempFile::new("ties");
+        let mut qemu = profile("x86_64", [report.arg("report")]).start().unwrap();
+
+        let b = qemu.translate(&block(0x2000, "b", 1));
+        let a = qemu.translate(&block(0x1000, "a", 1));
+        qemu.execute(&b, 0).unwrap();
+        qemu.execute(&a, 0).unwrap();
+        qemu.exit();
+
+        let report = report.read_to_string().unwrap();
+        let blocks = tables(&report)[1]
+            .iter()
+            .map(|(_, name)| name.split(' ').next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, ["0x1000", "0x2000"]);
+    }
+
+    /// A field of a protobuf message
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Field {
+        Varint(u64),
+        Bytes(Vec<u8>),
+    }
+
+    impl Field {
+        fn varint(&self) -> u64 {
+            match self {
+                Field::Varint(value) => *value,
+                Field::Bytes(_) => panic!("{self:?} is not a varint"),
+            }
+        }
+
+        fn bytes(&self) -> &[u8] {
+            match self {
+                Field::Bytes(value) => value,
+                Field::Varint(_) => panic!("{self:?} is not length delimited"),
+            }
+        }
+    }
+
+    /// Read a varint from the start of `data`
+    fn varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = data.split_first().unwrap();
+            *data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        value
+    }
+
+    /// Decode the fields of a protobuf message, by field number
+    fn decode(mut data: &[u8]) -> HashMap<u64, Vec<Field>> {
+        let mut fields = HashMap::<u64, Vec<Field>>::new();
+
+        while !data.is_empty() {
+            let key = varint(&mut data);
+            let field = match key & 7 {
+                0 => Field::Varint(varint(&mut data)),
+                2 => {
+                    let len = varint(&mut data) as usize;
+                    let (value, rest) = data.split_at(len);
+                    data = rest;
+                    Field::Bytes(value.to_vec())
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.entry(key >> 3).or_default().push(field);
+        }
+
+        fields
+    }
+
+    /// Decode a packed repeated varint field
+    fn packed(field: &Field) -> Vec<u64> {
+        let mut data = field.bytes();
+        let mut values = Vec::new();
+
+        while !data.is_empty() {
+            values.push(varint(&mut data));
+        }
+
+        values
+    }
+
+    #[test]
+    fn pprof_has_a_sample_and_location_for_each_block() {
+        let pprof = TempFile::new("pprof");
+        let mut qemu = profile("x86_64", [pprof.arg("pprof")]).start().unwrap();
+
+        run(&mut qemu);
+        qemu.exit();
+
+        let profile = decode(&pprof.read().unwrap());
+        let strings = profile[&6]
+            .iter()
+            .map(|string| String::from_utf8(string.bytes().to_vec()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(strings[0], "");
+
+        let sample_types = profile[&1]
+            .iter()
+            .map(|value_type| {
+                let value_type = decode(value_type.bytes());
+                (
+                    strings[value_type[&1][0].varint() as usize].as_str(),
+                    strings[value_type[&2][0].varint() as usize].as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sample_types,
+            [("executions", "count"), ("instructions", "count")]
+        );
+
+        let functions = profile[&5]
+            .iter()
+            .map(|function| {
+                let function = decode(function.bytes());
+                (
+                    function[&1][0].varint(),
+                    strings[function[&2][0].varint() as usize].clone(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let locations = profile[&4]
+            .iter()
+            .map(|location| {
+                let location = decode(location.bytes());
+                let line = decode(location[&4][0].bytes());
+                (
+                    location[&1][0].varint(),
+                    (
+                        location[&3][0].varint(),
+                        functions[&line[&1][0].varint()].clone(),
+                    ),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let samples = profile[&2]
+            .iter()
+            .map(|sample| {
+                let sample = decode(sample.bytes());
+                let locations = packed(&sample[&1][0])
+                    .into_iter()
+                    .map(|id| locations[&id].clone())
+                    .collect::<Vec<_>>();
+                (locations, packed(&sample[&2][0]))
+            })
+            .collect::<Vec<_>>();
+
+        #[cfg(not(feature = "plugin-api-v0"))]
+        let name = |function: &str| function.to_string();
+        #[cfg(feature = "plugin-api-v0")]
+        let name = |_: &str| super::UNKNOWN.to_string();
+
+        assert_eq!(
+            samples,
+            [
+                (vec![(0x2000, name("f"))], vec![4, 12]),
+                (vec![(0x3000, name("g"))], vec![5, 5]),
+                (vec![(0x1000, name("main"))], vec![1, 2]),
+            ]
+        );
+        assert!(!profile.contains_key(&11));
+    }
+
+    #[test]
+    fn folded_stacks_fall_back_to_functions() {
+        let folded = TempFile::new("fallback");
+        // NOTE: Calls can not be decoded on MIPS, so stacks are not followed
+        let mut qemu = profile("mips", [folded.arg("folded")]).start().unwrap();
+
+        run(&mut qemu);
+        qemu.exit();
+
+        let folded = folded.read_to_string().unwrap();
+
+        #[cfg(not(feature = "plugin-api-v0"))]
+        assert_eq!(folded, "f 12\ng 5\nmain 2\n");
+        #[cfg(feature = "plugin-api-v0")]
+        assert_eq!(folded, "[unknown] 19\n");
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Run `main` on `vcpu_index`, which calls `f` twice. Each call runs 2 instructions of
+    /// `main`, the 3 instructions of `f`, then 1 more of `main`.
+    fn run_calls(qemu: &mut MockQemu, vcpu_index: u32) {
+        // main: nop; call f
+        let call = qemu.translate(
+            &MockTranslationBlock::new(0x1000)
+                .instruction(MockInstruction::new(0x1000, [0x90]).symbol("main"))
+                .instruction(
+                    MockInstruction::new(0x1001, [0xe8, 0xfa, 0x0f, 0x00, 0x00]).symbol("main"),
+                ),
+        );
+        // f: nop; nop; ret
+        let f = qemu.translate(
+            &block(0x2000, "f", 2).instruction(MockInstruction::new(0x2002, [0xc3]).symbol("f")),
+        );
+        // main: nop
+        let back = qemu.translate(&block(0x1006, "main", 1));
+
+        for _ in 0..2 {
+            qemu.execute(&call, vcpu_index).unwrap();
+            qemu.execute(&f, vcpu_index).unwrap();
+            qemu.execute(&back, vcpu_index).unwrap();
+        }
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    #[test]
+    fn folded_stacks_follow_calls() {
+        let folded = TempFile::new("folded");
+        let mut qemu = profile("x86_64", [folded.arg("folded")]).start().unwrap();
+
+        run_calls(&mut qemu, 0);
+        qemu.exit();
+
+        let folded = folded.read_to_string().unwrap();
+        assert_eq!(folded, "main 6\nmain;f 6\n");
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    #[test]
+    fn folded_stacks_merge_every_vcpu() {
+        let folded = TempFile::new("vcpus");
+        let mut qemu = profile("x86_64", [folded.arg("folded")]).start().unwrap();
+        qemu.vcpu_init(1);
+
+        run_calls(&mut qemu, 0);
+        run_calls(&mut qemu, 1);
+        qemu.exit();
+
+        let folded = folded.read_to_string().unwrap();
+        assert_eq!(folded, "main 12\nmain;f 12\n");
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2"
+    )))]
+    #[test]
+    fn sampled_folded_stacks_follow_calls() {
+        let folded = TempFile::new("sampled");
+        let mut qemu = profile(
+            "x86_64",
+            ["sample_every=2".to_string(), folded.arg("folded")],
+        )
+        .start()
+        .unwrap();
+
+        // NOTE: Each call is sampled once as each block completes a period of 2
+        // instructions, in main, in f with 1 left over, then in main again
+        run_calls(&mut qemu, 0);
+        qemu.exit();
+
+        let folded = folded.read_to_string().unwrap();
+        assert_eq!(folded, "main 8\nmain;f 4\n");
+    }
+}
@@ -0,0 +1,557 @@
+use anyhow::anyhow;
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+use qemu_plugin::{ArgKind, GuestMemory};
+use qemu_plugin::{
+    Args, EventSink, FromArgValue, HasCallbacks, Info, PluginArgs, PluginId, Register, Result,
+    Syscall, SyscallTable, VCPUIndex, qemu_plugin_outs, register,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::Instant,
+};
+
+#[derive(FromArgValue, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// One line per syscall, like `strace`
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(PluginArgs, Clone, Debug)]
+struct Options {
+    /// Only trace these syscalls. May be given more than once, and each value may list
+    /// several syscalls separated by `:`
+    filter: Vec<String>,
+    /// Do not trace these syscalls. May be given more than once, and each value may list
+    /// several syscalls separated by `:`
+    exclude: Vec<String>,
+    /// The format to write records in
+    #[arg(default = Format::Text)]
+    format: Format,
+    /// The file to write records to. Records are written to the QEMU log if not given
+    output: Option<PathBuf>,
+    /// The number of bytes of strings and buffers to show
+    #[arg(default = 32)]
+    string_limit: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+/// A syscall made by the guest
+struct Record {
+    /// The vCPU which made the syscall
+    vcpu: VCPUIndex,
+    /// The time the syscall was made, in seconds since the plugin was loaded
+    timestamp: f64,
+    /// The syscall number
+    number: i64,
+    /// The syscall name, or `syscall_<number>` if it is not known
+    name: String,
+    /// The decoded arguments
+    args: Vec<String>,
+    /// The return value, if the syscall returned
+    #[serde(rename = "return")]
+    ret: Option<i64>,
+    /// The name of the error number returned, if the syscall failed
+    errno: Option<String>,
+    /// The time spent in the syscall in seconds, if it returned
+    duration: Option<f64>,
+    /// The return value as `strace` renders it, or `?` if the syscall did not return
+    #[serde(skip)]
+    result: String,
+}
+
+impl Record {
+    /// Write the record as a line of text in the style of `strace -T`, prefixed with the
+    /// vCPU and the time since the plugin was loaded
+    fn write_text(&self, out: &mut impl Write) -> std::io::Result<()> {
+        write!(
+            out,
+            "[vcpu {}] {:.6} {}({}) = {}",
+            self.vcpu,
+            self.timestamp,
+            self.name,
+            self.args.join(", "),
+            self.result
+        )?;
+
+        if let Some(duration) = self.duration {
+            write!(out, " <{duration:.6}>")?;
+        }
+
+        writeln!(out)
+    }
+}
+
+/// A syscall which has been made but has not yet returned
+struct Pending {
+    syscall: Syscall,
+    args: Vec<String>,
+    timestamp: f64,
+    start: Instant,
+}
+
+struct Strace {
+    /// The syscalls to trace, or `None` to trace all syscalls
+    filter: Option<HashSet<String>>,
+    /// The syscalls not to trace
+    exclude: HashSet<String>,
+    string_limit: usize,
+    start: Instant,
+    pending: HashMap<VCPUIndex, Pending>,
+    records: Option<EventSink<Record>>,
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    memory: Option<GuestMemory>,
+}
+
+impl Strace {
+    fn new() -> Self {
+        Self {
+            filter: None,
+            exclude: HashSet::new(),
+            string_limit: 32,
+            start: Instant::now(),
+            pending: HashMap::new(),
+            records: None,
+            #[cfg(not(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2",
+                feature = "plugin-api-v3"
+            )))]
+            memory: None,
+        }
+    }
+
+    /// Whether a syscall with a name is traced
+    fn traces(&self, name: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.contains(name))
+            && !self.exclude.contains(name)
+    }
+
+    /// Render the arguments of a syscall as it is made, reading guest memory if the
+    /// plugin API supports it
+    fn args(&self, syscall: &Syscall) -> Vec<String> {
+        #[cfg(not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2",
+            feature = "plugin-api-v3"
+        )))]
+        if let Some(memory) = &self.memory {
+            return syscall.decode_args(memory, self.string_limit, None);
+        }
+
+        syscall.render_args()
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// Replace the output buffers among the rendered arguments of a syscall with the
+    /// data it returned. Output buffers are filled by the syscall, so they can only be
+    /// read once it has returned.
+    fn returned_args(&self, syscall: &Syscall, mut args: Vec<String>, ret: i64) -> Vec<String> {
+        let Some(memory) = &self.memory else {
+            return args;
+        };
+
+        if !syscall
+            .arguments()
+            .any(|(kind, _)| matches!(kind, ArgKind::Buf { output: true, .. }))
+        {
+            return args;
+        }
+
+        let returned = syscall.decode_args(memory, self.string_limit, Some(ret));
+
+        for (index, (kind, _)) in syscall.arguments().enumerate() {
+            if let (ArgKind::Buf { output: true, .. }, Some(arg), Some(old)) =
+                (kind, returned.get(index), args.get_mut(index))
+            {
+                old.clone_from(arg);
+            }
+        }
+
+        args
+    }
+
+    fn push(&self, vcpu: VCPUIndex, record: Record) -> Result<()> {
+        self.records
+            .as_ref()
+            .ok_or_else(|| anyhow!("No record sink"))?
+            .push(vcpu, record);
+        Ok(())
+    }
+}
+
+/// The name of a syscall, or `syscall_<number>` if it is not known
+fn name(syscall: &Syscall) -> String {
+    syscall
+        .name()
+        .map_or_else(|| format!("syscall_{}", syscall.number()), str::to_string)
+}
+
+/// Split `filter=` and `exclude=` values into syscall names, checking that the names are
+/// known if the target has a syscall table
+fn names(values: &[String], table: Option<&SyscallTable>) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+
+    for name in values.iter().flat_map(|value| value.split(':')) {
+        if let Some(table) = table
+            && table.number(name).is_none()
+            && !name.starts_with("syscall_")
+        {
+            return Err(anyhow!("Unknown syscall '{name}' for {}", table.arch()).into());
+        }
+
+        names.insert(name.to_string());
+    }
+
+    Ok(names)
+}
+
+impl Register for Strace {
+    fn register(&mut self, id: PluginId, args: &Args, info: &Info) -> Result<()> {
+        let options = Options::parse(args)?;
+
+        if info.system.is_some() {
+            return Err(anyhow!("strace only supports user mode emulation").into());
+        }
+
+        let table = info.target.and_then(SyscallTable::for_target);
+
+        if !options.filter.is_empty() {
+            self.filter = Some(names(&options.filter, table)?);
+        }
+
+        self.exclude = names(&options.exclude, table)?;
+        self.string_limit = options.string_limit;
+
+        #[cfg(not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2",
+            feature = "plugin-api-v3"
+        )))]
+        {
+            self.memory = Some(GuestMemory::from_info(info));
+        }
+
+        let mut output = options
+            .output
+            .map(File::create)
+            .transpose()?
+            .map(BufWriter::new);
+        let format = options.format;
+
+        // Format and write records on the sink's writer thread rather than in the
+        // callbacks
+        let records = EventSink::builder().build(move |_, batch: &[Record]| {
+            let mut buffer = Vec::new();
+
+            for record in batch {
+                match format {
+                    Format::Text => record.write_text(&mut buffer)?,
+                    Format::Json => {
+                        serde_json::to_writer(&mut buffer, record).map_err(|e| anyhow!(e))?;
+                        buffer.push(b'\n');
+                    }
+                }
+            }
+
+            match &mut output {
+                Some(output) => {
+                    output.write_all(&buffer)?;
+                    output.flush()?;
+                }
+                None => qemu_plugin_outs(String::from_utf8_lossy(&buffer))?,
+            }
+
+            Ok(())
+        })?;
+        records.close_at_exit(id)?;
+        self.records = Some(records);
+        self.start = Instant::now();
+
+        Ok(())
+    }
+}
+
+#[qemu_plugin::callbacks]
+impl HasCallbacks for Strace {
+    fn on_syscall_typed(
+        &mut self,
+        _id: PluginId,
+        vcpu_index: VCPUIndex,
+        syscall: Syscall,
+    ) -> Result<()> {
+        if !self.traces(&name(&syscall)) {
+            return Ok(());
+        }
+
+        let pending = Pending {
+            syscall: syscall.clone(),
+            args: self.args(&syscall),
+            timestamp: self.start.elapsed().as_secs_f64(),
+            start: Instant::now(),
+        };
+
+        // These syscalls do not return, so they are recorded as they are made
+        if matches!(syscall.name(), Some("exit" | "exit_group")) {
+            return self.push(
+                vcpu_index,
+                Record {
+                    vcpu: vcpu_index,
+                    timestamp: pending.timestamp,
+                    number: syscall.number(),
+                    name: name(&syscall),
+                    args: pending.args,
+                    ret: None,
+                    errno: None,
+                    duration: None,
+                    result: "?".to_string(),
+                },
+            );
+        }
+
+        self.pending.insert(vcpu_index, pending);
+
+        Ok(())
+    }
+
+    fn on_syscall_return(
+        &mut self,
+        _id: PluginId,
+        vcpu_index: VCPUIndex,
+        num: i64,
+        ret: i64,
+    ) -> Result<()> {
+        let Some(pending) = self.pending.remove(&vcpu_index) else {
+            return Ok(());
+        };
+
+        let syscall = pending.syscall;
+
+        if syscall.number() != num {
+            return Err(anyhow!(
+                "Syscall {num} returned on vCPU {vcpu_index}, but syscall {} was made",
+                syscall.number()
+            )
+            .into());
+        }
+
+        #[cfg(not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2",
+            feature = "plugin-api-v3"
+        )))]
+        let args = self.returned_args(&syscall, pending.args, ret);
+        #[cfg(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2",
+            feature = "plugin-api-v3"
+        ))]
+        let args = pending.args;
+
+        let errno = syscall.errno(ret).map(|errno| {
+            syscall
+                .table()
+                .and_then(|table| table.errno_name(errno))
+                .map_or_else(|| errno.to_string(), str::to_string)
+        });
+
+        self.push(
+            vcpu_index,
+            Record {
+                vcpu: vcpu_index,
+                timestamp: pending.timestamp,
+                number: num,
+                name: name(&syscall),
+                args,
+                ret: Some(ret),
+                errno,
+                duration: Some(pending.start.elapsed().as_secs_f64()),
+                result: syscall.render_return(ret),
+            },
+        )
+    }
+}
+
+register!(Strace::new());
+
+#[cfg(test)]
+mod tests {
+    use qemu_plugin_mock::{MockQemu, MockQemuBuilder, TempFile};
+    use serde_json::Value;
+
+    const READ: i64 = 0;
+    const WRITE: i64 = 1;
+    const GETPID: i64 = 39;
+    const EXIT_GROUP: i64 = 231;
+    const OPENAT: i64 = 257;
+
+    /// A mock x86_64 user mode host writing records to `output`
+    fn strace(output: &TempFile) -> MockQemuBuilder {
+        MockQemu::user("x86_64")
+            .plugin(super::Strace::new())
+            .arg(output.arg("output"))
+    }
+
+    /// Exit the emulator, then read the text records written to `output` without their
+    /// timestamps and durations
+    fn text(qemu: MockQemu, output: &TempFile) -> Vec<String> {
+        qemu.exit();
+        let records = output.read_to_string().unwrap();
+
+        records
+            .lines()
+            .map(|line| {
+                let (vcpu, rest) = line.split_once("] ").unwrap();
+                let (_timestamp, rest) = rest.split_once(' ').unwrap();
+                let rest = rest.rsplit_once(" <").map_or(rest, |(rest, _)| rest);
+                format!("{vcpu}] {rest}")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn text_records_every_syscall() {
+        let output = TempFile::new("text");
+        let mut qemu = strace(&output).install().unwrap();
+
+        qemu.syscall(0, GETPID, [0; 8]);
+        qemu.syscall_return(0, GETPID, 1234);
+        qemu.syscall(1, EXIT_GROUP, [3, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(
+            text(qemu, &output),
+            ["[vcpu 0] getpid() = 1234", "[vcpu 1] exit_group(3) = ?"]
+        );
+    }
+
+    #[test]
+    fn text_renders_errno() {
+        let output = TempFile::new("errno");
+        let mut qemu = strace(&output).install().unwrap();
+
+        qemu.syscall(0, GETPID, [0; 8]);
+        qemu.syscall_return(0, GETPID, -2);
+
+        assert_eq!(text(qemu, &output), ["[vcpu 0] getpid() = -1 ENOENT"]);
+    }
+
+    #[test]
+    fn filter_and_exclude_select_syscalls() {
+        let output = TempFile::new("filter");
+        let mut qemu = strace(&output)
+            .args(["filter=getpid:read", "filter=write", "exclude=read"])
+            .install()
+            .unwrap();
+
+        for num in [READ, WRITE, GETPID, OPENAT] {
+            qemu.syscall(0, num, [0; 8]);
+            qemu.syscall_return(0, num, 0);
+        }
+
+        assert_eq!(
+            text(qemu, &output),
+            ["[vcpu 0] write(0, NULL, 0) = 0", "[vcpu 0] getpid() = 0"]
+        );
+    }
+
+    #[test]
+    fn unknown_syscalls_are_rejected() {
+        let output = TempFile::new("unknown");
+
+        assert!(
+            strace(&output)
+                .arg("filter=getpid:nosuchcall")
+                .install()
+                .is_err()
+        );
+        assert!(strace(&output).arg("exclude=nosuchcall").install().is_err());
+    }
+
+    #[test]
+    fn json_records_every_field() {
+        let output = TempFile::new("json");
+        let mut qemu = strace(&output).arg("format=json").install().unwrap();
+
+        qemu.syscall(0, GETPID, [0; 8]);
+        qemu.syscall_return(0, GETPID, -2);
+        qemu.syscall(0, EXIT_GROUP, [0; 8]);
+        qemu.exit();
+
+        let records = output.read_to_string().unwrap();
+        let records = records
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["vcpu"], 0);
+        assert_eq!(records[0]["number"], GETPID);
+        assert_eq!(records[0]["name"], "getpid");
+        assert_eq!(records[0]["args"], Value::Array(Vec::new()));
+        assert_eq!(records[0]["return"], -2);
+        assert_eq!(records[0]["errno"], "ENOENT");
+        assert!(records[0]["duration"].is_f64());
+        assert_eq!(records[1]["name"], "exit_group");
+        assert_eq!(records[1]["return"], Value::Null);
+        assert_eq!(records[1]["duration"], Value::Null);
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    #[test]
+    fn buffers_are_read_from_guest_memory() {
+        let output = TempFile::new("buffers");
+        let mut qemu = strace(&output).install().unwrap();
+        qemu.map_memory(0x10000, 0x1000);
+
+        // Input buffers are read as the syscall is made
+        qemu.write_memory(0x10000, b"hi\n").unwrap();
+        qemu.syscall(0, WRITE, [1, 0x10000, 3, 0, 0, 0, 0, 0]);
+        qemu.write_memory(0x10000, b"xx\n").unwrap();
+        qemu.syscall_return(0, WRITE, 3);
+
+        // Output buffers are read once the syscall returns, up to the returned length
+        qemu.syscall(0, READ, [0, 0x10100, 16, 0, 0, 0, 0, 0]);
+        qemu.write_memory(0x10100, b"hello, world").unwrap();
+        qemu.syscall_return(0, READ, 5);
+
+        assert_eq!(
+            text(qemu, &output),
+            [
+                "[vcpu 0] write(1, \"hi\\n\", 3) = 3",
+                "[vcpu 0] read(0, \"hello\", 16) = 5",
+            ]
+        );
+    }
+}
@@ -5,16 +5,17 @@ use anyhow::anyhow;
     feature = "plugin-api-v2",
     feature = "plugin-api-v3"
 )))]
-use qemu_plugin::qemu_plugin_read_memory_vaddr;
+use qemu_plugin::{ArgKind, Endian, GuestMemory, Syscall};
 use qemu_plugin::{
-    Args, Error, HasCallbacks, Info, Instruction, MemRW, MemoryInfo, PluginId, Register, Result,
-    TranslationBlock, VCPUIndex, Value, register,
+    Args, Error, EventSink, HasCallbacks, Info, Instruction, MemAccess, MemRW, PluginArgs as _,
+    PluginId, Register, Result, Target, TranslationBlock, VCPUIndex, register,
 };
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
-use qemu_plugin::{RegisterDescriptor, qemu_plugin_get_registers};
+use qemu_plugin::{RegisterDescriptor, RegisterValues, qemu_plugin_get_registers};
 use serde_cbor::to_writer;
 use std::{
     collections::HashMap,
+    io::{BufWriter, Write},
     os::unix::net::UnixStream,
     path::PathBuf,
     sync::{Arc, Mutex},
@@ -23,6 +24,13 @@ use tracer_events::{Event, InstructionEvent, MemoryEvent, SyscallEvent, SyscallS
 use typed_builder::TypedBuilder;
 use yaxpeax_x86::amd64::InstDecoder;
 
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+thread_local! {
+    /// The buffers register values are read into on this vCPU thread, reused by each read
+    static REGISTER_VALUES: std::cell::RefCell<RegisterValues> =
+        std::cell::RefCell::new(RegisterValues::new());
+}
+
 trait FromInstruction {
     fn from_instruction(ins: &Instruction) -> Result<Self>
     where
@@ -37,55 +45,73 @@ impl FromInstruction for InstructionEvent {
             .decode_slice(&data)
             .map(|d| d.to_string())
             .or_else(|_| value.disas())?;
+        #[cfg(not(feature = "plugin-api-v0"))]
+        let symbol = value.symbol()?;
+        // NOTE: The v0 API has no symbol lookup
+        #[cfg(feature = "plugin-api-v0")]
+        let symbol = None;
 
         Ok(Self::builder()
             .vaddr(value.vaddr())
             .haddr(value.haddr())
             .disas(disas)
-            .symbol(value.symbol()?)
+            .symbol(symbol)
             .data(data)
             .build())
     }
 }
 
-trait FromMemoryInfoVaddr {
-    fn from_memory_info_vaddr(info: &MemoryInfo, vaddr: u64) -> Result<Self>
-    where
-        Self: Sized;
+trait FromMemAccess {
+    fn from_mem_access(access: &MemAccess) -> Self;
 }
 
-impl FromMemoryInfoVaddr for MemoryEvent {
-    fn from_memory_info_vaddr(value: &MemoryInfo, vaddr: u64) -> Result<Self> {
-        let haddr = value.hwaddr(vaddr);
-        Ok(Self::builder()
-            .vaddr(vaddr)
-            .haddr(haddr.as_ref().map(|h| h.hwaddr()))
-            .haddr_is_io(haddr.as_ref().map(|h| h.is_io()))
-            .haddr_device_name(haddr.and_then(|h| h.device_name().ok().flatten()))
-            .size_shift(value.size_shift())
-            .size_bytes(match value.size_shift() {
-                0 => 1,
-                1 => 2,
-                2 => 4,
-                3 => 8,
-                _ => 0,
-            })
+impl FromMemAccess for MemoryEvent {
+    fn from_mem_access(value: &MemAccess) -> Self {
+        #[cfg(not(feature = "plugin-api-v0"))]
+        let (haddr, haddr_is_io, haddr_device_name) = {
+            let haddr = value.hwaddr();
+            (
+                haddr.as_ref().map(|h| h.hwaddr()),
+                haddr.as_ref().map(|h| h.is_io()),
+                haddr.and_then(|h| h.device_name().ok().flatten()),
+            )
+        };
+        // NOTE: The v0 API exposes no physical address details
+        #[cfg(feature = "plugin-api-v0")]
+        let (haddr, haddr_is_io, haddr_device_name) = (None, None, None);
+
+        Self::builder()
+            .vaddr(value.vaddr())
+            .haddr(haddr)
+            .haddr_is_io(haddr_is_io)
+            .haddr_device_name(haddr_device_name)
+            .size_shift(value.size().trailing_zeros() as usize)
+            .size_bytes(value.size())
             .sign_extended(value.sign_extended())
             .is_store(value.is_store())
             .big_endian(value.big_endian())
-            .build())
+            .build()
     }
 }
 
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+/// The default number of bytes of each syscall buffer to log
+const DEFAULT_BUFFER_LIMIT: usize = 4096;
+
 #[derive(TypedBuilder, Clone, Debug)]
 struct Tracer {
     #[builder(default)]
-    pub target_name: Option<String>,
+    pub target: Option<Target>,
     pub syscalls: Arc<Mutex<HashMap<SyscallSource, SyscallEvent>>>,
     #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
     pub registers: Arc<Mutex<Vec<RegisterDescriptor<'static>>>>,
     #[builder(default)]
-    pub tx: Arc<Mutex<Option<UnixStream>>>,
+    pub events: Option<EventSink<Event>>,
     #[builder(default)]
     pub log_insns: bool,
     #[builder(default)]
@@ -95,6 +121,14 @@ struct Tracer {
     #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
     #[builder(default)]
     pub log_registers: bool,
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    #[builder(default = DEFAULT_BUFFER_LIMIT)]
+    pub buffer_limit: usize,
 }
 
 impl Tracer {
@@ -113,8 +147,26 @@ impl Tracer {
                 .build()
         }
     }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// Read the syscall buffer of `len` bytes at `addr`. The length is controlled by the
+    /// guest, so it is capped, and only the bytes before the first page which can not be
+    /// read are logged, so an unreadable buffer is logged empty rather than failing.
+    fn read_buffer(&self, addr: u64, len: usize) -> Vec<u8> {
+        let endian = self.target.map_or(Endian::Little, |target| target.endian());
+        let mut buffer = vec![0; len.min(self.buffer_limit)];
+        let read = GuestMemory::new(endian).read_partial(addr, &mut buffer);
+        buffer.truncate(read);
+        buffer
+    }
 }
 
+#[qemu_plugin::callbacks]
 impl HasCallbacks for Tracer {
     #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
     fn on_vcpu_init(&mut self, _id: PluginId, _vcpu_id: VCPUIndex) -> Result<()> {
@@ -136,22 +188,19 @@ impl HasCallbacks for Tracer {
 
             #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
             if self.log_insns {
-                let tx = self.tx.clone();
-
-                insn.register_execute_callback(move |_| {
-                    tx.lock()
-                        .map_err(|e| anyhow!("Failed to lock tx: {}", e))
-                        .and_then(|tx| {
-                            to_writer(
-                                tx.as_ref().ok_or_else(|| anyhow!("No tx"))?,
-                                &Event::Instruction {
-                                    event: event.clone(),
-                                    registers: Default::default(),
-                                },
-                            )
-                            .map_err(|e| anyhow!(e))
-                        })
-                        .expect("Failed to send instruction event");
+                let events = self
+                    .events
+                    .clone()
+                    .ok_or_else(|| anyhow!("No event sink"))?;
+
+                insn.register_execute_callback(move |vcpu_index| {
+                    events.push(
+                        vcpu_index,
+                        Event::Instruction {
+                            event: event.clone(),
+                            registers: Default::default(),
+                        },
+                    );
                 });
             }
 
@@ -159,7 +208,10 @@ impl HasCallbacks for Tracer {
             if self.log_insns {
                 use qemu_plugin::CallbackFlags;
 
-                let tx = self.tx.clone();
+                let events = self
+                    .events
+                    .clone()
+                    .ok_or_else(|| anyhow!("No event sink"))?;
                 let registers = self
                     .registers
                     .lock()
@@ -167,52 +219,49 @@ impl HasCallbacks for Tracer {
                     .clone();
 
                 insn.register_execute_callback_flags(
-                    move |_| {
-                        tx.lock()
-                            .map_err(|e| anyhow!("Failed to lock tx: {}", e))
-                            .and_then(|tx| {
-                                use tracer_events::{Event, Registers};
-
-                                to_writer(
-                                    tx.as_ref().ok_or_else(|| anyhow!("No tx"))?,
-                                    &Event::Instruction {
-                                        event: event.clone(),
-                                        registers: Registers(
-                                            registers
-                                                .iter()
-                                                .map(|r| {
-                                                    let value = r.read().unwrap_or_else(|_| vec![]);
-                                                    (r.name.clone(), value)
-                                                })
-                                                .collect(),
-                                        ),
-                                    },
-                                )
-                                .map_err(|e| anyhow!(e))
-                            })
-                            .expect("Failed to send instruction event");
+                    move |vcpu_index| {
+                        use tracer_events::Registers;
+
+                        // NOTE: Callbacks run concurrently on every vCPU thread, so each
+                        // thread reads into its own buffers
+                        let registers = REGISTER_VALUES.with_borrow_mut(|values| {
+                            // NOTE: Registers which fail to read are logged with empty values
+                            let _ = RegisterDescriptor::read_many(&registers, values);
+
+                            Registers(
+                                registers
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(index, r)| {
+                                        let value = values.get(index).unwrap_or_default();
+                                        (r.name.clone(), value.to_vec())
+                                    })
+                                    .collect(),
+                            )
+                        });
+
+                        events.push(
+                            vcpu_index,
+                            Event::Instruction {
+                                event: event.clone(),
+                                registers,
+                            },
+                        );
                     },
                     CallbackFlags::QEMU_PLUGIN_CB_R_REGS,
                 );
             }
 
             if self.log_mem {
-                let tx = self.tx.clone();
-
-                insn.register_memory_access_callback(
-                    move |_, info, vaddr| {
-                        tx.lock()
-                            .map_err(|e| anyhow!("Failed to lock tx: {}", e))
-                            .and_then(|tx| {
-                                to_writer(
-                                    tx.as_ref().ok_or_else(|| anyhow!("No tx"))?,
-                                    &Event::Memory(MemoryEvent::from_memory_info_vaddr(
-                                        &info, vaddr,
-                                    )?),
-                                )
-                                .map_err(|e| anyhow!(e))
-                            })
-                            .expect("Failed to send memory event");
+                let events = self
+                    .events
+                    .clone()
+                    .ok_or_else(|| anyhow!("No event sink"))?;
+
+                insn.register_access_callback(
+                    move |vcpu_index, access| {
+                        let event = MemoryEvent::from_mem_access(&access);
+                        events.push(vcpu_index, Event::Memory(event));
                     },
                     MemRW::QEMU_PLUGIN_MEM_RW,
                 );
@@ -261,30 +310,23 @@ impl HasCallbacks for Tracer {
             feature = "plugin-api-v3"
         )))]
         let event = {
-            let buffers = if let Some(write_sysno) = match self.target_name.as_deref() {
-                Some("i386") => Some(4),
-                Some("x86_64") => Some(1),
-                Some("arm") => Some(4),
-                Some("aarch64") => Some(64),
-                _ => None,
-            } {
-                if num == write_sysno {
-                    let addr = a2;
-                    let len = a3 as usize;
-                    let mut buffer = vec![0; len];
-                    qemu_plugin_read_memory_vaddr(addr, &mut buffer)?;
-                    [(1, buffer)].into_iter().collect::<HashMap<_, _>>()
-                } else {
-                    Default::default()
+            let args = [a1, a2, a3, a4, a5, a6, a7, a8];
+            let mut buffers = HashMap::new();
+
+            // Input buffers are read on entry, output buffers once the syscall returns
+            for (index, (kind, addr)) in Syscall::with_target(self.target, num, args)
+                .arguments()
+                .enumerate()
+            {
+                if let ArgKind::Buf { len, output: false } = kind {
+                    buffers.insert(index, self.read_buffer(addr, args[len] as usize));
                 }
-            } else {
-                Default::default()
-            };
+            }
 
             SyscallEvent::builder()
                 .num(num)
                 .return_value(-1)
-                .args([a1, a2, a3, a4, a5, a6, a7, a8])
+                .args(args)
                 .buffers(buffers)
                 .build()
         };
@@ -339,19 +381,16 @@ impl HasCallbacks for Tracer {
             feature = "plugin-api-v3"
         )))]
         {
-            if let Some(read_sysno) = match self.target_name.as_deref() {
-                Some("i386") => Some(3),
-                Some("x86_64") => Some(0),
-                Some("arm") => Some(3),
-                Some("aarch64") => Some(63),
-                _ => None,
-            } && num == read_sysno
-            {
-                let addr = event.args[1];
-                let len = event.args[2] as usize;
-                let mut buffer = vec![0; len];
-                qemu_plugin_read_memory_vaddr(addr, &mut buffer)?;
-                event.buffers.insert(1, buffer);
+            let syscall = Syscall::with_target(self.target, num, event.args);
+
+            for (index, (kind, addr)) in syscall.arguments().enumerate() {
+                if let ArgKind::Buf { output: true, .. } = kind
+                    && ret >= 0
+                {
+                    event
+                        .buffers
+                        .insert(index, self.read_buffer(addr, ret as usize));
+                }
             }
         }
 
@@ -359,129 +398,59 @@ impl HasCallbacks for Tracer {
         event.return_value = ret;
 
         // Send the event
-        let tx = self
-            .tx
-            .lock()
-            .map_err(|e| anyhow!("Failed to lock tx: {e}"))?;
-        let tx_stream = tx.as_ref().ok_or_else(|| anyhow!("No tx"))?;
-
-        to_writer(tx_stream, &Event::Syscall(event)).map_err(|e| anyhow!(e))?;
+        self.events
+            .as_ref()
+            .ok_or_else(|| anyhow!("No event sink"))?
+            .push(vcpu_index, Event::Syscall(event));
 
         Ok(())
     }
 }
 
-#[derive(TypedBuilder, Clone, Debug)]
+#[derive(TypedBuilder, qemu_plugin::PluginArgs, Clone, Debug)]
 pub struct PluginArgs {
+    /// Log executed instructions
     pub log_insns: bool,
+    /// Log memory accesses
     pub log_mem: bool,
+    /// Log syscalls
     pub log_syscalls: bool,
     #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Log register values with each executed instruction
     pub log_registers: bool,
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// The maximum number of bytes of each syscall buffer to log
+    #[arg(default = DEFAULT_BUFFER_LIMIT)]
+    #[builder(default = DEFAULT_BUFFER_LIMIT)]
+    pub buffer_limit: usize,
+    /// The socket to send events to
     pub socket_path: PathBuf,
 }
 
-impl TryFrom<&Args> for PluginArgs {
-    type Error = Error;
-
-    fn try_from(value: &Args) -> Result<Self> {
-        #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
-        {
-            Ok(Self::builder()
-                .log_insns(
-                    value
-                        .parsed
-                        .get("log_insns")
-                        .map(|li| if let Value::Bool(v) = li { *v } else { false })
-                        .unwrap_or_default(),
-                )
-                .log_mem(
-                    value
-                        .parsed
-                        .get("log_mem")
-                        .map(|lm| if let Value::Bool(v) = lm { *v } else { false })
-                        .unwrap_or_default(),
-                )
-                .log_syscalls(
-                    value
-                        .parsed
-                        .get("log_syscalls")
-                        .map(|ls| if let Value::Bool(v) = ls { *v } else { false })
-                        .unwrap_or_default(),
-                )
-                .socket_path(
-                    value
-                        .parsed
-                        .get("socket_path")
-                        .and_then(|sp| {
-                            if let Value::String(v) = sp {
-                                Some(PathBuf::from(v))
-                            } else {
-                                None
-                            }
-                        })
-                        .ok_or_else(|| anyhow!("No socket path provided"))?,
-                )
-                .build())
-        }
-        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
-        {
-            Ok(Self::builder()
-                .log_insns(
-                    value
-                        .parsed
-                        .get("log_insns")
-                        .map(|li| if let Value::Bool(v) = li { *v } else { false })
-                        .unwrap_or_default(),
-                )
-                .log_mem(
-                    value
-                        .parsed
-                        .get("log_mem")
-                        .map(|lm| if let Value::Bool(v) = lm { *v } else { false })
-                        .unwrap_or_default(),
-                )
-                .log_syscalls(
-                    value
-                        .parsed
-                        .get("log_syscalls")
-                        .map(|ls| if let Value::Bool(v) = ls { *v } else { false })
-                        .unwrap_or_default(),
-                )
-                .log_registers(
-                    value
-                        .parsed
-                        .get("log_registers")
-                        .map(|lr| if let Value::Bool(v) = lr { *v } else { false })
-                        .unwrap_or_default(),
-                )
-                .socket_path(
-                    value
-                        .parsed
-                        .get("socket_path")
-                        .and_then(|sp| {
-                            if let Value::String(v) = sp {
-                                Some(PathBuf::from(v))
-                            } else {
-                                None
-                            }
-                        })
-                        .ok_or_else(|| anyhow!("No socket path provided"))?,
-                )
-                .build())
-        }
-    }
-}
-
 impl Register for Tracer {
-    fn register(&mut self, _: PluginId, args: &Args, info: &Info) -> Result<()> {
-        let plugin_args = PluginArgs::try_from(args)?;
+    fn register(&mut self, id: PluginId, args: &Args, info: &Info) -> Result<()> {
+        let plugin_args = PluginArgs::parse(args)?;
+
+        self.target = info.target;
 
-        self.target_name = Some(info.target_name.clone());
+        let mut stream = BufWriter::new(UnixStream::connect(plugin_args.socket_path)?);
 
-        self.tx = Arc::new(Mutex::new(Some(UnixStream::connect(
-            plugin_args.socket_path,
-        )?)));
+        // Serialize events on the sink's writer thread rather than in the callbacks
+        let events = EventSink::builder().build(move |_, batch: &[Event]| {
+            batch
+                .iter()
+                .try_for_each(|event| to_writer(&mut stream, event))
+                .map_err(|e| anyhow!(e))?;
+            stream.flush()?;
+            Ok(())
+        })?;
+        events.close_at_exit(id)?;
+        self.events = Some(events);
 
         self.log_insns = plugin_args.log_insns;
         self.log_mem = plugin_args.log_mem;
@@ -492,6 +461,16 @@ impl Register for Tracer {
             self.log_registers = plugin_args.log_registers;
         }
 
+        #[cfg(not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2",
+            feature = "plugin-api-v3"
+        )))]
+        {
+            self.buffer_limit = plugin_args.buffer_limit;
+        }
+
         Ok(())
     }
 }
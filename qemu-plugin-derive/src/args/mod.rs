@@ -0,0 +1,224 @@
+//! Implementation of `#[derive(PluginArgs)]`
+
+use crate::attrs::{ArgAttrs, cfgs, doc, snake_case};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Error, Expr, ExprLit, Fields, GenericArgument, Lit, PathArguments, Result,
+    Type,
+};
+
+/// How a field is filled from the arguments
+enum Kind {
+    /// The argument must be given exactly once
+    Required,
+    /// The argument may be given at most once, and the field is an `Option`
+    Optional,
+    /// The argument may be given any number of times, and the field is a `Vec`
+    Multiple,
+    /// The argument may be given at most once, and the expression is used otherwise
+    Default(TokenStream),
+}
+
+/// If `ty` is `wrapper<T>`, return `T`
+fn unwrap<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+
+    if path.qself.is_some() {
+        return None;
+    }
+
+    let segment = path.path.segments.last()?;
+
+    if segment.ident != wrapper {
+        return None;
+    }
+
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+
+    match args.args.first() {
+        Some(GenericArgument::Type(inner)) if args.args.len() == 1 => Some(inner),
+        _ => None,
+    }
+}
+
+/// A displayable form of a default expression for the usage string. Literals are shown
+/// as their value and enum variants as the value they are parsed from. Other expressions
+/// are not shown.
+fn display_default(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Lit(ExprLit { lit, .. }) => match lit {
+            Lit::Str(s) => Some(s.value()),
+            Lit::Int(i) => Some(i.base10_digits().to_string()),
+            Lit::Bool(b) => Some(if b.value { "on" } else { "off" }.to_string()),
+            _ => None,
+        },
+        Expr::Path(path) if path.path.segments.len() > 1 => {
+            let ident = path.path.segments.last()?.ident.to_string();
+            let upper_camel = ident.starts_with(|c: char| c.is_uppercase())
+                && ident.chars().any(|c| c.is_lowercase());
+
+            upper_camel.then(|| snake_case(&ident))
+        }
+        _ => None,
+    }
+}
+
+/// Derive `PluginArgs` for a struct with named fields
+pub(crate) fn derive(input: DeriveInput) -> Result<TokenStream> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "PluginArgs can only be derived for structs",
+        ));
+    };
+
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "PluginArgs can only be derived for structs with named fields",
+        ));
+    };
+
+    let mut locals = Vec::new();
+    let mut arms = Vec::new();
+    let mut inits = Vec::new();
+    let mut specs = Vec::new();
+
+    for (index, field) in fields.named.iter().enumerate() {
+        let attrs = ArgAttrs::parse(&field.attrs)?;
+        let ident = field
+            .ident
+            .as_ref()
+            .ok_or_else(|| Error::new_spanned(field, "expected a named field"))?;
+        let key = attrs.name.map(|name| name.value()).unwrap_or_else(|| {
+            let ident = ident.to_string();
+            ident.strip_prefix("r#").unwrap_or(&ident).to_string()
+        });
+        let local = format_ident!("__arg_{}", index);
+        let cfgs = cfgs(&field.attrs);
+        let description = doc(&field.attrs);
+        let ty = &field.ty;
+
+        let (kind, value_ty, display) =
+            match (attrs.default, unwrap(ty, "Option"), unwrap(ty, "Vec")) {
+                (Some(Some(expr)), _, _) => {
+                    (Kind::Default(quote!(#expr)), ty, display_default(&expr))
+                }
+                (Some(None), _, _) => (
+                    Kind::Default(quote!(::std::default::Default::default())),
+                    ty,
+                    None,
+                ),
+                (None, Some(inner), _) => (Kind::Optional, inner, None),
+                (None, None, Some(inner)) => (Kind::Multiple, inner, None),
+                (None, None, None) if matches!(ty, Type::Path(p) if p.path.is_ident("bool")) => {
+                    (Kind::Default(quote!(false)), ty, Some("off".to_string()))
+                }
+                (None, None, None) => (Kind::Required, ty, None),
+            };
+
+        let parse = quote! {
+            <#value_ty as ::qemu_plugin::FromArgValue>::from_arg(key, value)?
+        };
+
+        if let Kind::Multiple = kind {
+            locals.push(quote! {
+                #(#cfgs)*
+                let mut #local: ::std::vec::Vec<#value_ty> = ::std::vec::Vec::new();
+            });
+            arms.push(quote! {
+                #(#cfgs)*
+                #key => #local.push(#parse),
+            });
+        } else {
+            locals.push(quote! {
+                #(#cfgs)*
+                let mut #local: ::std::option::Option<#value_ty> = ::std::option::Option::None;
+            });
+            arms.push(quote! {
+                #(#cfgs)*
+                #key => {
+                    if #local.is_some() {
+                        return ::std::result::Result::Err(::qemu_plugin::Error::DuplicateArg {
+                            key: key.to_string(),
+                        });
+                    }
+                    #local = ::std::option::Option::Some(#parse);
+                }
+            });
+        }
+
+        let init = match &kind {
+            Kind::Required => quote! {
+                #local.ok_or_else(|| ::qemu_plugin::Error::MissingArg {
+                    key: #key.to_string(),
+                })?
+            },
+            Kind::Optional | Kind::Multiple => quote!(#local),
+            Kind::Default(default) => quote!(#local.unwrap_or_else(|| #default)),
+        };
+
+        inits.push(quote! {
+            #(#cfgs)*
+            #ident: #init,
+        });
+
+        let required = matches!(kind, Kind::Required);
+        let multiple = matches!(kind, Kind::Multiple);
+        let default = match display {
+            Some(display) => quote!(::std::option::Option::Some(#display)),
+            None => quote!(::std::option::Option::None),
+        };
+
+        specs.push(quote! {
+            #(#cfgs)*
+            specs.push(::qemu_plugin::ArgSpec {
+                key: #key,
+                expected: <#value_ty as ::qemu_plugin::FromArgValue>::expected(),
+                description: #description,
+                required: #required,
+                multiple: #multiple,
+                default: #default,
+            });
+        });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::qemu_plugin::PluginArgs for #ident #ty_generics #where_clause {
+            fn from_args(
+                args: &::qemu_plugin::Args,
+            ) -> ::std::result::Result<Self, ::qemu_plugin::Error> {
+                #(#locals)*
+
+                for (key, value) in args.pairs() {
+                    match key {
+                        #(#arms)*
+                        _ => {
+                            return ::std::result::Result::Err(::qemu_plugin::Error::UnknownArg {
+                                key: key.to_string(),
+                            });
+                        }
+                    }
+                }
+
+                ::std::result::Result::Ok(Self {
+                    #(#inits)*
+                })
+            }
+
+            fn specs() -> ::std::vec::Vec<::qemu_plugin::ArgSpec> {
+                let mut specs = ::std::vec::Vec::new();
+                #(#specs)*
+                specs
+            }
+        }
+    })
+}
@@ -0,0 +1,91 @@
+//! Parsing of the attributes shared by the derive macros
+
+use syn::{Attribute, Expr, ExprLit, Lit, LitStr, Meta, Result};
+
+/// The arguments of the `#[arg(...)]` attributes on a field or variant
+#[derive(Default)]
+pub(crate) struct ArgAttrs {
+    /// The argument name given with `name = "..."`
+    pub(crate) name: Option<LitStr>,
+    /// The default given with `default` (as `None`) or `default = expr`
+    pub(crate) default: Option<Option<Expr>>,
+}
+
+impl ArgAttrs {
+    /// Parse every `#[arg(...)]` attribute in `attrs`
+    pub(crate) fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut parsed = Self::default();
+
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("arg")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    parsed.name = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("default") {
+                    parsed.default = Some(if meta.input.peek(syn::Token![=]) {
+                        Some(meta.value()?.parse()?)
+                    } else {
+                        None
+                    });
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `name` or `default`"))
+                }
+            })?;
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// The `#[cfg(...)]` attributes in `attrs`, which must be repeated on everything
+/// generated for the field or variant they are attached to
+pub(crate) fn cfgs(attrs: &[Attribute]) -> Vec<&Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cfg"))
+        .collect()
+}
+
+/// The first paragraph of the documentation in `attrs`, joined into a single line
+pub(crate) fn doc(attrs: &[Attribute]) -> String {
+    attrs
+        .iter()
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(nv) if nv.path.is_ident("doc") => match &nv.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(s), ..
+                }) => Some(s.value()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(|line| line.trim().to_string())
+        .take_while(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Convert an `UpperCamelCase` identifier to `snake_case`
+pub(crate) fn snake_case(ident: &str) -> String {
+    let chars = ident.chars().collect::<Vec<_>>();
+    let mut snake = String::new();
+
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let previous = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+
+            if previous.is_lowercase()
+                || previous.is_ascii_digit()
+                || (previous.is_uppercase() && next_lower)
+            {
+                snake.push('_');
+            }
+        }
+
+        snake.extend(c.to_lowercase());
+    }
+
+    snake
+}
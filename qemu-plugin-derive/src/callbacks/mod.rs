@@ -0,0 +1,74 @@
+//! Implementation of `#[callbacks]`
+
+use crate::attrs::cfgs;
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{Error, ImplItem, ItemImpl, Result, parse_quote};
+
+/// The callback methods of `HasCallbacks` and `HasCallbacksSync`, and the event each
+/// one handles
+const EVENTS: &[(&str, &str)] = &[
+    ("on_vcpu_init", "VCPU_INIT"),
+    ("on_vcpu_exit", "VCPU_EXIT"),
+    ("on_vcpu_idle", "VCPU_IDLE"),
+    ("on_vcpu_resume", "VCPU_RESUME"),
+    (
+        "on_translation_block_translate",
+        "TRANSLATION_BLOCK_TRANSLATE",
+    ),
+    ("on_flush", "FLUSH"),
+    ("on_syscall", "SYSCALL"),
+    ("on_syscall_typed", "SYSCALL"),
+    ("on_syscall_return", "SYSCALL_RETURN"),
+];
+
+/// Add a `callbacks` method to an implementation of `HasCallbacks` or `HasCallbacksSync`
+/// which returns the events whose callbacks the implementation overrides
+pub(crate) fn expand(mut item: ItemImpl) -> Result<TokenStream> {
+    if item.trait_.is_none() {
+        return Err(Error::new_spanned(
+            &item.self_ty,
+            "#[callbacks] must be used on an implementation of `HasCallbacks` or `HasCallbacksSync`",
+        ));
+    }
+
+    let mut events = Vec::new();
+
+    for impl_item in &item.items {
+        let ImplItem::Fn(method) = impl_item else {
+            continue;
+        };
+
+        let name = method.sig.ident.to_string();
+
+        if name == "callbacks" {
+            return Err(Error::new_spanned(
+                &method.sig.ident,
+                "#[callbacks] generates `callbacks`, so it must not be implemented",
+            ));
+        }
+
+        if let Some((_, event)) = EVENTS.iter().find(|(method, _)| *method == name) {
+            let event = format_ident!("{}", event);
+            let cfgs = cfgs(&method.attrs);
+
+            events.push(quote! {
+                #(#cfgs)*
+                {
+                    callbacks |= ::qemu_plugin::Callbacks::#event;
+                }
+            });
+        }
+    }
+
+    item.items.push(parse_quote! {
+        fn callbacks(&self) -> ::qemu_plugin::Callbacks {
+            #[allow(unused_mut)]
+            let mut callbacks = ::qemu_plugin::Callbacks::NONE;
+            #(#events)*
+            callbacks
+        }
+    });
+
+    Ok(quote!(#item))
+}
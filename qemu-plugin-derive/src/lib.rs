@@ -0,0 +1,87 @@
+//! Derive macros for the `qemu-plugin` crate
+//!
+//! These macros are re-exported by `qemu-plugin` when its `derive` feature is enabled, and
+//! should be used through it rather than by depending on this crate directly.
+
+#![deny(missing_docs)]
+
+use proc_macro::TokenStream;
+use syn::{DeriveInput, ItemImpl, parse_macro_input};
+
+mod args;
+mod attrs;
+mod callbacks;
+mod value;
+
+#[proc_macro_derive(PluginArgs, attributes(arg))]
+/// Derive `qemu_plugin::PluginArgs` for a struct with named fields, parsing each field
+/// from the plugin argument with the same name.
+///
+/// Each field's type must implement `qemu_plugin::FromArgValue`, with the following
+/// exceptions, which change how the field is parsed:
+///
+/// - `Option<T>`: The argument is optional, and the field is `None` if it is not given
+/// - `Vec<T>`: The argument may be given any number of times, and the field holds each
+///   value in order
+/// - `bool`: The argument is optional, and defaults to `false`
+///
+/// Every other argument is required unless it has a default. Arguments which are given
+/// but do not match any field are rejected. The documentation of each field is used to
+/// describe it in the generated usage string.
+///
+/// Fields accept the following attributes:
+///
+/// - `#[arg(name = "key")]`: Parse the field from the argument `key` instead of the
+///   field's name
+/// - `#[arg(default)]`: Use `Default::default()` if the argument is not given
+/// - `#[arg(default = expr)]`: Use `expr` if the argument is not given
+pub fn derive_plugin_args(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    args::derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(FromArgValue, attributes(arg))]
+/// Derive `qemu_plugin::FromArgValue` for an enum whose variants have no fields. Each
+/// variant is parsed from its name in `snake_case`, or from the name given with
+/// `#[arg(name = "value")]`.
+pub fn derive_from_arg_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    value::derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_attribute]
+/// Implement `callbacks` for an implementation of `qemu_plugin::HasCallbacks` or
+/// `qemu_plugin::HasCallbacksSync`, returning the events whose callbacks are overridden
+/// in the implementation. QEMU then only calls into the plugin for those events.
+///
+/// ```ignore
+/// #[qemu_plugin::callbacks]
+/// impl HasCallbacks for MyPlugin {
+///     // Only the vCPU initialization callback is registered
+///     fn on_vcpu_init(&mut self, id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+///         Ok(())
+///     }
+/// }
+/// ```
+pub fn callbacks(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "#[callbacks] does not take arguments",
+        )
+        .into_compile_error()
+        .into();
+    }
+
+    let item = parse_macro_input!(item as ItemImpl);
+
+    callbacks::expand(item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,80 @@
+//! Implementation of `#[derive(FromArgValue)]`
+
+use crate::attrs::{ArgAttrs, cfgs, snake_case};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Error, Fields, Result};
+
+/// Derive `FromArgValue` for an enum whose variants have no fields
+pub(crate) fn derive(input: DeriveInput) -> Result<TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "FromArgValue can only be derived for enums",
+        ));
+    };
+
+    let mut arms = Vec::new();
+    let mut names = Vec::new();
+
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new_spanned(
+                variant,
+                "FromArgValue can only be derived for enums whose variants have no fields",
+            ));
+        }
+
+        let attrs = ArgAttrs::parse(&variant.attrs)?;
+
+        if attrs.default.is_some() {
+            return Err(Error::new_spanned(
+                variant,
+                "`default` is not supported on enum variants",
+            ));
+        }
+
+        let name = attrs
+            .name
+            .map(|name| name.value())
+            .unwrap_or_else(|| snake_case(&variant.ident.to_string()));
+        let ident = &variant.ident;
+        let cfgs = cfgs(&variant.attrs);
+
+        arms.push(quote! {
+            #(#cfgs)*
+            #name => ::std::result::Result::Ok(Self::#ident),
+        });
+        names.push(quote! {
+            #(#cfgs)*
+            names.push(#name);
+        });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::qemu_plugin::FromArgValue for #ident #ty_generics #where_clause {
+            fn from_arg_value(
+                key: &str,
+                value: &str,
+            ) -> ::std::result::Result<Self, ::qemu_plugin::Error> {
+                match value {
+                    #(#arms)*
+                    _ => ::std::result::Result::Err(::qemu_plugin::Error::InvalidArgValue {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                        expected: <Self as ::qemu_plugin::FromArgValue>::expected(),
+                    }),
+                }
+            }
+
+            fn expected() -> ::std::string::String {
+                let mut names: ::std::vec::Vec<&str> = ::std::vec::Vec::new();
+                #(#names)*
+                ::std::format!("<{}>", names.join("|"))
+            }
+        }
+    })
+}
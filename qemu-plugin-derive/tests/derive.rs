@@ -0,0 +1,164 @@
+//! Tests of the derive macros, used by a plugin installed into the mock host
+
+use qemu_plugin::{
+    Args, Callbacks, FromArgValue, HasCallbacks, Info, PluginArgs, PluginId, Register, Result,
+    VCPUIndex, callbacks, register,
+};
+use qemu_plugin_mock::MockQemu;
+use std::{
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+#[derive(FromArgValue, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    JsonLines,
+    #[arg(name = "cbor")]
+    Binary,
+}
+
+#[derive(PluginArgs, Debug, PartialEq, Eq)]
+struct Options {
+    /// Where to write the trace
+    output: PathBuf,
+    /// The format to write the trace in
+    #[arg(default = Format::Text)]
+    format: Format,
+    /// Whether to trace memory accesses
+    log_mem: bool,
+    /// Only trace these symbols
+    #[arg(name = "symbol")]
+    symbols: Vec<String>,
+    /// Stop tracing after this many instructions
+    limit: Option<u64>,
+    #[arg(default)]
+    depth: u32,
+}
+
+/// The options parsed by the last plugin installed, or the error parsing them
+static PARSED: Mutex<Option<std::result::Result<Options, String>>> = Mutex::new(None);
+
+struct Parser;
+
+impl Register for Parser {
+    fn register(&mut self, _id: PluginId, args: &Args, _info: &Info) -> Result<()> {
+        *PARSED.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(Options::parse(args).map_err(|e| e.to_string()));
+        Ok(())
+    }
+}
+
+#[callbacks]
+impl HasCallbacks for Parser {
+    fn on_vcpu_init(&mut self, _id: PluginId, _vcpu_id: VCPUIndex) -> Result<()> {
+        Ok(())
+    }
+}
+
+register!(Parser);
+
+/// Install the plugin with `args`, returning what it parsed and what it printed
+fn parse(args: &[&str]) -> (std::result::Result<Options, String>, Vec<String>) {
+    let qemu = MockQemu::user("x86_64")
+        .args(args.iter().copied())
+        .install()
+        .unwrap();
+    let parsed = PARSED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+        .unwrap();
+
+    (parsed, qemu.outs())
+}
+
+#[test]
+fn fields_are_parsed_by_type() {
+    let (options, outs) = parse(&[
+        "output=/tmp/trace",
+        "format=json_lines",
+        "log_mem",
+        "symbol=main",
+        "symbol=f",
+        "limit=0x10",
+        "depth=3",
+    ]);
+
+    assert_eq!(
+        options.unwrap(),
+        Options {
+            output: PathBuf::from("/tmp/trace"),
+            format: Format::JsonLines,
+            log_mem: true,
+            symbols: vec!["main".to_string(), "f".to_string()],
+            limit: Some(0x10),
+            depth: 3,
+        }
+    );
+    assert!(outs.is_empty());
+}
+
+#[test]
+fn missing_fields_take_their_defaults() {
+    let (options, _) = parse(&["output=out", "format=cbor"]);
+
+    assert_eq!(
+        options.unwrap(),
+        Options {
+            output: PathBuf::from("out"),
+            format: Format::Binary,
+            log_mem: false,
+            symbols: Vec::new(),
+            limit: None,
+            depth: 0,
+        }
+    );
+}
+
+#[test]
+fn invalid_arguments_are_rejected() {
+    let cases: [(&[&str], &str); 5] = [
+        (&["format=text"], "Missing required argument output"),
+        (&["output=a", "verbose"], "Unknown argument verbose"),
+        (
+            &["output=a", "output=b"],
+            "Argument output provided more than once",
+        ),
+        (
+            &["output=a", "format=xml"],
+            "Invalid value xml for argument format, expected <text|json_lines|cbor>",
+        ),
+        (
+            &["output=a", "limit=-1"],
+            "Invalid value -1 for argument limit, expected <integer>",
+        ),
+    ];
+
+    for (args, error) in cases {
+        let (options, outs) = parse(args);
+
+        assert_eq!(options.unwrap_err(), error, "{args:?}");
+        assert_eq!(outs.len(), 1);
+        assert!(outs[0].starts_with(&format!("{error}\n{}", Options::usage())));
+    }
+}
+
+#[test]
+fn usage_describes_every_argument() {
+    assert_eq!(
+        Options::usage(),
+        "Arguments:\n\
+         \x20 output=<path>                  Where to write the trace (required)\n\
+         \x20 format=<text|json_lines|cbor>  The format to write the trace in (default: text)\n\
+         \x20 log_mem=<on|off>               Whether to trace memory accesses (default: off)\n\
+         \x20 symbol=<string>                Only trace these symbols (may be repeated)\n\
+         \x20 limit=<integer>                Stop tracing after this many instructions\n\
+         \x20 depth=<integer>\n"
+    );
+}
+
+#[test]
+fn callbacks_lists_the_overridden_callbacks() {
+    assert_eq!(Parser.callbacks(), Callbacks::VCPU_INIT);
+}
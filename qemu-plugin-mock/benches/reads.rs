@@ -0,0 +1,177 @@
+//! Benchmarks of reading registers and guest memory through the QEMU plugin API
+//!
+//! Each group compares the crate's reads, which reuse a per-thread `GByteArray`, to
+//! creating and freeing a `GByteArray` for every read as the API requires when called
+//! directly.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use qemu_plugin::{
+    HasCallbacks, PluginId, Register, RegisterDescriptor, RegisterValues, Result, VCPUIndex,
+    qemu_plugin_get_registers, qemu_plugin_read_memory_vaddr, register,
+    sys::{GArray, GByteArray, qemu_plugin_reg_descriptor, qemu_plugin_register},
+};
+use qemu_plugin_mock::{MockQemu, MockRegister};
+use std::{
+    hint::black_box,
+    sync::{Mutex, PoisonError},
+};
+
+/// The general purpose registers of x86_64, as QEMU names them
+const REGISTERS: [&str; 18] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip", "eflags",
+];
+
+/// The address of the guest memory read by the memory benchmarks
+const MEMORY: u64 = 0x10000;
+
+unsafe extern "C" {
+    fn g_byte_array_new() -> *mut GByteArray;
+    fn g_byte_array_free(array: *mut GByteArray, free_segment: bool) -> *mut u8;
+    fn g_array_free(array: *mut GArray, free_segment: bool) -> *mut u8;
+}
+
+/// The registers of the vCPU, read when it is initialized
+static DESCRIPTORS: Mutex<Vec<RegisterDescriptor<'static>>> = Mutex::new(Vec::new());
+
+#[derive(Default)]
+struct Bench;
+
+impl Register for Bench {}
+
+impl HasCallbacks for Bench {
+    fn on_vcpu_init(&mut self, _: PluginId, _: VCPUIndex) -> Result<()> {
+        *DESCRIPTORS.lock().unwrap_or_else(PoisonError::into_inner) = qemu_plugin_get_registers()?;
+        Ok(())
+    }
+}
+
+register!(Bench);
+
+/// The opaque handles of the vCPU's registers, for reading them without the crate
+fn handles() -> Vec<*mut qemu_plugin_register> {
+    unsafe {
+        let array = qemu_plugin::sys::qemu_plugin_get_registers();
+        let handles = std::slice::from_raw_parts(
+            (*array).data as *const qemu_plugin_reg_descriptor,
+            (*array).len as usize,
+        )
+        .iter()
+        .map(|descriptor| descriptor.handle)
+        .collect();
+        g_array_free(array, true);
+        handles
+    }
+}
+
+/// Read a register into a new `GByteArray`, copying the value out before freeing it
+fn read_register_fresh(handle: *mut qemu_plugin_register) -> Vec<u8> {
+    unsafe {
+        let array = g_byte_array_new();
+        qemu_plugin::sys::qemu_plugin_read_register(handle, array);
+        let value = std::slice::from_raw_parts((*array).data, (*array).len as usize).to_vec();
+        g_byte_array_free(array, true);
+        value
+    }
+}
+
+/// Read guest memory into a new `GByteArray`, copying it into `buf` before freeing it
+fn read_memory_fresh(addr: u64, buf: &mut [u8]) {
+    unsafe {
+        let array = g_byte_array_new();
+        qemu_plugin::sys::qemu_plugin_read_memory_vaddr(addr, array, buf.len());
+        buf.copy_from_slice(std::slice::from_raw_parts((*array).data, buf.len()));
+        g_byte_array_free(array, true);
+    }
+}
+
+fn registers(c: &mut Criterion) {
+    let mut builder = MockQemu::user("x86_64");
+
+    for (index, name) in REGISTERS.iter().enumerate() {
+        builder = builder.register(MockRegister::new(*name, (index as u64).to_le_bytes()));
+    }
+
+    let mut qemu = builder.install().expect("Failed to install plugin");
+    qemu.vcpu_init(0);
+
+    let descriptors = DESCRIPTORS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let handles = handles();
+
+    let mut group = c.benchmark_group("registers");
+
+    group.bench_function("fresh_array", |b| {
+        b.iter(|| {
+            for handle in &handles {
+                black_box(read_register_fresh(*handle));
+            }
+        })
+    });
+
+    group.bench_function("read", |b| {
+        b.iter(|| {
+            for descriptor in &descriptors {
+                black_box(descriptor.read().expect("Failed to read register"));
+            }
+        })
+    });
+
+    group.bench_function("read_into", |b| {
+        let mut buf = [0; 16];
+        b.iter(|| {
+            for descriptor in &descriptors {
+                black_box(
+                    descriptor
+                        .read_into(&mut buf)
+                        .expect("Failed to read register"),
+                );
+            }
+        })
+    });
+
+    group.bench_function("read_many", |b| {
+        let mut values = RegisterValues::new();
+        b.iter(|| {
+            RegisterDescriptor::read_many(&descriptors, &mut values)
+                .expect("Failed to read registers");
+            black_box(&values);
+        })
+    });
+
+    group.finish();
+}
+
+fn memory(c: &mut Criterion) {
+    let mut qemu = MockQemu::user("x86_64")
+        .install()
+        .expect("Failed to install plugin");
+    qemu.map_memory(MEMORY, 0x1000);
+
+    let mut group = c.benchmark_group("memory");
+
+    for size in [8, 64, 4096] {
+        let mut buf = vec![0; size];
+
+        group.bench_function(format!("fresh_array/{size}"), |b| {
+            b.iter(|| {
+                read_memory_fresh(MEMORY, &mut buf);
+                black_box(&buf);
+            })
+        });
+
+        group.bench_function(format!("read_memory_vaddr/{size}"), |b| {
+            b.iter(|| {
+                qemu_plugin_read_memory_vaddr(MEMORY, &mut buf).expect("Failed to read memory");
+                black_box(&buf);
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, registers, memory);
+criterion_main!(benches);
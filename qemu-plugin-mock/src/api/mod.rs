@@ -0,0 +1,1081 @@
+//! Mock implementations of the `qemu_plugin_*` C API
+//!
+//! Each function here has the exact signature of its counterpart in `qemu-plugin-sys` for
+//! the selected plugin API version, and operates on the global mock host state instead of
+//! on a running emulator.
+
+#![allow(clippy::missing_safety_doc)]
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+use crate::glib::g_byte_array_set_size;
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+use crate::state::Condition;
+use crate::state::{
+    self, ExecCallback, InlineOp, InlineTarget, Instrumentation, MemCallback, MemInline, Pending,
+    key,
+};
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use crate::{
+    glib::{g_array_from, g_byte_array_append},
+    state::Scoreboard,
+};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+use qemu_plugin_sys::qemu_plugin_cond;
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+use qemu_plugin_sys::qemu_plugin_hwaddr_operation_result;
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use qemu_plugin_sys::{
+    GArray, GByteArray, qemu_plugin_reg_descriptor, qemu_plugin_register, qemu_plugin_scoreboard,
+    qemu_plugin_u64,
+};
+use qemu_plugin_sys::{
+    qemu_plugin_cb_flags, qemu_plugin_hwaddr, qemu_plugin_id_t, qemu_plugin_insn,
+    qemu_plugin_mem_rw, qemu_plugin_meminfo_t, qemu_plugin_op, qemu_plugin_simple_cb_t,
+    qemu_plugin_tb, qemu_plugin_udata_cb_t, qemu_plugin_vcpu_mem_cb_t,
+    qemu_plugin_vcpu_simple_cb_t, qemu_plugin_vcpu_syscall_cb_t, qemu_plugin_vcpu_syscall_ret_cb_t,
+    qemu_plugin_vcpu_tb_trans_cb_t, qemu_plugin_vcpu_udata_cb_t,
+};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+use qemu_plugin_sys::{
+    qemu_plugin_mem_value, qemu_plugin_mem_value__bindgen_ty_1,
+    qemu_plugin_mem_value__bindgen_ty_1__bindgen_ty_1, qemu_plugin_mem_value_type,
+};
+use std::ffi::{CStr, c_char, c_int, c_uint, c_void};
+
+/// Bit offset of the sign extension flag in a mock `qemu_plugin_meminfo_t`
+const MEMINFO_SIGN_EXTEND: u32 = 1 << 4;
+/// Bit offset of the big endian flag in a mock `qemu_plugin_meminfo_t`
+const MEMINFO_BIG_ENDIAN: u32 = 1 << 5;
+/// Bit offset of the store flag in a mock `qemu_plugin_meminfo_t`
+const MEMINFO_STORE: u32 = 1 << 6;
+
+/// Encode the properties of an access the way QEMU packs them into a
+/// `qemu_plugin_meminfo_t`
+pub(crate) fn meminfo(
+    size_shift: u32,
+    sign_extended: bool,
+    big_endian: bool,
+    store: bool,
+) -> qemu_plugin_meminfo_t {
+    (size_shift & 0xf)
+        | if sign_extended {
+            MEMINFO_SIGN_EXTEND
+        } else {
+            0
+        }
+        | if big_endian { MEMINFO_BIG_ENDIAN } else { 0 }
+        | if store { MEMINFO_STORE } else { 0 }
+}
+
+/// Register an execution callback on the TB or instruction with key `target`
+fn push_exec(target: usize, is_tb: bool, callback: ExecCallback) {
+    state::with(|s| {
+        if is_tb {
+            if let Some(tb) = s.tbs.get_mut(&target) {
+                tb.instrumentation.push(Instrumentation::Exec(callback));
+            }
+        } else if let Some(insn) = s.insns.get_mut(&target) {
+            insn.instrumentation.push(Instrumentation::Exec(callback));
+        }
+    })
+}
+
+/// Register an inline operation on the TB or instruction with key `target`
+fn push_inline(target: usize, is_tb: bool, op: InlineOp) {
+    state::with(|s| {
+        if is_tb {
+            if let Some(tb) = s.tbs.get_mut(&target) {
+                tb.instrumentation.push(Instrumentation::Inline(op));
+            }
+        } else if let Some(insn) = s.insns.get_mut(&target) {
+            insn.instrumentation.push(Instrumentation::Inline(op));
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_uninstall(_id: qemu_plugin_id_t, cb: qemu_plugin_simple_cb_t) {
+    state::with(|s| s.pending.push(Pending::Uninstall(cb)))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_reset(_id: qemu_plugin_id_t, cb: qemu_plugin_simple_cb_t) {
+    state::with(|s| s.pending.push(Pending::Reset(cb)))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_init_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_vcpu_simple_cb_t,
+) {
+    state::with(|s| s.callbacks.vcpu_init = cb)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_exit_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_vcpu_simple_cb_t,
+) {
+    state::with(|s| s.callbacks.vcpu_exit = cb)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_idle_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_vcpu_simple_cb_t,
+) {
+    state::with(|s| s.callbacks.vcpu_idle = cb)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_resume_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_vcpu_simple_cb_t,
+) {
+    state::with(|s| s.callbacks.vcpu_resume = cb)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_tb_trans_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_vcpu_tb_trans_cb_t,
+) {
+    state::with(|s| s.callbacks.tb_trans = cb)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_tb_exec_cb(
+    tb: *mut qemu_plugin_tb,
+    cb: qemu_plugin_vcpu_udata_cb_t,
+    flags: qemu_plugin_cb_flags,
+    userdata: *mut c_void,
+) {
+    push_exec(
+        tb as usize,
+        true,
+        ExecCallback {
+            cb,
+            flags,
+            userdata: userdata as usize,
+            #[cfg(not(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2"
+            )))]
+            condition: None,
+        },
+    )
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_tb_exec_cond_cb(
+    tb: *mut qemu_plugin_tb,
+    cb: qemu_plugin_vcpu_udata_cb_t,
+    flags: qemu_plugin_cb_flags,
+    cond: qemu_plugin_cond,
+    entry: qemu_plugin_u64,
+    imm: u64,
+    userdata: *mut c_void,
+) {
+    push_exec(
+        tb as usize,
+        true,
+        ExecCallback {
+            cb,
+            flags,
+            userdata: userdata as usize,
+            condition: Some(Condition { cond, entry, imm }),
+        },
+    )
+}
+
+#[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_tb_exec_inline(
+    tb: *mut qemu_plugin_tb,
+    op: qemu_plugin_op,
+    ptr: *mut c_void,
+    imm: u64,
+) {
+    push_inline(
+        tb as usize,
+        true,
+        InlineOp {
+            op,
+            target: InlineTarget::Pointer(ptr as usize),
+            imm,
+        },
+    )
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_tb_exec_inline_per_vcpu(
+    tb: *mut qemu_plugin_tb,
+    op: qemu_plugin_op,
+    entry: qemu_plugin_u64,
+    imm: u64,
+) {
+    push_inline(
+        tb as usize,
+        true,
+        InlineOp {
+            op,
+            target: InlineTarget::Entry(entry),
+            imm,
+        },
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_insn_exec_cb(
+    insn: *mut qemu_plugin_insn,
+    cb: qemu_plugin_vcpu_udata_cb_t,
+    flags: qemu_plugin_cb_flags,
+    userdata: *mut c_void,
+) {
+    push_exec(
+        insn as usize,
+        false,
+        ExecCallback {
+            cb,
+            flags,
+            userdata: userdata as usize,
+            #[cfg(not(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2"
+            )))]
+            condition: None,
+        },
+    )
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_insn_exec_cond_cb(
+    insn: *mut qemu_plugin_insn,
+    cb: qemu_plugin_vcpu_udata_cb_t,
+    flags: qemu_plugin_cb_flags,
+    cond: qemu_plugin_cond,
+    entry: qemu_plugin_u64,
+    imm: u64,
+    userdata: *mut c_void,
+) {
+    push_exec(
+        insn as usize,
+        false,
+        ExecCallback {
+            cb,
+            flags,
+            userdata: userdata as usize,
+            condition: Some(Condition { cond, entry, imm }),
+        },
+    )
+}
+
+#[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_insn_exec_inline(
+    insn: *mut qemu_plugin_insn,
+    op: qemu_plugin_op,
+    ptr: *mut c_void,
+    imm: u64,
+) {
+    push_inline(
+        insn as usize,
+        false,
+        InlineOp {
+            op,
+            target: InlineTarget::Pointer(ptr as usize),
+            imm,
+        },
+    )
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_insn_exec_inline_per_vcpu(
+    insn: *mut qemu_plugin_insn,
+    op: qemu_plugin_op,
+    entry: qemu_plugin_u64,
+    imm: u64,
+) {
+    push_inline(
+        insn as usize,
+        false,
+        InlineOp {
+            op,
+            target: InlineTarget::Entry(entry),
+            imm,
+        },
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_tb_n_insns(tb: *const qemu_plugin_tb) -> usize {
+    state::with(|s| s.tbs.get(&(tb as usize)).map(|tb| tb.insns.len())).unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_tb_vaddr(tb: *const qemu_plugin_tb) -> u64 {
+    state::with(|s| s.tbs.get(&(tb as usize)).map(|tb| tb.vaddr)).unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_tb_get_insn(
+    tb: *const qemu_plugin_tb,
+    idx: usize,
+) -> *mut qemu_plugin_insn {
+    state::with(|s| {
+        s.tbs
+            .get(&(tb as usize))
+            .and_then(|tb| tb.insns.get(idx).copied())
+    })
+    .unwrap_or_default() as *mut qemu_plugin_insn
+}
+
+#[cfg(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_insn_data(insn: *const qemu_plugin_insn) -> *const c_void {
+    // NOTE: The data is owned by the instruction, which lives until the next flush
+    state::with(|s| {
+        s.insns
+            .get(&(insn as usize))
+            .map(|insn| insn.data.as_ptr() as usize)
+    })
+    .unwrap_or_default() as *const c_void
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_insn_data(
+    insn: *const qemu_plugin_insn,
+    dest: *mut c_void,
+    len: usize,
+) -> usize {
+    let data = state::with(|s| s.insns.get(&(insn as usize)).map(|insn| insn.data.clone()))
+        .unwrap_or_default();
+    let len = len.min(data.len());
+    unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), dest as *mut u8, len) };
+    len
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_insn_size(insn: *const qemu_plugin_insn) -> usize {
+    state::with(|s| s.insns.get(&(insn as usize)).map(|insn| insn.data.len())).unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_insn_vaddr(insn: *const qemu_plugin_insn) -> u64 {
+    state::with(|s| s.insns.get(&(insn as usize)).map(|insn| insn.vaddr)).unwrap_or_default()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_insn_haddr(insn: *const qemu_plugin_insn) -> *mut c_void {
+    state::with(|s| s.insns.get(&(insn as usize)).map(|insn| insn.haddr)).unwrap_or_default()
+        as usize as *mut c_void
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_mem_size_shift(info: qemu_plugin_meminfo_t) -> c_uint {
+    info & 0xf
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_mem_is_sign_extended(info: qemu_plugin_meminfo_t) -> bool {
+    info & MEMINFO_SIGN_EXTEND != 0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_mem_is_big_endian(info: qemu_plugin_meminfo_t) -> bool {
+    info & MEMINFO_BIG_ENDIAN != 0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_mem_is_store(info: qemu_plugin_meminfo_t) -> bool {
+    info & MEMINFO_STORE != 0
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_mem_get_value(info: qemu_plugin_meminfo_t) -> qemu_plugin_mem_value {
+    let value =
+        state::with(|s| s.current_access.as_ref().map(|a| a.access.value)).unwrap_or_default();
+
+    let (type_, data) = match info & 0xf {
+        0 => (
+            qemu_plugin_mem_value_type::QEMU_PLUGIN_MEM_VALUE_U8,
+            qemu_plugin_mem_value__bindgen_ty_1 { u8_: value as u8 },
+        ),
+        1 => (
+            qemu_plugin_mem_value_type::QEMU_PLUGIN_MEM_VALUE_U16,
+            qemu_plugin_mem_value__bindgen_ty_1 { u16_: value as u16 },
+        ),
+        2 => (
+            qemu_plugin_mem_value_type::QEMU_PLUGIN_MEM_VALUE_U32,
+            qemu_plugin_mem_value__bindgen_ty_1 { u32_: value as u32 },
+        ),
+        3 => (
+            qemu_plugin_mem_value_type::QEMU_PLUGIN_MEM_VALUE_U64,
+            qemu_plugin_mem_value__bindgen_ty_1 { u64_: value as u64 },
+        ),
+        _ => (
+            qemu_plugin_mem_value_type::QEMU_PLUGIN_MEM_VALUE_U128,
+            qemu_plugin_mem_value__bindgen_ty_1 {
+                u128_: qemu_plugin_mem_value__bindgen_ty_1__bindgen_ty_1 {
+                    low: value as u64,
+                    high: (value >> 64) as u64,
+                },
+            },
+        ),
+    };
+
+    qemu_plugin_mem_value { type_, data }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_get_hwaddr(
+    _info: qemu_plugin_meminfo_t,
+    _vaddr: u64,
+) -> *mut qemu_plugin_hwaddr {
+    state::with(|s| {
+        if s.config.system.is_none() {
+            // NOTE: QEMU never reports hardware addresses in user mode
+            return 0;
+        }
+
+        s.current_access
+            .as_ref()
+            .and_then(|a| a.hwaddr.as_ref())
+            .map(|h| key(h.as_ref()))
+            .unwrap_or_default()
+    }) as *mut qemu_plugin_hwaddr
+}
+
+/// Look up the hardware address details for a handle returned by `qemu_plugin_get_hwaddr`
+fn with_hwaddr<R: Default>(
+    haddr: *const qemu_plugin_hwaddr,
+    f: impl FnOnce(&crate::MockHwaddr, Option<&std::ffi::CString>) -> R,
+) -> R {
+    state::with(|s| {
+        s.current_access
+            .as_ref()
+            .and_then(|a| a.hwaddr.as_ref())
+            .filter(|h| key(h.as_ref()) == haddr as usize)
+            .map(|h| f(&h.0, h.1.as_ref()))
+            .unwrap_or_default()
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_hwaddr_is_io(haddr: *const qemu_plugin_hwaddr) -> bool {
+    with_hwaddr(haddr, |h, _| h.is_io)
+}
+
+#[cfg(feature = "plugin-api-v0")]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_hwaddr_device_offset(haddr: *const qemu_plugin_hwaddr) -> u64 {
+    with_hwaddr(haddr, |h, _| h.addr)
+}
+
+#[cfg(not(feature = "plugin-api-v0"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_hwaddr_phys_addr(haddr: *const qemu_plugin_hwaddr) -> u64 {
+    with_hwaddr(haddr, |h, _| h.addr)
+}
+
+#[cfg(not(feature = "plugin-api-v0"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_hwaddr_device_name(h: *const qemu_plugin_hwaddr) -> *const c_char {
+    // NOTE: The name is owned by the current access, which outlives the memory callback
+    with_hwaddr(h, |_, name| {
+        name.map(|n| n.as_ptr() as usize).unwrap_or_default()
+    }) as *const c_char
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_mem_cb(
+    insn: *mut qemu_plugin_insn,
+    cb: qemu_plugin_vcpu_mem_cb_t,
+    _flags: qemu_plugin_cb_flags,
+    rw: qemu_plugin_mem_rw,
+    userdata: *mut c_void,
+) {
+    state::with(|s| {
+        if let Some(insn) = s.insns.get_mut(&(insn as usize)) {
+            insn.mem.push(MemCallback {
+                cb,
+                rw,
+                userdata: userdata as usize,
+            });
+        }
+    })
+}
+
+/// Register an inline operation on the memory accesses of the instruction with key `insn`
+fn push_mem_inline(insn: usize, rw: qemu_plugin_mem_rw, op: InlineOp) {
+    state::with(|s| {
+        if let Some(insn) = s.insns.get_mut(&insn) {
+            insn.mem_inline.push(MemInline { rw, op });
+        }
+    })
+}
+
+#[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_mem_inline(
+    insn: *mut qemu_plugin_insn,
+    rw: qemu_plugin_mem_rw,
+    op: qemu_plugin_op,
+    ptr: *mut c_void,
+    imm: u64,
+) {
+    push_mem_inline(
+        insn as usize,
+        rw,
+        InlineOp {
+            op,
+            target: InlineTarget::Pointer(ptr as usize),
+            imm,
+        },
+    )
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_mem_inline_per_vcpu(
+    insn: *mut qemu_plugin_insn,
+    rw: qemu_plugin_mem_rw,
+    op: qemu_plugin_op,
+    entry: qemu_plugin_u64,
+    imm: u64,
+) {
+    push_mem_inline(
+        insn as usize,
+        rw,
+        InlineOp {
+            op,
+            target: InlineTarget::Entry(entry),
+            imm,
+        },
+    )
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+/// The handle returned to the plugin holding time control
+static TIME_CONTROL_HANDLE: u8 = 0;
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_request_time_control() -> *const c_void {
+    state::with(|s| {
+        if s.time_control.is_some() {
+            std::ptr::null()
+        } else {
+            let handle = &TIME_CONTROL_HANDLE as *const u8 as *const c_void;
+            s.time_control = Some(handle as usize);
+            handle
+        }
+    })
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_update_ns(handle: *const c_void, time: i64) {
+    state::with(|s| {
+        if s.time_control == Some(handle as usize) && s.config.system.is_some() {
+            s.time_ns = time;
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_syscall_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_vcpu_syscall_cb_t,
+) {
+    state::with(|s| s.callbacks.syscall = cb)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_vcpu_syscall_ret_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_vcpu_syscall_ret_cb_t,
+) {
+    state::with(|s| s.callbacks.syscall_ret = cb)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_insn_disas(insn: *const qemu_plugin_insn) -> *mut c_char {
+    state::with(|s| {
+        s.insns
+            .get(&(insn as usize))
+            .and_then(|insn| insn.disas.as_ref())
+            .map(|disas| crate::glib::g_malloc_copy(disas.as_bytes_with_nul()))
+            .unwrap_or(std::ptr::null_mut())
+    }) as *mut c_char
+}
+
+#[cfg(not(feature = "plugin-api-v0"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_insn_symbol(insn: *const qemu_plugin_insn) -> *const c_char {
+    // NOTE: The symbol is owned by the instruction, which lives until the next flush
+    state::with(|s| {
+        s.insns
+            .get(&(insn as usize))
+            .and_then(|insn| insn.symbol.as_ref())
+            .map(|symbol| symbol.as_ptr() as usize)
+            .unwrap_or_default()
+    }) as *const c_char
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_vcpu_for_each(
+    id: qemu_plugin_id_t,
+    cb: qemu_plugin_vcpu_simple_cb_t,
+) {
+    let vcpus = state::with(|s| s.vcpus.iter().copied().collect::<Vec<_>>());
+    if let Some(cb) = cb {
+        vcpus.into_iter().for_each(|vcpu| unsafe { cb(id, vcpu) });
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_flush_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_simple_cb_t,
+) {
+    state::with(|s| s.callbacks.flush = cb)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_register_atexit_cb(
+    _id: qemu_plugin_id_t,
+    cb: qemu_plugin_udata_cb_t,
+    userdata: *mut c_void,
+) {
+    state::with(|s| s.callbacks.atexit = Some((cb, userdata as usize)))
+}
+
+#[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_n_vcpus() -> c_int {
+    state::with(|s| s.config.system.map(|(smp, _)| smp).unwrap_or(-1))
+}
+
+#[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_n_max_vcpus() -> c_int {
+    state::with(|s| s.config.system.map(|(_, max)| max).unwrap_or(-1))
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_num_vcpus() -> c_int {
+    state::with(|s| s.num_vcpus() as c_int)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_outs(string: *const c_char) {
+    let string = unsafe { CStr::from_ptr(string) }
+        .to_string_lossy()
+        .into_owned();
+    state::with(|s| s.outs.push(string))
+}
+
+#[cfg(not(feature = "plugin-api-v0"))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_bool_parse(
+    _name: *const c_char,
+    val: *const c_char,
+    ret: *mut bool,
+) -> bool {
+    let value = match unsafe { CStr::from_ptr(val) }.to_bytes() {
+        b"on" | b"yes" | b"true" => true,
+        b"off" | b"no" | b"false" => false,
+        _ => return false,
+    };
+    unsafe { *ret = value };
+    true
+}
+
+#[cfg(not(feature = "plugin-api-v0"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_path_to_binary() -> *const c_char {
+    state::with(|s| {
+        s.config
+            .binary
+            .as_ref()
+            .map(|b| crate::glib::g_malloc_copy(b.as_bytes_with_nul()))
+            .unwrap_or(std::ptr::null_mut())
+    }) as *const c_char
+}
+
+#[cfg(not(feature = "plugin-api-v0"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_start_code() -> u64 {
+    state::with(|s| s.config.start_code)
+}
+
+#[cfg(not(feature = "plugin-api-v0"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_end_code() -> u64 {
+    state::with(|s| s.config.end_code)
+}
+
+#[cfg(not(feature = "plugin-api-v0"))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_entry_code() -> u64 {
+    state::with(|s| s.config.entry_code)
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_get_registers() -> *mut GArray {
+    state::with(|s| {
+        // NOTE: Like QEMU, handles are the register index offset by one so that no
+        // handle is NULL
+        let descriptors = s
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(i, r)| qemu_plugin_reg_descriptor {
+                handle: (i + 1) as *mut qemu_plugin_register,
+                name: r.name.as_ptr(),
+                feature: r
+                    .feature
+                    .as_ref()
+                    .map(|f| f.as_ptr())
+                    .unwrap_or(std::ptr::null()),
+            })
+            .collect::<Vec<_>>();
+        g_array_from(&descriptors)
+    })
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_read_register(
+    handle: *mut qemu_plugin_register,
+    buf: *mut GByteArray,
+) -> c_int {
+    let value = state::with(|s| {
+        let vcpu = s.current_vcpu.unwrap_or_default();
+        (handle as usize)
+            .checked_sub(1)
+            .and_then(|i| s.registers.get_mut(i))
+            .map(|r| r.value(vcpu).clone())
+    });
+
+    match value {
+        Some(value) if unsafe { g_byte_array_append(buf, &value) } => value.len() as c_int,
+        _ => -1,
+    }
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_write_register(
+    handle: *mut qemu_plugin_register,
+    buf: *mut GByteArray,
+) -> c_int {
+    let data = unsafe { std::slice::from_raw_parts((*buf).data, (*buf).len as usize) }.to_vec();
+    state::with(|s| {
+        let vcpu = s.current_vcpu.unwrap_or_default();
+        match (handle as usize)
+            .checked_sub(1)
+            .and_then(|i| s.registers.get_mut(i))
+        {
+            Some(register) if register.initial.len() == data.len() => {
+                *register.value(vcpu) = data;
+                register.initial.len() as c_int
+            }
+            _ => 0,
+        }
+    })
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_read_memory_vaddr(
+    addr: u64,
+    data: *mut GByteArray,
+    len: usize,
+) -> bool {
+    if len == 0 {
+        return false;
+    }
+
+    let mut buf = vec![0; len];
+    if !state::with(|s| s.memory.read(addr, &mut buf)) {
+        return false;
+    }
+
+    if unsafe { g_byte_array_set_size(data, len) } {
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), (*data).data, len) };
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_write_memory_vaddr(addr: u64, data: *mut GByteArray) -> bool {
+    let buf = unsafe { std::slice::from_raw_parts((*data).data, (*data).len as usize) };
+    !buf.is_empty() && state::with(|s| s.memory.write(addr, buf))
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_read_memory_hwaddr(
+    addr: u64,
+    data: *mut GByteArray,
+    len: usize,
+) -> qemu_plugin_hwaddr_operation_result {
+    if len == 0 {
+        return qemu_plugin_hwaddr_operation_result::QEMU_PLUGIN_HWADDR_OPERATION_ERROR;
+    }
+
+    let mut buf = vec![0; len];
+    match state::with(|s| {
+        s.config
+            .system
+            .is_some()
+            .then(|| s.physical.read(addr, &mut buf))
+    }) {
+        None => {
+            qemu_plugin_hwaddr_operation_result::QEMU_PLUGIN_HWADDR_OPERATION_INVALID_ADDRESS_SPACE
+        }
+        Some(false) => {
+            qemu_plugin_hwaddr_operation_result::QEMU_PLUGIN_HWADDR_OPERATION_INVALID_ADDRESS
+        }
+        Some(true) if unsafe { g_byte_array_set_size(data, len) } => {
+            unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), (*data).data, len) };
+            qemu_plugin_hwaddr_operation_result::QEMU_PLUGIN_HWADDR_OPERATION_OK
+        }
+        Some(true) => qemu_plugin_hwaddr_operation_result::QEMU_PLUGIN_HWADDR_OPERATION_ERROR,
+    }
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_write_memory_hwaddr(
+    addr: u64,
+    data: *mut GByteArray,
+) -> qemu_plugin_hwaddr_operation_result {
+    let buf = unsafe { std::slice::from_raw_parts((*data).data, (*data).len as usize) };
+    match state::with(|s| {
+        s.config
+            .system
+            .is_some()
+            .then(|| s.physical.write(addr, buf))
+    }) {
+        None => {
+            qemu_plugin_hwaddr_operation_result::QEMU_PLUGIN_HWADDR_OPERATION_INVALID_ADDRESS_SPACE
+        }
+        Some(false) => {
+            qemu_plugin_hwaddr_operation_result::QEMU_PLUGIN_HWADDR_OPERATION_INVALID_ADDRESS
+        }
+        Some(true) => qemu_plugin_hwaddr_operation_result::QEMU_PLUGIN_HWADDR_OPERATION_OK,
+    }
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qemu_plugin_translate_vaddr(vaddr: u64, hwaddr: *mut u64) -> bool {
+    // NOTE: The mock identity maps virtual to physical memory in system mode
+    if state::with(|s| s.config.system.is_some() && s.memory.is_mapped(vaddr, 1)) {
+        unsafe { *hwaddr = vaddr };
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_scoreboard_new(element_size: usize) -> *mut qemu_plugin_scoreboard {
+    state::with(|s| {
+        let mut score = Box::new(Scoreboard {
+            size: element_size,
+            data: Vec::new(),
+        });
+        if let Some(last) = s.vcpus.last() {
+            score.ensure(*last);
+        }
+        let handle = key(score.as_ref());
+        s.scoreboards.insert(handle, score);
+        handle
+    }) as *mut qemu_plugin_scoreboard
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_scoreboard_free(score: *mut qemu_plugin_scoreboard) {
+    // NOTE: Scoreboards may legitimately outlive a mock session, so this does not insist
+    // on one being installed
+    if let Some(state) = state::lock().as_mut() {
+        state.scoreboards.remove(&(score as usize));
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_scoreboard_find(
+    score: *mut qemu_plugin_scoreboard,
+    vcpu_index: c_uint,
+) -> *mut c_void {
+    state::with(|s| {
+        s.scoreboards
+            .get_mut(&(score as usize))
+            .map(|score| {
+                score.ensure(vcpu_index);
+                score.data[vcpu_index as usize * score.size..].as_mut_ptr() as usize
+            })
+            .unwrap_or_default()
+    }) as *mut c_void
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_u64_add(entry: qemu_plugin_u64, vcpu_index: c_uint, added: u64) {
+    state::with(|s| {
+        let value = s.entry_get(entry, vcpu_index).wrapping_add(added);
+        qemu_plugin_u64_set_locked(s, entry, vcpu_index, value)
+    })
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_u64_get(entry: qemu_plugin_u64, vcpu_index: c_uint) -> u64 {
+    state::with(|s| s.entry_get(entry, vcpu_index))
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// Set a scoreboard entry with the state already locked
+fn qemu_plugin_u64_set_locked(
+    s: &mut state::State,
+    entry: qemu_plugin_u64,
+    vcpu_index: c_uint,
+    val: u64,
+) {
+    if let Some(score) = s.scoreboards.get_mut(&(entry.score as usize)) {
+        score
+            .u64_mut(vcpu_index, entry.offset)
+            .copy_from_slice(&val.to_ne_bytes());
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_u64_set(entry: qemu_plugin_u64, vcpu_index: c_uint, val: u64) {
+    state::with(|s| qemu_plugin_u64_set_locked(s, entry, vcpu_index, val))
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+pub extern "C" fn qemu_plugin_u64_sum(entry: qemu_plugin_u64) -> u64 {
+    state::with(|s| {
+        (0..s.num_vcpus())
+            .map(|vcpu| s.entry_get(entry, vcpu))
+            .fold(0u64, u64::wrapping_add)
+    })
+}
@@ -0,0 +1,181 @@
+//! Mock implementations of the GLib functions the `qemu-plugin` crate expects QEMU to
+//! provide
+//!
+//! Only the subset of GLib used by plugins is implemented. Arrays allocated here carry
+//! their capacity after the public `GByteArray` fields, mirroring GLib's private
+//! `GRealArray`, so that QEMU API functions which grow a caller-provided array behave as
+//! they do in QEMU.
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use qemu_plugin_sys::{GArray, GByteArray};
+use std::ffi::c_void;
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use std::{collections::BTreeSet, sync::Mutex};
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// Addresses of byte arrays allocated by `g_byte_array_new`, which may be grown
+static BYTE_ARRAYS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[repr(C)]
+/// A byte array allocated by the mock, with its capacity
+struct MockByteArray {
+    array: GByteArray,
+    capacity: usize,
+}
+
+/// Copy `bytes` into a freshly `malloc`ed buffer which the plugin will release with
+/// `g_free`
+pub(crate) fn g_malloc_copy(bytes: &[u8]) -> *mut u8 {
+    let ptr = unsafe { libc::malloc(bytes.len().max(1)) } as *mut u8;
+    assert!(!ptr.is_null(), "Failed to allocate memory");
+    unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+    ptr
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// Allocate a `GArray` holding a copy of `elements`, to be freed by `g_array_free`
+pub(crate) fn g_array_from<T: Copy>(elements: &[T]) -> *mut GArray {
+    let data = g_malloc_copy(unsafe {
+        std::slice::from_raw_parts(
+            elements.as_ptr() as *const u8,
+            std::mem::size_of_val(elements),
+        )
+    });
+    let array = unsafe { libc::malloc(std::mem::size_of::<GArray>()) } as *mut GArray;
+    assert!(!array.is_null(), "Failed to allocate memory");
+    unsafe {
+        array.write(GArray {
+            data: data as *mut _,
+            len: elements.len() as u32,
+        })
+    };
+    array
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// Resize `array` to `len` bytes. Arrays allocated by `g_byte_array_new` are grown as
+/// needed. Any other array is assumed to be a fixed view of caller memory, so it is only
+/// shrunk, and `false` is returned if it is too small.
+///
+/// # Safety
+///
+/// `array` must point to a valid `GByteArray`
+pub(crate) unsafe fn g_byte_array_set_size(array: *mut GByteArray, len: usize) -> bool {
+    let owned = BYTE_ARRAYS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(&(array as usize));
+
+    if owned {
+        let array = array as *mut MockByteArray;
+        unsafe {
+            if (*array).capacity < len {
+                let data = libc::realloc((*array).array.data as *mut c_void, len) as *mut u8;
+                assert!(!data.is_null(), "Failed to allocate memory");
+                (*array).array.data = data;
+                (*array).capacity = len;
+            }
+            (*array).array.len = len as u32;
+        }
+        true
+    } else if unsafe { (*array).len } as usize >= len {
+        unsafe { (*array).len = len as u32 };
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// Append `bytes` to `array`, growing it if it was allocated by `g_byte_array_new`
+///
+/// # Safety
+///
+/// `array` must point to a valid `GByteArray`
+pub(crate) unsafe fn g_byte_array_append(array: *mut GByteArray, bytes: &[u8]) -> bool {
+    let start = unsafe { (*array).len } as usize;
+    if unsafe { g_byte_array_set_size(array, start + bytes.len()) } {
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), (*array).data.add(start), bytes.len())
+        };
+        true
+    } else {
+        false
+    }
+}
+
+#[unsafe(no_mangle)]
+/// Free memory allocated by the mock host
+///
+/// # Safety
+///
+/// `mem` must be null or have been returned by the mock host with ownership passed to the
+/// caller
+pub unsafe extern "C" fn g_free(mem: *mut c_void) {
+    unsafe { libc::free(mem) }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+/// Allocate a new, empty byte array
+pub extern "C" fn g_byte_array_new() -> *mut GByteArray {
+    let array = unsafe { libc::malloc(std::mem::size_of::<MockByteArray>()) } as *mut MockByteArray;
+    assert!(!array.is_null(), "Failed to allocate memory");
+    unsafe {
+        array.write(MockByteArray {
+            array: GByteArray {
+                data: std::ptr::null_mut(),
+                len: 0,
+            },
+            capacity: 0,
+        })
+    };
+    BYTE_ARRAYS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(array as usize);
+    array as *mut GByteArray
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+/// Free a byte array allocated by `g_byte_array_new`, and its data if `free_segment` is
+/// true. Returns the data if it was not freed.
+///
+/// # Safety
+///
+/// `array` must have been returned by `g_byte_array_new` and not yet freed
+pub unsafe extern "C" fn g_byte_array_free(array: *mut GByteArray, free_segment: bool) -> *mut u8 {
+    BYTE_ARRAYS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&(array as usize));
+    let data = unsafe { (*array).data };
+    unsafe { libc::free(array as *mut c_void) };
+    if free_segment {
+        unsafe { libc::free(data as *mut c_void) };
+        std::ptr::null_mut()
+    } else {
+        data
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[unsafe(no_mangle)]
+/// Free an array allocated by the mock host, and its data if `free_segment` is true.
+/// Returns the data if it was not freed.
+///
+/// # Safety
+///
+/// `array` must have been returned by the mock host and not yet freed
+pub unsafe extern "C" fn g_array_free(array: *mut GArray, free_segment: bool) -> *mut u8 {
+    let data = unsafe { (*array).data } as *mut u8;
+    unsafe { libc::free(array as *mut c_void) };
+    if free_segment {
+        unsafe { libc::free(data as *mut c_void) };
+        std::ptr::null_mut()
+    } else {
+        data
+    }
+}
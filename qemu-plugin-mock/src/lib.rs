@@ -0,0 +1,977 @@
+//! An in-process mock QEMU host for testing QEMU plugins
+//!
+//! `qemu-plugin-mock` implements the QEMU plugin API (and the small part of GLib the
+//! `qemu-plugin` crate relies on) inside the test process, so plugins written with
+//! `qemu-plugin` can be unit tested without a QEMU binary. Tests install the plugin into a
+//! [`MockQemu`], then drive it by synthesizing vCPU, translation, execution, memory
+//! access and syscall events and inspecting what the plugin did in response.
+//!
+//! Only one mock can be installed at a time in a process, because QEMU plugins are
+//! process-global. [`MockQemu`] serializes sessions, so tests in the same binary may still
+//! run in parallel. Each session installs the plugin with no callbacks or pending
+//! uninstall left over from earlier sessions. The plugin instance passed to `register!`
+//! is created once and keeps its state between sessions, so tests which need a plugin in
+//! its initial state install a new instance with [`MockQemuBuilder::plugin`].
+//!
+//! # Example
+//!
+//! ```rust
+//! use qemu_plugin::{
+//!     HasCallbacks, PluginId, Register, Result, TranslationBlock, VCPUIndex, register,
+//! };
+//! use qemu_plugin_mock::{MockInstruction, MockQemu, MockTranslationBlock};
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//!
+//! static EXECUTED: AtomicU64 = AtomicU64::new(0);
+//!
+//! struct Counter;
+//!
+//! impl Register for Counter {}
+//!
+//! impl HasCallbacks for Counter {
+//!     fn on_translation_block_translate(
+//!         &mut self,
+//!         _id: PluginId,
+//!         tb: TranslationBlock,
+//!     ) -> Result<()> {
+//!         for insn in tb.instructions() {
+//!             insn.register_execute_callback(|_: VCPUIndex| {
+//!                 EXECUTED.fetch_add(1, Ordering::Relaxed);
+//!             });
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! register!(Counter);
+//!
+//! let mut qemu = MockQemu::user("x86_64").install().unwrap();
+//! qemu.vcpu_init(0);
+//!
+//! let tb = MockTranslationBlock::new(0x1000)
+//!     .instruction(MockInstruction::new(0x1000, [0x90]).disas("nop"))
+//!     .instruction(MockInstruction::new(0x1001, [0xc3]).disas("ret"));
+//!
+//! let translation = qemu.translate(&tb);
+//! qemu.execute(&translation, 0).unwrap();
+//! qemu.execute(&translation, 0).unwrap();
+//!
+//! assert_eq!(EXECUTED.load(Ordering::Relaxed), 4);
+//! ```
+
+#![deny(missing_docs)]
+
+mod api;
+mod glib;
+mod state;
+mod temp;
+
+pub use temp::TempFile;
+
+use crate::state::{Config, CurrentAccess, Instrumentation, Pending, State, key};
+use qemu_plugin::{Plugin, PluginSync};
+use qemu_plugin_sys::{
+    QEMU_PLUGIN_VERSION, qemu_info_t, qemu_info_t__bindgen_ty_1, qemu_info_t__bindgen_ty_2,
+    qemu_info_t__bindgen_ty_2__bindgen_ty_1, qemu_plugin_id_t, qemu_plugin_mem_rw, qemu_plugin_tb,
+};
+use std::{
+    ffi::{CString, c_int, c_uint, c_void},
+    fmt,
+    panic::{AssertUnwindSafe, catch_unwind},
+    sync::{Mutex, MutexGuard},
+};
+
+/// The ID the mock host gives to the installed plugin
+const MOCK_PLUGIN_ID: qemu_plugin_id_t = 1;
+
+/// Serializes mock sessions, since the plugin and the mock host are both process-global
+static SESSION: Mutex<()> = Mutex::new(());
+
+#[derive(thiserror::Error, Debug)]
+/// An error from the mock QEMU host
+pub enum Error {
+    #[error("Plugin installation failed with code {code}")]
+    /// Error when `qemu_plugin_install` returns a non-zero code
+    InstallFailed {
+        /// The code returned by `qemu_plugin_install`
+        code: i32,
+    },
+    #[error("Plugin panicked during installation: {message}")]
+    /// Error when `qemu_plugin_install` panics
+    InstallPanicked {
+        /// The panic message
+        message: String,
+    },
+    #[error("Argument {argument} contains a NUL byte")]
+    /// Error when a plugin argument cannot be passed to the plugin as a C string
+    InvalidArgument {
+        /// The argument containing a NUL byte
+        argument: String,
+    },
+    #[error("Translation block at {vaddr:#x} is no longer valid")]
+    /// Error when executing a translation which was invalidated by a flush, reset or
+    /// uninstall
+    StaleTranslation {
+        /// The virtual address of the translation block
+        vaddr: u64,
+    },
+    #[error("No register named {name}")]
+    /// Error when accessing a register the mock was not configured with
+    NoSuchRegister {
+        /// The register name
+        name: String,
+    },
+    #[error("Invalid size {size} for register {name} of size {expected}")]
+    /// Error when setting a register to a value of the wrong size
+    InvalidRegisterSize {
+        /// The register name
+        name: String,
+        /// The size of the value
+        size: usize,
+        /// The size of the register
+        expected: usize,
+    },
+    #[error("Memory at {addr:#x} of size {len} is not mapped")]
+    /// Error when accessing unmapped mock guest memory
+    UnmappedMemory {
+        /// The address of the access
+        addr: u64,
+        /// The length of the access
+        len: usize,
+    },
+}
+
+/// Result type for the mock QEMU host
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The hardware address of a memory access in system mode
+pub struct MockHwaddr {
+    pub(crate) addr: u64,
+    pub(crate) is_io: bool,
+    pub(crate) device: Option<String>,
+}
+
+impl MockHwaddr {
+    /// A hardware address backed by RAM
+    pub fn ram(addr: u64) -> Self {
+        Self {
+            addr,
+            is_io: false,
+            device: None,
+        }
+    }
+
+    /// A hardware address in an I/O region of the named device
+    pub fn io(addr: u64, device: impl Into<String>) -> Self {
+        Self {
+            addr,
+            is_io: true,
+            device: Some(device.into()),
+        }
+    }
+
+    /// Set the name of the device the address belongs to
+    pub fn device(mut self, device: impl Into<String>) -> Self {
+        self.device = Some(device.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A memory access performed when an instruction executes
+pub struct MockMemoryAccess {
+    pub(crate) vaddr: u64,
+    pub(crate) size_shift: u32,
+    pub(crate) value: u128,
+    pub(crate) sign_extended: bool,
+    pub(crate) big_endian: bool,
+    pub(crate) store: bool,
+    pub(crate) hwaddr: Option<MockHwaddr>,
+}
+
+impl MockMemoryAccess {
+    /// Create an access of `size` bytes, which must be a power of two no larger than 16
+    fn new(vaddr: u64, size: usize, value: u128, store: bool) -> Self {
+        assert!(
+            size.is_power_of_two() && size <= 16,
+            "memory access size must be a power of two no larger than 16"
+        );
+        Self {
+            vaddr,
+            size_shift: size.trailing_zeros(),
+            value,
+            sign_extended: false,
+            big_endian: false,
+            store,
+            hwaddr: None,
+        }
+    }
+
+    /// A load of `size` bytes from `vaddr`, which loaded `value`
+    pub fn load(vaddr: u64, size: usize, value: impl Into<u128>) -> Self {
+        Self::new(vaddr, size, value.into(), false)
+    }
+
+    /// A store of `size` bytes of `value` to `vaddr`
+    pub fn store(vaddr: u64, size: usize, value: impl Into<u128>) -> Self {
+        Self::new(vaddr, size, value.into(), true)
+    }
+
+    /// Mark the access as sign extended
+    pub fn sign_extended(mut self) -> Self {
+        self.sign_extended = true;
+        self
+    }
+
+    /// Mark the access as big endian
+    pub fn big_endian(mut self) -> Self {
+        self.big_endian = true;
+        self
+    }
+
+    /// Set the hardware address of the access, which is reported in system mode
+    pub fn hwaddr(mut self, hwaddr: MockHwaddr) -> Self {
+        self.hwaddr = Some(hwaddr);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An instruction in a mock translation block
+pub struct MockInstruction {
+    pub(crate) vaddr: u64,
+    pub(crate) haddr: u64,
+    pub(crate) data: Vec<u8>,
+    pub(crate) disas: Option<String>,
+    pub(crate) symbol: Option<String>,
+    pub(crate) accesses: Vec<MockMemoryAccess>,
+}
+
+impl MockInstruction {
+    /// An instruction at `vaddr` with the opcode bytes `data`
+    pub fn new(vaddr: u64, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            vaddr,
+            haddr: vaddr,
+            data: data.into(),
+            disas: None,
+            symbol: None,
+            accesses: Vec::new(),
+        }
+    }
+
+    /// Set the disassembly of the instruction
+    pub fn disas(mut self, disas: impl Into<String>) -> Self {
+        self.disas = Some(disas.into());
+        self
+    }
+
+    /// Set the symbol the instruction belongs to
+    pub fn symbol(mut self, symbol: impl Into<String>) -> Self {
+        self.symbol = Some(symbol.into());
+        self
+    }
+
+    /// Set the host address of the instruction, which defaults to its virtual address
+    pub fn haddr(mut self, haddr: u64) -> Self {
+        self.haddr = haddr;
+        self
+    }
+
+    /// Add a memory access performed each time the instruction executes
+    pub fn memory_access(mut self, access: MockMemoryAccess) -> Self {
+        self.accesses.push(access);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A mock translation block, which is translated with [`MockQemu::translate`]
+pub struct MockTranslationBlock {
+    vaddr: u64,
+    instructions: Vec<MockInstruction>,
+}
+
+impl MockTranslationBlock {
+    /// An empty translation block starting at `vaddr`
+    pub fn new(vaddr: u64) -> Self {
+        Self {
+            vaddr,
+            instructions: Vec::new(),
+        }
+    }
+
+    /// A translation block starting at `vaddr` made of `insns` one byte x86 `nop`
+    /// instructions
+    pub fn nops(vaddr: u64, insns: u64) -> Self {
+        (0..insns).fold(Self::new(vaddr), |tb, index| {
+            tb.instruction(MockInstruction::new(vaddr + index, [0x90]))
+        })
+    }
+
+    /// Append an instruction to the block
+    pub fn instruction(mut self, instruction: MockInstruction) -> Self {
+        self.instructions.push(instruction);
+        self
+    }
+
+    /// Set the symbol every instruction of the block belongs to
+    pub fn symbol(mut self, symbol: impl Into<String>) -> Self {
+        let symbol = symbol.into();
+
+        for instruction in &mut self.instructions {
+            instruction.symbol = Some(symbol.clone());
+        }
+
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A register exposed to the plugin through the register API
+pub struct MockRegister {
+    pub(crate) name: String,
+    pub(crate) feature: Option<String>,
+    pub(crate) value: Vec<u8>,
+}
+
+impl MockRegister {
+    /// A register named `name` with the initial value `value` on every vCPU. The size of
+    /// the register is the size of its initial value.
+    pub fn new(name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            name: name.into(),
+            feature: None,
+            value: value.into(),
+        }
+    }
+
+    /// Set the GDB feature the register belongs to
+    pub fn feature(mut self, feature: impl Into<String>) -> Self {
+        self.feature = Some(feature.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A translation block translated by the mock host. It can be executed until the next
+/// flush, reset or uninstall.
+pub struct Translation {
+    tb: usize,
+    vaddr: u64,
+}
+
+/// Installs the plugin instance a session starts with, in place of the global plugin
+struct InstallPlugin(Box<dyn FnOnce() + Send>);
+
+impl fmt::Debug for InstallPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("InstallPlugin")
+    }
+}
+
+#[derive(Debug)]
+/// Builder for a [`MockQemu`], created by [`MockQemu::user`] or [`MockQemu::system`]
+pub struct MockQemuBuilder {
+    target_name: String,
+    system: Option<(i32, i32)>,
+    args: Vec<String>,
+    binary: Option<String>,
+    start_code: u64,
+    end_code: u64,
+    entry_code: u64,
+    registers: Vec<MockRegister>,
+    plugin: Option<InstallPlugin>,
+}
+
+impl MockQemuBuilder {
+    /// Pass an argument (e.g. `key=value`) to the plugin
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Pass several arguments to the plugin
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the path of the binary being emulated and the bounds and entry point of its
+    /// code, as reported in user mode
+    pub fn binary(mut self, path: impl Into<String>, start: u64, end: u64, entry: u64) -> Self {
+        self.binary = Some(path.into());
+        self.start_code = start;
+        self.end_code = end;
+        self.entry_code = entry;
+        self
+    }
+
+    /// Add a register exposed through the register API
+    pub fn register(mut self, register: MockRegister) -> Self {
+        self.registers.push(register);
+        self
+    }
+
+    /// Install `plugin` in place of the plugin registered with `register!`, so the
+    /// session starts with a plugin in its initial state
+    pub fn plugin<P>(mut self, plugin: P) -> Self
+    where
+        P: Plugin,
+    {
+        self.plugin = Some(InstallPlugin(Box::new(|| {
+            qemu_plugin::mock::install_plugin(plugin)
+        })));
+        self
+    }
+
+    /// Install `plugin` in place of the plugin registered with `register!(sync ...)`, so
+    /// the session starts with a plugin in its initial state
+    pub fn plugin_sync<P>(mut self, plugin: P) -> Self
+    where
+        P: PluginSync,
+    {
+        // NOTE: The plugin is only replaced once the earlier session is over, when none
+        // of its callbacks can run
+        self.plugin = Some(InstallPlugin(Box::new(|| unsafe {
+            qemu_plugin::mock::install_plugin_sync(plugin)
+        })));
+        self
+    }
+
+    /// Install the plugin into a new mock host, like [`MockQemuBuilder::install`], then
+    /// initialize vCPU 0, as QEMU does before it runs the guest
+    pub fn start(self) -> Result<MockQemu> {
+        let mut qemu = self.install()?;
+        qemu.vcpu_init(0);
+        Ok(qemu)
+    }
+
+    /// Install the plugin linked into this process into a new mock host. Blocks until
+    /// any other installed mock host is dropped.
+    pub fn install(self) -> Result<MockQemu> {
+        let session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+
+        qemu_plugin::mock::reset();
+        if let Some(InstallPlugin(install)) = self.plugin {
+            install();
+        }
+
+        let target_name =
+            CString::new(self.target_name.clone()).map_err(|_| Error::InvalidArgument {
+                argument: self.target_name.clone(),
+            })?;
+        let args = self
+            .args
+            .iter()
+            .map(|a| {
+                CString::new(a.as_str()).map_err(|_| Error::InvalidArgument {
+                    argument: a.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let binary = self
+            .binary
+            .map(|b| CString::new(b.as_str()).map_err(|_| Error::InvalidArgument { argument: b }))
+            .transpose()?;
+
+        let mut state = State::new(
+            MOCK_PLUGIN_ID,
+            Config {
+                target_name,
+                system: self.system,
+                binary,
+                start_code: self.start_code,
+                end_code: self.end_code,
+                entry_code: self.entry_code,
+            },
+            self.registers,
+        );
+        state.args = args;
+        *state::lock() = Some(state);
+
+        let qemu = MockQemu { _session: session };
+
+        // NOTE: argv must stay valid for the lifetime of the plugin, so it points into the
+        // arguments owned by the state, which are not modified until the session ends
+        let (info, argv) = state::with(|s| {
+            let (smp_vcpus, max_vcpus) = s.config.system.unwrap_or_default();
+            let info = qemu_info_t {
+                target_name: s.config.target_name.as_ptr(),
+                version: qemu_info_t__bindgen_ty_1 {
+                    min: QEMU_PLUGIN_VERSION as c_int,
+                    cur: QEMU_PLUGIN_VERSION as c_int,
+                },
+                system_emulation: s.config.system.is_some(),
+                __bindgen_anon_1: qemu_info_t__bindgen_ty_2 {
+                    system: qemu_info_t__bindgen_ty_2__bindgen_ty_1 {
+                        smp_vcpus,
+                        max_vcpus,
+                    },
+                },
+            };
+            let argv = s.args.iter().map(|a| a.as_ptr()).collect::<Vec<_>>();
+            (info, argv)
+        });
+
+        let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+            qemu_plugin::install::qemu_plugin_install(
+                MOCK_PLUGIN_ID,
+                &info,
+                argv.len() as c_int,
+                argv.as_ptr(),
+            )
+        }));
+
+        match result {
+            Ok(0) => {
+                qemu.process_pending();
+                Ok(qemu)
+            }
+            Ok(code) => Err(Error::InstallFailed { code }),
+            Err(e) => Err(Error::InstallPanicked {
+                message: e
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+/// An installed mock QEMU host
+///
+/// Each method synthesizes an event the way QEMU would deliver it, running every callback
+/// the plugin registered for it before returning. Uninstall and reset requests made by the
+/// plugin are carried out once the event that made them has been handled, as QEMU does.
+pub struct MockQemu {
+    _session: MutexGuard<'static, ()>,
+}
+
+impl MockQemu {
+    /// Configure a mock host emulating `target_name` (e.g. `x86_64`) in user mode
+    pub fn user(target_name: impl Into<String>) -> MockQemuBuilder {
+        MockQemuBuilder {
+            target_name: target_name.into(),
+            system: None,
+            args: Vec::new(),
+            binary: None,
+            start_code: 0,
+            end_code: 0,
+            entry_code: 0,
+            registers: Vec::new(),
+            plugin: None,
+        }
+    }
+
+    /// Configure a mock host emulating `target_name` (e.g. `x86_64`) in system mode with
+    /// `smp_vcpus` initial and `max_vcpus` maximum vCPUs
+    pub fn system(
+        target_name: impl Into<String>,
+        smp_vcpus: u32,
+        max_vcpus: u32,
+    ) -> MockQemuBuilder {
+        MockQemuBuilder {
+            system: Some((smp_vcpus as i32, max_vcpus as i32)),
+            ..Self::user(target_name)
+        }
+    }
+
+    /// The ID given to the installed plugin
+    pub fn plugin_id(&self) -> u64 {
+        MOCK_PLUGIN_ID
+    }
+
+    /// Run a simple vCPU callback selected by `select` on `vcpu_index`
+    fn vcpu_event(
+        &mut self,
+        vcpu_index: u32,
+        select: impl FnOnce(&mut State) -> qemu_plugin_sys::qemu_plugin_vcpu_simple_cb_t,
+    ) {
+        let (id, cb) = state::with(|s| {
+            s.current_vcpu = Some(vcpu_index);
+            (s.id, select(s))
+        });
+        if let Some(cb) = cb {
+            unsafe { cb(id, vcpu_index) };
+        }
+        self.process_pending();
+    }
+
+    /// Initialize the vCPU `vcpu_index`
+    pub fn vcpu_init(&mut self, vcpu_index: u32) {
+        self.vcpu_event(vcpu_index, |s| {
+            s.vcpus.insert(vcpu_index);
+            s.scoreboards
+                .values_mut()
+                .for_each(|score| score.ensure(vcpu_index));
+            s.callbacks.vcpu_init
+        })
+    }
+
+    /// Exit the vCPU `vcpu_index`
+    pub fn vcpu_exit(&mut self, vcpu_index: u32) {
+        self.vcpu_event(vcpu_index, |s| s.callbacks.vcpu_exit)
+    }
+
+    /// Put the vCPU `vcpu_index` into the idle state
+    pub fn vcpu_idle(&mut self, vcpu_index: u32) {
+        self.vcpu_event(vcpu_index, |s| s.callbacks.vcpu_idle)
+    }
+
+    /// Resume the vCPU `vcpu_index` from the idle state
+    pub fn vcpu_resume(&mut self, vcpu_index: u32) {
+        self.vcpu_event(vcpu_index, |s| s.callbacks.vcpu_resume)
+    }
+
+    /// Translate a block, running the plugin's translation callback on it
+    pub fn translate(&mut self, tb: &MockTranslationBlock) -> Translation {
+        let (id, cb, translation) = state::with(|s| {
+            let insns = tb
+                .instructions
+                .iter()
+                .cloned()
+                .map(|insn| {
+                    let insn = Box::new(state::Insn::from(insn));
+                    let handle = key(insn.as_ref());
+                    s.insns.insert(handle, insn);
+                    handle
+                })
+                .collect();
+            let block = Box::new(state::Tb {
+                vaddr: tb.vaddr,
+                insns,
+                instrumentation: Vec::new(),
+            });
+            let handle = key(block.as_ref());
+            s.tbs.insert(handle, block);
+            (
+                s.id,
+                s.callbacks.tb_trans,
+                Translation {
+                    tb: handle,
+                    vaddr: tb.vaddr,
+                },
+            )
+        });
+
+        if let Some(cb) = cb {
+            unsafe { cb(id, translation.tb as *mut qemu_plugin_tb) };
+        }
+        self.process_pending();
+        translation
+    }
+
+    /// Run the inline operations and execution callbacks in `instrumentation` on
+    /// `vcpu_index`, in the order they were registered
+    fn run_instrumentation(vcpu_index: u32, instrumentation: &[Instrumentation]) {
+        for instrumentation in instrumentation {
+            let callback = match instrumentation {
+                Instrumentation::Inline(op) => {
+                    state::with(|s| s.apply_inline(op, vcpu_index));
+                    continue;
+                }
+                Instrumentation::Exec(callback) => callback,
+            };
+
+            #[cfg(not(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2"
+            )))]
+            if let Some(condition) = &callback.condition
+                && !state::with(|s| s.condition_holds(condition, vcpu_index))
+            {
+                continue;
+            }
+
+            if let Some(cb) = callback.cb {
+                unsafe { cb(vcpu_index, callback.userdata as *mut c_void) };
+            }
+        }
+    }
+
+    /// Execute a translated block on `vcpu_index`, running its instrumentation and that of
+    /// each instruction and memory access in it, in order
+    pub fn execute(&mut self, translation: &Translation, vcpu_index: u32) -> Result<()> {
+        let tb = state::with(|s| {
+            s.current_vcpu = Some(vcpu_index);
+            s.tbs
+                .get(&translation.tb)
+                .map(|tb| (tb.instrumentation.clone(), tb.insns.clone()))
+        });
+        let Some((instrumentation, insns)) = tb else {
+            return Err(Error::StaleTranslation {
+                vaddr: translation.vaddr,
+            });
+        };
+
+        Self::run_instrumentation(vcpu_index, &instrumentation);
+
+        for insn in insns {
+            let Some((instrumentation, mem, mem_inline, accesses)) = state::with(|s| {
+                s.insns.get(&insn).map(|insn| {
+                    (
+                        insn.instrumentation.clone(),
+                        insn.mem.clone(),
+                        insn.mem_inline.clone(),
+                        insn.accesses.clone(),
+                    )
+                })
+            }) else {
+                // NOTE: A callback flushed the translation out from under us
+                break;
+            };
+
+            Self::run_instrumentation(vcpu_index, &instrumentation);
+
+            for access in accesses {
+                let rw = if access.store {
+                    qemu_plugin_mem_rw::QEMU_PLUGIN_MEM_W as u32
+                } else {
+                    qemu_plugin_mem_rw::QEMU_PLUGIN_MEM_R as u32
+                };
+                let info = api::meminfo(
+                    access.size_shift,
+                    access.sign_extended,
+                    access.big_endian,
+                    access.store,
+                );
+                let vaddr = access.vaddr;
+
+                state::with(|s| {
+                    mem_inline
+                        .iter()
+                        .filter(|m| m.rw as u32 & rw != 0)
+                        .for_each(|m| s.apply_inline(&m.op, vcpu_index));
+                    s.current_access = Some(CurrentAccess {
+                        hwaddr: access.hwaddr.clone().map(|h| {
+                            let device = h.device.as_ref().map(|d| {
+                                CString::new(d.as_str()).expect("device name must not contain NUL")
+                            });
+                            Box::new((h, device))
+                        }),
+                        access,
+                    });
+                });
+
+                mem.iter().filter(|m| m.rw as u32 & rw != 0).for_each(|m| {
+                    if let Some(cb) = m.cb {
+                        unsafe { cb(vcpu_index, info, vaddr, m.userdata as *mut c_void) };
+                    }
+                });
+
+                state::with(|s| s.current_access = None);
+            }
+        }
+
+        self.process_pending();
+        Ok(())
+    }
+
+    /// Issue a system call `num` with arguments `args` on `vcpu_index`
+    pub fn syscall(&mut self, vcpu_index: u32, num: i64, args: [u64; 8]) {
+        let (id, cb) = state::with(|s| {
+            s.current_vcpu = Some(vcpu_index);
+            (s.id, s.callbacks.syscall)
+        });
+        if let Some(cb) = cb {
+            unsafe {
+                cb(
+                    id, vcpu_index, num, args[0], args[1], args[2], args[3], args[4], args[5],
+                    args[6], args[7],
+                )
+            };
+        }
+        self.process_pending();
+    }
+
+    /// Return from system call `num` with return value `ret` on `vcpu_index`
+    pub fn syscall_return(&mut self, vcpu_index: u32, num: i64, ret: i64) {
+        let (id, cb) = state::with(|s| {
+            s.current_vcpu = Some(vcpu_index);
+            (s.id, s.callbacks.syscall_ret)
+        });
+        if let Some(cb) = cb {
+            unsafe { cb(id, vcpu_index, num, ret) };
+        }
+        self.process_pending();
+    }
+
+    /// Flush the translation cache, invalidating every [`Translation`] and running the
+    /// plugin's flush callback
+    pub fn flush(&mut self) {
+        Self::do_flush();
+        self.process_pending();
+    }
+
+    /// Flush the translation cache without processing pending work
+    fn do_flush() {
+        let (id, cb) = state::with(|s| {
+            s.tbs.clear();
+            s.insns.clear();
+            (s.id, s.callbacks.flush)
+        });
+        if let Some(cb) = cb {
+            unsafe { cb(id) };
+        }
+    }
+
+    /// Exit the emulator, running the plugin's exit callbacks
+    pub fn exit(self) {
+        let (id, callback) = state::with(|s| (s.id, s.callbacks.atexit.take()));
+        if let Some((Some(cb), userdata)) = callback {
+            unsafe { cb(id, userdata as *mut c_void) };
+        }
+        self.process_pending();
+    }
+
+    /// Carry out uninstall and reset requests made by the plugin
+    fn process_pending(&self) {
+        while let Some(pending) = state::with(|s| {
+            if s.pending.is_empty() {
+                None
+            } else {
+                Some(s.pending.remove(0))
+            }
+        }) {
+            // NOTE: Like QEMU, flush the translation cache before dropping the plugin's
+            // callbacks, so its flush callback still runs
+            Self::do_flush();
+
+            let (id, cb) = state::with(|s| {
+                s.clear_instrumentation();
+                match pending {
+                    Pending::Uninstall(cb) => {
+                        s.uninstalled = true;
+                        s.pending.clear();
+                        (s.id, cb)
+                    }
+                    Pending::Reset(cb) => (s.id, cb),
+                }
+            });
+
+            if let Some(cb) = cb {
+                unsafe { cb(id) };
+            }
+        }
+    }
+
+    /// Whether the plugin has uninstalled itself
+    pub fn uninstalled(&self) -> bool {
+        state::with(|s| s.uninstalled)
+    }
+
+    /// The number of translated blocks currently cached
+    pub fn translations(&self) -> usize {
+        state::with(|s| s.tbs.len())
+    }
+
+    /// Everything the plugin has output with `qemu_plugin_outs` so far
+    pub fn outs(&self) -> Vec<String> {
+        state::with(|s| s.outs.clone())
+    }
+
+    /// Take everything the plugin has output with `qemu_plugin_outs` so far
+    pub fn take_outs(&mut self) -> Vec<String> {
+        state::with(|s| std::mem::take(&mut s.outs))
+    }
+
+    /// Map zeroed guest virtual memory covering `addr..addr+len`
+    pub fn map_memory(&mut self, addr: u64, len: u64) {
+        state::with(|s| s.memory.map(addr, len))
+    }
+
+    /// Write guest virtual memory, which must be mapped
+    pub fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        state::with(|s| s.memory.write(addr, data))
+            .then_some(())
+            .ok_or(Error::UnmappedMemory {
+                addr,
+                len: data.len(),
+            })
+    }
+
+    /// Read guest virtual memory, which must be mapped
+    pub fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        state::with(|s| s.memory.read(addr, &mut data))
+            .then_some(data)
+            .ok_or(Error::UnmappedMemory { addr, len })
+    }
+
+    /// Map zeroed guest physical memory covering `addr..addr+len`
+    pub fn map_physical_memory(&mut self, addr: u64, len: u64) {
+        state::with(|s| s.physical.map(addr, len))
+    }
+
+    /// Write guest physical memory, which must be mapped
+    pub fn write_physical_memory(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        state::with(|s| s.physical.write(addr, data))
+            .then_some(())
+            .ok_or(Error::UnmappedMemory {
+                addr,
+                len: data.len(),
+            })
+    }
+
+    /// Read guest physical memory, which must be mapped
+    pub fn read_physical_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        state::with(|s| s.physical.read(addr, &mut data))
+            .then_some(data)
+            .ok_or(Error::UnmappedMemory { addr, len })
+    }
+
+    /// Set the value of register `name` on `vcpu_index`
+    pub fn set_register(&mut self, vcpu_index: u32, name: &str, value: &[u8]) -> Result<()> {
+        state::with(|s| {
+            let register = s
+                .registers
+                .iter_mut()
+                .find(|r| r.name.as_bytes() == name.as_bytes())
+                .ok_or_else(|| Error::NoSuchRegister {
+                    name: name.to_string(),
+                })?;
+            if register.initial.len() != value.len() {
+                return Err(Error::InvalidRegisterSize {
+                    name: name.to_string(),
+                    size: value.len(),
+                    expected: register.initial.len(),
+                });
+            }
+            *register.value(vcpu_index as c_uint) = value.to_vec();
+            Ok(())
+        })
+    }
+
+    /// Get the value of register `name` on `vcpu_index`
+    pub fn register(&self, vcpu_index: u32, name: &str) -> Result<Vec<u8>> {
+        state::with(|s| {
+            s.registers
+                .iter_mut()
+                .find(|r| r.name.as_bytes() == name.as_bytes())
+                .map(|r| r.value(vcpu_index as c_uint).clone())
+                .ok_or_else(|| Error::NoSuchRegister {
+                    name: name.to_string(),
+                })
+        })
+    }
+
+    /// The virtual time in nanoseconds, as last set by a plugin holding time control
+    pub fn time_ns(&self) -> i64 {
+        state::with(|s| s.time_ns)
+    }
+}
+
+impl Drop for MockQemu {
+    fn drop(&mut self) {
+        *state::lock() = None;
+    }
+}
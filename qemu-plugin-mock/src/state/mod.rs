@@ -0,0 +1,456 @@
+//! Global state of the mock QEMU host
+//!
+//! The QEMU plugin API is a set of free functions, so the state they operate on has to
+//! be global. Every entry point locks the state for as short a time as possible and never
+//! holds the lock while calling back into the plugin, because plugin callbacks are free to
+//! call back into the API.
+
+// NOTE: The state models the newest plugin API, parts of which older API versions do not
+// expose
+#![cfg_attr(
+    any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    ),
+    allow(dead_code)
+)]
+
+use crate::{MockHwaddr, MockInstruction, MockMemoryAccess, MockRegister};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+use qemu_plugin_sys::qemu_plugin_cond;
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use qemu_plugin_sys::qemu_plugin_u64;
+use qemu_plugin_sys::{
+    qemu_plugin_cb_flags, qemu_plugin_id_t, qemu_plugin_mem_rw, qemu_plugin_op,
+    qemu_plugin_simple_cb_t, qemu_plugin_udata_cb_t, qemu_plugin_vcpu_mem_cb_t,
+    qemu_plugin_vcpu_simple_cb_t, qemu_plugin_vcpu_syscall_cb_t, qemu_plugin_vcpu_syscall_ret_cb_t,
+    qemu_plugin_vcpu_tb_trans_cb_t, qemu_plugin_vcpu_udata_cb_t,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::{CString, c_uint},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Size of a page of mock guest memory
+pub(crate) const PAGE_SIZE: u64 = 0x1000;
+
+/// The global mock host state
+pub(crate) static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// Lock the global state, ignoring poisoning. A panicking test must not take every later
+/// test in the same binary down with it.
+pub(crate) fn lock() -> MutexGuard<'static, Option<State>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run `f` on the global state. Panics if called outside of a mock session, which means a
+/// plugin called into the QEMU API without a `MockQemu` being installed.
+pub(crate) fn with<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    let mut state = lock();
+    let state = state
+        .as_mut()
+        .expect("QEMU plugin API called without an installed MockQemu");
+    f(state)
+}
+
+/// Return the address of a boxed value, which is used as the opaque handle for it
+pub(crate) fn key<T>(value: &T) -> usize {
+    value as *const T as usize
+}
+
+/// Callbacks registered for the whole plugin (as opposed to per-TB or per-instruction)
+#[derive(Default)]
+pub(crate) struct PluginCallbacks {
+    pub vcpu_init: qemu_plugin_vcpu_simple_cb_t,
+    pub vcpu_exit: qemu_plugin_vcpu_simple_cb_t,
+    pub vcpu_idle: qemu_plugin_vcpu_simple_cb_t,
+    pub vcpu_resume: qemu_plugin_vcpu_simple_cb_t,
+    pub tb_trans: qemu_plugin_vcpu_tb_trans_cb_t,
+    pub flush: qemu_plugin_simple_cb_t,
+    pub syscall: qemu_plugin_vcpu_syscall_cb_t,
+    pub syscall_ret: qemu_plugin_vcpu_syscall_ret_cb_t,
+    /// Like QEMU, only the last exit callback registered is kept
+    pub atexit: Option<(qemu_plugin_udata_cb_t, usize)>,
+}
+
+/// The target of an inline operation
+#[derive(Clone, Copy)]
+pub(crate) enum InlineTarget {
+    /// A raw pointer into plugin memory, used by the legacy (v0 and v1) inline API
+    #[cfg_attr(
+        not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")),
+        allow(dead_code)
+    )]
+    Pointer(usize),
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// A scoreboard entry, used by the per-vCPU inline API
+    Entry(qemu_plugin_u64),
+}
+
+/// An inline operation registered on a TB, instruction or memory access
+#[derive(Clone, Copy)]
+pub(crate) struct InlineOp {
+    pub op: qemu_plugin_op,
+    pub target: InlineTarget,
+    pub imm: u64,
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2"
+)))]
+/// A condition guarding a conditional callback
+#[derive(Clone, Copy)]
+pub(crate) struct Condition {
+    pub cond: qemu_plugin_cond,
+    pub entry: qemu_plugin_u64,
+    pub imm: u64,
+}
+
+/// An execution callback with its user data, and an optional condition
+#[derive(Clone, Copy)]
+pub(crate) struct ExecCallback {
+    pub cb: qemu_plugin_vcpu_udata_cb_t,
+    #[allow(dead_code)]
+    pub flags: qemu_plugin_cb_flags,
+    pub userdata: usize,
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2"
+    )))]
+    pub condition: Option<Condition>,
+}
+
+/// An execution callback or inline operation registered on a TB or instruction. QEMU
+/// runs both kinds in the order they were registered, so they are kept in one list.
+#[derive(Clone, Copy)]
+pub(crate) enum Instrumentation {
+    Exec(ExecCallback),
+    Inline(InlineOp),
+}
+
+/// A memory callback with its user data
+#[derive(Clone, Copy)]
+pub(crate) struct MemCallback {
+    pub cb: qemu_plugin_vcpu_mem_cb_t,
+    pub rw: qemu_plugin_mem_rw,
+    pub userdata: usize,
+}
+
+/// An inline operation on memory accesses
+#[derive(Clone, Copy)]
+pub(crate) struct MemInline {
+    pub rw: qemu_plugin_mem_rw,
+    pub op: InlineOp,
+}
+
+/// A translated instruction and the instrumentation registered on it
+pub(crate) struct Insn {
+    pub vaddr: u64,
+    pub haddr: u64,
+    pub data: Vec<u8>,
+    pub disas: Option<CString>,
+    pub symbol: Option<CString>,
+    pub accesses: Vec<MockMemoryAccess>,
+    pub instrumentation: Vec<Instrumentation>,
+    pub mem: Vec<MemCallback>,
+    pub mem_inline: Vec<MemInline>,
+}
+
+impl From<MockInstruction> for Insn {
+    fn from(value: MockInstruction) -> Self {
+        Self {
+            vaddr: value.vaddr,
+            haddr: value.haddr,
+            data: value.data,
+            disas: value
+                .disas
+                .map(|d| CString::new(d).expect("disassembly must not contain NUL")),
+            symbol: value
+                .symbol
+                .map(|s| CString::new(s).expect("symbol must not contain NUL")),
+            accesses: value.accesses,
+            instrumentation: Vec::new(),
+            mem: Vec::new(),
+            mem_inline: Vec::new(),
+        }
+    }
+}
+
+/// A translated block and the instrumentation registered on it
+pub(crate) struct Tb {
+    pub vaddr: u64,
+    /// Keys of the instructions of this block, in order
+    pub insns: Vec<usize>,
+    pub instrumentation: Vec<Instrumentation>,
+}
+
+/// A scoreboard, one entry of `size` bytes per vCPU
+pub(crate) struct Scoreboard {
+    pub size: usize,
+    pub data: Vec<u8>,
+}
+
+impl Scoreboard {
+    /// Make sure the scoreboard has an entry for `vcpu_index`, growing it with zeroed
+    /// entries like QEMU does when new vCPUs are created
+    pub fn ensure(&mut self, vcpu_index: c_uint) {
+        let needed = (vcpu_index as usize + 1) * self.size;
+        if self.data.len() < needed {
+            self.data.resize(needed, 0);
+        }
+    }
+
+    /// Return the u64 at `offset` in the entry for `vcpu_index`
+    pub fn u64_mut(&mut self, vcpu_index: c_uint, offset: usize) -> &mut [u8] {
+        self.ensure(vcpu_index);
+        let start = vcpu_index as usize * self.size + offset;
+        &mut self.data[start..start + 8]
+    }
+}
+
+/// Sparse, page-granular mock memory
+#[derive(Default)]
+pub(crate) struct Memory {
+    pages: HashMap<u64, Box<[u8; PAGE_SIZE as usize]>>,
+}
+
+impl Memory {
+    /// Map pages covering `addr..addr+len`, zero filled
+    pub fn map(&mut self, addr: u64, len: u64) {
+        let mut page = addr & !(PAGE_SIZE - 1);
+        while page < addr.saturating_add(len) {
+            self.pages
+                .entry(page)
+                .or_insert_with(|| Box::new([0; PAGE_SIZE as usize]));
+            page += PAGE_SIZE;
+        }
+    }
+
+    /// Whether every byte in `addr..addr+len` is mapped
+    pub fn is_mapped(&self, addr: u64, len: u64) -> bool {
+        (0..len).all(|i| {
+            self.pages
+                .contains_key(&(addr.wrapping_add(i) & !(PAGE_SIZE - 1)))
+        })
+    }
+
+    /// Read `buf.len()` bytes at `addr`. Returns false without reading if any byte is
+    /// unmapped.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        if !self.is_mapped(addr, buf.len() as u64) {
+            return false;
+        }
+        buf.iter_mut().enumerate().for_each(|(i, b)| {
+            let a = addr.wrapping_add(i as u64);
+            *b = self.pages[&(a & !(PAGE_SIZE - 1))][(a & (PAGE_SIZE - 1)) as usize];
+        });
+        true
+    }
+
+    /// Write `buf` at `addr`. Returns false without writing if any byte is unmapped.
+    pub fn write(&mut self, addr: u64, buf: &[u8]) -> bool {
+        if !self.is_mapped(addr, buf.len() as u64) {
+            return false;
+        }
+        buf.iter().enumerate().for_each(|(i, b)| {
+            let a = addr.wrapping_add(i as u64);
+            if let Some(page) = self.pages.get_mut(&(a & !(PAGE_SIZE - 1))) {
+                page[(a & (PAGE_SIZE - 1)) as usize] = *b;
+            }
+        });
+        true
+    }
+}
+
+/// A register and its value on each vCPU
+pub(crate) struct Register {
+    pub name: CString,
+    pub feature: Option<CString>,
+    pub initial: Vec<u8>,
+    pub values: HashMap<c_uint, Vec<u8>>,
+}
+
+impl From<MockRegister> for Register {
+    fn from(value: MockRegister) -> Self {
+        Self {
+            name: CString::new(value.name).expect("register name must not contain NUL"),
+            feature: value
+                .feature
+                .map(|f| CString::new(f).expect("register feature must not contain NUL")),
+            initial: value.value,
+            values: HashMap::new(),
+        }
+    }
+}
+
+impl Register {
+    /// The value of the register on `vcpu_index`
+    pub fn value(&mut self, vcpu_index: c_uint) -> &mut Vec<u8> {
+        self.values
+            .entry(vcpu_index)
+            .or_insert_with(|| self.initial.clone())
+    }
+}
+
+/// The memory access currently being reported to memory callbacks
+pub(crate) struct CurrentAccess {
+    pub access: MockMemoryAccess,
+    /// Boxed so the pointer handed out by `qemu_plugin_get_hwaddr` stays stable
+    pub hwaddr: Option<Box<(MockHwaddr, Option<CString>)>>,
+}
+
+/// Work QEMU performs asynchronously, once the current event has been handled
+pub(crate) enum Pending {
+    Uninstall(qemu_plugin_simple_cb_t),
+    Reset(qemu_plugin_simple_cb_t),
+}
+
+/// Configuration of the mocked emulator, as set up by `MockQemuBuilder`
+pub(crate) struct Config {
+    pub target_name: CString,
+    pub system: Option<(i32, i32)>,
+    pub binary: Option<CString>,
+    pub start_code: u64,
+    pub end_code: u64,
+    pub entry_code: u64,
+}
+
+/// The complete mock host state for one session
+pub(crate) struct State {
+    pub id: qemu_plugin_id_t,
+    pub config: Config,
+    pub args: Vec<CString>,
+    pub callbacks: PluginCallbacks,
+    pub uninstalled: bool,
+    pub vcpus: BTreeSet<c_uint>,
+    pub current_vcpu: Option<c_uint>,
+    pub tbs: HashMap<usize, Box<Tb>>,
+    pub insns: HashMap<usize, Box<Insn>>,
+    pub scoreboards: HashMap<usize, Box<Scoreboard>>,
+    pub registers: Vec<Register>,
+    pub memory: Memory,
+    pub physical: Memory,
+    pub current_access: Option<CurrentAccess>,
+    pub outs: Vec<String>,
+    pub time_control: Option<usize>,
+    pub time_ns: i64,
+    pub pending: Vec<Pending>,
+}
+
+// NOTE: The raw pointers in the state are opaque handles into plugin or mock-owned memory,
+// and the state is only ever accessed under the `STATE` lock
+unsafe impl Send for State {}
+
+impl State {
+    pub fn new(id: qemu_plugin_id_t, config: Config, registers: Vec<MockRegister>) -> Self {
+        Self {
+            id,
+            config,
+            args: Vec::new(),
+            callbacks: PluginCallbacks::default(),
+            uninstalled: false,
+            vcpus: BTreeSet::new(),
+            current_vcpu: None,
+            tbs: HashMap::new(),
+            insns: HashMap::new(),
+            scoreboards: HashMap::new(),
+            registers: registers.into_iter().map(Register::from).collect(),
+            memory: Memory::default(),
+            physical: Memory::default(),
+            current_access: None,
+            outs: Vec::new(),
+            time_control: None,
+            time_ns: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The number of vCPUs QEMU would report, which is one more than the highest vCPU
+    /// index that has been initialized
+    pub fn num_vcpus(&self) -> c_uint {
+        self.vcpus.last().map(|v| v + 1).unwrap_or(0)
+    }
+
+    /// Drop every callback and translation, as QEMU does on reset and uninstall
+    pub fn clear_instrumentation(&mut self) {
+        self.callbacks = PluginCallbacks::default();
+        self.tbs.clear();
+        self.insns.clear();
+    }
+
+    /// Apply an inline operation for `vcpu_index`
+    pub fn apply_inline(&mut self, op: &InlineOp, vcpu_index: c_uint) {
+        let apply = |value: u64| -> u64 {
+            #[allow(unreachable_patterns)]
+            match op.op {
+                qemu_plugin_op::QEMU_PLUGIN_INLINE_ADD_U64 => value.wrapping_add(op.imm),
+                _ => op.imm,
+            }
+        };
+
+        match op.target {
+            InlineTarget::Pointer(ptr) => {
+                // NOTE: Legacy inline operations are defined to operate directly on plugin
+                // memory, which the plugin guarantees outlives the instrumentation
+                let ptr = ptr as *mut u64;
+                unsafe { ptr.write_unaligned(apply(ptr.read_unaligned())) };
+            }
+            #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+            InlineTarget::Entry(entry) => {
+                if let Some(score) = self.scoreboards.get_mut(&(entry.score as usize)) {
+                    let bytes = score.u64_mut(vcpu_index, entry.offset);
+                    let value = u64::from_ne_bytes(bytes.try_into().expect("8 byte entry"));
+                    bytes.copy_from_slice(&apply(value).to_ne_bytes());
+                }
+            }
+        }
+
+        let _ = vcpu_index;
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Read a scoreboard entry for `vcpu_index`
+    pub fn entry_get(&mut self, entry: qemu_plugin_u64, vcpu_index: c_uint) -> u64 {
+        self.scoreboards
+            .get_mut(&(entry.score as usize))
+            .map(|score| {
+                u64::from_ne_bytes(
+                    score
+                        .u64_mut(vcpu_index, entry.offset)
+                        .try_into()
+                        .expect("8 byte entry"),
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2"
+    )))]
+    /// Whether the condition of a conditional callback holds for `vcpu_index`
+    pub fn condition_holds(&mut self, condition: &Condition, vcpu_index: c_uint) -> bool {
+        let value = self.entry_get(condition.entry, vcpu_index);
+        match condition.cond {
+            qemu_plugin_cond::QEMU_PLUGIN_COND_NEVER => false,
+            qemu_plugin_cond::QEMU_PLUGIN_COND_ALWAYS => true,
+            qemu_plugin_cond::QEMU_PLUGIN_COND_EQ => value == condition.imm,
+            qemu_plugin_cond::QEMU_PLUGIN_COND_NE => value != condition.imm,
+            qemu_plugin_cond::QEMU_PLUGIN_COND_LT => value < condition.imm,
+            qemu_plugin_cond::QEMU_PLUGIN_COND_LE => value <= condition.imm,
+            qemu_plugin_cond::QEMU_PLUGIN_COND_GT => value > condition.imm,
+            qemu_plugin_cond::QEMU_PLUGIN_COND_GE => value >= condition.imm,
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Temporary files for the outputs of plugins under test
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of temporary files created so far in this process, which makes each path
+/// unique
+static CREATED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+/// A unique path in the temporary directory, for a plugin under test to write an output
+/// file to. The file is removed when this is dropped, so outputs do not outlive the test
+/// that made them even if it fails.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// A unique path in the temporary directory ending with `name`. No file is created.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "qemu-plugin-mock-{}-{}-{name}",
+            std::process::id(),
+            CREATED.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        Self { path }
+    }
+
+    /// The path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The plugin argument `key=<path>`, passing the path of the file to the plugin
+    pub fn arg(&self, key: &str) -> String {
+        format!("{key}={}", self.path.display())
+    }
+
+    /// Read the contents of the file
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        fs::read(&self.path)
+    }
+
+    /// Read the contents of the file as UTF-8
+    pub fn read_to_string(&self) -> io::Result<String> {
+        fs::read_to_string(&self.path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
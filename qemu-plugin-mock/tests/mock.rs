@@ -0,0 +1,439 @@
+//! Tests of the mock host, driving a plugin which records every event it receives
+
+use qemu_plugin::{
+    Args, Callbacks, HasCallbacks, Info, MemRW, PluginController, PluginId, Register, Result,
+    Syscall, TranslationBlock, VCPUIndex, qemu_plugin_outs, qemu_plugin_register_atexit_cb,
+    qemu_plugin_uninstall, register,
+};
+use qemu_plugin_mock::{
+    Error, MockInstruction, MockMemoryAccess, MockQemu, MockQemuBuilder, MockTranslationBlock,
+};
+use std::sync::{
+    Mutex, PoisonError,
+    atomic::{AtomicU32, Ordering},
+};
+
+/// The number of times the plugin passed to `register!` has been created
+static REGISTERED: AtomicU32 = AtomicU32::new(0);
+
+/// The events the plugin has received, in order
+static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Record an event
+fn record(event: impl Into<String>) {
+    EVENTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(event.into());
+}
+
+/// Take the events recorded so far
+fn events() -> Vec<String> {
+    std::mem::take(&mut *EVENTS.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+struct Recorder {
+    /// The number of times this instance has been registered, which is 1 for a fresh
+    /// instance
+    registrations: u32,
+    /// The number of blocks this instance has translated
+    translations: u32,
+}
+
+impl Recorder {
+    fn new() -> Self {
+        Self {
+            registrations: 0,
+            translations: 0,
+        }
+    }
+
+    /// The recorder passed to `register!`
+    fn registered() -> Self {
+        REGISTERED.fetch_add(1, Ordering::Relaxed);
+        Self::new()
+    }
+}
+
+impl Register for Recorder {
+    fn register(&mut self, id: PluginId, args: &Args, info: &Info) -> Result<()> {
+        self.registrations += 1;
+        qemu_plugin_register_atexit_cb(id, |_| record("exit 1"))?;
+        qemu_plugin_register_atexit_cb(id, |_| record("exit 2"))?;
+        qemu_plugin_outs(format!(
+            "registered {} times on {} with {:?}",
+            self.registrations, info.target_name, args.raw
+        ))
+    }
+}
+
+impl HasCallbacks for Recorder {
+    fn on_vcpu_init(&mut self, _id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+        record(format!("init {vcpu_id}"));
+        Ok(())
+    }
+
+    fn on_translation_block_translate(
+        &mut self,
+        _id: PluginId,
+        tb: TranslationBlock,
+    ) -> Result<()> {
+        self.translations += 1;
+        record(format!(
+            "translate {:#x} #{}",
+            tb.vaddr(),
+            self.translations
+        ));
+
+        let vaddr = tb.vaddr();
+        tb.register_execute_callback(move |vcpu_index| {
+            record(format!("tb {vaddr:#x} on {vcpu_index}"));
+        });
+
+        for insn in tb.instructions() {
+            let vaddr = insn.vaddr();
+            insn.register_execute_callback(move |_| record(format!("insn {vaddr:#x}")));
+            insn.register_access_callback(
+                move |_, access| {
+                    record(format!(
+                        "{} {:#x} size {}",
+                        if access.is_store() { "store" } else { "load" },
+                        access.vaddr(),
+                        access.size()
+                    ));
+                },
+                MemRW::QEMU_PLUGIN_MEM_RW,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn on_flush(&mut self, _id: PluginId) -> Result<()> {
+        record("flush");
+        Ok(())
+    }
+
+    fn on_syscall_typed(
+        &mut self,
+        id: PluginId,
+        vcpu_index: VCPUIndex,
+        syscall: Syscall,
+    ) -> Result<()> {
+        record(format!(
+            "syscall {} {:?} on {vcpu_index}",
+            syscall.number(),
+            syscall.name()
+        ));
+
+        // The guest exits, so the plugin uninstalls itself
+        if syscall.name() == Some("exit_group") {
+            qemu_plugin_uninstall(id, |_| record("uninstalled"))?;
+        }
+
+        // The plugin uninstalls itself before a reset it requests can complete
+        if syscall.name() == Some("pause") {
+            qemu_plugin_uninstall(id, |_| record("uninstalled"))?;
+        }
+
+        if matches!(syscall.name(), Some("sched_yield" | "pause")) {
+            PluginController::new(id)
+                .reset_with(Callbacks::ALL, |result| record(format!("reset {result:?}")))?;
+        }
+
+        Ok(())
+    }
+
+    fn on_syscall_return(
+        &mut self,
+        _id: PluginId,
+        vcpu_index: VCPUIndex,
+        num: i64,
+        ret: i64,
+    ) -> Result<()> {
+        record(format!("return {num} = {ret} on {vcpu_index}"));
+        Ok(())
+    }
+}
+
+register!(Recorder::registered());
+
+/// A mock host which installs a new recorder
+fn recorder() -> MockQemuBuilder {
+    MockQemu::user("x86_64").plugin(Recorder::new())
+}
+
+/// A block of two instructions, the second loading from and storing to memory
+fn block() -> MockTranslationBlock {
+    MockTranslationBlock::new(0x1000)
+        .instruction(MockInstruction::new(0x1000, [0x90]))
+        .instruction(
+            MockInstruction::new(0x1001, [0x48, 0x01, 0x07])
+                .memory_access(MockMemoryAccess::load(0x2000, 8, 1u64))
+                .memory_access(MockMemoryAccess::store(0x2000, 8, 2u64)),
+        )
+}
+
+#[test]
+fn install_passes_arguments_and_target() {
+    let qemu = recorder().arg("verbose=on").install().unwrap();
+
+    assert_eq!(
+        qemu.outs(),
+        ["registered 1 times on x86_64 with [\"verbose=on\"]"]
+    );
+}
+
+#[test]
+fn execute_runs_instrumentation_in_order() {
+    let mut qemu = recorder().install().unwrap();
+    events();
+
+    qemu.vcpu_init(0);
+    let translation = qemu.translate(&block());
+    qemu.execute(&translation, 0).unwrap();
+
+    assert_eq!(
+        events(),
+        [
+            "init 0",
+            "translate 0x1000 #1",
+            "tb 0x1000 on 0",
+            "insn 0x1000",
+            "insn 0x1001",
+            "load 0x2000 size 8",
+            "store 0x2000 size 8",
+        ]
+    );
+
+    // Executing again runs the same instrumentation without translating again
+    qemu.execute(&translation, 1).unwrap();
+    assert_eq!(events()[0], "tb 0x1000 on 1");
+    assert_eq!(qemu.translations(), 1);
+}
+
+#[test]
+fn memory_is_readable_and_writable() {
+    let mut qemu = recorder().install().unwrap();
+
+    qemu.map_memory(0x4000, 0x100);
+    qemu.write_memory(0x4010, b"hello").unwrap();
+
+    assert_eq!(qemu.read_memory(0x4010, 5).unwrap(), b"hello");
+    assert_eq!(qemu.read_memory(0x40fe, 2).unwrap(), [0, 0]);
+    assert!(matches!(
+        qemu.read_memory(0x9000, 2),
+        Err(Error::UnmappedMemory {
+            addr: 0x9000,
+            len: 2
+        })
+    ));
+    assert!(qemu.write_memory(0x8000, b"x").is_err());
+}
+
+#[test]
+fn syscalls_reach_the_plugin() {
+    let mut qemu = recorder().install().unwrap();
+    events();
+
+    qemu.syscall(0, 39, [0; 8]);
+    qemu.syscall_return(0, 39, 1234);
+
+    assert_eq!(
+        events(),
+        ["syscall 39 Some(\"getpid\") on 0", "return 39 = 1234 on 0"]
+    );
+}
+
+#[test]
+fn flush_invalidates_translations() {
+    let mut qemu = recorder().install().unwrap();
+    events();
+
+    let translation = qemu.translate(&block());
+    qemu.flush();
+
+    assert_eq!(events(), ["translate 0x1000 #1", "flush"]);
+    assert_eq!(qemu.translations(), 0);
+    assert!(matches!(
+        qemu.execute(&translation, 0),
+        Err(Error::StaleTranslation { vaddr: 0x1000 })
+    ));
+
+    let translation = qemu.translate(&block());
+    qemu.execute(&translation, 0).unwrap();
+    assert_eq!(events()[..2], ["translate 0x1000 #2", "tb 0x1000 on 0"]);
+}
+
+#[test]
+fn uninstall_removes_callbacks() {
+    let mut qemu = recorder().install().unwrap();
+    events();
+
+    let translation = qemu.translate(&block());
+    qemu.syscall(0, 231, [0; 8]);
+
+    assert!(qemu.uninstalled());
+    assert_eq!(
+        events(),
+        [
+            "translate 0x1000 #1",
+            "syscall 231 Some(\"exit_group\") on 0",
+            "flush",
+            "uninstalled",
+        ]
+    );
+
+    // Nothing reaches the plugin once it is uninstalled
+    assert!(qemu.execute(&translation, 0).is_err());
+    qemu.syscall(0, 39, [0; 8]);
+    let translation = qemu.translate(&block());
+    qemu.execute(&translation, 0).unwrap();
+    assert!(events().is_empty());
+}
+
+/// Panics when dropped, like state captured by a callback whose destructor fails
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("dropped");
+    }
+}
+
+/// A plugin whose execution callbacks panic when they are dropped
+struct Dropper;
+
+impl Register for Dropper {}
+
+impl HasCallbacks for Dropper {
+    fn on_translation_block_translate(
+        &mut self,
+        _id: PluginId,
+        tb: TranslationBlock,
+    ) -> Result<()> {
+        let state = PanicOnDrop;
+        tb.register_execute_callback(move |_| {
+            let _ = &state;
+        });
+        Ok(())
+    }
+}
+
+#[test]
+fn panics_dropping_callbacks_are_caught() {
+    let mut qemu = MockQemu::user("x86_64").plugin(Dropper).install().unwrap();
+
+    qemu.translate(&block());
+    qemu.flush();
+
+    assert!(
+        qemu.outs()
+            .iter()
+            .any(|out| out.starts_with("panic in dropping callbacks: dropped"))
+    );
+}
+
+#[test]
+fn exit_callbacks_run_in_order() {
+    let qemu = recorder().install().unwrap();
+    events();
+
+    qemu.exit();
+    assert_eq!(events(), ["exit 1", "exit 2"]);
+}
+
+#[test]
+fn resets_abandoned_by_uninstalling_fail() {
+    let mut qemu = recorder().install().unwrap();
+    events();
+
+    qemu.syscall(0, 34, [0; 8]);
+
+    assert!(qemu.uninstalled());
+    assert!(!PluginController::is_resetting());
+    assert_eq!(
+        events(),
+        [
+            "syscall 34 Some(\"pause\") on 0",
+            "flush",
+            "reset Err(PluginResetAbandoned)",
+            "uninstalled",
+        ]
+    );
+}
+
+#[test]
+fn reset_keeps_exit_callbacks() {
+    let mut qemu = recorder().install().unwrap();
+    events();
+
+    let translation = qemu.translate(&block());
+    qemu.syscall(0, 24, [0; 8]);
+
+    assert_eq!(
+        events(),
+        [
+            "translate 0x1000 #1",
+            "syscall 24 Some(\"sched_yield\") on 0",
+            "flush",
+            "reset Ok(())",
+        ]
+    );
+    assert!(qemu.execute(&translation, 0).is_err());
+
+    qemu.exit();
+    assert_eq!(events(), ["exit 1", "exit 2"]);
+}
+
+#[test]
+fn sessions_install_a_fresh_plugin() {
+    for _ in 0..2 {
+        let mut qemu = recorder().install().unwrap();
+        events();
+
+        assert_eq!(qemu.take_outs(), ["registered 1 times on x86_64 with []"]);
+
+        let _ = qemu.translate(&block());
+        qemu.syscall(0, 231, [0; 8]);
+
+        assert!(qemu.uninstalled());
+        assert_eq!(events()[0], "translate 0x1000 #1");
+    }
+}
+
+#[test]
+fn the_registered_plugin_is_created_once() {
+    for _ in 0..2 {
+        let qemu = MockQemu::user("x86_64").install().unwrap();
+        qemu.exit();
+    }
+
+    assert_eq!(REGISTERED.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+#[test]
+fn time_saturates_at_the_latest_time() {
+    use qemu_plugin::time::TimeControl;
+
+    let qemu = MockQemu::system("x86_64", 1, 1)
+        .plugin(Recorder::new())
+        .install()
+        .unwrap();
+    let time = TimeControl::request().unwrap();
+
+    assert_eq!(time.advance_ns(10), 10);
+    assert_eq!(time.update_ns(5), 10);
+    assert_eq!(qemu.time_ns(), 10);
+
+    assert_eq!(time.advance_ns(u64::MAX), i64::MAX as u64);
+    assert_eq!(time.advance_ns(1), i64::MAX as u64);
+    assert_eq!(time.now_ns(), i64::MAX as u64);
+    assert_eq!(qemu.time_ns(), i64::MAX);
+}
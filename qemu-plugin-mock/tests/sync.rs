@@ -0,0 +1,102 @@
+//! Tests of the mock host driving a plugin registered with `register!(sync ...)`
+
+use qemu_plugin::{
+    HasCallbacksSync, PluginId, RegisterSync, Result, Syscall, TranslationBlock, VCPUIndex,
+    register,
+};
+use qemu_plugin_mock::{MockInstruction, MockQemu, MockTranslationBlock};
+use std::sync::{Mutex, PoisonError};
+
+/// The events the plugin has received, in order
+static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Record an event
+fn record(event: impl Into<String>) {
+    EVENTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(event.into());
+}
+
+/// Take the events recorded so far
+fn events() -> Vec<String> {
+    std::mem::take(&mut *EVENTS.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+/// A plugin which records the events it receives, with callbacks taking `&self`
+#[derive(Default)]
+struct Recorder;
+
+impl RegisterSync for Recorder {}
+
+impl HasCallbacksSync for Recorder {
+    fn on_vcpu_init(&self, _id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+        record(format!("init {vcpu_id}"));
+        Ok(())
+    }
+
+    fn on_translation_block_translate(&self, _id: PluginId, tb: TranslationBlock) -> Result<()> {
+        let vaddr = tb.vaddr();
+        record(format!("translate {vaddr:#x}"));
+        tb.register_execute_callback(move |vcpu_index| {
+            record(format!("tb {vaddr:#x} on {vcpu_index}"));
+        });
+        Ok(())
+    }
+
+    fn on_flush(&self, _id: PluginId) -> Result<()> {
+        record("flush");
+        Ok(())
+    }
+
+    fn on_syscall_typed(
+        &self,
+        _id: PluginId,
+        vcpu_index: VCPUIndex,
+        syscall: Syscall,
+    ) -> Result<()> {
+        record(format!("syscall {:?} on {vcpu_index}", syscall.name()));
+        Ok(())
+    }
+
+    fn on_syscall_return(
+        &self,
+        _id: PluginId,
+        vcpu_index: VCPUIndex,
+        num: i64,
+        ret: i64,
+    ) -> Result<()> {
+        record(format!("return {num} = {ret} on {vcpu_index}"));
+        Ok(())
+    }
+}
+
+register!(sync Recorder);
+
+#[test]
+fn callbacks_reach_the_plugin() {
+    let mut qemu = MockQemu::user("x86_64")
+        .plugin_sync(Recorder)
+        .start()
+        .unwrap();
+
+    let translation = qemu.translate(
+        &MockTranslationBlock::new(0x1000).instruction(MockInstruction::new(0x1000, [0x90])),
+    );
+    qemu.execute(&translation, 0).unwrap();
+    qemu.syscall(0, 39, [0; 8]);
+    qemu.syscall_return(0, 39, 1234);
+    qemu.flush();
+
+    assert_eq!(
+        events(),
+        [
+            "init 0",
+            "translate 0x1000",
+            "tb 0x1000 on 0",
+            "syscall Some(\"getpid\") on 0",
+            "return 39 = 1234 on 0",
+            "flush",
+        ]
+    );
+}
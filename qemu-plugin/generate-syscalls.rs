@@ -0,0 +1,177 @@
+#!/usr/bin/env -S cargo +nightly-gnu -Z script
+---
+[package]
+edition = "2024"
+---
+
+//! Generate `src/syscalls/tables.rs` from the Linux UAPI constants in the source of the
+//! `linux-raw-sys` crate. Run with the path to the crate's `src` directory, for example:
+//!
+//! ```sh
+//! ./generate-syscalls.rs ~/.cargo/registry/src/*/linux-raw-sys-0.9.4/src
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    env::args,
+    fmt::Write as _,
+    fs::{read_to_string, write},
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+/// The tables to generate, as the name of the constant, the `linux-raw-sys` architecture
+/// directory, and the name of the architecture
+const TABLES: &[(&str, &str, &str)] = &[
+    ("X86_64", "x86_64", "x86_64"),
+    ("I386", "x86", "i386"),
+    ("ARM", "arm", "arm"),
+    ("AARCH64", "aarch64", "aarch64"),
+    ("RISCV64", "riscv64", "riscv64"),
+    ("MIPS", "mips", "mips"),
+    ("PPC64", "powerpc64", "ppc64"),
+];
+
+/// Constants named like syscalls which are not syscalls
+const NOT_SYSCALLS: &[&str] = &[
+    "Linux",
+    "OABI_SYSCALL_BASE",
+    "SYSCALL_BASE",
+    "SYSCALL_MASK",
+];
+
+/// The `open` flags to render, besides the access mode
+const OPEN_FLAGS: &[&str] = &[
+    "O_CREAT",
+    "O_EXCL",
+    "O_NOCTTY",
+    "O_TRUNC",
+    "O_APPEND",
+    "O_NONBLOCK",
+    "O_DSYNC",
+    "O_SYNC",
+    "O_DIRECT",
+    "O_LARGEFILE",
+    "O_DIRECTORY",
+    "O_NOFOLLOW",
+    "O_NOATIME",
+    "O_CLOEXEC",
+    "O_PATH",
+    "O_TMPFILE",
+];
+
+/// The `mmap` flags to render, besides the mapping type
+const MMAP_FLAGS: &[&str] = &[
+    "MAP_FIXED",
+    "MAP_ANONYMOUS",
+    "MAP_NORESERVE",
+    "MAP_GROWSDOWN",
+    "MAP_DENYWRITE",
+    "MAP_EXECUTABLE",
+    "MAP_LOCKED",
+    "MAP_POPULATE",
+    "MAP_NONBLOCK",
+    "MAP_STACK",
+    "MAP_HUGETLB",
+    "MAP_SYNC",
+    "MAP_FIXED_NOREPLACE",
+];
+
+/// Parse the `pub const NAME: ty = value;` lines of a `linux-raw-sys` source file
+fn constants(path: &Path) -> Result<Vec<(String, i64)>> {
+    Ok(read_to_string(path)?
+        .lines()
+        .filter_map(|line| {
+            let (name, rest) = line.strip_prefix("pub const ")?.split_once(':')?;
+            let (_, value) = rest.split_once('=')?;
+            let value = value.trim().strip_suffix(';')?.parse().ok()?;
+            Some((name.to_string(), value))
+        })
+        .collect())
+}
+
+/// Write a slice of `(value, name)` pairs
+fn write_pairs<T: std::fmt::Display>(out: &mut String, field: &str, pairs: &[(T, String)]) {
+    writeln!(out, "    {field}: &[").unwrap();
+    for (value, name) in pairs {
+        writeln!(out, "        ({value}, \"{name}\"),").unwrap();
+    }
+    writeln!(out, "    ],").unwrap();
+}
+
+/// The flags of `wanted` defined by an architecture, ordered so flags which contain
+/// other flags, like `O_SYNC` containing `O_DSYNC`, come first
+fn flags(general: &[(String, i64)], wanted: &[&str]) -> Vec<(String, String)> {
+    let mut flags = wanted
+        .iter()
+        .filter_map(|wanted| {
+            general
+                .iter()
+                .find(|(name, value)| name == wanted && *value != 0)
+                .map(|(name, value)| (*value as u64, name.clone()))
+        })
+        .collect::<Vec<_>>();
+    flags.sort_by_key(|(value, _)| std::cmp::Reverse(value.count_ones()));
+    flags
+        .into_iter()
+        .map(|(value, name)| (format!("{value:#x}"), name))
+        .collect()
+}
+
+fn generate(src: &Path) -> Result<String> {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "// Generated by `generate-syscalls.rs` from the Linux UAPI headers. Do not edit."
+    )
+    .unwrap();
+
+    for (constant, directory, name) in TABLES {
+        let general = constants(&src.join(directory).join("general.rs"))?;
+        let errno = constants(&src.join(directory).join("errno.rs"))?;
+
+        let mut syscalls = BTreeMap::new();
+        for (constant, number) in &general {
+            if let Some(syscall) = constant.strip_prefix("__NR_")
+                && !NOT_SYSCALLS.contains(&syscall)
+            {
+                syscalls.entry(*number).or_insert_with(|| syscall.to_string());
+            }
+        }
+
+        let mut errnos = BTreeMap::new();
+        for (constant, number) in &errno {
+            if constant.starts_with('E') {
+                errnos.entry(*number).or_insert_with(|| constant.clone());
+            }
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "/// The Linux syscalls of {name}").unwrap();
+        writeln!(out, "pub(crate) static {constant}: SyscallTable = SyscallTable {{").unwrap();
+        writeln!(out, "    arch: \"{name}\",").unwrap();
+        write_pairs(&mut out, "syscalls", &syscalls.into_iter().collect::<Vec<_>>());
+        write_pairs(&mut out, "errnos", &errnos.into_iter().collect::<Vec<_>>());
+        write_pairs(&mut out, "open_flags", &flags(&general, OPEN_FLAGS));
+        write_pairs(&mut out, "mmap_flags", &flags(&general, MMAP_FLAGS));
+        writeln!(out, "}};").unwrap();
+    }
+
+    Ok(out)
+}
+
+fn main() -> Result<()> {
+    let src = args().nth(1).map(PathBuf::from).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "usage: generate-syscalls.rs <path to linux-raw-sys/src>",
+        )
+    })?;
+
+    let out = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("src")
+        .join("syscalls")
+        .join("tables.rs");
+
+    write(out, generate(&src)?)
+}
@@ -0,0 +1,173 @@
+//! Architecture profiles describing the registers of QEMU's targets
+//!
+//! QEMU names registers after the GDB target descriptions of each architecture, so the
+//! register holding the program counter is `rip` on x86_64 but `pc` on aarch64. An
+//! [`Arch`] profile maps architecture-independent [`Alias`]es, like the program counter
+//! or the first argument register, to those names. The byte order and pointer width of
+//! a target are described by its [`Target`].
+
+use crate::Target;
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The byte order of register values
+pub enum Endian {
+    /// Least significant byte first
+    Little,
+    /// Most significant byte first
+    Big,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// An architecture-independent name for a register
+pub enum Alias {
+    /// The program counter
+    Pc,
+    /// The stack pointer
+    Sp,
+    /// The frame pointer
+    Fp,
+    /// The register holding the return value of a function
+    ReturnValue,
+    /// The register holding the return address of a function, on architectures which
+    /// have one
+    ReturnAddress,
+    /// The register holding the argument of a function with the given index, counting
+    /// from zero, under the platform's C calling convention
+    Argument(usize),
+}
+
+impl FromStr for Alias {
+    type Err = ();
+
+    /// Parse an alias from `pc`, `sp`, `fp`, `ret`, `ra` or `argN`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pc" => Ok(Self::Pc),
+            "sp" => Ok(Self::Sp),
+            "fp" => Ok(Self::Fp),
+            "ret" => Ok(Self::ReturnValue),
+            "ra" => Ok(Self::ReturnAddress),
+            _ => s
+                .strip_prefix("arg")
+                .and_then(|index| index.parse().ok())
+                .map(Self::Argument)
+                .ok_or(()),
+        }
+    }
+}
+
+impl fmt::Display for Alias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pc => write!(f, "pc"),
+            Self::Sp => write!(f, "sp"),
+            Self::Fp => write!(f, "fp"),
+            Self::ReturnValue => write!(f, "ret"),
+            Self::ReturnAddress => write!(f, "ra"),
+            Self::Argument(index) => write!(f, "arg{index}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// An architecture with a register profile
+pub enum Arch {
+    /// 64-bit x86
+    X86_64,
+    /// 32-bit x86
+    I386,
+    /// 64-bit ARM
+    Aarch64,
+    /// 32-bit ARM
+    Arm,
+    /// 64-bit RISC-V
+    Riscv64,
+    /// 64-bit PowerPC
+    Ppc64,
+}
+
+impl Arch {
+    /// The architecture of a QEMU target, from its name as given in
+    /// [`crate::Info::target_name`], for example `x86_64` or `aarch64-softmmu`
+    pub fn from_target_name(target_name: &str) -> Option<Self> {
+        Target::from_target_name(target_name).and_then(|target| target.arch())
+    }
+
+    /// The name of the architecture
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64",
+            Self::I386 => "i386",
+            Self::Aarch64 => "aarch64",
+            Self::Arm => "arm",
+            Self::Riscv64 => "riscv64",
+            Self::Ppc64 => "ppc64",
+        }
+    }
+
+    /// The names of the registers holding function arguments under the platform's C
+    /// calling convention, in order. On i386, arguments are passed on the stack.
+    pub fn argument_registers(&self) -> &'static [&'static str] {
+        match self {
+            Self::X86_64 => &["rdi", "rsi", "rdx", "rcx", "r8", "r9"],
+            Self::I386 => &[],
+            Self::Aarch64 => &["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"],
+            Self::Arm => &["r0", "r1", "r2", "r3"],
+            Self::Riscv64 => &["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"],
+            Self::Ppc64 => &["r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10"],
+        }
+    }
+
+    /// The name of the register `alias` refers to, if the architecture has one
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use qemu_plugin::{Alias, Arch};
+    ///
+    /// assert_eq!(Arch::X86_64.register(Alias::Pc), Some("rip"));
+    /// assert_eq!(Arch::Aarch64.register(Alias::Argument(1)), Some("x1"));
+    /// assert_eq!(Arch::X86_64.register(Alias::ReturnAddress), None);
+    /// ```
+    pub fn register(&self, alias: Alias) -> Option<&'static str> {
+        match (self, alias) {
+            (Self::X86_64, Alias::Pc) => Some("rip"),
+            (Self::X86_64, Alias::Sp) => Some("rsp"),
+            (Self::X86_64, Alias::Fp) => Some("rbp"),
+            (Self::X86_64, Alias::ReturnValue) => Some("rax"),
+            (Self::I386, Alias::Pc) => Some("eip"),
+            (Self::I386, Alias::Sp) => Some("esp"),
+            (Self::I386, Alias::Fp) => Some("ebp"),
+            (Self::I386, Alias::ReturnValue) => Some("eax"),
+            (Self::X86_64 | Self::I386, Alias::ReturnAddress) => None,
+            (Self::Aarch64, Alias::Pc) => Some("pc"),
+            (Self::Aarch64, Alias::Sp) => Some("sp"),
+            (Self::Aarch64, Alias::Fp) => Some("x29"),
+            (Self::Aarch64, Alias::ReturnValue) => Some("x0"),
+            (Self::Aarch64, Alias::ReturnAddress) => Some("x30"),
+            (Self::Arm, Alias::Pc) => Some("pc"),
+            (Self::Arm, Alias::Sp) => Some("sp"),
+            (Self::Arm, Alias::Fp) => Some("r11"),
+            (Self::Arm, Alias::ReturnValue) => Some("r0"),
+            (Self::Arm, Alias::ReturnAddress) => Some("lr"),
+            (Self::Riscv64, Alias::Pc) => Some("pc"),
+            (Self::Riscv64, Alias::Sp) => Some("sp"),
+            (Self::Riscv64, Alias::Fp) => Some("fp"),
+            (Self::Riscv64, Alias::ReturnValue) => Some("a0"),
+            (Self::Riscv64, Alias::ReturnAddress) => Some("ra"),
+            (Self::Ppc64, Alias::Pc) => Some("pc"),
+            (Self::Ppc64, Alias::Sp) => Some("r1"),
+            (Self::Ppc64, Alias::Fp) => Some("r31"),
+            (Self::Ppc64, Alias::ReturnValue) => Some("r3"),
+            (Self::Ppc64, Alias::ReturnAddress) => Some("lr"),
+            (_, Alias::Argument(index)) => self.argument_registers().get(index).copied(),
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
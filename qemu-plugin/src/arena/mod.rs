@@ -0,0 +1,268 @@
+//! Storage for closures registered as execution callbacks
+//!
+//! QEMU passes the closures registered on translation blocks, instructions and memory
+//! accesses back to their callbacks as opaque userdata pointers, and never tells the
+//! plugin when it is done with them. Instead of leaking them, this crate allocates them
+//! in an arena owned by the crate. Closures registered since the last flush of QEMU's
+//! translation block cache belong to the current *generation*. When the cache is
+//! flushed, every translation block which could call them is discarded, so the
+//! generation is dropped and a new one begins.
+//!
+//! Each closure is tagged with the generation it was allocated in, and callbacks check
+//! the tag before calling it. A callback QEMU runs for a closure of an earlier
+//! generation is reported as an [`crate::Error::StaleCallback`] instead of calling a
+//! closure which has been dropped.
+//!
+//! Flushes are observed through the plugin's flush callback, which `register_default`
+//! always registers. Plugins which override `register_default`, or which register their
+//! own flush callback with [`crate::qemu_plugin_register_flush_cb`], replace it, and the
+//! closures they register are then only reclaimed when the plugin is reset.
+//!
+//! Because closures are dropped on flush, state captured by a closure only lives until
+//! the next flush. State which must outlive it should be shared with the plugin, for
+//! example through an `Arc`.
+
+use crate::{Error, PluginId, Result, policy::guard};
+use std::{
+    ffi::c_void,
+    mem::ManuallyDrop,
+    sync::{
+        Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+/// A closure and the generation it was allocated in. The userdata pointer passed to
+/// QEMU points to an entry.
+struct Entry<F> {
+    /// The generation the closure was allocated in
+    generation: u64,
+    /// The closure, which is dropped when its generation is reclaimed
+    closure: ManuallyDrop<F>,
+}
+
+/// A closure allocated in the arena, dropped with the generation it belongs to
+struct Closure {
+    /// The userdata pointer passed to QEMU, which points to the boxed entry
+    userdata: *mut c_void,
+    /// Drops the closure of the entry `userdata` points to
+    drop: unsafe fn(*mut c_void),
+    /// Frees the entry `userdata` points to once its closure has been dropped
+    free: unsafe fn(*mut c_void),
+}
+
+// NOTE: Closures are only allocated from `Send` closures, and the pointer is only
+// dereferenced by the callbacks QEMU runs, which never run concurrently with a flush
+unsafe impl Send for Closure {}
+
+impl Closure {
+    /// Drop the closure, keeping its entry so callbacks can still read its generation
+    fn retire(self) -> Retired {
+        let closure = ManuallyDrop::new(self);
+        // NOTE: The entry is freed if dropping the closure panics
+        let retired = Retired {
+            userdata: closure.userdata,
+            free: closure.free,
+        };
+        unsafe { (closure.drop)(closure.userdata) };
+        retired
+    }
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        // NOTE: The entry is freed after the closure is dropped, even if dropping it panics
+        let _entry = Retired {
+            userdata: self.userdata,
+            free: self.free,
+        };
+        unsafe { (self.drop)(self.userdata) }
+    }
+}
+
+/// The entry of a closure which has been dropped, freed one generation later
+struct Retired {
+    /// The userdata pointer passed to QEMU, which points to the boxed entry
+    userdata: *mut c_void,
+    /// Frees the entry `userdata` points to
+    free: unsafe fn(*mut c_void),
+}
+
+// NOTE: Retired entries are only read by callbacks checking their generation
+unsafe impl Send for Retired {}
+
+impl Drop for Retired {
+    fn drop(&mut self) {
+        unsafe { (self.free)(self.userdata) }
+    }
+}
+
+/// Drop the closure of type `F` of an entry allocated by [`alloc`]
+unsafe fn drop_closure<F>(userdata: *mut c_void) {
+    unsafe { ManuallyDrop::drop(&mut (*(userdata as *mut Entry<F>)).closure) };
+}
+
+/// Free an entry of type `F` allocated by [`alloc`], whose closure has been dropped
+unsafe fn free_entry<F>(userdata: *mut c_void) {
+    drop(unsafe { Box::from_raw(userdata as *mut Entry<F>) });
+}
+
+/// The number of shards closures are allocated in
+const SHARDS: usize = 16;
+
+/// The closures of the current generation. Each thread allocates in one shard, so
+/// vCPU threads translating blocks at the same time rarely contend for a lock.
+static ARENA: [Mutex<Vec<Closure>>; SHARDS] = [const { Mutex::new(Vec::new()) }; SHARDS];
+
+/// The entries of the closures of the previous generation
+static RETIRED: Mutex<Vec<Retired>> = Mutex::new(Vec::new());
+
+/// The number of times the arena has been reclaimed
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The shard the next thread to allocate a closure is assigned
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The shard the current thread allocates closures in
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+}
+
+/// Lock part of the arena. A panic while the lock is held can not leave it
+/// inconsistent, so a poisoned lock is recovered.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Allocate `closure` in the current generation, returning the pointer to pass to QEMU as
+/// callback userdata. The closure is dropped when the generation is reclaimed.
+pub(crate) fn alloc<F>(closure: F) -> *mut c_void
+where
+    F: Send + 'static,
+{
+    let mut shard = lock(&ARENA[SHARD.with(|shard| *shard)]);
+
+    // NOTE: The generation is read with the shard locked, so it can not advance before
+    // the closure is in the arena
+    let userdata = Box::into_raw(Box::new(Entry {
+        generation: generation(),
+        closure: ManuallyDrop::new(closure),
+    })) as *mut c_void;
+
+    shard.push(Closure {
+        userdata,
+        drop: drop_closure::<F>,
+        free: free_entry::<F>,
+    });
+
+    userdata
+}
+
+/// The closure of type `F` `userdata` points to, if it belongs to the current
+/// generation
+///
+/// # Safety
+///
+/// `userdata` must have been returned by [`alloc`] for a closure of type `F`, in the
+/// current or the previous generation.
+pub(crate) unsafe fn closure<'a, F>(userdata: *mut c_void) -> Result<&'a mut F> {
+    let entry = userdata as *mut Entry<F>;
+    let generation = unsafe { (*entry).generation };
+    let current = self::generation();
+
+    if generation == current {
+        Ok(unsafe { &mut (*entry).closure })
+    } else {
+        Err(Error::StaleCallback {
+            generation,
+            current,
+        })
+    }
+}
+
+/// Drop every closure in the current generation and begin a new one. This must only be
+/// called once QEMU has discarded every translation block, when none of the closures can
+/// be called again.
+pub(crate) fn reclaim() {
+    let closures = {
+        let mut shards = ARENA.iter().map(lock).collect::<Vec<_>>();
+        GENERATION.fetch_add(1, Ordering::Relaxed);
+        shards
+            .iter_mut()
+            .flat_map(|shard| std::mem::take(&mut **shard))
+            .collect::<Vec<_>>()
+    };
+
+    // NOTE: Closures are dropped without holding the lock, so their captured state can
+    // register new callbacks when dropped. Dropping runs the plugin's code, so it is
+    // guarded like a callback to keep a panic from unwinding into QEMU.
+    let mut retired = Vec::with_capacity(closures.len());
+    guard("dropping callbacks", || {
+        retired.extend(closures.into_iter().map(Closure::retire));
+        Ok(())
+    });
+
+    // NOTE: Entries are kept for a generation after their closures are dropped, so a
+    // callback QEMU still runs can find it is stale without reading freed memory
+    drop(std::mem::replace(&mut *lock(&RETIRED), retired));
+}
+
+/// Handler for the flush callback registered when the plugin does not handle flushes
+/// itself, which only reclaims the arena
+pub(crate) extern "C" fn handle_qemu_plugin_flush_reclaim_cb(_id: PluginId) {
+    reclaim();
+}
+
+/// The current generation of the arena, which starts at zero and advances each time
+/// QEMU flushes its translation block cache or the plugin is reset
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{alloc, closure, generation, reclaim};
+    use crate::Error;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Counts the closures dropped
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn closures_are_dropped_and_stale_after_a_reclaim() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let before = generation();
+        let first = alloc(Counted(dropped.clone()));
+        let second = std::thread::spawn({
+            let dropped = dropped.clone();
+            move || alloc(Counted(dropped)) as usize
+        })
+        .join()
+        .unwrap() as *mut _;
+
+        assert!(unsafe { closure::<Counted>(first) }.is_ok());
+        assert!(unsafe { closure::<Counted>(second) }.is_ok());
+
+        reclaim();
+
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+        assert!(matches!(
+            unsafe { closure::<Counted>(first) },
+            Err(Error::StaleCallback { generation, current })
+                if generation == before && current == before + 1
+        ));
+
+        reclaim();
+
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+}
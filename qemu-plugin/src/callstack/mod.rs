@@ -0,0 +1,970 @@
+//! Shadow call stacks of the guest
+//!
+//! Translation blocks end at control flow instructions, so the final instruction of each
+//! block tells whether executing it calls a function, returns from one or jumps elsewhere.
+//! [`Branch::decode`] classifies an instruction by its bytes on x86, aarch64 and RISC-V
+//! targets. A [`CallStack`] instruments the final instruction of every block with a call
+//! or return, and keeps a shadow stack of [`Frame`]s for each vCPU, which any callback can
+//! then read.
+//!
+//! Code does not always return the way it was called: `longjmp`, C++ exceptions and
+//! signal handlers discard frames without returning from them. The stack pointer is read
+//! with the register API before every call, return and indirect jump, and frames whose
+//! callee's stack has been unwound past are discarded, so the shadow stack recovers at the
+//! next such instruction.
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use crate::{
+    CallbackFlags, Error, Info, RegisterFile, Result, TranslationBlock, VCPUIndex,
+    qemu_plugin_get_registers,
+};
+use crate::{Target, syscalls::sign_extend};
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The deepest a shadow stack grows. The outermost frames are discarded past this depth,
+/// so runaway recursion does not grow the stack without bound.
+const MAX_DEPTH: usize = 1 << 14;
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The names QEMU gives the RISC-V integer registers, by register number
+pub(crate) const RISCV_REGISTERS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The way an instruction transfers control
+pub enum Branch {
+    /// A call, which transfers control to `target` and leaves the address of the next
+    /// instruction as the return address
+    Call {
+        /// The address called, if the call is direct
+        target: Option<u64>,
+    },
+    /// A return to the address held in register `link`, or on the top of the stack if
+    /// `link` is `None`
+    Return {
+        /// The number of the register holding the return address, on targets which
+        /// return through a register
+        link: Option<u8>,
+    },
+    /// A jump, which transfers control without affecting the call stack. Tail calls are
+    /// jumps.
+    Jump {
+        /// The address jumped to, if the jump is direct
+        target: Option<u64>,
+        /// Whether the jump is only taken if a condition holds
+        conditional: bool,
+    },
+}
+
+impl Branch {
+    /// Classify the instruction `data` at `vaddr` on `target`. Returns `None` if the
+    /// instruction does not transfer control, or if the target is not supported. x86,
+    /// aarch64 and RISC-V targets are supported.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use qemu_plugin::{Branch, Target};
+    ///
+    /// // call 0x1105
+    /// let call = Branch::decode(Target::X86_64, 0x1000, &[0xe8, 0x00, 0x01, 0x00, 0x00]);
+    /// assert_eq!(call, Some(Branch::Call { target: Some(0x1105) }));
+    ///
+    /// // ret
+    /// let ret = Branch::decode(Target::Aarch64, 0x1000, &[0xc0, 0x03, 0x5f, 0xd6]);
+    /// assert_eq!(ret, Some(Branch::Return { link: Some(30) }));
+    ///
+    /// // addi a0, a0, 1
+    /// assert_eq!(Branch::decode(Target::Riscv64, 0x1000, &[0x13, 0x05, 0x15, 0x00]), None);
+    /// ```
+    pub fn decode(target: Target, vaddr: u64, data: &[u8]) -> Option<Self> {
+        match target {
+            Target::X86_64 => decode_x86(vaddr, data, true),
+            Target::I386 => decode_x86(vaddr, data, false)
+                .map(|branch| branch.map_target(|target| target & u64::from(u32::MAX))),
+            Target::Aarch64 | Target::Aarch64Be => decode_aarch64(vaddr, data),
+            Target::Riscv64 => decode_riscv(vaddr, data, true),
+            Target::Riscv32 => decode_riscv(vaddr, data, false)
+                .map(|branch| branch.map_target(|target| target & u64::from(u32::MAX))),
+            _ => None,
+        }
+    }
+
+    /// Whether the branch is a call
+    pub fn is_call(&self) -> bool {
+        matches!(self, Self::Call { .. })
+    }
+
+    /// Whether the branch is a return
+    pub fn is_return(&self) -> bool {
+        matches!(self, Self::Return { .. })
+    }
+
+    /// The address the branch transfers control to, if it is known from the instruction
+    pub fn target(&self) -> Option<u64> {
+        match self {
+            Self::Call { target } | Self::Jump { target, .. } => *target,
+            Self::Return { .. } => None,
+        }
+    }
+
+    /// Apply `f` to the target of the branch, if it has one
+    fn map_target(self, f: impl FnOnce(u64) -> u64) -> Self {
+        match self {
+            Self::Call { target } => Self::Call {
+                target: target.map(f),
+            },
+            Self::Jump {
+                target,
+                conditional,
+            } => Self::Jump {
+                target: target.map(f),
+                conditional,
+            },
+            Self::Return { link } => Self::Return { link },
+        }
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The name QEMU gives register `number` of `target`, for the registers a return can use
+fn link_register(target: Target, number: u8) -> Option<String> {
+    match target {
+        Target::Aarch64 | Target::Aarch64Be => Some(format!("x{number}")),
+        Target::Riscv32 | Target::Riscv64 => RISCV_REGISTERS
+            .get(number as usize)
+            .map(|name| name.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The name QEMU gives the stack pointer of `target`, for the targets calls are decoded on
+fn stack_pointer_register(target: Target) -> Option<&'static str> {
+    match target {
+        Target::X86_64 => Some("rsp"),
+        Target::I386 => Some("esp"),
+        Target::Aarch64 | Target::Aarch64Be | Target::Riscv32 | Target::Riscv64 => Some("sp"),
+        _ => None,
+    }
+}
+
+/// Whether `byte` is an x86 instruction prefix. REX prefixes are increments and
+/// decrements outside of 64-bit mode.
+fn is_x86_prefix(byte: u8, long_mode: bool) -> bool {
+    matches!(
+        byte,
+        0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3
+    ) || (long_mode && (0x40..=0x4f).contains(&byte))
+}
+
+/// Classify an x86 instruction, in 64-bit mode if `long_mode` is set
+fn decode_x86(vaddr: u64, data: &[u8], long_mode: bool) -> Option<Branch> {
+    let start = data
+        .iter()
+        .position(|&byte| !is_x86_prefix(byte, long_mode))?;
+    let (&opcode, operands) = data[start..].split_first()?;
+    let next = vaddr.wrapping_add(data.len() as u64);
+    let rel8 = |operands: &[u8]| Some(sign_extend(u64::from(*operands.first()?), 8));
+    let rel32 = |operands: &[u8]| {
+        let bytes = operands.get(..4)?.try_into().ok()?;
+        Some(sign_extend(u64::from(u32::from_le_bytes(bytes)), 32))
+    };
+
+    match opcode {
+        0xe8 => Some(Branch::Call {
+            target: Some(next.wrapping_add_signed(rel32(operands)?)),
+        }),
+        0x9a if !long_mode => Some(Branch::Call { target: None }),
+        0xc2 | 0xc3 | 0xca | 0xcb => Some(Branch::Return { link: None }),
+        0xe9 => Some(Branch::Jump {
+            target: Some(next.wrapping_add_signed(rel32(operands)?)),
+            conditional: false,
+        }),
+        0xeb => Some(Branch::Jump {
+            target: Some(next.wrapping_add_signed(rel8(operands)?)),
+            conditional: false,
+        }),
+        0x70..=0x7f | 0xe0..=0xe3 => Some(Branch::Jump {
+            target: Some(next.wrapping_add_signed(rel8(operands)?)),
+            conditional: true,
+        }),
+        0x0f => match operands.split_first()? {
+            (0x80..=0x8f, operands) => Some(Branch::Jump {
+                target: Some(next.wrapping_add_signed(rel32(operands)?)),
+                conditional: true,
+            }),
+            _ => None,
+        },
+        0xcf | 0xea => Some(Branch::Jump {
+            target: None,
+            conditional: false,
+        }),
+        0xff => match (operands.first()? >> 3) & 7 {
+            2 | 3 => Some(Branch::Call { target: None }),
+            4 | 5 => Some(Branch::Jump {
+                target: None,
+                conditional: false,
+            }),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Classify an aarch64 instruction. Instructions are little-endian even on big-endian
+/// targets.
+fn decode_aarch64(vaddr: u64, data: &[u8]) -> Option<Branch> {
+    let word = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+    let imm26 = || sign_extend(u64::from(word & 0x03ff_ffff) << 2, 28);
+    let imm19 = || sign_extend(u64::from((word >> 5) & 0x7_ffff) << 2, 21);
+    let imm14 = || sign_extend(u64::from((word >> 5) & 0x3fff) << 2, 16);
+    let rn = ((word >> 5) & 0x1f) as u8;
+
+    if word & 0xfc00_0000 == 0x9400_0000 {
+        // BL
+        Some(Branch::Call {
+            target: Some(vaddr.wrapping_add_signed(imm26())),
+        })
+    } else if word & 0xffff_fc1f == 0xd63f_0000 || word & 0xfeff_f800 == 0xd63f_0800 {
+        // BLR, and BLRAA, BLRAAZ, BLRAB and BLRABZ with pointer authentication
+        Some(Branch::Call { target: None })
+    } else if word & 0xffff_fc1f == 0xd65f_0000 {
+        // RET
+        Some(Branch::Return { link: Some(rn) })
+    } else if word == 0xd65f_0bff || word == 0xd65f_0fff {
+        // RETAA and RETAB
+        Some(Branch::Return { link: Some(30) })
+    } else if word & 0xfc00_0000 == 0x1400_0000 {
+        // B
+        Some(Branch::Jump {
+            target: Some(vaddr.wrapping_add_signed(imm26())),
+            conditional: false,
+        })
+    } else if word & 0xff00_0010 == 0x5400_0000 || word & 0x7e00_0000 == 0x3400_0000 {
+        // B.cond, CBZ and CBNZ
+        Some(Branch::Jump {
+            target: Some(vaddr.wrapping_add_signed(imm19())),
+            conditional: true,
+        })
+    } else if word & 0x7e00_0000 == 0x3600_0000 {
+        // TBZ and TBNZ
+        Some(Branch::Jump {
+            target: Some(vaddr.wrapping_add_signed(imm14())),
+            conditional: true,
+        })
+    } else if word & 0xffff_fc1f == 0xd61f_0000
+        || word & 0xfeff_f800 == 0xd61f_0800
+        || word == 0xd69f_03e0
+    {
+        // BR, BRAA, BRAAZ, BRAB and BRABZ with pointer authentication, and ERET
+        Some(Branch::Jump {
+            target: None,
+            conditional: false,
+        })
+    } else {
+        None
+    }
+}
+
+/// Whether RISC-V register `number` is a link register, `ra` or `t0`, which the calling
+/// convention uses to hint that a jump is a call or return
+fn riscv_link(number: u32) -> bool {
+    number == 1 || number == 5
+}
+
+/// Classify a RISC-V instruction, which may be compressed. `c.jal` is only a call on
+/// RV32, and is `c.addiw` on RV64.
+fn decode_riscv(vaddr: u64, data: &[u8], rv64: bool) -> Option<Branch> {
+    let half = u32::from(u16::from_le_bytes(data.get(..2)?.try_into().ok()?));
+
+    if half & 3 != 3 {
+        return decode_riscv_compressed(vaddr, half, rv64);
+    }
+
+    let word = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+    let rd = (word >> 7) & 0x1f;
+    let rs1 = (word >> 15) & 0x1f;
+    let funct3 = (word >> 12) & 7;
+
+    match word & 0x7f {
+        // JAL
+        0x6f => {
+            let offset = ((word >> 31) & 1) << 20
+                | ((word >> 21) & 0x3ff) << 1
+                | ((word >> 20) & 1) << 11
+                | ((word >> 12) & 0xff) << 12;
+            let target = Some(vaddr.wrapping_add_signed(sign_extend(u64::from(offset), 21)));
+
+            Some(if riscv_link(rd) {
+                Branch::Call { target }
+            } else {
+                Branch::Jump {
+                    target,
+                    conditional: false,
+                }
+            })
+        }
+        // JALR
+        0x67 if funct3 == 0 => Some(if riscv_link(rd) {
+            Branch::Call { target: None }
+        } else if rd == 0 && riscv_link(rs1) {
+            Branch::Return {
+                link: Some(rs1 as u8),
+            }
+        } else {
+            Branch::Jump {
+                target: None,
+                conditional: false,
+            }
+        }),
+        // BEQ, BNE, BLT, BGE, BLTU and BGEU
+        0x63 if funct3 != 2 && funct3 != 3 => {
+            let offset = ((word >> 31) & 1) << 12
+                | ((word >> 25) & 0x3f) << 5
+                | ((word >> 8) & 0xf) << 1
+                | ((word >> 7) & 1) << 11;
+
+            Some(Branch::Jump {
+                target: Some(vaddr.wrapping_add_signed(sign_extend(u64::from(offset), 13))),
+                conditional: true,
+            })
+        }
+        // MRET and SRET
+        0x73 if word == 0x3020_0073 || word == 0x1020_0073 => Some(Branch::Jump {
+            target: None,
+            conditional: false,
+        }),
+        _ => None,
+    }
+}
+
+/// Classify a compressed RISC-V instruction
+fn decode_riscv_compressed(vaddr: u64, half: u32, rv64: bool) -> Option<Branch> {
+    let funct3 = half >> 13;
+    let rs1 = (half >> 7) & 0x1f;
+    let rs2 = (half >> 2) & 0x1f;
+    let cj_offset = || {
+        let offset = ((half >> 12) & 1) << 11
+            | ((half >> 11) & 1) << 4
+            | ((half >> 9) & 3) << 8
+            | ((half >> 8) & 1) << 10
+            | ((half >> 7) & 1) << 6
+            | ((half >> 6) & 1) << 7
+            | ((half >> 3) & 7) << 1
+            | ((half >> 2) & 1) << 5;
+        sign_extend(u64::from(offset), 12)
+    };
+
+    match (half & 3, funct3) {
+        // C.JAL
+        (1, 1) if !rv64 => Some(Branch::Call {
+            target: Some(vaddr.wrapping_add_signed(cj_offset())),
+        }),
+        // C.J
+        (1, 5) => Some(Branch::Jump {
+            target: Some(vaddr.wrapping_add_signed(cj_offset())),
+            conditional: false,
+        }),
+        // C.BEQZ and C.BNEZ
+        (1, 6 | 7) => {
+            let offset = ((half >> 12) & 1) << 8
+                | ((half >> 10) & 3) << 3
+                | ((half >> 5) & 3) << 6
+                | ((half >> 3) & 3) << 1
+                | ((half >> 2) & 1) << 5;
+
+            Some(Branch::Jump {
+                target: Some(vaddr.wrapping_add_signed(sign_extend(u64::from(offset), 9))),
+                conditional: true,
+            })
+        }
+        // C.JR and C.JALR
+        (2, 4) if rs1 != 0 && rs2 == 0 => Some(if (half >> 12) & 1 == 1 {
+            Branch::Call { target: None }
+        } else if riscv_link(rs1) {
+            Branch::Return {
+                link: Some(rs1 as u8),
+            }
+        } else {
+            Branch::Jump {
+                target: None,
+                conditional: false,
+            }
+        }),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A function call on a shadow call stack
+pub struct Frame {
+    /// The address of the call instruction
+    pub call_site: u64,
+    /// The address the callee returns to
+    pub return_address: u64,
+    /// The address of the callee, if the call was direct
+    pub callee: Option<u64>,
+    /// The stack pointer on entry to the callee, if it could be read. The callee's frame
+    /// is live as long as the stack pointer is at or below this address.
+    pub stack_pointer: Option<u64>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// What a vCPU does at the end of an instrumented block
+enum Transfer {
+    /// A call from `call_site`, returning to `return_address`
+    Call {
+        call_site: u64,
+        return_address: u64,
+        callee: Option<u64>,
+    },
+    /// A return through the register named `link`, or through the stack
+    Return { link: Option<String> },
+    /// An indirect jump, which may unwind the stack
+    Jump,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[derive(Default)]
+/// The shadow call stack of one vCPU
+struct Shadow {
+    /// The vCPU's registers, read when the vCPU first reaches an instrumented instruction
+    registers: Option<Option<RegisterFile<'static>>>,
+    /// The frames of the stack, outermost first
+    frames: Vec<Frame>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The state shared between the handles of a call stack and its callbacks
+struct Shared {
+    target: Target,
+    /// The name of the stack pointer register
+    stack_pointer: &'static str,
+    /// The shadow stack of each vCPU, created when the vCPU first reaches an instrumented
+    /// instruction or its stack is first read
+    vcpus: RwLock<Vec<Arc<Mutex<Shadow>>>>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// Lock `mutex`. A shadow stack is consistent between statements, so a poisoned lock is
+/// recovered.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl Shared {
+    /// The shadow stack of `vcpu_index`, created if it does not exist yet
+    fn shadow(&self, vcpu_index: VCPUIndex) -> Arc<Mutex<Shadow>> {
+        let index = vcpu_index as usize;
+
+        if let Some(shadow) = self
+            .vcpus
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(index)
+        {
+            return shadow.clone();
+        }
+
+        let mut vcpus = self.vcpus.write().unwrap_or_else(PoisonError::into_inner);
+
+        if vcpus.len() <= index {
+            vcpus.resize_with(index + 1, Default::default);
+        }
+
+        vcpus[index].clone()
+    }
+
+    /// Update the shadow stack of `vcpu_index` as it executes `transfer`. This runs in
+    /// a callback registered with `QEMU_PLUGIN_CB_R_REGS`, before the instruction.
+    fn transfer(&self, vcpu_index: VCPUIndex, transfer: &Transfer) {
+        let shadow = self.shadow(vcpu_index);
+        let mut shadow = lock(&shadow);
+        let registers = shadow
+            .registers
+            .get_or_insert_with(|| {
+                qemu_plugin_get_registers()
+                    .ok()
+                    .map(|registers| RegisterFile::new(registers, Some(self.target)))
+            })
+            .as_ref();
+        let sp = registers.and_then(|registers| registers.read_u64(self.stack_pointer).ok());
+        let link = match transfer {
+            Transfer::Return { link: Some(link) } => {
+                registers.and_then(|registers| registers.read_u64(link).ok())
+            }
+            _ => None,
+        };
+
+        shadow.update(self.target, transfer, sp, link);
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl Shadow {
+    /// Update the stack as the vCPU executes `transfer` on `target`, with the stack pointer
+    /// `sp`, and the address in the link register `link` if `transfer` returns through one
+    fn update(&mut self, target: Target, transfer: &Transfer, sp: Option<u64>, link: Option<u64>) {
+        let frames = &mut self.frames;
+
+        // The stack grows down, so a frame whose stack pointer on entry is below the
+        // current stack pointer has been unwound without returning, by longjmp or an
+        // exception
+        if let Some(sp) = sp {
+            let live = frames
+                .iter()
+                .rposition(|frame| frame.stack_pointer.is_none_or(|entry| entry >= sp))
+                .map_or(0, |index| index + 1);
+            frames.truncate(live);
+        }
+
+        match transfer {
+            Transfer::Call {
+                call_site,
+                return_address,
+                callee,
+            } => {
+                if frames.len() == MAX_DEPTH {
+                    frames.remove(0);
+                }
+
+                // An x86 call pushes the return address, so the callee starts one pointer
+                // below the caller's stack pointer
+                let pushed = match target {
+                    Target::X86_64 | Target::I386 => target.pointer_width() as u64,
+                    _ => 0,
+                };
+
+                frames.push(Frame {
+                    call_site: *call_site,
+                    return_address: *return_address,
+                    callee: *callee,
+                    stack_pointer: sp.map(|sp| sp.wrapping_sub(pushed)),
+                });
+            }
+            Transfer::Return { link: Some(_) } => {
+                // Return to the innermost frame with the return address in the link
+                // register. A return matching no frame leaves a function entered before
+                // the stack was tracked.
+                match link {
+                    Some(address) => {
+                        if let Some(index) = frames
+                            .iter()
+                            .rposition(|frame| frame.return_address == address)
+                        {
+                            frames.truncate(index);
+                        }
+                    }
+                    None => {
+                        frames.pop();
+                    }
+                }
+            }
+            Transfer::Return { link: None } => {
+                // An x86 return pops the return address the call pushed, so the stack
+                // pointer is the callee's stack pointer on entry. A return matching no
+                // frame leaves a function entered before the stack was tracked.
+                if sp.is_none() || frames.last().is_some_and(|frame| frame.stack_pointer == sp) {
+                    frames.pop();
+                }
+            }
+            Transfer::Jump => {}
+        }
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[derive(Clone)]
+/// Shadow call stacks of every vCPU, kept by instrumenting calls and returns
+///
+/// A call stack is a cheap handle which can be cloned into callbacks. Blocks passed to
+/// [`CallStack::instrument`] from the translation callback update the stacks as they run,
+/// and [`CallStack::frames`] reads the stack of a vCPU from any of its callbacks. Callbacks
+/// registered on a block run before the call or return the block ends with, so they see
+/// the stack of the function the block belongs to.
+///
+/// In system mode, the stack follows the vCPU through context switches between guest
+/// threads and privilege levels, so it mixes the calls of every thread the vCPU runs.
+///
+/// # Example
+///
+/// ```no_run
+/// # use qemu_plugin::Result;
+/// use qemu_plugin::{
+///     Args, CallStack, HasCallbacks, Info, PluginId, Register, TranslationBlock,
+/// };
+///
+/// #[derive(Default)]
+/// struct Depth {
+///     stack: Option<CallStack>,
+/// }
+///
+/// impl Register for Depth {
+///     fn register(&mut self, _id: PluginId, _args: &Args, info: &Info) -> Result<()> {
+///         self.stack = Some(CallStack::from_info(info)?);
+///         Ok(())
+///     }
+/// }
+///
+/// impl HasCallbacks for Depth {
+///     fn on_translation_block_translate(
+///         &mut self,
+///         _id: PluginId,
+///         tb: TranslationBlock,
+///     ) -> Result<()> {
+///         let Some(stack) = &self.stack else {
+///             return Ok(());
+///         };
+///
+///         stack.instrument(&tb);
+///
+///         let stack = stack.clone();
+///         let vaddr = tb.vaddr();
+///         tb.register_execute_callback(move |vcpu_index| {
+///             println!("{vaddr:#x} at depth {}", stack.depth(vcpu_index));
+///         });
+///
+///         Ok(())
+///     }
+/// }
+/// ```
+pub struct CallStack {
+    shared: Arc<Shared>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl CallStack {
+    /// Track the call stacks of `target`, which must be an x86, aarch64 or RISC-V target
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnsupportedCallStackTarget`] if calls can not be decoded on
+    /// `target`.
+    pub fn new(target: Target) -> Result<Self> {
+        let stack_pointer =
+            stack_pointer_register(target).ok_or_else(|| Error::UnsupportedCallStackTarget {
+                target: target.name().to_string(),
+            })?;
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                target,
+                stack_pointer,
+                vcpus: RwLock::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// Track the call stacks of the target described by `info`
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnsupportedCallStackTarget`] if the target is not known, or
+    /// calls can not be decoded on it.
+    pub fn from_info(info: &Info) -> Result<Self> {
+        match info.target {
+            Some(target) => Self::new(target),
+            None => Err(Error::UnsupportedCallStackTarget {
+                target: info.target_name.clone(),
+            }),
+        }
+    }
+
+    /// The target whose calls are tracked
+    pub fn target(&self) -> Target {
+        self.shared.target
+    }
+
+    /// Instrument the final instruction of `tb` if it is a call, a return or an indirect
+    /// jump, returning how it transfers control. Direct jumps do not change the stack and
+    /// are not instrumented.
+    pub fn instrument(&self, tb: &TranslationBlock) -> Option<Branch> {
+        let insn = tb.instructions().last()?;
+        let vaddr = insn.vaddr();
+        let data = insn.data();
+        let branch = Branch::decode(self.shared.target, vaddr, &data)?;
+
+        let transfer = match branch {
+            Branch::Call { target } => Transfer::Call {
+                call_site: vaddr,
+                return_address: vaddr.wrapping_add(data.len() as u64),
+                callee: target,
+            },
+            Branch::Return { link } => Transfer::Return {
+                link: link.and_then(|link| link_register(self.shared.target, link)),
+            },
+            Branch::Jump { target: None, .. } => Transfer::Jump,
+            Branch::Jump { .. } => return Some(branch),
+        };
+
+        let shared = self.shared.clone();
+
+        insn.register_execute_callback_flags(
+            move |vcpu_index| shared.transfer(vcpu_index, &transfer),
+            CallbackFlags::QEMU_PLUGIN_CB_R_REGS,
+        );
+
+        Some(branch)
+    }
+
+    /// Call `f` with the frames of the call stack of `vcpu_index`, outermost first
+    pub fn with_frames<R>(&self, vcpu_index: VCPUIndex, f: impl FnOnce(&[Frame]) -> R) -> R {
+        let shadow = self.shared.shadow(vcpu_index);
+        let shadow = lock(&shadow);
+        f(&shadow.frames)
+    }
+
+    /// The frames of the call stack of `vcpu_index`, outermost first
+    pub fn frames(&self, vcpu_index: VCPUIndex) -> Vec<Frame> {
+        self.with_frames(vcpu_index, |frames| frames.to_vec())
+    }
+
+    /// The number of frames on the call stack of `vcpu_index`
+    pub fn depth(&self, vcpu_index: VCPUIndex) -> usize {
+        self.with_frames(vcpu_index, |frames| frames.len())
+    }
+
+    /// Discard the call stack of every vCPU, for example when the guest is reset
+    pub fn clear(&self) {
+        for shadow in self
+            .shared
+            .vcpus
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            lock(shadow).frames.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode each instruction at 0x1000 on `target` and compare it to the expected branch
+    fn check(target: Target, cases: &[(&[u8], Option<Branch>)]) {
+        for (data, expected) in cases {
+            assert_eq!(
+                Branch::decode(target, 0x1000, data),
+                *expected,
+                "{target:?} {data:02x?}"
+            );
+        }
+    }
+
+    fn call(target: Option<u64>) -> Option<Branch> {
+        Some(Branch::Call { target })
+    }
+
+    fn ret(link: Option<u8>) -> Option<Branch> {
+        Some(Branch::Return { link })
+    }
+
+    fn jump(target: Option<u64>, conditional: bool) -> Option<Branch> {
+        Some(Branch::Jump {
+            target,
+            conditional,
+        })
+    }
+
+    #[test]
+    fn x86_branches_are_decoded() {
+        check(
+            Target::X86_64,
+            &[
+                (&[0xe8, 0x00, 0x01, 0x00, 0x00], call(Some(0x1105))),
+                (&[0xe8, 0xfb, 0xff, 0xff, 0xff], call(Some(0x1000))),
+                (&[0xff, 0xd0], call(None)),
+                (&[0x41, 0xff, 0xd3], call(None)),
+                (&[0xff, 0x10], call(None)),
+                (&[0xc3], ret(None)),
+                (&[0xf3, 0xc3], ret(None)),
+                (&[0xc2, 0x08, 0x00], ret(None)),
+                (&[0xeb, 0xfe], jump(Some(0x1000), false)),
+                (&[0xe9, 0x00, 0x01, 0x00, 0x00], jump(Some(0x1105), false)),
+                (&[0x74, 0x10], jump(Some(0x1012), true)),
+                (
+                    &[0x0f, 0x84, 0x00, 0x01, 0x00, 0x00],
+                    jump(Some(0x1106), true),
+                ),
+                (&[0xff, 0xe0], jump(None, false)),
+                (&[0xff, 0xc0], None),
+                (&[0x90], None),
+                (&[0xe8, 0x00], None),
+                (&[], None),
+            ],
+        );
+    }
+
+    #[test]
+    fn i386_targets_wrap_at_32_bits() {
+        check(
+            Target::I386,
+            &[
+                (&[0xe8, 0xf0, 0xef, 0xff, 0xff], call(Some(0xffff_fff5))),
+                (&[0x9a, 0, 0, 0, 0, 0, 0], call(None)),
+                // REX prefixes are decrements outside of 64-bit mode
+                (&[0x48, 0xc3], None),
+            ],
+        );
+    }
+
+    #[test]
+    fn aarch64_branches_are_decoded() {
+        check(
+            Target::Aarch64,
+            &[
+                (&[0x00, 0x04, 0x00, 0x94], call(Some(0x2000))),
+                (&[0xff, 0xff, 0xff, 0x97], call(Some(0xffc))),
+                (&[0x00, 0x01, 0x3f, 0xd6], call(None)),
+                (&[0x1f, 0x09, 0x3f, 0xd7], call(None)),
+                (&[0xc0, 0x03, 0x5f, 0xd6], ret(Some(30))),
+                (&[0x20, 0x00, 0x5f, 0xd6], ret(Some(1))),
+                (&[0xff, 0x0b, 0x5f, 0xd6], ret(Some(30))),
+                (&[0x04, 0x00, 0x00, 0x14], jump(Some(0x1010), false)),
+                (&[0x40, 0x00, 0x00, 0x54], jump(Some(0x1008), true)),
+                (&[0xe0, 0xff, 0xff, 0xb4], jump(Some(0xffc), true)),
+                (&[0x40, 0x00, 0x00, 0x36], jump(Some(0x1008), true)),
+                (&[0x00, 0x02, 0x1f, 0xd6], jump(None, false)),
+                (&[0x1f, 0x20, 0x03, 0xd5], None),
+                (&[0x00, 0x04], None),
+            ],
+        );
+    }
+
+    #[test]
+    fn riscv_branches_are_decoded() {
+        check(
+            Target::Riscv64,
+            &[
+                (&[0xef, 0x00, 0x00, 0x10], call(Some(0x1100))),
+                (&[0xe7, 0x80, 0x07, 0x00], call(None)),
+                (&[0x67, 0x80, 0x00, 0x00], ret(Some(1))),
+                (&[0x6f, 0xf0, 0xdf, 0xff], jump(Some(0xffc), false)),
+                (&[0x67, 0x80, 0x07, 0x00], jump(None, false)),
+                (&[0x63, 0x04, 0xb5, 0x00], jump(Some(0x1008), true)),
+                (&[0x73, 0x00, 0x20, 0x30], jump(None, false)),
+                (&[0x13, 0x05, 0x15, 0x00], None),
+                // Compressed instructions
+                (&[0x82, 0x97], call(None)),
+                (&[0x82, 0x80], ret(Some(1))),
+                (&[0x82, 0x87], jump(None, false)),
+                (&[0x11, 0xa0], jump(Some(0x1004), false)),
+                (&[0x01, 0xc5], jump(Some(0x1008), true)),
+                // C.ADDIW on RV64
+                (&[0x01, 0x20], None),
+            ],
+        );
+        check(
+            Target::Riscv32,
+            &[
+                (&[0x01, 0x20], call(Some(0x1000))),
+                (&[0xef, 0xf0, 0xdf, 0xff], call(Some(0xffc))),
+            ],
+        );
+        assert_eq!(
+            Branch::decode(Target::Riscv32, 0, &[0xef, 0xf0, 0xdf, 0xff]),
+            call(Some(0xffff_fffc))
+        );
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    fn transfer_call(call_site: u64) -> Transfer {
+        Transfer::Call {
+            call_site,
+            return_address: call_site + 5,
+            callee: None,
+        }
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    fn return_addresses(shadow: &Shadow) -> Vec<u64> {
+        shadow
+            .frames
+            .iter()
+            .map(|frame| frame.return_address)
+            .collect()
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    #[test]
+    fn x86_returns_pop_the_frame_of_the_stack_pointer() {
+        let mut shadow = Shadow::default();
+
+        shadow.update(Target::X86_64, &transfer_call(0x1000), Some(0x8000), None);
+        shadow.update(Target::X86_64, &transfer_call(0x2000), Some(0x7fe0), None);
+        assert_eq!(return_addresses(&shadow), [0x1005, 0x2005]);
+        assert_eq!(shadow.frames[1].stack_pointer, Some(0x7fd8));
+
+        // A return whose stack pointer is not the callee's entry matches no frame
+        let ret = Transfer::Return { link: None };
+        shadow.update(Target::X86_64, &ret, Some(0x7fd0), None);
+        assert_eq!(return_addresses(&shadow), [0x1005, 0x2005]);
+
+        shadow.update(Target::X86_64, &ret, Some(0x7fd8), None);
+        assert_eq!(return_addresses(&shadow), [0x1005]);
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    #[test]
+    fn frames_unwound_past_are_discarded() {
+        let mut shadow = Shadow::default();
+
+        shadow.update(Target::X86_64, &transfer_call(0x1000), Some(0x8000), None);
+        shadow.update(Target::X86_64, &transfer_call(0x2000), Some(0x7f00), None);
+        shadow.update(Target::X86_64, &transfer_call(0x3000), Some(0x7e00), None);
+
+        // A longjmp back to the frame of the second call
+        shadow.update(Target::X86_64, &Transfer::Jump, Some(0x7ef8), None);
+        assert_eq!(return_addresses(&shadow), [0x1005, 0x2005]);
+
+        // A return through the stack recovers once the stack pointer is back in the
+        // outermost frame
+        shadow.update(
+            Target::X86_64,
+            &Transfer::Return { link: None },
+            Some(0x7ff8),
+            None,
+        );
+        assert!(shadow.frames.is_empty());
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    #[test]
+    fn link_register_returns_pop_to_the_matching_frame() {
+        let mut shadow = Shadow::default();
+        let ret = Transfer::Return {
+            link: Some("x30".to_string()),
+        };
+
+        for call_site in [0x1000, 0x2000, 0x3000] {
+            shadow.update(
+                Target::Aarch64,
+                &transfer_call(call_site),
+                Some(0x8000),
+                None,
+            );
+        }
+
+        // A return to an address no call left, from a function entered before the stack
+        // was tracked
+        shadow.update(Target::Aarch64, &ret, Some(0x8000), Some(0x9000));
+        assert_eq!(return_addresses(&shadow), [0x1005, 0x2005, 0x3005]);
+
+        // A return past a tail call pops both frames
+        shadow.update(Target::Aarch64, &ret, Some(0x8000), Some(0x2005));
+        assert_eq!(return_addresses(&shadow), [0x1005]);
+
+        // A return whose link register can not be read pops the innermost frame
+        shadow.update(Target::Aarch64, &ret, None, None);
+        assert!(shadow.frames.is_empty());
+    }
+}
@@ -0,0 +1,278 @@
+//! Runtime control of which callbacks a plugin has registered
+//!
+//! A [`PluginController`] resets the plugin, which removes every callback it registered
+//! (except exit callbacks, which are registered again) and flushes QEMU's translation
+//! block cache, then registers the handlers for a new set of [`Callbacks`]. Because
+//! every translation block is translated again after a reset, a plugin can also switch
+//! how it instruments them, for example from cheap inline counting to full instruction
+//! tracing once a trigger address is reached.
+//!
+//! Resets are asynchronous: QEMU completes them once no vCPU is running, after the
+//! callback which requested the reset has returned. Completion is reported either to a
+//! closure, with [`PluginController::reset_with`], or through the [`Reset`] returned by
+//! [`PluginController::reset`], which is a [`Future`] and can also be waited on from a
+//! thread which is not running a callback. A reset QEMU never completes, because the
+//! plugin is uninstalled first or another call to [`crate::qemu_plugin_reset`] replaces
+//! it, fails with [`Error::PluginResetAbandoned`].
+//!
+//! # Example
+//!
+//! ```
+//! use qemu_plugin::{
+//!     Callbacks, HasCallbacks, PluginController, PluginId, Register, Result, TranslationBlock,
+//! };
+//!
+//! const TRIGGER: u64 = 0x401000;
+//!
+//! #[derive(Default)]
+//! struct Tracer {
+//!     tracing: bool,
+//! }
+//!
+//! impl Register for Tracer {}
+//!
+//! impl HasCallbacks for Tracer {
+//!     fn on_translation_block_translate(
+//!         &mut self,
+//!         _id: PluginId,
+//!         tb: TranslationBlock,
+//!     ) -> Result<()> {
+//!         if self.tracing {
+//!             for insn in tb.instructions() {
+//!                 let vaddr = insn.vaddr();
+//!                 insn.register_execute_callback(move |vcpu| println!("{vcpu}: {vaddr:#x}"));
+//!             }
+//!         } else if tb.instructions().any(|insn| insn.vaddr() == TRIGGER) {
+//!             // Translate every block again, now with tracing
+//!             self.tracing = true;
+//!             PluginController::current()?.reset_with(Callbacks::ALL, |result| {
+//!                 if let Err(e) = result {
+//!                     eprintln!("Failed to start tracing: {e}");
+//!                 }
+//!             })?;
+//!         }
+//!
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+use crate::{
+    Callbacks, Error, PluginId, Result, plugin::register_callbacks, policy::plugin_id,
+    qemu_plugin_reset,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, Waker},
+};
+
+/// Whether a reset requested by a controller has not completed yet
+static RESETTING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "mock")]
+/// Forget a reset requested by an earlier installation of the plugin
+pub(crate) fn clear_resetting() {
+    RESETTING.store(false, Ordering::Release);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A handle which can reset the plugin and change which callbacks it has registered. It
+/// can be used from any callback, or from any other thread.
+pub struct PluginController {
+    id: PluginId,
+}
+
+impl PluginController {
+    /// A controller for the plugin with ID `id`
+    pub fn new(id: PluginId) -> Self {
+        Self { id }
+    }
+
+    /// A controller for the installed plugin
+    pub fn current() -> Result<Self> {
+        plugin_id().map(Self::new).ok_or(Error::PluginNotRegistered)
+    }
+
+    /// The ID of the controlled plugin
+    pub fn id(&self) -> PluginId {
+        self.id
+    }
+
+    /// Whether a reset requested by a controller has not completed yet
+    pub fn is_resetting() -> bool {
+        RESETTING.load(Ordering::Acquire)
+    }
+
+    /// Reset the plugin, then register the handlers for `callbacks` and call `then` with
+    /// the result. Until the reset completes, the plugin keeps receiving the callbacks it
+    /// currently has registered.
+    ///
+    /// # Arguments
+    ///
+    /// - `callbacks`: The events to register the plugin's handlers for after the reset
+    /// - `then`: Called once the reset has completed and the handlers are registered, or
+    ///   with [`Error::PluginResetAbandoned`] once QEMU will not complete the reset
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::PluginResetPending`] if a reset requested by a controller has
+    /// not completed yet.
+    pub fn reset_with<F>(&self, callbacks: Callbacks, then: F) -> Result<()>
+    where
+        F: FnOnce(Result<()>) + Send + Sync + 'static,
+    {
+        if RESETTING.swap(true, Ordering::AcqRel) {
+            return Err(Error::PluginResetPending);
+        }
+
+        let pending = Pending { then: Some(then) };
+
+        qemu_plugin_reset(self.id, move |id| {
+            pending.complete(register_callbacks(id, callbacks));
+        })
+    }
+
+    /// Reset the plugin, then register the handlers for `callbacks`. The returned
+    /// [`Reset`] completes once the handlers are registered.
+    ///
+    /// # Arguments
+    ///
+    /// - `callbacks`: The events to register the plugin's handlers for after the reset
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::PluginResetPending`] if a reset requested by a controller has
+    /// not completed yet.
+    pub fn reset(&self, callbacks: Callbacks) -> Result<Reset> {
+        let reset = Reset::default();
+        let state = reset.state.clone();
+
+        self.reset_with(callbacks, move |result| state.complete(result))?;
+
+        Ok(reset)
+    }
+}
+
+/// A reset requested by a controller, held by the reset callback until it completes.
+/// If QEMU drops the callback without calling it, the reset is abandoned, so a later
+/// reset can be requested.
+struct Pending<F>
+where
+    F: FnOnce(Result<()>),
+{
+    /// Called with the result of the reset
+    then: Option<F>,
+}
+
+impl<F> Pending<F>
+where
+    F: FnOnce(Result<()>),
+{
+    /// Complete the reset with `result`
+    fn complete(mut self, result: Result<()>) {
+        if let Some(then) = self.then.take() {
+            // NOTE: The reset is complete before `then` runs, so it can reset again
+            RESETTING.store(false, Ordering::Release);
+            then(result);
+        }
+    }
+}
+
+impl<F> Drop for Pending<F>
+where
+    F: FnOnce(Result<()>),
+{
+    fn drop(&mut self) {
+        if let Some(then) = self.then.take() {
+            RESETTING.store(false, Ordering::Release);
+            then(Err(Error::PluginResetAbandoned));
+        }
+    }
+}
+
+#[derive(Default)]
+/// The state shared between a [`Reset`] and the closure completing it
+struct ResetState {
+    /// The result of the reset once it has completed, and the waker of the task polling
+    /// the reset
+    inner: Mutex<(Option<Result<()>>, Option<Waker>)>,
+    /// Notified when the reset completes
+    completed: Condvar,
+}
+
+impl ResetState {
+    /// Lock the state. Neither the result nor the waker can be left inconsistent by a
+    /// panic, so a poisoned lock is recovered.
+    fn lock(&self) -> MutexGuard<'_, (Option<Result<()>>, Option<Waker>)> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Complete the reset with `result`, waking any waiting task or thread
+    fn complete(&self, result: Result<()>) {
+        let waker = {
+            let mut inner = self.lock();
+            inner.0 = Some(result);
+            inner.1.take()
+        };
+
+        self.completed.notify_all();
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Default)]
+/// A pending reset of the plugin, returned by [`PluginController::reset`]. It is a
+/// [`Future`] which resolves to the result of registering the new callbacks once the
+/// reset has completed.
+pub struct Reset {
+    state: Arc<ResetState>,
+}
+
+impl Reset {
+    /// Whether the reset has completed
+    pub fn is_complete(&self) -> bool {
+        self.state.lock().0.is_some()
+    }
+
+    /// Block the current thread until the reset has completed. QEMU only completes resets
+    /// once no vCPU is running a callback, so this must not be called from a callback.
+    pub fn wait(self) -> Result<()> {
+        let mut inner = self.state.lock();
+
+        loop {
+            if let Some(result) = inner.0.take() {
+                return result;
+            }
+
+            inner = self
+                .state
+                .completed
+                .wait(inner)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl Future for Reset {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.state.lock();
+
+        match inner.0.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                inner.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -23,6 +23,34 @@ pub enum Error {
         /// The value of the key-value argument pair which does not correctly parse as boolean
         val: String,
     },
+    #[error("Unknown argument {key}")]
+    /// Error when an argument is not accepted by the plugin
+    UnknownArg {
+        /// The key of the unknown argument
+        key: String,
+    },
+    #[error("Missing required argument {key}")]
+    /// Error when a required argument is not provided
+    MissingArg {
+        /// The key of the missing argument
+        key: String,
+    },
+    #[error("Argument {key} provided more than once")]
+    /// Error when an argument which takes a single value is provided more than once
+    DuplicateArg {
+        /// The key of the duplicated argument
+        key: String,
+    },
+    #[error("Invalid value {value} for argument {key}, expected {expected}")]
+    /// Error when an argument value does not parse as the expected type
+    InvalidArgValue {
+        /// The key of the argument
+        key: String,
+        /// The value which does not parse
+        value: String,
+        /// A description of the values the argument accepts
+        expected: String,
+    },
     #[error(
         "Setting the QEMU plugin uninstall callback was attempted concurrently and this attempt failed."
     )]
@@ -36,6 +64,13 @@ pub enum Error {
     #[error("Invalid state for plugin reset callback")]
     /// Error when the plugin reset callback is in an invalid state
     PluginResetCallbackState,
+    #[error("A reset of the plugin is already pending")]
+    /// Error when a reset is requested before the previous reset has completed
+    PluginResetPending,
+    #[error("The reset of the plugin was abandoned before it completed")]
+    /// Error when QEMU will not complete a reset, because the plugin was uninstalled or
+    /// the reset callback was replaced
+    PluginResetAbandoned,
     #[error("Invalid instruction index {index} for translation block of size {size}")]
     /// Error when an instruction index is invalid
     InvalidInstructionIndex {
@@ -67,6 +102,22 @@ pub enum Error {
         /// The register name
         name: String,
     },
+    #[error("No register named {name}")]
+    /// Error when a register file has no register with a name or alias
+    UnknownRegister {
+        /// The register name or alias
+        name: String,
+    },
+    #[error("Value {value:#x} does not fit in the {size} bytes of register {name}")]
+    /// Error when writing a value wider than the register it is written to
+    RegisterValueTooLarge {
+        /// The register name
+        name: String,
+        /// The value which was written
+        value: u64,
+        /// The size of the register in bytes
+        size: usize,
+    },
     #[cfg(not(any(
         feature = "plugin-api-v0",
         feature = "plugin-api-v1",
@@ -143,6 +194,102 @@ pub enum Error {
         /// The virtual address that failed to translate
         vaddr: u64,
     },
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    #[error("Read only {read} of {len} bytes from guest address {addr:#x}")]
+    /// Error when guest memory can only be read up to an unmapped or inaccessible page
+    PartialMemoryRead {
+        /// The address read from
+        addr: u64,
+        /// The number of bytes requested
+        len: usize,
+        /// The number of bytes read before the first inaccessible page
+        read: usize,
+    },
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3",
+        feature = "plugin-api-v4"
+    )))]
+    #[error("Wrote only {written} of {len} bytes to guest address {addr:#x}")]
+    /// Error when guest memory can only be written up to an unmapped or inaccessible page
+    PartialMemoryWrite {
+        /// The address written to
+        addr: u64,
+        /// The number of bytes requested
+        len: usize,
+        /// The number of bytes written before the first inaccessible page
+        written: usize,
+    },
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    #[error("No terminator within {max_len} bytes of guest string at {addr:#x}")]
+    /// Error when a guest string is not terminated within the maximum length read
+    UnterminatedGuestString {
+        /// The address of the string
+        addr: u64,
+        /// The maximum number of bytes read
+        max_len: usize,
+    },
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    #[error("Time control has already been granted to a plugin")]
+    /// Error when requesting time control after QEMU has already granted it
+    TimeControlUnavailable,
+    #[error("Call stacks can not be tracked on target {target}")]
+    /// Error when tracking call stacks on a target whose calls can not be decoded
+    UnsupportedCallStackTarget {
+        /// The target name
+        target: String,
+    },
+    #[cfg(feature = "unwind")]
+    #[error("Stacks can not be unwound on target {target}")]
+    /// Error when unwinding stacks on a target whose frames can not be unwound
+    UnsupportedUnwindTarget {
+        /// The target name
+        target: String,
+    },
+    #[cfg(feature = "objects")]
+    #[error("Error while reading object file {path}: {message}")]
+    /// Error when an object file can not be read or parsed
+    ObjectFileError {
+        /// The path of the object file
+        path: String,
+        /// A description of the error
+        message: String,
+    },
+    #[error("Event sink writer panicked: {message}")]
+    /// Error when the writer of an event sink panics
+    EventSinkWriterPanic {
+        /// The panic message
+        message: String,
+    },
+    #[error("Callback allocated in generation {generation} called in generation {current}")]
+    /// Error when QEMU runs a callback whose closure was dropped when the translation block
+    /// cache was flushed
+    StaleCallback {
+        /// The generation the callback's closure was allocated in
+        generation: u64,
+        /// The current generation
+        current: u64,
+    },
+    #[error("No plugin has been registered")]
+    /// Error when a callback runs before a plugin has been registered with `register!`
+    PluginNotRegistered,
     #[error("Error while setting global plugin instance")]
     /// Error when setting the global plugin instance fails
     PluginInstanceSetError,
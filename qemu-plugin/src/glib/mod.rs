@@ -142,3 +142,59 @@ pub(crate) unsafe fn g_byte_array_free(array: *mut GByteArray, free_segment: boo
 pub(crate) unsafe fn g_array_free(array: *mut GArray, free_segment: bool) -> *mut u8 {
     unsafe { G_ARRAY_FREE(array as *mut c_void, free_segment) }
 }
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// A `GByteArray` owned by a thread, reused by every read through the QEMU API on that
+/// thread so that hot-path reads do not allocate
+struct ScratchByteArray(std::cell::Cell<*mut GByteArray>);
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl Drop for ScratchByteArray {
+    fn drop(&mut self) {
+        let array = self.0.get();
+
+        if !array.is_null() {
+            unsafe { g_byte_array_free(array, true) };
+        }
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+thread_local! {
+    static SCRATCH_BYTE_ARRAY: ScratchByteArray =
+        const { ScratchByteArray(std::cell::Cell::new(std::ptr::null_mut())) };
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// Call `f` with this thread's scratch `GByteArray`, emptied. The array is allocated on
+/// first use and keeps its capacity between calls, so QEMU only grows it when a read is
+/// larger than any before it on this thread. During thread teardown, a temporary array
+/// is used instead.
+///
+/// `f` must not call `with_scratch_byte_array` again, and the array must not be used after
+/// `f` returns.
+pub(crate) fn with_scratch_byte_array<R>(f: impl FnOnce(*mut GByteArray) -> R) -> R {
+    let scratch = SCRATCH_BYTE_ARRAY
+        .try_with(|scratch| {
+            if scratch.0.get().is_null() {
+                scratch.0.set(unsafe { g_byte_array_new() });
+            }
+
+            scratch.0.get()
+        })
+        .ok();
+
+    match scratch {
+        Some(array) => {
+            // NOTE: QEMU appends register values to the array, so it must start empty
+            unsafe { (*array).len = 0 };
+            f(array)
+        }
+        None => {
+            let array = unsafe { g_byte_array_new() };
+            let result = f(array);
+            unsafe { g_byte_array_free(array, true) };
+            result
+        }
+    }
+}
@@ -0,0 +1,595 @@
+//! Typed access to guest memory
+//!
+//! [`crate::qemu_plugin_read_memory_vaddr`] and [`crate::qemu_plugin_write_memory_vaddr`]
+//! move raw bytes, and fail unless every requested byte is accessible. [`GuestMemory`]
+//! builds on them to read integers in the byte order of the target, strings, and plain
+//! data structures, and reports reads which cross into an unmapped page with the number
+//! of bytes which could be read. A [`GuestCursor`] adapts guest memory to
+//! [`std::io::Read`], [`std::io::Write`] and [`std::io::Seek`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use qemu_plugin::{GuestMemory, Info, Result};
+//!
+//! fn read_argv0(info: &Info, argv: u64) -> Result<String> {
+//!     let memory = GuestMemory::from_info(info);
+//!     let argv0 = memory.read_u64(argv)?;
+//!     Ok(memory.read_cstr(argv0, 4096)?.to_string_lossy().into_owned())
+//! }
+//! ```
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+use crate::qemu_plugin_write_memory_vaddr;
+use crate::{Endian, Error, Info, Result, qemu_plugin_read_memory_vaddr};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+use crate::{qemu_plugin_read_memory_hwaddr, qemu_plugin_write_memory_hwaddr};
+use std::{
+    ffi::CString,
+    io::{self, Read, Seek, SeekFrom},
+    mem::MaybeUninit,
+};
+
+/// The granularity at which guest memory is mapped. Reads and writes which fail are
+/// retried one page at a time to find how many bytes are accessible. This is the
+/// smallest page size of the common targets, so a page never straddles two mappings.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The number of bytes read at a time while scanning for a string terminator
+const SCAN_SIZE: u64 = 0x100;
+
+/// A type which can be read from and written to guest memory as its raw bytes
+///
+/// # Safety
+///
+/// Every bit pattern of the size of the type must be a valid value of the type, and the
+/// type must not contain padding. This holds for integers, floating point numbers, and
+/// arrays and `#[repr(C)]` structures of them with no padding between fields.
+///
+/// # Example
+///
+/// ```
+/// use qemu_plugin::Pod;
+///
+/// #[derive(Clone, Copy)]
+/// #[repr(C)]
+/// struct Timespec {
+///     tv_sec: i64,
+///     tv_nsec: i64,
+/// }
+///
+/// unsafe impl Pod for Timespec {}
+/// ```
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+unsafe impl<T, const N: usize> Pod for [T; N] where T: Pod {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// The address space guest memory is accessed through
+pub enum AddressSpace {
+    #[default]
+    /// Virtual addresses, translated by the current vCPU
+    Virtual,
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3",
+        feature = "plugin-api-v4"
+    )))]
+    /// Physical addresses, only available in system emulation
+    Physical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Guest memory, read and written in the byte order of the target
+///
+/// Virtual addresses are translated by the current vCPU, so accesses must be made from a
+/// vCPU callback.
+pub struct GuestMemory {
+    endian: Endian,
+    space: AddressSpace,
+}
+
+impl GuestMemory {
+    /// Guest memory accessed through virtual addresses, with integers in `endian` byte
+    /// order
+    pub fn new(endian: Endian) -> Self {
+        Self {
+            endian,
+            space: AddressSpace::Virtual,
+        }
+    }
+
+    /// Guest memory accessed through virtual addresses, with integers in the byte order
+    /// of the target described by `info`. Targets whose architecture is not known are
+    /// assumed to be little-endian.
+    pub fn from_info(info: &Info) -> Self {
+        Self::new(info.target.map_or(Endian::Little, |target| target.endian()))
+    }
+
+    /// Access guest memory through `space` instead
+    pub fn with_address_space(mut self, space: AddressSpace) -> Self {
+        self.space = space;
+        self
+    }
+
+    /// The byte order integers are read in
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// The address space guest memory is accessed through
+    pub fn address_space(&self) -> AddressSpace {
+        self.space
+    }
+
+    /// A cursor over guest memory starting at `addr`
+    pub fn cursor(&self, addr: u64) -> GuestCursor {
+        GuestCursor {
+            memory: *self,
+            position: addr,
+        }
+    }
+
+    /// Read exactly `buf.len()` bytes in a single request
+    fn read_exact_raw(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        match self.space {
+            AddressSpace::Virtual => qemu_plugin_read_memory_vaddr(addr, buf),
+            #[cfg(not(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2",
+                feature = "plugin-api-v3",
+                feature = "plugin-api-v4"
+            )))]
+            AddressSpace::Physical => qemu_plugin_read_memory_hwaddr(addr, buf),
+        }
+    }
+
+    /// Read as many bytes of `buf` as are accessible from `addr`, returning how many were
+    /// read. Reading stops at the first page which can not be read.
+    pub fn read_partial(&self, addr: u64, buf: &mut [u8]) -> usize {
+        if buf.is_empty() || self.read_exact_raw(addr, buf).is_ok() {
+            return buf.len();
+        }
+
+        let mut read = 0;
+
+        while read < buf.len() {
+            let page = addr.wrapping_add(read as u64);
+            let len = ((PAGE_SIZE - page % PAGE_SIZE) as usize).min(buf.len() - read);
+
+            if self
+                .read_exact_raw(page, &mut buf[read..read + len])
+                .is_err()
+            {
+                break;
+            }
+
+            read += len;
+        }
+
+        read
+    }
+
+    /// Read `buf.len()` bytes from `addr`
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::PartialMemoryRead`], which records how many bytes could be
+    /// read, if any page of the range can not be read.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        match self.read_partial(addr, buf) {
+            read if read == buf.len() => Ok(()),
+            read => Err(Error::PartialMemoryRead {
+                addr,
+                len: buf.len(),
+                read,
+            }),
+        }
+    }
+
+    /// Read `len` bytes from `addr`
+    pub fn read_vec(&self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.read(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read a value of a plain data type from `addr`. The value is read as its raw bytes,
+    /// so its fields are in the byte order of the target.
+    pub fn read_pod<T>(&self, addr: u64) -> Result<T>
+    where
+        T: Pod,
+    {
+        let mut value = MaybeUninit::<T>::zeroed();
+        // NOTE: The value is zeroed, and `Pod` types have no padding, so every byte is
+        // initialized
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        self.read(addr, bytes)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Read `N` bytes from `addr`
+    fn read_array<const N: usize>(&self, addr: u64) -> Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.read(addr, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Read a `u8` from `addr`
+    pub fn read_u8(&self, addr: u64) -> Result<u8> {
+        self.read_array::<1>(addr).map(|bytes| bytes[0])
+    }
+
+    /// Read a `u16` from `addr` in the byte order of the target
+    pub fn read_u16(&self, addr: u64) -> Result<u16> {
+        let bytes = self.read_array(addr)?;
+        Ok(match self.endian {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        })
+    }
+
+    /// Read a `u32` from `addr` in the byte order of the target
+    pub fn read_u32(&self, addr: u64) -> Result<u32> {
+        let bytes = self.read_array(addr)?;
+        Ok(match self.endian {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        })
+    }
+
+    /// Read a `u64` from `addr` in the byte order of the target
+    pub fn read_u64(&self, addr: u64) -> Result<u64> {
+        let bytes = self.read_array(addr)?;
+        Ok(match self.endian {
+            Endian::Little => u64::from_le_bytes(bytes),
+            Endian::Big => u64::from_be_bytes(bytes),
+        })
+    }
+
+    /// Read an `i8` from `addr`
+    pub fn read_i8(&self, addr: u64) -> Result<i8> {
+        self.read_u8(addr).map(|value| value as i8)
+    }
+
+    /// Read an `i16` from `addr` in the byte order of the target
+    pub fn read_i16(&self, addr: u64) -> Result<i16> {
+        self.read_u16(addr).map(|value| value as i16)
+    }
+
+    /// Read an `i32` from `addr` in the byte order of the target
+    pub fn read_i32(&self, addr: u64) -> Result<i32> {
+        self.read_u32(addr).map(|value| value as i32)
+    }
+
+    /// Read an `i64` from `addr` in the byte order of the target
+    pub fn read_i64(&self, addr: u64) -> Result<i64> {
+        self.read_u64(addr).map(|value| value as i64)
+    }
+
+    /// Read bytes from `addr` up to and including the first `delimiter`, reading at most
+    /// `max_len` bytes. If no delimiter is found within `max_len` bytes, the bytes read
+    /// are returned without one.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::PartialMemoryRead`] if an inaccessible page is reached before
+    /// the delimiter or `max_len` bytes.
+    pub fn read_until(&self, addr: u64, delimiter: u8, max_len: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut chunk = [0; SCAN_SIZE as usize];
+
+        while data.len() < max_len {
+            let position = addr.wrapping_add(data.len() as u64);
+            // NOTE: Chunks never cross a page, so a string ending just before an
+            // inaccessible page is still read completely
+            let len = ((SCAN_SIZE - position % SCAN_SIZE) as usize).min(max_len - data.len());
+            let read = self.read_partial(position, &mut chunk[..len]);
+
+            if let Some(end) = chunk[..read].iter().position(|byte| *byte == delimiter) {
+                data.extend_from_slice(&chunk[..=end]);
+                return Ok(data);
+            }
+
+            data.extend_from_slice(&chunk[..read]);
+
+            if read < len {
+                return Err(Error::PartialMemoryRead {
+                    addr,
+                    len: max_len,
+                    read: data.len(),
+                });
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Read a NUL-terminated string from `addr`, reading at most `max_len` bytes
+    /// including the terminator
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnterminatedGuestString`] if there is no terminator within
+    /// `max_len` bytes, and with [`Error::PartialMemoryRead`] if an inaccessible page is
+    /// reached first.
+    pub fn read_cstr(&self, addr: u64, max_len: usize) -> Result<CString> {
+        let mut data = self.read_until(addr, 0, max_len)?;
+
+        if data.pop() != Some(0) {
+            return Err(Error::UnterminatedGuestString { addr, max_len });
+        }
+
+        // NOTE: The only NUL byte read is the terminator
+        Ok(CString::new(data).expect("String has no interior NUL bytes"))
+    }
+
+    /// Read a NUL-terminated UTF-16 string in the byte order of the target from `addr`,
+    /// reading at most `max_units` code units including the terminator
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnterminatedGuestString`] if there is no terminator within
+    /// `max_units` code units, with [`Error::PartialMemoryRead`] if an inaccessible page
+    /// is reached first, and with [`Error::DecodeUtf16Error`] if the string is not valid
+    /// UTF-16.
+    pub fn read_utf16(&self, addr: u64, max_units: usize) -> Result<String> {
+        let mut units = Vec::new();
+        let mut chunk = [0; SCAN_SIZE as usize];
+
+        while units.len() < max_units {
+            let position = addr.wrapping_add(units.len() as u64 * 2);
+            // NOTE: Chunks hold whole code units, even when the string is not aligned
+            let len = (((SCAN_SIZE - position % SCAN_SIZE) as usize) & !1)
+                .max(2)
+                .min((max_units - units.len()) * 2);
+            let read = self.read_partial(position, &mut chunk[..len]);
+
+            for bytes in chunk[..read].chunks_exact(2) {
+                let unit = match self.endian {
+                    Endian::Little => u16::from_le_bytes([bytes[0], bytes[1]]),
+                    Endian::Big => u16::from_be_bytes([bytes[0], bytes[1]]),
+                };
+
+                if unit == 0 {
+                    return char::decode_utf16(units)
+                        .collect::<std::result::Result<String, _>>()
+                        .map_err(Error::from);
+                }
+
+                units.push(unit);
+            }
+
+            if read < len {
+                return Err(Error::PartialMemoryRead {
+                    addr,
+                    len: max_units * 2,
+                    read: units.len() * 2 + read % 2,
+                });
+            }
+        }
+
+        Err(Error::UnterminatedGuestString {
+            addr,
+            max_len: max_units * 2,
+        })
+    }
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+impl GuestMemory {
+    /// Write all of `buf` in a single request
+    fn write_exact_raw(&self, addr: u64, buf: &[u8]) -> Result<()> {
+        // NOTE: QEMU only reads from the buffer it is given to write
+        let mut buf = buf.to_vec();
+
+        match self.space {
+            AddressSpace::Virtual => qemu_plugin_write_memory_vaddr(addr, &mut buf),
+            AddressSpace::Physical => qemu_plugin_write_memory_hwaddr(addr, &mut buf),
+        }
+    }
+
+    /// Write as many bytes of `buf` as are accessible from `addr`, returning how many were
+    /// written. Writing stops at the first page which can not be written.
+    pub fn write_partial(&self, addr: u64, buf: &[u8]) -> usize {
+        if buf.is_empty() || self.write_exact_raw(addr, buf).is_ok() {
+            return buf.len();
+        }
+
+        let mut written = 0;
+
+        while written < buf.len() {
+            let page = addr.wrapping_add(written as u64);
+            let len = ((PAGE_SIZE - page % PAGE_SIZE) as usize).min(buf.len() - written);
+
+            if self
+                .write_exact_raw(page, &buf[written..written + len])
+                .is_err()
+            {
+                break;
+            }
+
+            written += len;
+        }
+
+        written
+    }
+
+    /// Write all of `buf` to `addr`
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::PartialMemoryWrite`], which records how many bytes could be
+    /// written, if any page of the range can not be written.
+    pub fn write(&self, addr: u64, buf: &[u8]) -> Result<()> {
+        match self.write_partial(addr, buf) {
+            written if written == buf.len() => Ok(()),
+            written => Err(Error::PartialMemoryWrite {
+                addr,
+                len: buf.len(),
+                written,
+            }),
+        }
+    }
+
+    /// Write a value of a plain data type to `addr` as its raw bytes
+    pub fn write_pod<T>(&self, addr: u64, value: &T) -> Result<()>
+    where
+        T: Pod,
+    {
+        // NOTE: `Pod` types have no padding, so every byte is initialized
+        self.write(addr, unsafe {
+            std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
+        })
+    }
+
+    /// Write a `u8` to `addr`
+    pub fn write_u8(&self, addr: u64, value: u8) -> Result<()> {
+        self.write(addr, &[value])
+    }
+
+    /// Write a `u16` to `addr` in the byte order of the target
+    pub fn write_u16(&self, addr: u64, value: u16) -> Result<()> {
+        match self.endian {
+            Endian::Little => self.write(addr, &value.to_le_bytes()),
+            Endian::Big => self.write(addr, &value.to_be_bytes()),
+        }
+    }
+
+    /// Write a `u32` to `addr` in the byte order of the target
+    pub fn write_u32(&self, addr: u64, value: u32) -> Result<()> {
+        match self.endian {
+            Endian::Little => self.write(addr, &value.to_le_bytes()),
+            Endian::Big => self.write(addr, &value.to_be_bytes()),
+        }
+    }
+
+    /// Write a `u64` to `addr` in the byte order of the target
+    pub fn write_u64(&self, addr: u64, value: u64) -> Result<()> {
+        match self.endian {
+            Endian::Little => self.write(addr, &value.to_le_bytes()),
+            Endian::Big => self.write(addr, &value.to_be_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A position in guest memory, read and written through [`std::io`]
+///
+/// Positions are guest addresses, so seeking from the start seeks to an absolute address.
+/// Guest memory has no end, so seeking from the end is not supported. Reads stop at the
+/// first inaccessible page, and fail if no byte can be read.
+pub struct GuestCursor {
+    memory: GuestMemory,
+    position: u64,
+}
+
+impl GuestCursor {
+    /// The guest address of the cursor
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Move the cursor to the guest address `addr`
+    pub fn set_position(&mut self, addr: u64) {
+        self.position = addr;
+    }
+
+    /// The guest memory the cursor reads from
+    pub fn memory(&self) -> &GuestMemory {
+        &self.memory
+    }
+}
+
+impl Read for GuestCursor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.memory.read_partial(self.position, buf);
+
+        if read == 0 && !buf.is_empty() {
+            return Err(io::Error::other(Error::PartialMemoryRead {
+                addr: self.position,
+                len: buf.len(),
+                read,
+            }));
+        }
+
+        self.position = self.position.wrapping_add(read as u64);
+        Ok(read)
+    }
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+    feature = "plugin-api-v4"
+)))]
+impl io::Write for GuestCursor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.memory.write_partial(self.position, buf);
+
+        if written == 0 && !buf.is_empty() {
+            return Err(io::Error::other(Error::PartialMemoryWrite {
+                addr: self.position,
+                len: buf.len(),
+                written,
+            }));
+        }
+
+        self.position = self.position.wrapping_add(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for GuestCursor {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(addr) => addr,
+            SeekFrom::Current(offset) => self
+                .position
+                .checked_add_signed(offset)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?,
+            SeekFrom::End(_) => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,293 @@
+//! Inline operations for QEMU plugins
+//!
+//! Inline operations are performed by the translated code itself rather than by calling
+//! back into the plugin, which makes them much cheaper than execution callbacks for simple
+//! counting. An [`InlineOp`] describes an operation on a [`Counter`] (or, with plugin API
+//! version 2 and later, a scoreboard field) and is registered with
+//! [`TranslationBlock::register_inline`](crate::TranslationBlock::register_inline),
+//! [`Instruction::register_inline`](crate::Instruction::register_inline) or
+//! [`Instruction::register_memory_inline`](crate::Instruction::register_memory_inline).
+//!
+//! With plugin API version 2 and later, operations are performed on a separate value for
+//! each vCPU. Older versions only support a single value shared by all vCPUs, which
+//! `Counter` falls back to automatically. Note that inline operations are not atomic, so
+//! in that case the result is inexact when several vCPUs run concurrently.
+//!
+//! # Example
+//!
+//! ```
+//! # use qemu_plugin::Result;
+//! use qemu_plugin::{Counter, HasCallbacks, InlineOp, PluginId, Register, TranslationBlock};
+//!
+//! struct ICount {
+//!     instructions: Counter,
+//! }
+//!
+//! impl Register for ICount {}
+//!
+//! impl HasCallbacks for ICount {
+//!     fn on_translation_block_translate(
+//!         &mut self,
+//!         _id: PluginId,
+//!         tb: TranslationBlock,
+//!     ) -> Result<()> {
+//!         tb.register_inline(InlineOp::add(self.instructions, tb.size() as u64));
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+use crate::PluginOp;
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use crate::{PluginU64, Scoreboard, ScoreboardU64, VCPUIndex};
+#[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy)]
+/// A `u64` counter which lives as long as the plugin, and can be the target of inline
+/// operations. With plugin API version 2 and later, each vCPU has its own value, backed by
+/// a scoreboard. Older versions share a single value between all vCPUs.
+///
+/// Because QEMU may perform inline operations on the counter at any time until the plugin
+/// is unloaded, the storage for a counter is never freed.
+pub struct Counter {
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    field: ScoreboardU64<'static, u64>,
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    value: &'static AtomicU64,
+}
+
+impl Counter {
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Allocate a new counter, initialized to zero
+    pub fn new() -> Self {
+        // NOTE: The scoreboard is intentionally leaked, see the type documentation
+        let scoreboard: &'static Scoreboard<'static, u64> = Box::leak(Box::default());
+
+        Self {
+            field: scoreboard.field(|value| value),
+        }
+    }
+
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    /// Allocate a new counter, initialized to zero
+    pub fn new() -> Self {
+        // NOTE: The value is intentionally leaked, see the type documentation
+        Self {
+            value: Box::leak(Box::new(AtomicU64::new(0))),
+        }
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Get the value of the counter for a given vCPU
+    pub fn get(&self, vcpu_index: VCPUIndex) -> u64 {
+        self.field.get(vcpu_index)
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Set the value of the counter for a given vCPU
+    pub fn set(&self, vcpu_index: VCPUIndex, value: u64) {
+        self.field.set(vcpu_index, value)
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Add to the value of the counter for a given vCPU
+    pub fn add(&self, vcpu_index: VCPUIndex, added: u64) {
+        self.field.add(vcpu_index, added)
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Iterate over the value of the counter for every vCPU, with the vCPU index
+    pub fn iter(&self) -> impl Iterator<Item = (VCPUIndex, u64)> + '_ {
+        self.field.iter()
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// Get the total value of the counter across all vCPUs
+    pub fn sum(&self) -> u64 {
+        self.field.sum()
+    }
+
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    /// Get the total value of the counter across all vCPUs
+    pub fn sum(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    /// Add to the value of the counter
+    pub fn add(&self, added: u64) {
+        self.value.fetch_add(added, Ordering::Relaxed);
+    }
+
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    /// Set the value of the counter
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The number of counters of a [`Counters`] table which share a scoreboard
+const COUNTERS_PER_SCOREBOARD: usize = 512;
+
+#[derive(Debug, Default)]
+/// A table of [`Counter`]s indexed from zero, for plugins which keep a counter for each
+/// block or instruction they instrument. With plugin API version 2 and later, counters are
+/// allocated many to a scoreboard, instead of one scoreboard each.
+///
+/// Like every counter, the counters of a table are never freed.
+pub struct Counters {
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    scoreboards: Vec<&'static Scoreboard<'static, [u64; COUNTERS_PER_SCOREBOARD]>>,
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    counters: Vec<Counter>,
+}
+
+impl Counters {
+    /// Create an empty table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// The counter at `index`, allocating it if needed
+    pub fn counter(&mut self, index: usize) -> Counter {
+        let (scoreboard, offset) = (
+            index / COUNTERS_PER_SCOREBOARD,
+            index % COUNTERS_PER_SCOREBOARD,
+        );
+
+        while self.scoreboards.len() <= scoreboard {
+            // NOTE: The scoreboard is intentionally leaked, see the type documentation
+            self.scoreboards.push(Box::leak(Box::default()));
+        }
+
+        let scoreboard: &'static Scoreboard<'static, [u64; COUNTERS_PER_SCOREBOARD]> =
+            self.scoreboards[scoreboard];
+
+        Counter {
+            field: scoreboard.field(|entry| &entry[offset]).cast(),
+        }
+    }
+
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    /// The counter at `index`, allocating it if needed
+    pub fn counter(&mut self, index: usize) -> Counter {
+        if self.counters.len() <= index {
+            self.counters.resize_with(index + 1, Counter::new);
+        }
+
+        self.counters[index]
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// The total value of the counter at `index` across all vCPUs, which is zero if it
+    /// was never allocated
+    pub fn sum(&self, index: usize) -> u64 {
+        self.scoreboards
+            .get(index / COUNTERS_PER_SCOREBOARD)
+            .map_or(0, |scoreboard| {
+                scoreboard
+                    .field(|entry| &entry[index % COUNTERS_PER_SCOREBOARD])
+                    .sum()
+            })
+    }
+
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    /// The total value of the counter at `index` across all vCPUs, which is zero if it
+    /// was never allocated
+    pub fn sum(&self, index: usize) -> u64 {
+        self.counters.get(index).map_or(0, Counter::sum)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// The target of an inline operation
+pub struct InlineTarget {
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    entry: PluginU64,
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    ptr: usize,
+}
+
+impl From<Counter> for InlineTarget {
+    fn from(value: Counter) -> Self {
+        Self {
+            #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+            entry: value.field.entry(),
+            #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+            ptr: value.value.as_ptr() as usize,
+        }
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl<T> From<ScoreboardU64<'static, T>> for InlineTarget {
+    /// Target a scoreboard field. QEMU may perform the operation at any time until the
+    /// plugin is unloaded, so only fields of a scoreboard which is never freed can be
+    /// targeted.
+    fn from(value: ScoreboardU64<'static, T>) -> Self {
+        Self {
+            entry: value.entry(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// An operation performed inline by translated code, without calling back into the
+/// plugin
+pub struct InlineOp {
+    pub(crate) op: PluginOp,
+    pub(crate) target: InlineTarget,
+    pub(crate) imm: u64,
+}
+
+impl InlineOp {
+    /// Add `imm` to `target` each time the operation runs
+    pub fn add<T>(target: T, imm: u64) -> Self
+    where
+        T: Into<InlineTarget>,
+    {
+        Self {
+            op: PluginOp::QEMU_PLUGIN_INLINE_ADD_U64,
+            target: target.into(),
+            imm,
+        }
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2"
+    )))]
+    /// Store `imm` into `target` each time the operation runs
+    pub fn store<T>(target: T, imm: u64) -> Self
+    where
+        T: Into<InlineTarget>,
+    {
+        Self {
+            op: PluginOp::QEMU_PLUGIN_INLINE_STORE_U64,
+            target: target.into(),
+            imm,
+        }
+    }
+
+    #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+    /// The scoreboard entry targeted by the operation
+    pub(crate) fn entry(&self) -> PluginU64 {
+        self.target.entry
+    }
+
+    #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+    /// The memory targeted by the operation
+    pub(crate) fn ptr(&self) -> *mut std::ffi::c_void {
+        self.target.ptr as *mut std::ffi::c_void
+    }
+}
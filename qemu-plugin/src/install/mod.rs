@@ -1,6 +1,11 @@
 //! Installation for the QEMU plugin
 
-use crate::qemu_plugin_bool_parse;
+use crate::{
+    Mode, Target,
+    plugin::register_default,
+    policy::{panic_message, set_plugin_id},
+    qemu_plugin_bool_parse, qemu_plugin_outs,
+};
 use qemu_plugin_sys::{
     QEMU_PLUGIN_VERSION, qemu_info_t, qemu_info_t__bindgen_ty_1,
     qemu_info_t__bindgen_ty_2__bindgen_ty_1, qemu_plugin_id_t,
@@ -8,28 +13,41 @@ use qemu_plugin_sys::{
 use std::{
     collections::HashMap,
     ffi::{CStr, c_char, c_int},
+    fmt::Write,
+    panic::{AssertUnwindSafe, catch_unwind},
+    path::PathBuf,
 };
 
-use crate::{error::Error, plugin::PLUGIN};
+use crate::error::Error;
 
 #[allow(non_upper_case_globals)]
-#[unsafe(no_mangle)]
+// NOTE: The unit tests link the mock host, which brings its own copy of this crate
+#[cfg_attr(not(test), unsafe(no_mangle))]
 /// The version of the plugin API that this plugin is compatible with
 pub static qemu_plugin_version: c_int = QEMU_PLUGIN_VERSION as c_int;
 
 /// Code returned from `qemu_plugin_install` to indicate successful installation
 pub const PLUGIN_INSTALL_SUCCESS: c_int = 0;
 
-#[derive(Debug, Clone)]
+/// Code returned from `qemu_plugin_install` to indicate installation failed, in which
+/// case QEMU unloads the plugin and exits
+pub const PLUGIN_INSTALL_FAILURE: c_int = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// A value passed to a QEMU plugin via the command line, either as a boolean,
 /// integer, or string. Booleans are parsed using the `qemu_plugin_bool_parse`
 /// function, integers are parsed from strings, and strings are taken as-is.
 pub enum Value {
     /// A boolean argument to a QEMU plugin, for example `val=true` or `val=on`
     /// see https://www.qemu.org/docs/master/devel/tcg-plugins.html#c.qemu_plugin_bool_parse
+    /// A bare flag with no value, for example `val`, is `true`.
     Bool(bool),
-    /// An integer argument to a QEMU plugin, for example `val=1`
+    /// An integer argument to a QEMU plugin, for example `val=1`, `val=-0x10`, `val=0o17`
+    /// or `val=0b101`
     Integer(i64),
+    /// An integer argument to a QEMU plugin which is too large for an `i64`, for example
+    /// `val=0xffffffff81000000`
+    UnsignedInteger(u64),
     /// A string argument to a QEMU plugin, for example `val=foo`
     String(String),
 }
@@ -38,8 +56,10 @@ impl Value {
     fn new(key: &str, value: &str) -> Result<Self, Error> {
         if let Ok(maybe_bool) = qemu_plugin_bool_parse(key, value) {
             Ok(Self::Bool(maybe_bool))
-        } else if let Ok(int) = value.parse::<i64>() {
+        } else if let Ok(int) = i64::from_arg_value(key, value) {
             Ok(Self::Integer(int))
+        } else if let Ok(int) = u64::from_arg_value(key, value) {
+            Ok(Self::UnsignedInteger(int))
         } else {
             Ok(Self::String(value.to_string()))
         }
@@ -49,7 +69,8 @@ impl Value {
 #[derive(Debug, Clone)]
 /// Arguments to QEMU as passed to `qemu_plugin_install`. `qemu_plugin_install`
 /// takes a comma-separated list of key=value pairs, such as
-/// `val1=foo,val2=bar`.
+/// `val1=foo,val2=bar`. A key may be given without a value as a boolean flag, such as
+/// `val1`, and may be given more than once, such as `filter=a,filter=b`.
 pub struct Args {
     /// Arguments to the QEMU plugin as passed in by QEMU. Each entry is a
     /// key=value pair where the key is the name of the argument and the value
@@ -57,35 +78,352 @@ pub struct Args {
     pub raw: Vec<String>,
     /// Arguments to the QEMU plugin, parsed into valid argument types and value
     /// types. Each key is the name of the argument and the value is a `Value`
-    /// enum which can be a boolean, integer, or string.
+    /// enum which can be a boolean, integer, or string. If a key is given more than
+    /// once, this holds the last value, see [`Args::get_all`] for every value.
     pub parsed: HashMap<String, Value>,
+    /// Every value of each argument, in the order they were given
+    values: HashMap<String, Vec<Value>>,
 }
 
 impl Args {
     /// Create a new QEMU `Args` container from the raw arguments passed to the plugin on the
     /// command line
     fn new(argc: c_int, value: *const *const c_char) -> Result<Self, Error> {
+        let raw = (0..argc)
+            .map(|i| unsafe { CStr::from_ptr(*value.offset(i as isize)) })
+            .map(|cstr| cstr.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        let mut parsed = HashMap::new();
+        let mut values = HashMap::<_, Vec<_>>::new();
+
+        for argument in &raw {
+            let (key, value) = split_argument(argument);
+
+            if key.is_empty() {
+                return Err(Error::MissingArgKey {
+                    argument: argument.clone(),
+                });
+            }
+
+            let value = match value {
+                Some(value) => Value::new(key, value)?,
+                None => Value::Bool(true),
+            };
+
+            parsed.insert(key.to_string(), value.clone());
+            values.entry(key.to_string()).or_default().push(value);
+        }
+
         Ok(Self {
-            raw: (0..argc)
-                .map(|i| unsafe { CStr::from_ptr(*value.offset(i as isize)) })
-                .map(|cstr| cstr.to_string_lossy().into_owned())
-                .collect::<Vec<_>>(),
-            parsed: (0..argc)
-                .map(|i| unsafe { CStr::from_ptr(*value.offset(i as isize)) })
-                .map(|cstr| cstr.to_string_lossy().into_owned())
-                .map(|argument| {
-                    let mut split = argument.splitn(2, '=');
-                    let Some(key) = split.next() else {
-                        return Err(Error::MissingArgKey { argument });
-                    };
-                    let Some(value) = split.next() else {
-                        return Err(Error::MissingArgValue { argument });
-                    };
-                    Ok((key.to_string(), Value::new(key, value)?))
-                })
-                .collect::<Result<Vec<(_, _)>, Error>>()?
-                .into_iter()
-                .collect::<HashMap<_, _>>(),
+            raw,
+            parsed,
+            values,
+        })
+    }
+
+    /// Iterate over the arguments passed to the plugin as key and value pairs, in the order
+    /// they were given. The value is `None` for bare flags. Unlike `parsed`, keys which
+    /// are passed more than once are yielded once for each occurrence.
+    pub fn pairs(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.raw.iter().map(|argument| split_argument(argument))
+    }
+
+    /// Whether the argument `key` was given
+    pub fn contains(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    /// The value of the argument `key`. If it was given more than once, this is the last
+    /// value.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.parsed.get(key)
+    }
+
+    /// Every value of the argument `key`, in the order they were given
+    pub fn get_all(&self, key: &str) -> &[Value] {
+        self.values.get(key).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Parse the value of the argument `key` as `T`. If it was given more than once, the
+    /// last value is used. Returns `None` if the argument was not given, and an error if
+    /// its value does not parse.
+    pub fn get_as<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: FromArgValue,
+    {
+        self.pairs()
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(key, value)| T::from_arg(key, value))
+            .transpose()
+    }
+
+    /// Parse every value of the argument `key` as `T`, in the order they were given
+    pub fn get_all_as<T>(&self, key: &str) -> Result<Vec<T>, Error>
+    where
+        T: FromArgValue,
+    {
+        self.pairs()
+            .filter(|(k, _)| *k == key)
+            .map(|(key, value)| T::from_arg(key, value))
+            .collect()
+    }
+
+    /// Parse the value of the argument `key` as a boolean. A bare flag is `true`.
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, Error> {
+        self.get_as(key)
+    }
+
+    /// Parse the value of the argument `key` as an `i64`, in decimal or with a `0x`, `0o`
+    /// or `0b` prefix
+    pub fn get_i64(&self, key: &str) -> Result<Option<i64>, Error> {
+        self.get_as(key)
+    }
+
+    /// Parse the value of the argument `key` as a `u64`, in decimal or with a `0x`, `0o`
+    /// or `0b` prefix
+    pub fn get_u64(&self, key: &str) -> Result<Option<u64>, Error> {
+        self.get_as(key)
+    }
+
+    /// The value of the argument `key` as a string, exactly as it was given
+    pub fn get_string(&self, key: &str) -> Result<Option<String>, Error> {
+        self.get_as(key)
+    }
+
+    /// The value of the argument `key` as a path
+    pub fn get_path(&self, key: &str) -> Result<Option<PathBuf>, Error> {
+        self.get_as(key)
+    }
+}
+
+/// Split an argument into its key and, unless it is a bare flag, its value
+fn split_argument(argument: &str) -> (&str, Option<&str>) {
+    match argument.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (argument, None),
+    }
+}
+
+/// A type which can be parsed from the value of a plugin argument. Implemented for
+/// booleans, integers, strings and paths, and can be derived for enums whose variants
+/// have no fields with `#[derive(FromArgValue)]` when the `derive` feature is enabled.
+pub trait FromArgValue: Sized {
+    /// Parse the value of the argument `key`
+    fn from_arg_value(key: &str, value: &str) -> Result<Self, Error>;
+
+    /// Parse the argument `key` given as a bare flag, with no value. Only booleans
+    /// accept bare flags by default.
+    fn from_flag(key: &str) -> Result<Self, Error> {
+        Err(Error::MissingArgValue {
+            argument: key.to_string(),
+        })
+    }
+
+    /// A short description of the accepted values, for usage strings
+    fn expected() -> String;
+
+    /// Parse the argument `key`, which is a bare flag if `value` is `None`
+    fn from_arg(key: &str, value: Option<&str>) -> Result<Self, Error> {
+        match value {
+            Some(value) => Self::from_arg_value(key, value),
+            None => Self::from_flag(key),
+        }
+    }
+}
+
+impl FromArgValue for bool {
+    fn from_arg_value(key: &str, value: &str) -> Result<Self, Error> {
+        qemu_plugin_bool_parse(key, value).map_err(|_| Error::InvalidArgValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            expected: Self::expected(),
+        })
+    }
+
+    fn from_flag(_key: &str) -> Result<Self, Error> {
+        Ok(true)
+    }
+
+    fn expected() -> String {
+        "<on|off>".to_string()
+    }
+}
+
+impl FromArgValue for String {
+    fn from_arg_value(_key: &str, value: &str) -> Result<Self, Error> {
+        Ok(value.to_string())
+    }
+
+    fn expected() -> String {
+        "<string>".to_string()
+    }
+}
+
+impl FromArgValue for PathBuf {
+    fn from_arg_value(_key: &str, value: &str) -> Result<Self, Error> {
+        Ok(PathBuf::from(value))
+    }
+
+    fn expected() -> String {
+        "<path>".to_string()
+    }
+}
+
+/// Split an integer into its sign and digits and its radix, which is given by a `0x`,
+/// `0o` or `0b` prefix after the sign, and is otherwise decimal
+fn split_radix(value: &str) -> (String, u32) {
+    let (sign, unsigned) = match value.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", value.strip_prefix('+').unwrap_or(value)),
+    };
+
+    let prefix = unsigned.get(..2).map(str::to_ascii_lowercase);
+    let (digits, radix) = match prefix.as_deref() {
+        Some("0x") => (&unsigned[2..], 16),
+        Some("0o") => (&unsigned[2..], 8),
+        Some("0b") => (&unsigned[2..], 2),
+        _ => (unsigned, 10),
+    };
+
+    // NOTE: from_str_radix accepts a sign, so one in the digits must be rejected here
+    if digits.starts_with(['+', '-']) {
+        return (String::new(), radix);
+    }
+
+    (format!("{sign}{digits}"), radix)
+}
+
+macro_rules! impl_from_arg_value_integer {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl FromArgValue for $ty {
+                fn from_arg_value(key: &str, value: &str) -> Result<Self, Error> {
+                    let (digits, radix) = split_radix(value);
+
+                    <$ty>::from_str_radix(&digits, radix).map_err(|_| Error::InvalidArgValue {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                        expected: Self::expected(),
+                    })
+                }
+
+                fn expected() -> String {
+                    "<integer>".to_string()
+                }
+            }
+        )*
+    };
+}
+
+impl_from_arg_value_integer!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+
+#[derive(Debug, Clone)]
+/// The description of one argument accepted by a type implementing [`PluginArgs`]
+pub struct ArgSpec {
+    /// The key of the argument
+    pub key: &'static str,
+    /// A short description of the accepted values, from [`FromArgValue::expected`]
+    pub expected: String,
+    /// The documentation of the argument
+    pub description: &'static str,
+    /// Whether the argument must be provided
+    pub required: bool,
+    /// Whether the argument may be provided more than once
+    pub multiple: bool,
+    /// The default value of the argument, if it has one which can be displayed
+    pub default: Option<&'static str>,
+}
+
+/// A set of typed plugin arguments, parsed from the [`Args`] passed to the plugin. This
+/// trait is usually implemented with `#[derive(PluginArgs)]`, which is available when the
+/// `derive` feature is enabled.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "derive")]
+/// # {
+/// use qemu_plugin::{FromArgValue, PluginArgs};
+/// use std::path::PathBuf;
+///
+/// #[derive(FromArgValue)]
+/// enum Format {
+///     Text,
+///     Json,
+/// }
+///
+/// #[derive(PluginArgs)]
+/// struct Options {
+///     /// Where to write the trace
+///     output: PathBuf,
+///     /// The format to write the trace in
+///     #[arg(default = Format::Text)]
+///     format: Format,
+///     /// Whether to trace memory accesses
+///     log_mem: bool,
+///     /// Only trace these symbols
+///     #[arg(name = "symbol")]
+///     symbols: Vec<String>,
+///     /// Stop tracing after this many instructions
+///     limit: Option<u64>,
+/// }
+/// # }
+/// ```
+pub trait PluginArgs: Sized {
+    /// Parse the plugin arguments
+    fn from_args(args: &Args) -> Result<Self, Error>;
+
+    /// The arguments accepted by the plugin
+    fn specs() -> Vec<ArgSpec>;
+
+    /// A usage string describing the arguments accepted by the plugin
+    fn usage() -> String {
+        let specs = Self::specs();
+        let arguments = specs
+            .iter()
+            .map(|spec| format!("{}={}", spec.key, spec.expected))
+            .collect::<Vec<_>>();
+        let width = arguments.iter().map(|a| a.len()).max().unwrap_or_default();
+        let mut usage = "Arguments:\n".to_string();
+
+        for (spec, argument) in specs.iter().zip(arguments) {
+            let mut notes = Vec::new();
+
+            if !spec.description.is_empty() {
+                notes.push(spec.description.to_string());
+            }
+
+            if spec.required {
+                notes.push("(required)".to_string());
+            }
+
+            if spec.multiple {
+                notes.push("(may be repeated)".to_string());
+            }
+
+            if let Some(default) = spec.default {
+                notes.push(format!("(default: {default})"));
+            }
+
+            let _ = writeln!(
+                usage,
+                "  {}",
+                format!("{argument:width$}  {}", notes.join(" ")).trim_end()
+            );
+        }
+
+        usage
+    }
+
+    /// Parse the plugin arguments, printing the error and the usage string with
+    /// `qemu_plugin_outs` if they are invalid
+    fn parse(args: &Args) -> Result<Self, Error> {
+        Self::from_args(args).inspect_err(|e| {
+            let _ = qemu_plugin_outs(format!("{e}\n{}", Self::usage()));
         })
     }
 }
@@ -133,6 +471,8 @@ impl From<&qemu_info_t__bindgen_ty_2__bindgen_ty_1> for System {
 pub struct Info {
     /// The target name of the simulation (e.g. `x86_64-softmmu`)
     pub target_name: String,
+    /// The target of the simulation, if `target_name` names a known target
+    pub target: Option<Target>,
     /// The minimum and current plugin API version
     pub version: Version,
     /// Information about the system, if the emulator is running in full system
@@ -163,15 +503,27 @@ impl Info {
         };
 
         Ok(Self {
+            target: Target::from_target_name(&target_name),
             target_name,
             version,
             system,
         })
     }
+
+    /// Whether QEMU is emulating a single user-mode process or a full system
+    pub fn mode(&self) -> Mode {
+        if self.system.is_some() {
+            Mode::System
+        } else {
+            Mode::User
+        }
+    }
 }
 
-#[unsafe(no_mangle)]
-/// Called by QEMU when the plugin is loaded
+#[cfg_attr(not(test), unsafe(no_mangle))]
+/// Called by QEMU when the plugin is loaded. If parsing the arguments or registering the
+/// plugin fails or panics, the error is printed with `qemu_plugin_outs` and
+/// `PLUGIN_INSTALL_FAILURE` is returned so QEMU refuses to load the plugin.
 ///
 /// # Safety
 ///
@@ -185,20 +537,157 @@ pub unsafe extern "C" fn qemu_plugin_install(
     argc: c_int,
     argv: *const *const c_char,
 ) -> c_int {
-    let args = Args::new(argc, argv).expect("Failed to parse arguments");
-    let info = unsafe { Info::try_from(info) }.expect("Failed to convert qemu_info_t");
+    let install = || -> Result<(), Error> {
+        let args = Args::new(argc, argv)?;
+        let info = unsafe { Info::try_from(info) }?;
 
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
+        set_plugin_id(id);
+        Target::set_current(info.target);
+
+        register_default(id, &args, &info)
     };
 
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
+    let message = match catch_unwind(AssertUnwindSafe(install)) {
+        Ok(Ok(())) => return PLUGIN_INSTALL_SUCCESS,
+        Ok(Err(e)) => format!("Failed to install plugin: {e}"),
+        Err(payload) => format!("Failed to install plugin: {}", panic_message(&*payload)),
     };
 
-    plugin
-        .register_default(id, &args, &info)
-        .expect("Failed to register plugin");
+    let _ = qemu_plugin_outs(format!("{message}\n"));
+
+    PLUGIN_INSTALL_FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    /// Parse `arguments` as QEMU passes them to the plugin
+    fn args(arguments: &[&str]) -> Result<Args, Error> {
+        let arguments = arguments
+            .iter()
+            .map(|argument| CString::new(*argument).unwrap())
+            .collect::<Vec<_>>();
+        let argv = arguments.iter().map(|a| a.as_ptr()).collect::<Vec<_>>();
+
+        Args::new(argv.len() as c_int, argv.as_ptr())
+    }
+
+    #[test]
+    fn values_are_parsed_by_type() {
+        let cases = [
+            ("on", Value::Bool(true)),
+            ("off", Value::Bool(false)),
+            ("10", Value::Integer(10)),
+            ("-10", Value::Integer(-10)),
+            ("+10", Value::Integer(10)),
+            ("0x401000", Value::Integer(0x401000)),
+            ("0X1f", Value::Integer(0x1f)),
+            ("-0x10", Value::Integer(-0x10)),
+            ("0o17", Value::Integer(0o17)),
+            ("0b101", Value::Integer(0b101)),
+            (
+                "0xffffffff81000000",
+                Value::UnsignedInteger(0xffffffff81000000),
+            ),
+            ("0x", Value::String("0x".to_string())),
+            ("0x-1", Value::String("0x-1".to_string())),
+            ("--1", Value::String("--1".to_string())),
+            ("0b102", Value::String("0b102".to_string())),
+            ("foo", Value::String("foo".to_string())),
+            ("", Value::String(String::new())),
+        ];
+
+        for (value, expected) in cases {
+            let args = args(&[&format!("key={value}")]).unwrap();
+            assert_eq!(args.get("key"), Some(&expected), "key={value}");
+        }
+    }
+
+    #[test]
+    fn bare_flags_are_true() {
+        let args = args(&["verbose", "limit=3"]).unwrap();
+
+        assert_eq!(args.get("verbose"), Some(&Value::Bool(true)));
+        assert_eq!(args.get_bool("verbose").unwrap(), Some(true));
+        assert_eq!(args.get_bool("quiet").unwrap(), None);
+        assert_eq!(
+            args.pairs().collect::<Vec<_>>(),
+            [("verbose", None), ("limit", Some("3"))]
+        );
+
+        // Only booleans may be given as bare flags
+        assert!(matches!(
+            args.get_u64("verbose"),
+            Err(Error::MissingArgValue { argument }) if argument == "verbose"
+        ));
+    }
+
+    #[test]
+    fn repeated_keys_keep_every_value() {
+        let args = args(&["filter=a", "filter=b", "limit=1", "limit=0x10"]).unwrap();
+
+        assert_eq!(
+            args.get_all("filter"),
+            [
+                Value::String("a".to_string()),
+                Value::String("b".to_string())
+            ]
+        );
+        assert_eq!(args.get("filter"), Some(&Value::String("b".to_string())));
+        assert_eq!(args.get_all_as::<String>("filter").unwrap(), ["a", "b"]);
+        assert_eq!(args.get_u64("limit").unwrap(), Some(0x10));
+        assert!(args.get_all("missing").is_empty());
+        assert!(args.contains("filter") && !args.contains("missing"));
+    }
+
+    #[test]
+    fn typed_accessors_parse_values() {
+        let args = args(&[
+            "addr=0xffffffff81000000",
+            "offset=-0b11",
+            "small=0x100",
+            "path=/tmp/out",
+            "name=0x10",
+        ])
+        .unwrap();
+
+        assert_eq!(args.get_u64("addr").unwrap(), Some(0xffffffff81000000));
+        assert_eq!(args.get_i64("offset").unwrap(), Some(-3));
+        assert_eq!(
+            args.get_path("path").unwrap(),
+            Some(PathBuf::from("/tmp/out"))
+        );
+        assert_eq!(args.get_string("name").unwrap(), Some("0x10".to_string()));
+        assert_eq!(args.get_as::<u16>("small").unwrap(), Some(0x100));
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        let args = args(&["addr=-1", "small=0x100", "flag=maybe", "count=ten"]).unwrap();
+        let invalid = |result: Result<(), Error>, key: &str, expected: &str| {
+            assert!(
+                matches!(
+                    &result,
+                    Err(Error::InvalidArgValue { key: k, expected: e, .. })
+                        if k == key && e == expected
+                ),
+                "{key}: {result:?}"
+            );
+        };
+
+        invalid(args.get_u64("addr").map(drop), "addr", "<integer>");
+        invalid(args.get_as::<u8>("small").map(drop), "small", "<integer>");
+        invalid(args.get_bool("flag").map(drop), "flag", "<on|off>");
+        invalid(args.get_i64("count").map(drop), "count", "<integer>");
+    }
 
-    PLUGIN_INSTALL_SUCCESS
+    #[test]
+    fn arguments_need_a_key() {
+        assert!(matches!(
+            args(&["=value"]),
+            Err(Error::MissingArgKey { argument }) if argument == "=value"
+        ));
+    }
 }
@@ -1,9 +1,9 @@
 //! Instruction-related functionality for QEMU plugins
 
 use crate::{
-    CallbackFlags, Error, MemRW, MemoryInfo, Result, TranslationBlock, VCPUIndex, g_free,
-    handle_qemu_plugin_register_vcpu_insn_exec_cb, handle_qemu_plugin_register_vcpu_mem_cb,
-    sys::qemu_plugin_insn,
+    CallbackFlags, Error, InlineOp, MemAccess, MemRW, MemoryInfo, Result, TranslationBlock,
+    VCPUIndex, arena, g_free, handle_qemu_plugin_register_vcpu_insn_exec_cb,
+    handle_qemu_plugin_register_vcpu_mem_cb, sys::qemu_plugin_insn,
 };
 #[cfg(not(any(
     feature = "plugin-api-v0",
@@ -11,10 +11,7 @@ use crate::{
     feature = "plugin-api-v2"
 )))]
 use crate::{PluginCondition, PluginU64};
-use std::{
-    ffi::{CStr, c_void},
-    marker::PhantomData,
-};
+use std::{ffi::CStr, marker::PhantomData};
 
 #[derive(Debug, Clone)]
 /// Wrapper structure for a `qemu_plugin_insn *`
@@ -27,6 +24,7 @@ use std::{
 /// # Example
 ///
 /// ```
+/// # use qemu_plugin::Result;
 /// struct MyPlugin;
 ///
 /// impl qemu_plugin::plugin::Register for MyPlugin {}
@@ -205,9 +203,7 @@ impl<'a> Instruction<'a> {
     where
         F: FnMut(VCPUIndex) + Send + Sync + 'static,
     {
-        let callback = Box::new(cb);
-        let callback_box = Box::new(callback);
-        let userdata = Box::into_raw(callback_box) as *mut c_void;
+        let userdata = arena::alloc(cb);
 
         unsafe {
             crate::sys::qemu_plugin_register_vcpu_insn_exec_cb(
@@ -219,6 +215,33 @@ impl<'a> Instruction<'a> {
         };
     }
 
+    /// Register an inline operation to be run on execution of this instruction
+    ///
+    /// # Arguments
+    ///
+    /// - `op`: The operation to be run
+    pub fn register_inline(&self, op: InlineOp) {
+        #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+        unsafe {
+            crate::sys::qemu_plugin_register_vcpu_insn_exec_inline(
+                self.instruction as *mut qemu_plugin_insn,
+                op.op,
+                op.ptr(),
+                op.imm,
+            )
+        };
+
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        unsafe {
+            crate::sys::qemu_plugin_register_vcpu_insn_exec_inline_per_vcpu(
+                self.instruction as *mut qemu_plugin_insn,
+                op.op,
+                op.entry(),
+                op.imm,
+            )
+        };
+    }
+
     /// Register a callback to be conditionally run on execution of this instruction
     /// with no capability to inspect registers
     ///
@@ -277,9 +300,7 @@ impl<'a> Instruction<'a> {
     ) where
         F: FnMut(VCPUIndex) + Send + Sync + 'static,
     {
-        let callback = Box::new(cb);
-        let callback_box = Box::new(callback);
-        let userdata = Box::into_raw(callback_box) as *mut c_void;
+        let userdata = arena::alloc(cb);
 
         unsafe {
             crate::sys::qemu_plugin_register_vcpu_insn_exec_cond_cb(
@@ -317,9 +338,7 @@ impl<'a> Instruction<'a> {
     where
         F: for<'b> FnMut(VCPUIndex, MemoryInfo<'b>, u64) + Send + Sync + 'static,
     {
-        let callback = Box::new(cb);
-        let callback_box = Box::new(callback);
-        let userdata = Box::into_raw(callback_box) as *mut c_void;
+        let userdata = arena::alloc(cb);
 
         unsafe {
             crate::sys::qemu_plugin_register_vcpu_mem_cb(
@@ -331,4 +350,67 @@ impl<'a> Instruction<'a> {
             )
         };
     }
+
+    /// Register a callback to be run on memory access of this instruction, which receives
+    /// the decoded [`MemAccess`]
+    ///
+    /// # Arguments
+    ///
+    /// - `cb`: The callback to be run
+    /// - `rw`: The type of memory access to trigger the callback on
+    pub fn register_access_callback<F>(&self, cb: F, rw: MemRW)
+    where
+        F: for<'b> FnMut(VCPUIndex, MemAccess<'b>) + Send + Sync + 'static,
+    {
+        self.register_access_callback_flags(cb, rw, CallbackFlags::QEMU_PLUGIN_CB_NO_REGS)
+    }
+
+    /// Register a callback to be run on memory access of this instruction, which receives
+    /// the decoded [`MemAccess`]
+    ///
+    /// # Arguments
+    ///
+    /// - `cb`: The callback to be run
+    /// - `rw`: The type of memory access to trigger the callback on
+    /// - `flags`: The flags for the callback specifying access to registers
+    pub fn register_access_callback_flags<F>(&self, mut cb: F, rw: MemRW, flags: CallbackFlags)
+    where
+        F: for<'b> FnMut(VCPUIndex, MemAccess<'b>) + Send + Sync + 'static,
+    {
+        self.register_memory_access_callback_flags(
+            move |vcpu_index, info, vaddr| cb(vcpu_index, MemAccess::new(info, vaddr)),
+            rw,
+            flags,
+        );
+    }
+
+    /// Register an inline operation to be run on memory access of this instruction
+    ///
+    /// # Arguments
+    ///
+    /// - `op`: The operation to be run
+    /// - `rw`: The type of memory access to trigger the operation on
+    pub fn register_memory_inline(&self, op: InlineOp, rw: MemRW) {
+        #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+        unsafe {
+            crate::sys::qemu_plugin_register_vcpu_mem_inline(
+                self.instruction as *mut qemu_plugin_insn,
+                rw,
+                op.op,
+                op.ptr(),
+                op.imm,
+            )
+        };
+
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        unsafe {
+            crate::sys::qemu_plugin_register_vcpu_mem_inline_per_vcpu(
+                self.instruction as *mut qemu_plugin_insn,
+                rw,
+                op.op,
+                op.entry(),
+                op.imm,
+            )
+        };
+    }
 }
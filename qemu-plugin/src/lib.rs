@@ -62,6 +62,11 @@
 #[cfg(windows)]
 mod win_link_hook;
 
+// NOTE: The unit tests call into the QEMU plugin API, which the mock host implements
+#[cfg(test)]
+use qemu_plugin_mock as _;
+
+use crate::policy::guard;
 use crate::sys::{
     qemu_plugin_cb_flags, qemu_plugin_id_t, qemu_plugin_insn, qemu_plugin_mem_rw,
     qemu_plugin_meminfo_t, qemu_plugin_op, qemu_plugin_simple_cb_t, qemu_plugin_tb,
@@ -81,19 +86,38 @@ use qemu_plugin_sys::qemu_plugin_cond;
 use std::{ffi::CStr, path::PathBuf};
 use std::{
     ffi::{CString, c_uint, c_void},
-    sync::{Mutex, OnceLock},
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
+pub mod arch;
+pub use arch::*;
+pub mod arena;
+pub mod callstack;
+pub use callstack::*;
+pub mod controller;
+pub use controller::*;
 pub mod error;
 #[allow(unused_imports)]
 pub use error::*;
 pub mod install;
 pub use install::*;
+#[cfg(feature = "derive")]
+pub use qemu_plugin_derive::{FromArgValue, PluginArgs, callbacks};
 pub mod plugin;
 pub use plugin::*;
+pub mod policy;
+pub use policy::{ErrorPolicy, error_policy, set_error_policy};
 pub mod instruction;
+#[cfg(feature = "mock")]
+#[doc(hidden)]
+pub mod mock;
 pub mod sys;
+pub mod target;
 pub use instruction::*;
+pub use target::*;
 pub mod translation_block;
 pub use translation_block::*;
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
@@ -107,7 +131,69 @@ pub mod scoreboard;
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
 pub use scoreboard::*;
 pub mod glib;
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+pub mod guest_memory;
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+pub use guest_memory::*;
+pub mod sink;
+pub mod syscalls;
 pub(crate) use glib::*;
+pub use sink::{Backpressure, EventSink, EventSinkBuilder, SinkStats};
+pub use syscalls::*;
+pub mod inline;
+pub use inline::*;
+#[cfg(feature = "objects")]
+pub mod objects;
+#[cfg(feature = "objects")]
+pub use objects::ObjectLayout;
+#[cfg(all(
+    feature = "unwind",
+    not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    ))
+))]
+pub mod unwind;
+#[cfg(all(
+    feature = "unwind",
+    not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    ))
+))]
+pub use unwind::*;
+#[cfg(feature = "symbolize")]
+pub mod symbolize;
+#[cfg(feature = "symbolize")]
+pub use symbolize::*;
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+pub mod time;
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+pub use time::*;
 
 /// The index of a vCPU
 pub type VCPUIndex = c_uint;
@@ -214,42 +300,106 @@ pub type SyscallReturnCallback = qemu_plugin_vcpu_syscall_ret_cb_t;
 // FFI which means we never downcast to an 8-byte pointer from fat, but it is best not
 // to rely on that.
 
-#[allow(clippy::type_complexity)]
+/// A callback run once when QEMU calls back into the plugin
+type PluginCallback = Box<dyn FnOnce(qemu_plugin_id_t) + Send + Sync + 'static>;
+
+/// A callback stored until QEMU calls back into the plugin
+type PendingCallback = Mutex<Option<Box<PluginCallback>>>;
+
 /// A callback which will run once removal and uninstallation of the plugin is finalized. This callback
-/// can only be set once, by calling the `qemu_plugin_uninstall` function.
-static UNINSTALL_CALLBACK: OnceLock<
-    Mutex<Option<Box<Box<dyn FnOnce(qemu_plugin_id_t) + Send + Sync + 'static>>>>,
-> = OnceLock::new();
+/// can only be set once per installation, by calling the `qemu_plugin_uninstall` function.
+static UNINSTALL_CALLBACK: PendingCallback = Mutex::new(None);
+
+/// Whether an uninstallation has been requested since the plugin was installed
+static UNINSTALLING: AtomicBool = AtomicBool::new(false);
 
-#[allow(clippy::type_complexity)]
 /// A callback which will run once the plugin is reset. This callback is set by calling the
 /// `qemu_plugin_reset` function.
-static RESET_CALLBACK: OnceLock<
-    Mutex<Option<Box<Box<dyn FnOnce(qemu_plugin_id_t) + Send + Sync + 'static>>>>,
-> = OnceLock::new();
+static RESET_CALLBACK: PendingCallback = Mutex::new(None);
+
+/// Callbacks which will run once execution is finished, in the order they were set by
+/// calling the `qemu_plugin_register_atexit_cb` function. QEMU only keeps one exit callback
+/// per plugin and drops it when the plugin is reset, so a single handler running all of
+/// them is registered, and registered again after each reset.
+static ATEXIT_CALLBACKS: Mutex<Vec<PluginCallback>> = Mutex::new(Vec::new());
+
+#[cfg(feature = "mock")]
+/// Forget the uninstallation, reset and exit callbacks requested by an earlier
+/// installation of the plugin
+pub(crate) fn clear_pending_callbacks() {
+    UNINSTALLING.store(false, Ordering::Release);
+    *UNINSTALL_CALLBACK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = None;
+    // NOTE: A pending reset reports that it was abandoned when dropped, so it is dropped
+    // without holding the lock
+    let reset = RESET_CALLBACK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    drop(reset);
+    ATEXIT_CALLBACKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
 
 /// Handle the invocation of the uninstall callback by calling the stored
 /// callback closure, if one exists.
 extern "C" fn handle_qemu_plugin_uninstall_callback(id: qemu_plugin_id_t) {
-    if let Some(callback) = UNINSTALL_CALLBACK.get()
-        && let Ok(mut callback) = callback.lock()
-        && let Some(callback) = callback.take()
-    {
-        callback(id);
-    }
-    // NOTE: An error here is ignored, and exceedingly fatal
+    // NOTE: Once uninstalled, no callback of the plugin can run again
+    arena::reclaim();
+
+    guard("uninstall callback", || {
+        // NOTE: QEMU never completes a reset requested before the plugin was uninstalled
+        let reset = RESET_CALLBACK
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        drop(reset);
+
+        let callback = UNINSTALL_CALLBACK
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        if let Some(callback) = callback {
+            callback(id);
+        }
+        Ok(())
+    });
 }
 
 /// Handle the invocation of the reset callback by calling the stored
 /// callback closure, if one exists.
 extern "C" fn handle_qemu_plugin_reset_callback(id: qemu_plugin_id_t) {
-    if let Some(callback) = UNINSTALL_CALLBACK.get()
-        && let Ok(mut callback) = callback.lock()
-        && let Some(callback) = callback.take()
+    // NOTE: Resetting removes every callback of the plugin and flushes the translation
+    // block cache
+    arena::reclaim();
+
+    // NOTE: The reset also removed the exit handler, which must run even if the plugin
+    // never registers new exit callbacks
+    if !ATEXIT_CALLBACKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_empty()
     {
-        callback(id);
+        register_atexit_handler(id);
     }
-    // NOTE: An error here is ignored, and exceedingly fatal
+
+    guard("reset callback", || {
+        // NOTE: The lock is released before calling the callback, so it can reset the
+        // plugin again
+        let callback = RESET_CALLBACK
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        if let Some(callback) = callback {
+            callback(id);
+        }
+        Ok(())
+    });
 }
 
 /// Uninstall a plugin.
@@ -270,16 +420,21 @@ pub fn qemu_plugin_uninstall<F>(id: qemu_plugin_id_t, cb: F) -> Result<()>
 where
     F: FnOnce(qemu_plugin_id_t) + Send + Sync + 'static,
 {
-    UNINSTALL_CALLBACK
-        .set(Mutex::new(Some(Box::new(Box::new(cb)))))
-        .map_err(|_| Error::ConcurrentPluginUninstallCallbackSet)?;
+    if UNINSTALLING.swap(true, Ordering::AcqRel) {
+        return Err(Error::ConcurrentPluginUninstallCallbackSet);
+    }
+
+    *UNINSTALL_CALLBACK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(Box::new(cb)));
 
     unsafe { crate::sys::qemu_plugin_uninstall(id, Some(handle_qemu_plugin_uninstall_callback)) };
 
     Ok(())
 }
 
-/// Reset a plugin
+/// Reset a plugin, which removes every callback it registered except those registered
+/// with [`qemu_plugin_register_atexit_cb`], which are registered again before `cb` runs.
 ///
 /// # Arguments
 ///
@@ -295,16 +450,14 @@ pub fn qemu_plugin_reset<F>(id: qemu_plugin_id_t, cb: F) -> Result<()>
 where
     F: FnOnce(qemu_plugin_id_t) + Send + Sync + 'static,
 {
-    if let Some(callback) = RESET_CALLBACK.get() {
-        let Ok(mut callback) = callback.lock() else {
-            return Err(Error::PluginResetCallbackState);
-        };
-        let _ = callback.replace(Box::new(Box::new(cb)));
-    } else {
-        RESET_CALLBACK
-            .set(Mutex::new(Some(Box::new(Box::new(cb)))))
-            .map_err(|_| Error::ConcurrentPluginResetCallbackSet)?;
-    }
+    let Ok(mut callback) = RESET_CALLBACK.lock() else {
+        return Err(Error::PluginResetCallbackState);
+    };
+    let previous = callback.replace(Box::new(Box::new(cb)));
+    drop(callback);
+    // NOTE: The replaced callback will never be called, and is dropped without holding
+    // the lock because dropping it can run the plugin's code
+    drop(previous);
 
     unsafe { crate::sys::qemu_plugin_reset(id, Some(handle_qemu_plugin_reset_callback)) };
 
@@ -385,9 +538,13 @@ extern "C" fn handle_qemu_plugin_register_vcpu_tb_exec_cb<F>(
 ) where
     F: FnMut(VCPUIndex) + Send + Sync + 'static,
 {
-    let mut cb: Box<Box<F>> = unsafe { Box::from_raw(userdata as *mut _) };
-    cb(vcpu_index);
-    Box::leak(cb);
+    guard("translation block execute callback", || {
+        // NOTE: The callback is owned by the arena, which drops it when the translation
+        // block cache is flushed
+        let cb = unsafe { arena::closure::<F>(userdata) }?;
+        cb(vcpu_index);
+        Ok(())
+    });
 }
 
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
@@ -499,10 +656,13 @@ extern "C" fn handle_qemu_plugin_register_vcpu_insn_exec_cb<F>(
 ) where
     F: FnMut(VCPUIndex) + Send + Sync + 'static,
 {
-    let mut cb: Box<Box<F>> = unsafe { Box::from_raw(userdata as *mut _) };
-    cb(vcpu_index);
-    // NOTE: This memory will be freed on plugin exit
-    Box::leak(cb);
+    guard("instruction execute callback", || {
+        // NOTE: The callback is owned by the arena, which drops it when the translation
+        // block cache is flushed
+        let cb = unsafe { arena::closure::<F>(userdata) }?;
+        cb(vcpu_index);
+        Ok(())
+    });
 }
 
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
@@ -607,11 +767,14 @@ extern "C" fn handle_qemu_plugin_register_vcpu_mem_cb<F>(
 ) where
     F: for<'a> FnMut(VCPUIndex, MemoryInfo<'a>, u64) + Send + Sync + 'static,
 {
-    let mut cb: Box<Box<F>> = unsafe { Box::from_raw(userdata as *mut _) };
-    let meminfo = MemoryInfo::from(meminfo);
-    cb(vcpu_index, meminfo, vaddr);
-    // NOTE: This memory will be freed on plugin exit
-    Box::leak(cb);
+    guard("memory access callback", || {
+        // NOTE: The callback is owned by the arena, which drops it when the translation
+        // block cache is flushed
+        let cb = unsafe { arena::closure::<F>(userdata) }?;
+        let meminfo = MemoryInfo::from(meminfo);
+        cb(vcpu_index, meminfo, vaddr);
+        Ok(())
+    });
 }
 
 /// Register a callback for every memory transaction of a particular instruction. If the
@@ -691,18 +854,37 @@ pub fn qemu_plugin_register_vcpu_mem_inline_per_vcpu(
     }
 }
 
-extern "C" fn handle_qemu_plugin_register_atexit_cb<F>(id: qemu_plugin_id_t, userdata: *mut c_void)
-where
-    F: FnOnce(qemu_plugin_id_t) + Send + Sync + 'static,
-{
-    let cb: Box<Box<F>> = unsafe { Box::from_raw(userdata as *mut _) };
-    cb(id);
-    // NOTE: This memory is not leaked because this is the last callback to be called
-    // and it can only be called once, so we allow it to drop
+extern "C" fn handle_qemu_plugin_atexit_callback(id: qemu_plugin_id_t, _userdata: *mut c_void) {
+    // NOTE: The lock is released before calling the callbacks, because they are all
+    // taken at once and nothing registered afterwards could run
+    let callbacks = std::mem::take(
+        &mut *ATEXIT_CALLBACKS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+
+    for callback in callbacks {
+        guard("atexit callback", || {
+            callback(id);
+            Ok(())
+        });
+    }
+}
+
+/// Register the handler which runs every exit callback with QEMU
+fn register_atexit_handler(id: qemu_plugin_id_t) {
+    unsafe {
+        crate::sys::qemu_plugin_register_atexit_cb(
+            id,
+            Some(handle_qemu_plugin_atexit_callback),
+            std::ptr::null_mut(),
+        )
+    };
 }
 
 /// Register a callback to run once execution is finished. Plugins should be able to free all
-/// their resources at this point.
+/// their resources at this point. Every registered callback runs, in the order they were
+/// registered, and they stay registered when the plugin is reset.
 ///
 /// # Arguments
 ///
@@ -712,15 +894,18 @@ pub fn qemu_plugin_register_atexit_cb<F>(id: qemu_plugin_id_t, cb: F) -> Result<
 where
     F: FnOnce(qemu_plugin_id_t) + Send + Sync + 'static,
 {
-    let callback = Box::new(cb);
-    let callback_box = Box::new(callback);
-    unsafe {
-        crate::sys::qemu_plugin_register_atexit_cb(
-            id,
-            Some(handle_qemu_plugin_register_atexit_cb::<F>),
-            Box::into_raw(callback_box) as *mut c_void,
-        )
+    let first = {
+        let mut callbacks = ATEXIT_CALLBACKS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        callbacks.push(Box::new(cb));
+        callbacks.len() == 1
     };
+
+    if first {
+        register_atexit_handler(id);
+    }
+
     Ok(())
 }
 
@@ -951,3 +1136,27 @@ pub fn qemu_plugin_u64_set(entry: PluginU64, vcpu_index: VCPUIndex, value: u64)
 pub fn qemu_plugin_scoreboard_sum(entry: PluginU64) -> u64 {
     unsafe { crate::sys::qemu_plugin_u64_sum(entry) }
 }
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+/// Request the ability to control virtual time. Only one plugin can control time, so
+/// this returns an error if control has already been granted.
+pub fn qemu_plugin_request_time_control() -> Result<TimeControl> {
+    TimeControl::request()
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+/// Move virtual time forward to `time_ns` nanoseconds. This has no effect in user mode,
+/// where time comes from the host kernel.
+pub fn qemu_plugin_update_ns(handle: &TimeControl, time_ns: u64) -> u64 {
+    handle.update_ns(time_ns)
+}
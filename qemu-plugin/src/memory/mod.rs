@@ -1,12 +1,5 @@
 //! Memory-related functionality for QEMU plugins
 
-#[cfg(not(any(
-    feature = "plugin-api-v0",
-    feature = "plugin-api-v1",
-    feature = "plugin-api-v2",
-    feature = "plugin-api-v3",
-)))]
-use crate::Error;
 #[cfg(not(feature = "plugin-api-v0"))]
 use crate::Result;
 #[cfg(not(any(
@@ -25,6 +18,13 @@ use crate::sys::qemu_plugin_hwaddr_operation_result;
 )))]
 use crate::sys::{GByteArray, qemu_plugin_mem_value, qemu_plugin_mem_value_type};
 use crate::sys::{qemu_plugin_hwaddr, qemu_plugin_meminfo_t};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3",
+)))]
+use crate::{Error, with_scratch_byte_array};
 use std::marker::PhantomData;
 
 #[derive(Debug, Clone)]
@@ -141,6 +141,225 @@ impl From<qemu_plugin_mem_value> for MemValue {
     }
 }
 
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+impl MemValue {
+    /// The value zero-extended to 128 bits
+    pub fn as_u128(&self) -> u128 {
+        match *self {
+            Self::U8(value) => value as u128,
+            Self::U16(value) => value as u128,
+            Self::U32(value) => value as u128,
+            Self::U64(value) => value as u128,
+            Self::U128(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A decoded memory access, passed to callbacks registered with
+/// [`crate::Instruction::register_access_callback`]
+///
+/// The access bundles the address, size and direction of the access, which QEMU reports
+/// separately through [`MemoryInfo`]. With the V4 plugin API and later, it also carries
+/// the value loaded or stored, normalized to 128 bits with the access's sign extension
+/// applied.
+///
+/// # Safety
+///
+/// Like [`MemoryInfo`], this structure is only valid during the invocation of the
+/// callback which receives it.
+pub struct MemAccess<'a> {
+    info: MemoryInfo<'a>,
+    vaddr: u64,
+    size: usize,
+    is_store: bool,
+    sign_extended: bool,
+    big_endian: bool,
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    value: u128,
+}
+
+impl<'a> MemAccess<'a> {
+    /// Decode the access described by `info` to the virtual address `vaddr`
+    pub fn new(info: MemoryInfo<'a>, vaddr: u64) -> Self {
+        let size = 1 << info.size_shift();
+        let sign_extended = info.sign_extended();
+
+        #[cfg(not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2",
+            feature = "plugin-api-v3"
+        )))]
+        let value = {
+            let value = info.value().as_u128();
+            let bits = size * 8;
+
+            // NOTE: The value QEMU reports is the value in memory, which sign-extending
+            // loads extend to the width of the destination
+            if sign_extended && bits < 128 && value >> (bits - 1) & 1 == 1 {
+                value | (u128::MAX << bits)
+            } else {
+                value
+            }
+        };
+
+        Self {
+            vaddr,
+            size,
+            is_store: info.is_store(),
+            sign_extended,
+            big_endian: info.big_endian(),
+            #[cfg(not(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2",
+                feature = "plugin-api-v3"
+            )))]
+            value,
+            info,
+        }
+    }
+
+    /// The virtual address accessed
+    pub fn vaddr(&self) -> u64 {
+        self.vaddr
+    }
+
+    /// The size of the access in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the access was a store
+    pub fn is_store(&self) -> bool {
+        self.is_store
+    }
+
+    /// Whether the access was a load
+    pub fn is_load(&self) -> bool {
+        !self.is_store
+    }
+
+    /// Whether the access was sign extended
+    pub fn sign_extended(&self) -> bool {
+        self.sign_extended
+    }
+
+    /// Whether the access was big-endian
+    pub fn big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// The raw information QEMU reported about the access
+    pub fn info(&self) -> &MemoryInfo<'a> {
+        &self.info
+    }
+
+    /// A handle to query details about the physical address backing the access in system
+    /// emulation. In user-mode, this method always returns `None`.
+    pub fn hwaddr(&'a self) -> Option<HwAddr<'a>> {
+        self.info.hwaddr(self.vaddr)
+    }
+
+    #[cfg(not(feature = "plugin-api-v0"))]
+    /// The physical address backing the access in system emulation. In user-mode, this
+    /// method always returns `None`.
+    pub fn phys_addr(&'a self) -> Option<u64> {
+        self.hwaddr().map(|hwaddr| hwaddr.hwaddr())
+    }
+
+    #[cfg(not(feature = "plugin-api-v0"))]
+    /// The name of the device backing the access, if it is to MMIO in system emulation
+    pub fn device_name(&'a self) -> Result<Option<String>> {
+        match self.hwaddr() {
+            Some(hwaddr) if hwaddr.is_io() => hwaddr.device_name(),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+impl<'a> MemAccess<'a> {
+    /// The value loaded or stored, zero-extended to 128 bits, or sign-extended if the
+    /// access was sign extended
+    pub fn value(&self) -> u128 {
+        self.value
+    }
+
+    /// The value loaded or stored as a signed integer. Accesses which were not sign
+    /// extended are zero-extended.
+    pub fn signed_value(&self) -> i128 {
+        self.value as i128
+    }
+
+    /// The value truncated to a `u8`, which is the full value of 1-byte accesses
+    pub fn as_u8(&self) -> u8 {
+        self.value as u8
+    }
+
+    /// The value truncated to a `u16`, which is the full value of accesses of up to 2
+    /// bytes
+    pub fn as_u16(&self) -> u16 {
+        self.value as u16
+    }
+
+    /// The value truncated to a `u32`, which is the full value of accesses of up to 4
+    /// bytes
+    pub fn as_u32(&self) -> u32 {
+        self.value as u32
+    }
+
+    /// The value truncated to a `u64`, which is the full value of accesses of up to 8
+    /// bytes
+    pub fn as_u64(&self) -> u64 {
+        self.value as u64
+    }
+
+    /// The value truncated to an `i8`
+    pub fn as_i8(&self) -> i8 {
+        self.value as i8
+    }
+
+    /// The value truncated to an `i16`
+    pub fn as_i16(&self) -> i16 {
+        self.value as i16
+    }
+
+    /// The value truncated to an `i32`
+    pub fn as_i32(&self) -> i32 {
+        self.value as i32
+    }
+
+    /// The value truncated to an `i64`
+    pub fn as_i64(&self) -> i64 {
+        self.value as i64
+    }
+
+    /// The value truncated to an unsigned integer of the target's pointer width
+    pub fn as_target_usize(&self, target: crate::Target) -> u64 {
+        match target.pointer_width() {
+            4 => self.as_u32() as u64,
+            _ => self.as_u64(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 /// Wrapper structure for a `qemu_plugin_hwaddr *`
 ///
@@ -203,23 +422,42 @@ impl<'a> HwAddr<'a> {
     feature = "plugin-api-v2",
     feature = "plugin-api-v3"
 )))]
-/// Read memory from a virtual address. The address must be valid and mapped.
+/// Copy the contents of `data` into `buf`, returning whether QEMU filled the array with
+/// exactly as many bytes as `buf` holds
+fn copy_from_byte_array(data: *const GByteArray, buf: &mut [u8]) -> bool {
+    let (ptr, len) = unsafe { ((*data).data, (*data).len as usize) };
+
+    if len != buf.len() {
+        return false;
+    }
+
+    if len > 0 {
+        buf.copy_from_slice(unsafe { std::slice::from_raw_parts(ptr, len) });
+    }
+
+    true
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+/// Read memory from a virtual address into `buf`. The address must be valid and mapped.
+///
+/// QEMU reads into this thread's scratch `GByteArray`, which is reused between reads, so
+/// reading does not allocate once the array has grown to the size of the largest read.
 pub fn qemu_plugin_read_memory_vaddr(addr: u64, buf: &mut [u8]) -> Result<()> {
-    let mut buf = GByteArray {
-        data: buf.as_mut_ptr(),
-        len: buf.len() as u32,
-    };
+    let len = buf.len() as u32;
 
-    if unsafe {
-        crate::sys::qemu_plugin_read_memory_vaddr(
-            addr,
-            &mut buf as *mut GByteArray,
-            buf.len as usize,
-        )
-    } {
+    if with_scratch_byte_array(|data| {
+        let read = unsafe { crate::sys::qemu_plugin_read_memory_vaddr(addr, data, buf.len()) };
+        read && copy_from_byte_array(data, buf)
+    }) {
         Ok(())
     } else {
-        Err(Error::VaddrReadError { addr, len: buf.len })
+        Err(Error::VaddrReadError { addr, len })
     }
 }
 
@@ -301,26 +539,25 @@ impl From<qemu_plugin_hwaddr_operation_result> for HwaddrOperationResult {
     feature = "plugin-api-v3",
     feature = "plugin-api-v4"
 )))]
-/// Read memory from a hardware address. The address must be valid and mapped.
+/// Read memory from a hardware address into `buf`. The address must be valid and mapped.
+///
+/// QEMU reads into this thread's scratch `GByteArray`, which is reused between reads, so
+/// reading does not allocate once the array has grown to the size of the largest read.
 pub fn qemu_plugin_read_memory_hwaddr(addr: u64, buf: &mut [u8]) -> Result<()> {
-    let mut buf = GByteArray {
-        data: buf.as_mut_ptr(),
-        len: buf.len() as u32,
-    };
+    let len = buf.len() as u32;
 
-    match unsafe {
-        crate::sys::qemu_plugin_read_memory_hwaddr(
-            addr,
-            &mut buf as *mut GByteArray,
-            buf.len as usize,
-        )
-    }
-    .into()
-    {
+    match with_scratch_byte_array(|data| {
+        match unsafe { crate::sys::qemu_plugin_read_memory_hwaddr(addr, data, buf.len()) }.into() {
+            HwaddrOperationResult::Ok if !copy_from_byte_array(data, buf) => {
+                HwaddrOperationResult::Error
+            }
+            result => result,
+        }
+    }) {
         HwaddrOperationResult::Ok => Ok(()),
         error => Err(Error::HwaddrReadError {
             addr,
-            len: buf.len,
+            len,
             result: error,
         }),
     }
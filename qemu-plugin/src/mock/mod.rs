@@ -0,0 +1,41 @@
+//! Hooks for test hosts like `qemu-plugin-mock`
+//!
+//! QEMU loads a separate copy of the plugin library each time it loads a plugin, so a
+//! plugin is only ever installed once in a process. A test host installs the same plugin
+//! again for each session, and uses these hooks to forget what the earlier session left
+//! behind and to choose the plugin instance the next session installs.
+
+use crate::{
+    arena, controller,
+    plugin::{Plugin, PluginSync, set_plugin, set_plugin_sync},
+};
+
+/// Forget the callbacks an earlier installation of the plugin registered or requested,
+/// and the reset it may have left in progress
+pub fn reset() {
+    arena::reclaim();
+    crate::clear_pending_callbacks();
+    controller::clear_resetting();
+}
+
+/// Install `plugin` in the next session in place of the global plugin
+pub fn install_plugin<P>(plugin: P)
+where
+    P: Plugin,
+{
+    set_plugin(Box::new(plugin));
+}
+
+/// Install `plugin` in the next session in place of the global plugin registered with
+/// `register!(sync ...)`, dropping the one it replaces
+///
+/// # Safety
+///
+/// No callback of the plugin may be running, or run again before the next session
+/// installs the plugin.
+pub unsafe fn install_plugin_sync<P>(plugin: P)
+where
+    P: PluginSync,
+{
+    unsafe { set_plugin_sync(Box::new(plugin)) };
+}
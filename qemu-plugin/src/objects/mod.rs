@@ -0,0 +1,137 @@
+//! Object files loaded into the guest
+//!
+//! An [`ObjectLayout`] describes where the loadable segments of an ELF object file are,
+//! and locates the whole object from the address one of its mappings was loaded at. QEMU
+//! only reports where the executable segments of the binary being emulated are loaded,
+//! and the dynamic loader maps a shared object from its start, so both are located
+//! relative to the layout of the file.
+//!
+//! The `Symbolizer` and the `Unwinder`, which symbolizes its frames with a `Symbolizer`,
+//! keep a table of the object files loaded into the guest, which follows the dynamic
+//! loader as it opens and maps shared objects in user mode.
+
+use crate::{Error, Result};
+use object::{Object, ObjectSegment, SegmentFlags, elf::PF_X};
+use std::{fs::File, io::Read, ops::Range, path::Path};
+
+#[cfg(feature = "symbolize")]
+mod table;
+#[cfg(feature = "symbolize")]
+pub(crate) use table::*;
+
+/// The page size segments are aligned to when they are loaded
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The magic number at the start of every ELF file
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+
+/// Read the ELF file at the host path `path`. Files which are not ELF files are rejected
+/// before they are read in full.
+pub(crate) fn read(path: &Path) -> Result<Vec<u8>> {
+    let error = |message: String| Error::ObjectFileError {
+        path: path.display().to_string(),
+        message,
+    };
+    let mut data = Vec::new();
+    let mut reader = File::open(path).map_err(|e| error(e.to_string()))?;
+    let mut magic = [0; ELF_MAGIC.len()];
+
+    if reader.read_exact(&mut magic).is_err() || magic != ELF_MAGIC {
+        return Err(error("not an ELF file".to_string()));
+    }
+
+    data.extend_from_slice(&magic);
+    reader
+        .read_to_end(&mut data)
+        .map_err(|e| error(e.to_string()))?;
+
+    Ok(data)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Where the loadable segments of an object file are, in the addresses of the file
+///
+/// The difference between the loaded and file addresses of an object is its bias, which
+/// converts between them.
+pub struct ObjectLayout {
+    /// The address of the first loadable segment, rounded down to a page
+    pub base: u64,
+    /// The address the last loadable segment ends at
+    pub end: u64,
+    /// The address of the first executable segment, if the object has one
+    pub code: Option<u64>,
+}
+
+impl ObjectLayout {
+    /// Read the layout of the object file at the host path `path`
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::ObjectFileError`] if the file can not be read, or is not an
+    /// ELF file.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = read(path)?;
+        let file = object::File::parse(&*data).map_err(|e| Error::ObjectFileError {
+            path: path.display().to_string(),
+            message: e.to_string(),
+        })?;
+
+        Ok(Self::from_file(&file))
+    }
+
+    /// The layout of the parsed object file `file`
+    pub(crate) fn from_file(file: &object::File) -> Self {
+        let segments = file
+            .segments()
+            .map(|segment| {
+                let executable = matches!(
+                    segment.flags(),
+                    SegmentFlags::Elf { p_flags } if p_flags & PF_X != 0
+                );
+                (
+                    segment.address()..segment.address() + segment.size(),
+                    executable,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            base: segments
+                .iter()
+                .map(|(range, _)| range.start)
+                .min()
+                .unwrap_or_default()
+                & !(PAGE_SIZE - 1),
+            end: segments
+                .iter()
+                .map(|(range, _)| range.end)
+                .max()
+                .unwrap_or_default(),
+            code: segments
+                .iter()
+                .filter(|(_, executable)| *executable)
+                .map(|(range, _)| range.start)
+                .min(),
+        }
+    }
+
+    /// The bias of the object if its first executable segment is loaded at `start`, as
+    /// QEMU reports for the binary being emulated with
+    /// [`crate::qemu_plugin_start_code`]. Objects without an executable segment are
+    /// located by their first segment instead.
+    pub fn bias_from_code(&self, start: u64) -> u64 {
+        start.wrapping_sub(self.code.unwrap_or(self.base))
+    }
+
+    /// The bias of the object if its first loadable segment is mapped at `base`, as the
+    /// start of its mapping is listed in `/proc/<pid>/maps`
+    pub fn bias_from_base(&self, base: u64) -> u64 {
+        base.wrapping_sub(self.base)
+    }
+
+    /// The loaded addresses of the object, if it is loaded with `bias`
+    pub fn loaded(&self, bias: u64) -> Range<u64> {
+        self.base.wrapping_add(bias)..self.end.wrapping_add(bias)
+    }
+}
@@ -0,0 +1,271 @@
+//! The table of the object files loaded into the guest, shared by the symbolizer and the
+//! unwinder
+
+use super::ObjectLayout;
+use crate::Result;
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+use crate::{Endian, GuestMemory, Syscall, VCPUIndex, syscalls::errno};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+use std::collections::HashSet;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard},
+};
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+/// The longest path read from the arguments of an `open` syscall
+const PATH_MAX: usize = 4096;
+
+/// An object file parsed from its file
+pub(crate) trait LoadObject: Sized {
+    /// Read and parse the object file at the guest path `path`. Guest paths are
+    /// translated to host paths with `host_path`.
+    fn load(path: &Path, host_path: &dyn Fn(&Path) -> PathBuf) -> Result<Self>;
+
+    /// The layout of the object file
+    fn layout(&self) -> &ObjectLayout;
+}
+
+/// An object file loaded into the guest
+pub(crate) struct Mapping<T> {
+    /// The loaded addresses of the object file
+    pub(crate) range: Range<u64>,
+    /// The difference between the loaded and file addresses of the object file
+    pub(crate) bias: u64,
+    pub(crate) object: Arc<T>,
+}
+
+/// The object file loaded at `address` in `mappings`, which are sorted by address
+pub(crate) fn mapping<T>(mappings: &[Mapping<T>], address: u64) -> Option<&Mapping<T>> {
+    let index = mappings
+        .partition_point(|mapping| mapping.range.start <= address)
+        .checked_sub(1)?;
+    let mapping = &mappings[index];
+
+    mapping.range.contains(&address).then_some(mapping)
+}
+
+/// Lock `mutex`. The state of the object table is consistent between statements, so a
+/// poisoned lock is recovered.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+/// A syscall a vCPU has made which may open or map an object file, awaiting its return
+enum PendingSyscall {
+    /// A file is being opened
+    Open(PathBuf),
+    /// The start of a file is being mapped
+    Map { fd: i64 },
+    /// A file descriptor is being closed
+    Close { fd: i64 },
+}
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+#[derive(Default)]
+/// The files the guest has open, tracked to locate the object files it maps
+struct Files {
+    /// The path of each open file descriptor
+    paths: HashMap<i64, PathBuf>,
+    /// The syscall each vCPU is making
+    pending: HashMap<VCPUIndex, PendingSyscall>,
+    /// The paths of files mapped from their start which are not object files, so they
+    /// are not read again each time they are mapped
+    not_objects: HashSet<PathBuf>,
+}
+
+/// The object files loaded into the guest, each parsed once, when it is first added
+pub(crate) struct GuestObjects<T> {
+    /// A directory guest paths are looked up in before the host file system, like the
+    /// `-L` option of QEMU user mode
+    sysroot: Option<PathBuf>,
+    /// Every object file parsed, by guest path
+    objects: Mutex<HashMap<PathBuf, Arc<T>>>,
+    /// The object files loaded into the guest, sorted by address
+    mappings: RwLock<Vec<Mapping<T>>>,
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    files: Mutex<Files>,
+}
+
+impl<T: LoadObject> GuestObjects<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            sysroot: None,
+            objects: Mutex::new(HashMap::new()),
+            mappings: RwLock::new(Vec::new()),
+            #[cfg(not(any(
+                feature = "plugin-api-v0",
+                feature = "plugin-api-v1",
+                feature = "plugin-api-v2",
+                feature = "plugin-api-v3"
+            )))]
+            files: Mutex::new(Files::default()),
+        }
+    }
+
+    /// Look guest paths up in `sysroot` before the host file system
+    pub(crate) fn with_sysroot(mut self, sysroot: PathBuf) -> Self {
+        self.sysroot = Some(sysroot);
+        self
+    }
+
+    /// The path on the host of the guest path `path`
+    fn host_path(&self, path: &Path) -> PathBuf {
+        self.sysroot
+            .as_ref()
+            .map(|sysroot| sysroot.join(path.strip_prefix("/").unwrap_or(path)))
+            .filter(|path| path.exists())
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    /// The parsed object file at the guest path `path`, parsed if it has not been yet
+    pub(crate) fn object(&self, path: &Path) -> Result<Arc<T>> {
+        if let Some(object) = lock(&self.objects).get(path) {
+            return Ok(object.clone());
+        }
+
+        let object = Arc::new(T::load(path, &|path| self.host_path(path))?);
+        lock(&self.objects).insert(path.to_path_buf(), object.clone());
+
+        Ok(object)
+    }
+
+    /// Add `object` loaded with `bias`, replacing any object it overlaps
+    pub(crate) fn insert(&self, object: Arc<T>, bias: u64) {
+        let range = object.layout().loaded(bias);
+        let mut mappings = self
+            .mappings
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        mappings
+            .retain(|mapping| mapping.range.end <= range.start || range.end <= mapping.range.start);
+
+        let index = mappings.partition_point(|mapping| mapping.range.start < range.start);
+        mappings.insert(
+            index,
+            Mapping {
+                range,
+                bias,
+                object,
+            },
+        );
+    }
+
+    /// The object files loaded into the guest, sorted by address
+    pub(crate) fn mappings(&self) -> RwLockReadGuard<'_, Vec<Mapping<T>>> {
+        self.mappings.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// Watch a syscall made by `vcpu_index` for object files being opened and mapped
+    pub(crate) fn on_syscall(&self, vcpu_index: VCPUIndex, syscall: &Syscall) {
+        // NOTE: Only strings are read, which have no byte order
+        let memory = GuestMemory::new(Endian::Little);
+        let pending = match (syscall.name(), syscall.args()) {
+            (Some("openat"), [_, path, ..]) | (Some("open"), [path, ..]) => memory
+                .read_cstr(*path, PATH_MAX)
+                .ok()
+                .map(|path| PendingSyscall::Open(PathBuf::from(path.to_string_lossy().as_ref()))),
+            // The dynamic loader maps the whole of an object file from its start before
+            // mapping each segment over it, so the start of that mapping is its base
+            (Some("mmap"), [_, _, _, _, fd, 0, ..]) => Some(PendingSyscall::Map { fd: *fd as i64 }),
+            (Some("close"), [fd, ..]) => Some(PendingSyscall::Close { fd: *fd as i64 }),
+            _ => None,
+        };
+
+        let mut files = lock(&self.files);
+
+        match pending {
+            Some(pending) => files.pending.insert(vcpu_index, pending),
+            None => files.pending.remove(&vcpu_index),
+        };
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// Complete a syscall passed to [`GuestObjects::on_syscall`], adding an object file
+    /// if it was mapped. Returns whether an object file was added.
+    pub(crate) fn on_syscall_return(&self, vcpu_index: VCPUIndex, ret: i64) -> bool {
+        let mut files = lock(&self.files);
+        let succeeded = errno(ret).is_none();
+
+        match files.pending.remove(&vcpu_index) {
+            Some(PendingSyscall::Open(path)) if succeeded => {
+                files.paths.insert(ret, path);
+            }
+            Some(PendingSyscall::Map { fd }) if succeeded => {
+                let Some(path) = files.paths.get(&fd).cloned() else {
+                    return false;
+                };
+
+                if files.not_objects.contains(&path) {
+                    return false;
+                }
+
+                drop(files);
+
+                // NOTE: Files which are not object files are mapped too, and are not
+                // an error
+                match self.object(&path) {
+                    Ok(object) => {
+                        let bias = object.layout().bias_from_base(ret as u64);
+                        self.insert(object, bias);
+                        return true;
+                    }
+                    Err(_) => {
+                        lock(&self.files).not_objects.insert(path);
+                    }
+                }
+            }
+            Some(PendingSyscall::Close { fd }) if succeeded => {
+                files.paths.remove(&fd);
+            }
+            _ => {}
+        }
+
+        false
+    }
+}
@@ -1,126 +1,239 @@
 //! Traits and helpers enabling idiomatic QEMU plugin implementation
 
-use std::sync::{Mutex, OnceLock};
+use std::{
+    ops::{BitOr, BitOrAssign},
+    ptr,
+    sync::{
+        Mutex, OnceLock, PoisonError,
+        atomic::{AtomicPtr, Ordering},
+    },
+};
 
 use crate::{
-    Args, Error, Info, PluginId, Result, TranslationBlock, VCPUIndex,
-    qemu_plugin_register_flush_cb, qemu_plugin_register_vcpu_exit_cb,
+    Args, Error, Info, PluginId, Result, Syscall, TranslationBlock, VCPUIndex, arena,
+    policy::guard, qemu_plugin_register_flush_cb, qemu_plugin_register_vcpu_exit_cb,
     qemu_plugin_register_vcpu_idle_cb, qemu_plugin_register_vcpu_init_cb,
     qemu_plugin_register_vcpu_resume_cb, qemu_plugin_register_vcpu_syscall_cb,
     qemu_plugin_register_vcpu_syscall_ret_cb, qemu_plugin_register_vcpu_tb_trans_cb,
 };
 
-/// Handler for callbacks registered via the `qemu_plugin_register_vcpu_init_cb`
-/// function. These callbacks are called when a vCPU is initialized in QEMU (in softmmu
-/// mode only) and notify us which vCPU index is newly initialized.
-extern "C" fn handle_qemu_plugin_register_vcpu_init_cb(id: PluginId, vcpu_id: VCPUIndex) {
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
-    };
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A set of events a plugin handles. Only the callbacks for events in the set returned by
+/// [`HasCallbacks::callbacks`] (or [`HasCallbacksSync::callbacks`]) are registered with
+/// QEMU, so QEMU does not need to call into the plugin for events it does not handle.
+///
+/// # Example
+///
+/// ```
+/// use qemu_plugin::Callbacks;
+///
+/// let callbacks = Callbacks::VCPU_INIT | Callbacks::VCPU_EXIT;
+///
+/// assert!(callbacks.contains(Callbacks::VCPU_INIT));
+/// assert!(!callbacks.contains(Callbacks::SYSCALL));
+/// assert!(Callbacks::ALL.contains(callbacks));
+/// ```
+pub struct Callbacks(u8);
+
+impl Callbacks {
+    /// No events
+    pub const NONE: Self = Self(0);
+    /// vCPU initialization, handled by `on_vcpu_init`
+    pub const VCPU_INIT: Self = Self(1 << 0);
+    /// vCPU exit, handled by `on_vcpu_exit`
+    pub const VCPU_EXIT: Self = Self(1 << 1);
+    /// vCPU idle, handled by `on_vcpu_idle`
+    pub const VCPU_IDLE: Self = Self(1 << 2);
+    /// vCPU resume, handled by `on_vcpu_resume`
+    pub const VCPU_RESUME: Self = Self(1 << 3);
+    /// Translation block translation, handled by `on_translation_block_translate`
+    pub const TRANSLATION_BLOCK_TRANSLATE: Self = Self(1 << 4);
+    /// Translation block cache flush, handled by `on_flush`
+    pub const FLUSH: Self = Self(1 << 5);
+    /// Syscall, handled by `on_syscall` or `on_syscall_typed`
+    pub const SYSCALL: Self = Self(1 << 6);
+    /// Syscall return, handled by `on_syscall_return`
+    pub const SYSCALL_RETURN: Self = Self(1 << 7);
+    /// Every event
+    pub const ALL: Self = Self(u8::MAX);
+
+    /// Whether every event in `other` is also in this set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The union of this set and `other`
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Whether this set contains no events
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Default for Callbacks {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl BitOr for Callbacks {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        self.union(rhs)
+    }
+}
+
+impl BitOrAssign for Callbacks {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = self.union(rhs);
+    }
+}
+
+/// How the handlers registered with QEMU reach the global plugin. The handlers are
+/// generic over it, so plugins registered with `register!(plugin)` and with
+/// `register!(sync plugin)` share them. Each method runs the plugin's callback of the
+/// same name.
+trait Dispatch: 'static {
+    fn on_vcpu_init(id: PluginId, vcpu_id: VCPUIndex) -> Result<()>;
+    fn on_vcpu_exit(id: PluginId, vcpu_id: VCPUIndex) -> Result<()>;
+    fn on_vcpu_idle(id: PluginId, vcpu_id: VCPUIndex) -> Result<()>;
+    fn on_vcpu_resume(id: PluginId, vcpu_id: VCPUIndex) -> Result<()>;
+    fn on_translation_block_translate(id: PluginId, tb: TranslationBlock) -> Result<()>;
+    fn on_flush(id: PluginId) -> Result<()>;
+    fn on_syscall(id: PluginId, vcpu_index: VCPUIndex, num: i64, args: [u64; 8]) -> Result<()>;
+    fn on_syscall_return(id: PluginId, vcpu_index: VCPUIndex, num: i64, ret: i64) -> Result<()>;
+}
 
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
+/// Dispatches to the plugin registered with `register!(plugin)`, locking it for each
+/// callback
+struct Locked;
+
+/// Dispatches to the plugin registered with `register!(sync plugin)`, without a lock
+struct LockFree;
+
+/// Implement [`Dispatch`] by running each callback on the plugin passed to `$with`
+macro_rules! impl_dispatch {
+    ($dispatch:ty, $with:ident) => {
+        impl Dispatch for $dispatch {
+            fn on_vcpu_init(id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+                $with(|plugin| plugin.on_vcpu_init(id, vcpu_id))
+            }
+
+            fn on_vcpu_exit(id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+                $with(|plugin| plugin.on_vcpu_exit(id, vcpu_id))
+            }
+
+            fn on_vcpu_idle(id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+                $with(|plugin| plugin.on_vcpu_idle(id, vcpu_id))
+            }
+
+            fn on_vcpu_resume(id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+                $with(|plugin| plugin.on_vcpu_resume(id, vcpu_id))
+            }
+
+            fn on_translation_block_translate(id: PluginId, tb: TranslationBlock) -> Result<()> {
+                $with(|plugin| plugin.on_translation_block_translate(id, tb))
+            }
+
+            fn on_flush(id: PluginId) -> Result<()> {
+                $with(|plugin| plugin.on_flush(id))
+            }
+
+            fn on_syscall(
+                id: PluginId,
+                vcpu_index: VCPUIndex,
+                num: i64,
+                [a1, a2, a3, a4, a5, a6, a7, a8]: [u64; 8],
+            ) -> Result<()> {
+                $with(|plugin| {
+                    plugin.on_syscall(id, vcpu_index, num, a1, a2, a3, a4, a5, a6, a7, a8)
+                })
+            }
+
+            fn on_syscall_return(
+                id: PluginId,
+                vcpu_index: VCPUIndex,
+                num: i64,
+                ret: i64,
+            ) -> Result<()> {
+                $with(|plugin| plugin.on_syscall_return(id, vcpu_index, num, ret))
+            }
+        }
     };
+}
+
+impl_dispatch!(Locked, with_plugin);
+impl_dispatch!(LockFree, with_plugin_sync);
 
-    plugin
-        .on_vcpu_init(id, vcpu_id)
-        .expect("Failed running callback on_vcpu_init");
+/// Handler for callbacks registered via the `qemu_plugin_register_vcpu_init_cb`
+/// function. These callbacks are called when a vCPU is initialized in QEMU (in softmmu
+/// mode only) and notify us which vCPU index is newly initialized.
+extern "C" fn handle_qemu_plugin_register_vcpu_init_cb<D: Dispatch>(
+    id: PluginId,
+    vcpu_id: VCPUIndex,
+) {
+    guard("on_vcpu_init", || D::on_vcpu_init(id, vcpu_id));
 }
 
 /// Handler for callbacks registered via the `qemu_plugin_register_vcpu_exit_cb`
 /// function. These callbacks are called when a vCPU exits in QEMU (in softmmu mode
 /// only) and notify us which vCPU index is exiting.
-extern "C" fn handle_qemu_plugin_register_vcpu_exit_cb(id: PluginId, vcpu_id: VCPUIndex) {
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
-    };
-
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
-    };
-
-    plugin
-        .on_vcpu_exit(id, vcpu_id)
-        .expect("Failed running callback on_vcpu_exit");
+extern "C" fn handle_qemu_plugin_register_vcpu_exit_cb<D: Dispatch>(
+    id: PluginId,
+    vcpu_id: VCPUIndex,
+) {
+    guard("on_vcpu_exit", || D::on_vcpu_exit(id, vcpu_id));
 }
 
 /// Handler for callbacks registered via the `qemu_plugin_register_vcpu_idle_cb`
 /// function. These callbacks are called when a vCPU goes idle in QEMU (in softmmu mode
 /// only) and notify us which vCPU index is going idle.
-extern "C" fn handle_qemu_plugin_register_vcpu_idle_cb(id: PluginId, vcpu_id: VCPUIndex) {
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
-    };
-
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
-    };
-
-    plugin
-        .on_vcpu_idle(id, vcpu_id)
-        .expect("Failed running callback on_vcpu_idle");
+extern "C" fn handle_qemu_plugin_register_vcpu_idle_cb<D: Dispatch>(
+    id: PluginId,
+    vcpu_id: VCPUIndex,
+) {
+    guard("on_vcpu_idle", || D::on_vcpu_idle(id, vcpu_id));
 }
 
 /// Handler for callbacks registered via the `qemu_plugin_register_vcpu_resume_cb`
 /// function. These callbacks are called when a vCPU resumes in QEMU (in softmmu mode
 /// only) and notify us which vCPU index is resuming.
-extern "C" fn handle_qemu_plugin_register_vcpu_resume_cb(id: PluginId, vcpu_id: VCPUIndex) {
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
-    };
-
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
-    };
-
-    plugin
-        .on_vcpu_resume(id, vcpu_id)
-        .expect("Failed running callback on_vcpu_resume");
+extern "C" fn handle_qemu_plugin_register_vcpu_resume_cb<D: Dispatch>(
+    id: PluginId,
+    vcpu_id: VCPUIndex,
+) {
+    guard("on_vcpu_resume", || D::on_vcpu_resume(id, vcpu_id));
 }
 
 /// Handler for callbacks registered via the `qemu_plugin_register_vcpu_tb_trans_cb`
 /// function. These callbacks are called when a translation block is translated in QEMU
 /// and pass an opaque pointer to the translation block.
-extern "C" fn handle_qemu_plugin_register_vcpu_tb_trans_cb(
+extern "C" fn handle_qemu_plugin_register_vcpu_tb_trans_cb<D: Dispatch>(
     id: PluginId,
     tb: *mut crate::sys::qemu_plugin_tb,
 ) {
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
-    };
-
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
-    };
-
-    let tb = TranslationBlock::from(tb);
-
-    plugin
-        .on_translation_block_translate(id, tb)
-        .expect("Failed running callback on_translation_block_translate");
+    guard("on_translation_block_translate", || {
+        D::on_translation_block_translate(id, TranslationBlock::from(tb))
+    });
 }
 
 /// Handler for callbacks registered via the `qemu_plugin_register_flush_cb`
 /// function. These callbacks are called when QEMU flushes all TBs, which is
 /// roughly equivalent to a TLB flush to invalidate all cached instructions.
-extern "C" fn handle_qemu_plugin_register_flush_cb(id: PluginId) {
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
-    };
+extern "C" fn handle_qemu_plugin_register_flush_cb<D: Dispatch>(id: PluginId) {
+    arena::reclaim();
 
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
-    };
-
-    plugin
-        .on_flush(id)
-        .expect("Failed running callback on_flush");
+    guard("on_flush", || D::on_flush(id));
 }
 
 /// Handler for callbacks registered via the `qemu_plugin_register_vcpu_syscall_cb`
 /// function. These callbacks are called when a syscall is made in QEMU and pass the
 /// syscall number and its arguments.
-extern "C" fn handle_qemu_plugin_register_syscall_cb(
+extern "C" fn handle_qemu_plugin_register_syscall_cb<D: Dispatch>(
     id: PluginId,
     vcpu_index: VCPUIndex,
     num: i64,
@@ -133,39 +246,83 @@ extern "C" fn handle_qemu_plugin_register_syscall_cb(
     a7: u64,
     a8: u64,
 ) {
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
-    };
-
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
-    };
-
-    plugin
-        .on_syscall(id, vcpu_index, num, a1, a2, a3, a4, a5, a6, a7, a8)
-        .expect("Failed running callback on_syscall");
+    guard("on_syscall", || {
+        D::on_syscall(id, vcpu_index, num, [a1, a2, a3, a4, a5, a6, a7, a8])
+    });
 }
 
 /// Handler for callbacks registered via the `qemu_plugin_register_vcpu_syscall_ret_cb`
 /// function. These callbacks are called when a syscall returns in QEMU and pass the
 /// syscall number and its return value.
-extern "C" fn handle_qemu_plugin_register_syscall_ret_cb(
+extern "C" fn handle_qemu_plugin_register_syscall_ret_cb<D: Dispatch>(
     id: PluginId,
     vcpu_index: VCPUIndex,
     num: i64,
     ret: i64,
 ) {
-    let Some(plugin) = PLUGIN.get() else {
-        panic!("Plugin not set");
-    };
+    guard("on_syscall_return", || {
+        D::on_syscall_return(id, vcpu_index, num, ret)
+    });
+}
 
-    let Ok(mut plugin) = plugin.lock() else {
-        panic!("Failed to lock plugin");
-    };
+/// Register the handlers for `callbacks` which dispatch to the global plugin through `D`
+fn register_handlers<D: Dispatch>(id: PluginId, callbacks: Callbacks) -> Result<()> {
+    if callbacks.contains(Callbacks::VCPU_INIT) {
+        qemu_plugin_register_vcpu_init_cb(id, Some(handle_qemu_plugin_register_vcpu_init_cb::<D>))?;
+    }
 
-    plugin
-        .on_syscall_return(id, vcpu_index, num, ret)
-        .expect("Failed running callback on_syscall_return");
+    if callbacks.contains(Callbacks::VCPU_EXIT) {
+        qemu_plugin_register_vcpu_exit_cb(id, Some(handle_qemu_plugin_register_vcpu_exit_cb::<D>))?;
+    }
+
+    if callbacks.contains(Callbacks::VCPU_IDLE) {
+        qemu_plugin_register_vcpu_idle_cb(id, Some(handle_qemu_plugin_register_vcpu_idle_cb::<D>))?;
+    }
+
+    if callbacks.contains(Callbacks::VCPU_RESUME) {
+        qemu_plugin_register_vcpu_resume_cb(
+            id,
+            Some(handle_qemu_plugin_register_vcpu_resume_cb::<D>),
+        )?;
+    }
+
+    if callbacks.contains(Callbacks::TRANSLATION_BLOCK_TRANSLATE) {
+        qemu_plugin_register_vcpu_tb_trans_cb(
+            id,
+            Some(handle_qemu_plugin_register_vcpu_tb_trans_cb::<D>),
+        )?;
+    }
+
+    if callbacks.contains(Callbacks::FLUSH) {
+        qemu_plugin_register_flush_cb(id, Some(handle_qemu_plugin_register_flush_cb::<D>));
+    } else {
+        // NOTE: Flushes are always observed, to reclaim the closures registered on
+        // the flushed translation blocks
+        qemu_plugin_register_flush_cb(id, Some(arena::handle_qemu_plugin_flush_reclaim_cb));
+    }
+
+    if callbacks.contains(Callbacks::SYSCALL) {
+        qemu_plugin_register_vcpu_syscall_cb(id, Some(handle_qemu_plugin_register_syscall_cb::<D>));
+    }
+
+    if callbacks.contains(Callbacks::SYSCALL_RETURN) {
+        qemu_plugin_register_vcpu_syscall_ret_cb(
+            id,
+            Some(handle_qemu_plugin_register_syscall_ret_cb::<D>),
+        );
+    }
+
+    Ok(())
+}
+
+/// Register the handlers for `callbacks` which dispatch to the global plugin, whichever
+/// way it was registered
+pub(crate) fn register_callbacks(id: PluginId, callbacks: Callbacks) -> Result<()> {
+    if plugin_sync().is_some() {
+        register_handlers::<LockFree>(id, callbacks)
+    } else {
+        register_handlers::<Locked>(id, callbacks)
+    }
 }
 
 /// Trait which implemenents registering the callbacks implemented on a struct which
@@ -176,8 +333,10 @@ extern "C" fn handle_qemu_plugin_register_syscall_ret_cb(
 /// Using default registration, you can simply declare an empty `impl` block for
 /// `Register` on your type. Then, on plugin load, any callbacks you implement in
 /// `HasCallbacks` will be automatically registered with QEMU. Callback events you don't
-/// implement will default to no-ops. The only drawback of this approach is a small
-/// performance penalty for events even when there is no callback registered for them.
+/// implement will default to no-ops. Callbacks are registered for the events returned by
+/// [`HasCallbacks::callbacks`], which is every event unless you override it or use the
+/// `#[callbacks]` attribute (with the `derive` feature), and QEMU calls into the plugin for
+/// each event registered even when its callback is a no-op.
 ///
 /// ```
 /// struct MyPlugin;
@@ -208,10 +367,11 @@ extern "C" fn handle_qemu_plugin_register_syscall_ret_cb(
 /// ```
 ///
 /// Finally, if you want to override the default registration behavior, you can
-/// implement `register_default` yourself. This allows you to circumvent any minor
-/// performance penalties.
+/// implement `register_default` yourself, for example to register your own callback
+/// handlers instead of those dispatching to `HasCallbacks`.
 ///
 /// ```
+/// # use qemu_plugin::Result;
 /// struct MyPlugin;
 ///
 /// impl qemu_plugin::plugin::HasCallbacks for MyPlugin {}
@@ -235,27 +395,7 @@ pub trait Register: HasCallbacks + Send + Sync + 'static {
     /// default callbacks are desired, and will require re-implementing handlers which is not
     /// recommended.
     fn register_default(&mut self, id: PluginId, args: &Args, info: &Info) -> Result<()> {
-        qemu_plugin_register_vcpu_init_cb(id, Some(handle_qemu_plugin_register_vcpu_init_cb))?;
-
-        qemu_plugin_register_vcpu_exit_cb(id, Some(handle_qemu_plugin_register_vcpu_exit_cb))?;
-
-        qemu_plugin_register_vcpu_idle_cb(id, Some(handle_qemu_plugin_register_vcpu_idle_cb))?;
-
-        qemu_plugin_register_vcpu_resume_cb(id, Some(handle_qemu_plugin_register_vcpu_resume_cb))?;
-
-        qemu_plugin_register_vcpu_tb_trans_cb(
-            id,
-            Some(handle_qemu_plugin_register_vcpu_tb_trans_cb),
-        )?;
-
-        qemu_plugin_register_flush_cb(id, Some(handle_qemu_plugin_register_flush_cb));
-
-        qemu_plugin_register_vcpu_syscall_cb(id, Some(handle_qemu_plugin_register_syscall_cb));
-
-        qemu_plugin_register_vcpu_syscall_ret_cb(
-            id,
-            Some(handle_qemu_plugin_register_syscall_ret_cb),
-        );
+        register_handlers::<Locked>(id, self.callbacks())?;
 
         self.register(id, args, info)?;
 
@@ -288,6 +428,15 @@ pub trait Register: HasCallbacks + Send + Sync + 'static {
 /// impl qemu_plugin::plugin::Register for MyPlugin {}
 /// ```
 pub trait HasCallbacks: Send + Sync + 'static {
+    /// The events this plugin handles. Callbacks are only registered with QEMU for these
+    /// events, and by default they are registered for every event. Plugins which override
+    /// only some of the callbacks should return just those events, or use the
+    /// `#[callbacks]` attribute (with the `derive` feature) on their implementation of
+    /// this trait to generate this method.
+    fn callbacks(&self) -> Callbacks {
+        Callbacks::ALL
+    }
+
     #[allow(unused)]
     /// Callback triggered on vCPU init
     ///
@@ -398,6 +547,29 @@ pub trait HasCallbacks: Send + Sync + 'static {
         a6: u64,
         a7: u64,
         a8: u64,
+    ) -> Result<()> {
+        self.on_syscall_typed(
+            id,
+            vcpu_index,
+            Syscall::new(num, [a1, a2, a3, a4, a5, a6, a7, a8]),
+        )
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on syscall with a typed view of the syscall, which names it and
+    /// renders its arguments. This is called by the default implementation of
+    /// [`Self::on_syscall`], so it is not called if `on_syscall` is overridden.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `vcpu_index` - The ID of the vCPU
+    /// * `syscall` - The syscall
+    fn on_syscall_typed(
+        &mut self,
+        id: PluginId,
+        vcpu_index: VCPUIndex,
+        syscall: Syscall,
     ) -> Result<()> {
         Ok(())
     }
@@ -422,31 +594,335 @@ pub trait HasCallbacks: Send + Sync + 'static {
     }
 }
 
+/// Trait which implements registering the callbacks implemented on a struct which
+/// `HasCallbacksSync` with QEMU. This is the counterpart of [`Register`] for plugins
+/// registered with `register!(sync ...)`. Because callbacks may run concurrently as soon
+/// as the plugin is registered, registration also takes `&self`.
+pub trait RegisterSync: HasCallbacksSync + Send + Sync + 'static {
+    #[allow(unused)]
+    /// Called by QEMU when registering the plugin. This method should only be overridden if no
+    /// default callbacks are desired, and will require re-implementing handlers which is not
+    /// recommended.
+    fn register_default(&self, id: PluginId, args: &Args, info: &Info) -> Result<()> {
+        register_handlers::<LockFree>(id, self.callbacks())?;
+
+        self.register(id, args, info)?;
+
+        Ok(())
+    }
+
+    #[allow(unused)]
+    /// Called when registering the plugin. User definition of on-registration behavior should
+    /// be implemented here.
+    fn register(&self, id: PluginId, args: &Args, info: &Info) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Trait implemented by structs which have callbacks which should be registered with QEMU,
+/// and which can handle callbacks from many vCPU threads at once. Unlike
+/// [`HasCallbacks`], callbacks take `&self` and are dispatched without any global lock, so
+/// under multi-threaded TCG each vCPU thread runs its callbacks in parallel. State shared
+/// between callbacks must use interior mutability, for example atomics, or locks sharded
+/// by vCPU index.
+///
+/// Plugins implementing this trait are registered with `register!(sync ...)`.
+///
+/// # Example
+///
+/// ```
+/// use qemu_plugin::{HasCallbacksSync, PluginId, RegisterSync, Result, TranslationBlock};
+/// use std::sync::atomic::{AtomicU64, Ordering};
+///
+/// #[derive(Default)]
+/// struct CountBlocks {
+///     translated: AtomicU64,
+/// }
+///
+/// impl RegisterSync for CountBlocks {}
+///
+/// impl HasCallbacksSync for CountBlocks {
+///     fn on_translation_block_translate(&self, _: PluginId, _: TranslationBlock) -> Result<()> {
+///         self.translated.fetch_add(1, Ordering::Relaxed);
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait HasCallbacksSync: Send + Sync + 'static {
+    /// The events this plugin handles. Callbacks are only registered with QEMU for these
+    /// events, and by default they are registered for every event. Plugins which override
+    /// only some of the callbacks should return just those events, or use the
+    /// `#[callbacks]` attribute (with the `derive` feature) on their implementation of
+    /// this trait to generate this method.
+    fn callbacks(&self) -> Callbacks {
+        Callbacks::ALL
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on vCPU init
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `vcpu_id` - The ID of the vCPU
+    fn on_vcpu_init(&self, id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+        Ok(())
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on vCPU exit
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `vcpu_id` - The ID of the vCPU
+    fn on_vcpu_exit(&self, id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+        Ok(())
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on vCPU idle
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `vcpu_id` - The ID of the vCPU
+    fn on_vcpu_idle(&self, id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+        Ok(())
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on vCPU resume
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `vcpu_id` - The ID of the vCPU
+    fn on_vcpu_resume(&self, id: PluginId, vcpu_id: VCPUIndex) -> Result<()> {
+        Ok(())
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on translation block translation
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `tb` - The translation block
+    fn on_translation_block_translate(&self, id: PluginId, tb: TranslationBlock) -> Result<()> {
+        Ok(())
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on flush
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    fn on_flush(&self, id: PluginId) -> Result<()> {
+        Ok(())
+    }
+
+    #[allow(unused, clippy::too_many_arguments)]
+    /// Callback triggered on syscall
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `vcpu_index` - The ID of the vCPU
+    /// * `num` - The syscall number
+    /// * `a1` - The first syscall argument
+    /// * `a2` - The second syscall argument
+    /// * `a3` - The third syscall argument
+    /// * `a4` - The fourth syscall argument
+    /// * `a5` - The fifth syscall argument
+    /// * `a6` - The sixth syscall argument
+    /// * `a7` - The seventh syscall argument
+    /// * `a8` - The eighth syscall argument
+    fn on_syscall(
+        &self,
+        id: PluginId,
+        vcpu_index: VCPUIndex,
+        num: i64,
+        a1: u64,
+        a2: u64,
+        a3: u64,
+        a4: u64,
+        a5: u64,
+        a6: u64,
+        a7: u64,
+        a8: u64,
+    ) -> Result<()> {
+        self.on_syscall_typed(
+            id,
+            vcpu_index,
+            Syscall::new(num, [a1, a2, a3, a4, a5, a6, a7, a8]),
+        )
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on syscall with a typed view of the syscall, which names it and
+    /// renders its arguments. This is called by the default implementation of
+    /// [`Self::on_syscall`], so it is not called if `on_syscall` is overridden.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `vcpu_index` - The ID of the vCPU
+    /// * `syscall` - The syscall
+    fn on_syscall_typed(
+        &self,
+        id: PluginId,
+        vcpu_index: VCPUIndex,
+        syscall: Syscall,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    #[allow(unused)]
+    /// Callback triggered on syscall return
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the plugin
+    /// * `vcpu_index` - The ID of the vCPU
+    /// * `num` - The syscall number
+    /// * `ret` - The return value of the syscall
+    fn on_syscall_return(
+        &self,
+        id: PluginId,
+        vcpu_index: VCPUIndex,
+        num: i64,
+        ret: i64,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
 /// Trait implemented by structs which are QEMU plugin contexts
 pub trait Plugin: Register + HasCallbacks {}
 
 impl<T> Plugin for T where T: Register + HasCallbacks {}
 
+/// Trait implemented by structs which are QEMU plugin contexts dispatched without a lock
+pub trait PluginSync: RegisterSync + HasCallbacksSync {}
+
+impl<T> PluginSync for T where T: RegisterSync + HasCallbacksSync {}
+
 #[doc(hidden)]
 /// The global plugin item
 pub static PLUGIN: OnceLock<Mutex<Box<dyn Plugin>>> = OnceLock::new();
 
+/// Run `f` with the global plugin locked. A lock poisoned by a panic in an earlier
+/// callback is recovered, because the panic has already been handled by the error policy.
+pub(crate) fn with_plugin<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce(&mut dyn Plugin) -> Result<T>,
+{
+    let plugin = PLUGIN.get().ok_or(Error::PluginNotRegistered)?;
+    let mut plugin = plugin.lock().unwrap_or_else(PoisonError::into_inner);
+
+    f(plugin.as_mut())
+}
+
+#[doc(hidden)]
+/// The global plugin item for plugins registered with `register!(sync ...)`
+pub static PLUGIN_SYNC: AtomicPtr<Box<dyn PluginSync>> = AtomicPtr::new(ptr::null_mut());
+
+/// The global lock-free plugin, if the plugin was registered with `register!(sync ...)`
+fn plugin_sync() -> Option<&'static dyn PluginSync> {
+    let plugin = PLUGIN_SYNC.load(Ordering::Acquire);
+
+    // NOTE: Items are only freed by a test host between sessions, see `set_plugin_sync`
+    (!plugin.is_null()).then(|| unsafe { (*plugin).as_ref() })
+}
+
+/// Run `f` with the global lock-free plugin
+fn with_plugin_sync<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce(&dyn PluginSync) -> Result<T>,
+{
+    f(plugin_sync().ok_or(Error::PluginNotRegistered)?)
+}
+
+/// Register the global plugin with QEMU, whichever way it was registered
+pub(crate) fn register_default(id: PluginId, args: &Args, info: &Info) -> Result<()> {
+    if let Some(plugin) = plugin_sync() {
+        plugin.register_default(id, args, info)
+    } else {
+        with_plugin(|plugin| plugin.register_default(id, args, info))
+    }
+}
+
 #[doc(hidden)]
 #[inline(never)]
-pub fn register_plugin(plugin: impl Plugin) {
+pub fn register_plugin<P>(plugin: P)
+where
+    P: Plugin + 'static,
+{
     PLUGIN
         .set(Mutex::new(Box::new(plugin)))
         .map_err(|_| Error::PluginInstanceSetError)
         .expect("Failed to set plugin");
 }
 
+#[doc(hidden)]
+#[inline(never)]
+pub fn register_plugin_sync<P>(plugin: P)
+where
+    P: PluginSync + 'static,
+{
+    PLUGIN_SYNC
+        .compare_exchange(
+            ptr::null_mut(),
+            Box::into_raw(Box::new(Box::new(plugin) as Box<dyn PluginSync>)),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .map_err(|_| Error::PluginInstanceSetError)
+        .expect("Failed to set plugin");
+}
+
+#[cfg(feature = "mock")]
+/// Replace the global plugin item with `plugin`, or set it if no plugin was registered
+pub(crate) fn set_plugin(plugin: Box<dyn Plugin>) {
+    if let Err(plugin) = PLUGIN.set(Mutex::new(plugin)) {
+        let plugin = plugin.into_inner().unwrap_or_else(PoisonError::into_inner);
+        *PLUGIN
+            .get()
+            .expect("Plugin is set")
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = plugin;
+    }
+}
+
+#[cfg(feature = "mock")]
+/// Replace the global plugin item for plugins registered with `register!(sync ...)` with
+/// `plugin`, dropping the item it replaces
+///
+/// # Safety
+///
+/// No callback of the plugin may be running, or run again before the plugin is installed.
+pub(crate) unsafe fn set_plugin_sync(plugin: Box<dyn PluginSync>) {
+    let old = PLUGIN_SYNC.swap(Box::into_raw(Box::new(plugin)), Ordering::AcqRel);
+
+    if !old.is_null() {
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
 #[macro_export]
-/// Register a plugin
+/// Register a plugin. Plugins implementing [`Register`] and [`HasCallbacks`] are
+/// registered with `register!(plugin)`, and plugins implementing [`RegisterSync`] and
+/// [`HasCallbacksSync`] with `register!(sync plugin)`.
 macro_rules! register {
-    ($plugin:expr) => {
+    (sync $plugin:expr) => {
+        $crate::register!(@ctor $crate::plugin::register_plugin_sync, $plugin);
+    };
+    (@ctor $register:path, $plugin:expr) => {
         #[cfg_attr(target_os = "linux", unsafe(link_section = ".text.startup"))]
         extern "C" fn __plugin_ctor() {
-            $crate::plugin::register_plugin($plugin);
+            $register($plugin);
         }
 
         #[used]
@@ -463,4 +939,7 @@ macro_rules! register {
         #[cfg_attr(windows, unsafe(link_section = ".CRT$XCU"))]
         static __PLUGIN_CTOR: unsafe extern "C" fn() = __plugin_ctor;
     };
+    ($plugin:expr) => {
+        $crate::register!(@ctor $crate::plugin::register_plugin, $plugin);
+    };
 }
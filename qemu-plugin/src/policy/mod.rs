@@ -0,0 +1,127 @@
+//! Containment of errors and panics raised by plugin callbacks
+//!
+//! Callbacks run on QEMU's threads, called through `extern "C"` trampolines, so an error
+//! or panic in a callback can not be propagated back to the caller. Instead, every
+//! trampoline catches errors and panics and handles them according to the plugin's
+//! [`ErrorPolicy`], which can be changed at any time with [`set_error_policy`].
+//!
+//! # Example
+//!
+//! ```
+//! # use qemu_plugin::Result;
+//! use qemu_plugin::{ErrorPolicy, HasCallbacks, Info, PluginId, Register, install::Args};
+//!
+//! struct MyPlugin;
+//!
+//! impl Register for MyPlugin {
+//!     fn register(&mut self, _: PluginId, _: &Args, _: &Info) -> Result<()> {
+//!         // A failing callback means the results would be incomplete, so stop
+//!         // instrumenting instead of continuing
+//!         qemu_plugin::set_error_policy(ErrorPolicy::Uninstall);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! impl HasCallbacks for MyPlugin {}
+//! ```
+
+use crate::{PluginId, Result, qemu_plugin_outs, qemu_plugin_uninstall};
+use std::{
+    any::Any,
+    panic::{AssertUnwindSafe, catch_unwind},
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicU8, Ordering},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+/// What to do when a callback returns an error or panics
+pub enum ErrorPolicy {
+    #[default]
+    /// Log the error with `qemu_plugin_outs` and continue running. This is the default.
+    Log,
+    /// Log the error with `qemu_plugin_outs`, then uninstall the plugin. QEMU keeps
+    /// running without the plugin, and callbacks may still run until the uninstallation
+    /// completes.
+    Uninstall,
+    /// Print a report of the error to standard error, then abort QEMU
+    Abort,
+}
+
+/// The current error policy
+static ERROR_POLICY: AtomicU8 = AtomicU8::new(ErrorPolicy::Log as u8);
+
+/// The ID of the plugin, set when it is installed
+static PLUGIN_ID: Mutex<Option<PluginId>> = Mutex::new(None);
+
+/// Set the policy for handling errors and panics in callbacks
+pub fn set_error_policy(policy: ErrorPolicy) {
+    ERROR_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// The policy for handling errors and panics in callbacks
+pub fn error_policy() -> ErrorPolicy {
+    match ERROR_POLICY.load(Ordering::Relaxed) {
+        1 => ErrorPolicy::Uninstall,
+        2 => ErrorPolicy::Abort,
+        _ => ErrorPolicy::Log,
+    }
+}
+
+/// Record the ID of the plugin, which is needed to uninstall it
+pub(crate) fn set_plugin_id(id: PluginId) {
+    *PLUGIN_ID.lock().unwrap_or_else(PoisonError::into_inner) = Some(id);
+}
+
+/// The ID of the plugin, if it has been installed
+pub(crate) fn plugin_id() -> Option<PluginId> {
+    *PLUGIN_ID.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Run a callback, catching any error it returns or panic it raises and handling it
+/// according to the error policy. `context` names the callback in the report.
+pub(crate) fn guard<F>(context: &str, f: F)
+where
+    F: FnOnce() -> Result<()>,
+{
+    let message = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) => format!("error in {context}: {e}"),
+        Err(payload) => format!("panic in {context}: {}", panic_message(&*payload)),
+    };
+
+    // NOTE: Reporting must not panic either, so failures to log are ignored
+    match error_policy() {
+        ErrorPolicy::Log => {
+            let _ = qemu_plugin_outs(format!("{message}\n"));
+        }
+        ErrorPolicy::Uninstall => {
+            let _ = qemu_plugin_outs(format!("{message}, uninstalling plugin\n"));
+
+            if let Some(id) = plugin_id() {
+                // NOTE: This fails if the plugin is already being uninstalled, which is
+                // fine because that is all we want
+                let _ = qemu_plugin_uninstall(id, |_| {});
+            }
+        }
+        ErrorPolicy::Abort => {
+            eprintln!(
+                "qemu-plugin: fatal {message}\nqemu-plugin: aborting due to ErrorPolicy::Abort"
+            );
+            std::process::abort();
+        }
+    }
+}
+
+/// The message of a panic payload, if it is a string
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
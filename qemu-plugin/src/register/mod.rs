@@ -1,8 +1,9 @@
 //! Register-related functionality for QEMU plugins
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
 use crate::{
-    Error, Result,
+    Alias, Arch, Endian, Error, Result, Target, qemu_plugin_get_registers,
     sys::{qemu_plugin_read_register, qemu_plugin_reg_descriptor, qemu_plugin_register},
+    with_scratch_byte_array,
 };
 #[cfg(all(
     not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")),
@@ -11,6 +12,7 @@ use crate::{
 use num_traits::{FromBytes, PrimInt};
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
 use std::{
+    collections::HashMap,
     ffi::CStr,
     fmt::{Debug, Formatter},
     marker::PhantomData,
@@ -84,41 +86,92 @@ impl<'a> Debug for RegisterDescriptor<'a> {
 
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
 impl<'a> RegisterDescriptor<'a> {
+    /// Call `f` with the value of the register, read into this thread's scratch
+    /// `GByteArray` without allocating
+    fn with_value<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        with_scratch_byte_array(|byte_array| {
+            let result = unsafe {
+                qemu_plugin_read_register(self.handle as *mut qemu_plugin_register, byte_array)
+            };
+
+            if result == -1 {
+                return Err(Error::RegisterReadError {
+                    name: self.name.clone(),
+                });
+            }
+
+            let data = unsafe { (*byte_array).data };
+            let len = unsafe { (*byte_array).len } as usize;
+
+            Ok(f(if len == 0 {
+                &[]
+            } else {
+                unsafe { std::slice::from_raw_parts(data, len) }
+            }))
+        })
+    }
+
     /// Read a register value
     ///
     /// This must only be called in a callback which has been registered with
     /// `CallbackFlags::QEMU_PLUGIN_CB_R_REGS` or
     /// `CallbackFlags::QEMU_PLUGIN_CB_RW_REGS`, otherwise it will fail.
     pub fn read(&self) -> Result<Vec<u8>> {
-        use crate::g_byte_array_free;
+        self.with_value(|value| value.to_vec())
+    }
 
-        let byte_array = unsafe {
-            use crate::g_byte_array_new;
-            g_byte_array_new()
-        };
+    /// Read a register value into `buf` without allocating, returning the size of the
+    /// register in bytes. Bytes of `buf` past the size of the register are left
+    /// unchanged.
+    ///
+    /// This must only be called in a callback which has been registered with
+    /// `CallbackFlags::QEMU_PLUGIN_CB_R_REGS` or
+    /// `CallbackFlags::QEMU_PLUGIN_CB_RW_REGS`, otherwise it will fail.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::InvalidRegisterReadSize`] if `buf` is smaller than the
+    /// register.
+    pub fn read_into(&self, buf: &mut [u8]) -> Result<usize> {
+        self.with_value(|value| {
+            buf.get_mut(..value.len())
+                .map(|buf| {
+                    buf.copy_from_slice(value);
+                    value.len()
+                })
+                .ok_or_else(|| Error::InvalidRegisterReadSize {
+                    name: self.name.clone(),
+                    size: value.len(),
+                })
+        })?
+    }
 
-        let result = unsafe {
-            qemu_plugin_read_register(self.handle as *mut qemu_plugin_register, byte_array)
-        };
+    /// Read the values of `registers` into `values`, replacing its contents. Each register
+    /// is read into this thread's scratch `GByteArray` and appended to the buffers of
+    /// `values`, so no allocation is made once they have grown to fit the registers.
+    ///
+    /// This must only be called in a callback which has been registered with
+    /// `CallbackFlags::QEMU_PLUGIN_CB_R_REGS` or
+    /// `CallbackFlags::QEMU_PLUGIN_CB_RW_REGS`, otherwise it will fail.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of the first register which can not be read. `values` then
+    /// holds the values of the registers before it.
+    pub fn read_many<'b, I>(registers: I, values: &mut RegisterValues) -> Result<()>
+    where
+        'a: 'b,
+        I: IntoIterator<Item = &'b RegisterDescriptor<'a>>,
+    {
+        values.data.clear();
+        values.ends.clear();
 
-        if result == -1 {
-            return Err(Error::RegisterReadError {
-                name: self.name.clone(),
-            });
+        for register in registers {
+            register.with_value(|value| values.data.extend_from_slice(value))?;
+            values.ends.push(values.data.len());
         }
 
-        let mut data = Vec::new();
-        data.extend_from_slice(unsafe {
-            std::slice::from_raw_parts((*byte_array).data, (*byte_array).len as usize)
-        });
-
-        assert_eq!(
-            unsafe { g_byte_array_free(byte_array, true) },
-            std::ptr::null_mut(),
-            "g_byte_array_free must return NULL"
-        );
-
-        Ok(data)
+        Ok(())
     }
 
     #[cfg(not(any(
@@ -159,9 +212,16 @@ impl<'a> RegisterDescriptor<'a> {
         T: PrimInt + FromBytes + Sized,
         T: FromBytes<Bytes = [u8; std::mem::size_of::<T>()]>,
     {
-        let data = self.read()?;
         let mut bytes = [0; std::mem::size_of::<T>()];
-        bytes.copy_from_slice(&data);
+        let size = self.read_into(&mut bytes)?;
+
+        if size != bytes.len() {
+            return Err(Error::InvalidRegisterReadSize {
+                name: self.name.clone(),
+                size,
+            });
+        }
+
         Ok(T::from_be_bytes(&bytes))
     }
 
@@ -176,9 +236,287 @@ impl<'a> RegisterDescriptor<'a> {
         T: PrimInt + FromBytes + Sized,
         T: FromBytes<Bytes = [u8; std::mem::size_of::<T>()]>,
     {
-        let data = self.read()?;
         let mut bytes = [0; std::mem::size_of::<T>()];
-        bytes.copy_from_slice(&data);
+        let size = self.read_into(&mut bytes)?;
+
+        if size != bytes.len() {
+            return Err(Error::InvalidRegisterReadSize {
+                name: self.name.clone(),
+                size,
+            });
+        }
+
         Ok(T::from_le_bytes(&bytes))
     }
 }
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// The values of several registers, read together by [`RegisterDescriptor::read_many`].
+/// The buffers are reused by each read, so reading into the same `RegisterValues`
+/// repeatedly does not allocate once they have grown to fit the registers.
+pub struct RegisterValues {
+    /// The values of every register, concatenated
+    data: Vec<u8>,
+    /// The end of each register's value in `data`
+    ends: Vec<usize>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl RegisterValues {
+    /// Create an empty set of register values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of register values
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// Whether there are no register values
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// The value of the register at `index`, in the order the registers were read
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        let end = *self.ends.get(index)?;
+        let start = index.checked_sub(1).map_or(0, |i| self.ends[i]);
+        Some(&self.data[start..end])
+    }
+
+    /// Iterate over the register values, in the order the registers were read
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.len()).filter_map(|index| self.get(index))
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[derive(Debug, Clone)]
+/// The registers of the current vCPU, keyed by name
+///
+/// A register file can look registers up by the name QEMU gives them, or by an
+/// architecture-independent [`Alias`] like `pc` or `arg0` if it was built for a known
+/// [`Arch`]. Values are read and written as integers in the byte order of the target.
+///
+/// Like [`RegisterDescriptor`], registers can only be read from a callback which has
+/// been registered with `CallbackFlags::QEMU_PLUGIN_CB_R_REGS` or
+/// `CallbackFlags::QEMU_PLUGIN_CB_RW_REGS`, and only written from a callback which has
+/// been registered with `CallbackFlags::QEMU_PLUGIN_CB_RW_REGS`.
+pub struct RegisterFile<'a> {
+    /// The register descriptors, in the order QEMU reported them
+    registers: Vec<RegisterDescriptor<'a>>,
+    /// The index of each register in `registers` by name
+    names: HashMap<String, usize>,
+    /// The architecture used to resolve aliases
+    arch: Option<Arch>,
+    /// The byte order of register values
+    endian: Endian,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl<'a> RegisterFile<'a> {
+    /// Create a register file from register descriptors. Aliases are resolved with the
+    /// architecture profile of `target`, and values are read in its byte order, or in
+    /// little-endian byte order if the target is not known.
+    pub fn new(registers: Vec<RegisterDescriptor<'a>>, target: Option<Target>) -> Self {
+        let names = registers
+            .iter()
+            .enumerate()
+            .map(|(index, register)| (register.name.clone(), index))
+            .collect();
+
+        Self {
+            registers,
+            names,
+            arch: target.and_then(|target| target.arch()),
+            endian: target.map_or(Endian::Little, |target| target.endian()),
+        }
+    }
+
+    /// Create a register file from the registers of the current vCPU on `target`, as
+    /// given in [`crate::Info::target`]. This must be called from a vCPU callback, such
+    /// as [`crate::HasCallbacks::on_vcpu_init`].
+    pub fn current(target: Target) -> Result<Self> {
+        Ok(Self::new(qemu_plugin_get_registers()?, Some(target)))
+    }
+
+    /// Set the byte order register values are read and written in
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    /// The architecture used to resolve aliases, if it is known
+    pub fn arch(&self) -> Option<Arch> {
+        self.arch
+    }
+
+    /// The byte order register values are read and written in
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// The number of registers
+    pub fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    /// Whether there are no registers
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+    }
+
+    /// Iterate over the registers, in the order QEMU reported them
+    pub fn iter(&self) -> impl Iterator<Item = &RegisterDescriptor<'a>> {
+        self.registers.iter()
+    }
+
+    /// Look up a register by name, or by the name of an [`Alias`] such as `pc`, `sp`,
+    /// `fp`, `ret`, `ra` or `argN`. Register names take precedence over aliases, so on
+    /// aarch64 `sp` is the register QEMU names `sp`.
+    pub fn get(&self, name: &str) -> Option<&RegisterDescriptor<'a>> {
+        self.names
+            .get(name)
+            .map(|&index| &self.registers[index])
+            .or_else(|| name.parse().ok().and_then(|alias| self.alias(alias)))
+    }
+
+    /// Look up the register `alias` refers to on the register file's architecture
+    pub fn alias(&self, alias: Alias) -> Option<&RegisterDescriptor<'a>> {
+        self.arch
+            .and_then(|arch| arch.register(alias))
+            .and_then(|name| self.names.get(name))
+            .map(|&index| &self.registers[index])
+    }
+
+    /// Look up a register like [`RegisterFile::get`], failing if there is none
+    fn register(&self, name: &str) -> Result<&RegisterDescriptor<'a>> {
+        self.get(name).ok_or_else(|| Error::UnknownRegister {
+            name: name.to_string(),
+        })
+    }
+
+    /// Read the value of the register with a name or alias
+    pub fn read(&self, name: &str) -> Result<Vec<u8>> {
+        self.register(name)?.read()
+    }
+
+    /// Read the value of the register with a name or alias as an integer. Registers of
+    /// up to 8 bytes are zero-extended.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::InvalidRegisterReadSize`] if the register is empty or wider
+    /// than 8 bytes.
+    pub fn read_u64(&self, name: &str) -> Result<u64> {
+        let register = self.register(name)?;
+        let mut bytes = [0; 8];
+        let size = register.read_into(&mut bytes)?;
+
+        if size == 0 {
+            return Err(Error::InvalidRegisterReadSize {
+                name: register.name.clone(),
+                size,
+            });
+        }
+
+        Ok(match self.endian {
+            Endian::Little => u64::from_le_bytes(bytes),
+            Endian::Big => u64::from_be_bytes(bytes) >> ((8 - size) * 8),
+        })
+    }
+
+    /// Read the values of every register into `values`, in the order QEMU reported
+    /// them. See [`RegisterDescriptor::read_many`].
+    pub fn read_many(&self, values: &mut RegisterValues) -> Result<()> {
+        RegisterDescriptor::read_many(&self.registers, values)
+    }
+
+    /// Read the program counter
+    pub fn pc(&self) -> Result<u64> {
+        self.alias_u64(Alias::Pc)
+    }
+
+    /// Read the stack pointer
+    pub fn sp(&self) -> Result<u64> {
+        self.alias_u64(Alias::Sp)
+    }
+
+    /// Read the frame pointer
+    pub fn fp(&self) -> Result<u64> {
+        self.alias_u64(Alias::Fp)
+    }
+
+    /// Read the register holding the return value of a function
+    pub fn return_value(&self) -> Result<u64> {
+        self.alias_u64(Alias::ReturnValue)
+    }
+
+    /// Read the register holding the argument of a function with the given index
+    pub fn argument(&self, index: usize) -> Result<u64> {
+        self.alias_u64(Alias::Argument(index))
+    }
+
+    /// Read the register `alias` refers to as an integer
+    fn alias_u64(&self, alias: Alias) -> Result<u64> {
+        match self.alias(alias) {
+            Some(register) => self.read_u64(&register.name),
+            None => Err(Error::UnknownRegister {
+                name: alias.to_string(),
+            }),
+        }
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3",
+        feature = "plugin-api-v4"
+    )))]
+    /// Write an integer to the register with a name or alias, in the register's width
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::InvalidRegisterReadSize`] if the register is empty or wider
+    /// than 8 bytes, and with [`Error::RegisterValueTooLarge`] if `value` does not fit in
+    /// the register.
+    pub fn write_u64(&self, name: &str, value: u64) -> Result<()> {
+        let register = self.register(name)?;
+        let size = register.read_into(&mut [0; 8])?;
+
+        if size == 0 {
+            return Err(Error::InvalidRegisterReadSize {
+                name: register.name.clone(),
+                size,
+            });
+        }
+
+        if size < 8 && value >> (size * 8) != 0 {
+            return Err(Error::RegisterValueTooLarge {
+                name: register.name.clone(),
+                value,
+                size,
+            });
+        }
+
+        let mut data = match self.endian {
+            Endian::Little => value.to_le_bytes()[..size].to_vec(),
+            Endian::Big => value.to_be_bytes()[8 - size..].to_vec(),
+        };
+
+        register.write(&mut data)
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl<'a, 'b> IntoIterator for &'b RegisterFile<'a> {
+    type Item = &'b RegisterDescriptor<'a>;
+    type IntoIter = std::slice::Iter<'b, RegisterDescriptor<'a>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.registers.iter()
+    }
+}
@@ -1,19 +1,117 @@
 //! Scoreboard-related functionality for QEMU plugins
+//!
+//! A scoreboard holds one entry per vCPU, allocated and grown by QEMU as vCPUs are
+//! created. Entries are zero-initialized, so entry types implement [`ScoreboardEntry`] to
+//! guarantee an all-zero entry is valid. Individual `u64` fields of an entry are addressed
+//! with a [`ScoreboardU64`] handle, which can be read and written from the plugin and
+//! passed to the inline operation APIs.
+//!
+//! # Example
+//!
+//! ```
+//! # use qemu_plugin::Result;
+//! use qemu_plugin::{
+//!     HasCallbacks, PluginId, PluginOp, Register, Scoreboard, ScoreboardEntry,
+//!     TranslationBlock, VCPUIndex,
+//! };
+//!
+//! #[derive(Clone, Copy)]
+//! #[repr(C)]
+//! struct Counters {
+//!     blocks: u64,
+//!     instructions: u64,
+//! }
+//!
+//! // SAFETY: `Counters` only contains integers, so all-zero bytes are a valid value
+//! unsafe impl ScoreboardEntry for Counters {}
+//!
+//! struct Count {
+//!     counters: Scoreboard<'static, Counters>,
+//! }
+//!
+//! impl Register for Count {}
+//!
+//! impl HasCallbacks for Count {
+//!     fn on_translation_block_translate(
+//!         &mut self,
+//!         _id: PluginId,
+//!         tb: TranslationBlock,
+//!     ) -> Result<()> {
+//!         let blocks = self.counters.field(|c| &c.blocks);
+//!         let instructions = self.counters.field(|c| &c.instructions);
+//!
+//!         for (index, insn) in tb.instructions().enumerate() {
+//!             if index == 0 {
+//!                 qemu_plugin::qemu_plugin_register_vcpu_insn_exec_inline_per_vcpu(
+//!                     insn.clone(),
+//!                     PluginOp::QEMU_PLUGIN_INLINE_ADD_U64,
+//!                     blocks.into(),
+//!                     1,
+//!                 );
+//!             }
+//!             qemu_plugin::qemu_plugin_register_vcpu_insn_exec_inline_per_vcpu(
+//!                 insn,
+//!                 PluginOp::QEMU_PLUGIN_INLINE_ADD_U64,
+//!                 instructions.into(),
+//!                 1,
+//!             );
+//!         }
+//!
+//!         Ok(())
+//!     }
+//!
+//!     fn on_vcpu_exit(&mut self, _id: PluginId, vcpu_index: VCPUIndex) -> Result<()> {
+//!         let Counters { blocks, instructions } = self.counters.get(vcpu_index);
+//!         println!("vCPU {vcpu_index}: {blocks} blocks, {instructions} instructions");
+//!         Ok(())
+//!     }
+//! }
+//! ```
 
-#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
-use crate::VCPUIndex;
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
 use crate::sys::qemu_plugin_scoreboard;
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+use crate::{PluginU64, VCPUIndex, qemu_plugin_num_vcpus};
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
 use std::{marker::PhantomData, mem::MaybeUninit};
 
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// A type which can be stored in a scoreboard. QEMU zero-initializes every entry, so
+/// typed access to a scoreboard requires that an all-zero `Self` is valid.
+///
+/// # Safety
+///
+/// Implementors must be valid when every byte of their representation is zero, and must
+/// not contain interior mutability or pointers. Structs of integers, floats and arrays of
+/// them satisfy this.
+pub unsafe trait ScoreboardEntry: Copy + Send + Sync + 'static {}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+macro_rules! impl_scoreboard_entry {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            // SAFETY: All-zero bytes are a valid value of every primitive number type
+            unsafe impl ScoreboardEntry for $ty {}
+        )*
+    };
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl_scoreboard_entry!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+// SAFETY: An all-zero array of entries is an array of all-zero entries
+unsafe impl<T, const N: usize> ScoreboardEntry for [T; N] where T: ScoreboardEntry {}
+
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
 #[derive(Debug)]
 /// A wrapper structure for a `qemu_plugin_scoreboard *`. This is a way of having one
 /// entry per VCPU, the count of which is managed automatically by QEMU. Keep in mind
 /// that additional entries *and* existing entries will be allocated and reallocated by
-/// *qemu*, not by the plugin, so every use of a `T` should include a check for whether
-/// it is initialized.
+/// *qemu*, not by the plugin, so references to entries must not be held across calls
+/// back into QEMU.
 pub struct Scoreboard<'a, T>
 where
     T: Sized,
@@ -36,9 +134,10 @@ impl<'a, T> Scoreboard<'a, T> {
         }
     }
 
-    /// Returns a reference to entry of a scoreboard matching a given vcpu index. This address
-    /// is only valid until the next call to `get` or `set`.
-    pub fn find<'b>(&mut self, vcpu_index: VCPUIndex) -> &'b mut MaybeUninit<T> {
+    /// Returns a reference to entry of a scoreboard matching a given vcpu index. The
+    /// reference borrows the scoreboard, because QEMU may reallocate entries when vCPUs
+    /// are created.
+    pub fn find(&mut self, vcpu_index: VCPUIndex) -> &mut MaybeUninit<T> {
         unsafe {
             &mut *(crate::sys::qemu_plugin_scoreboard_find(
                 self.handle as *mut qemu_plugin_scoreboard,
@@ -48,6 +147,91 @@ impl<'a, T> Scoreboard<'a, T> {
     }
 }
 
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl<'a, T> Scoreboard<'a, T>
+where
+    T: ScoreboardEntry,
+{
+    /// Returns a pointer to the entry for a given vcpu index
+    fn entry(&self, vcpu_index: VCPUIndex) -> *mut T {
+        check_vcpu_index(vcpu_index);
+        unsafe {
+            crate::sys::qemu_plugin_scoreboard_find(
+                self.handle as *mut qemu_plugin_scoreboard,
+                vcpu_index,
+            ) as *mut T
+        }
+    }
+
+    /// Returns a copy of the entry for a given vcpu index
+    ///
+    /// # Panics
+    ///
+    /// Panics if no vCPU with the given index has been created
+    pub fn get(&self, vcpu_index: VCPUIndex) -> T {
+        // NOTE: Entries are zero-initialized by QEMU, which is a valid `T`
+        unsafe { self.entry(vcpu_index).read() }
+    }
+
+    /// Overwrite the entry for a given vcpu index
+    ///
+    /// # Panics
+    ///
+    /// Panics if no vCPU with the given index has been created
+    pub fn set(&self, vcpu_index: VCPUIndex, value: T) {
+        unsafe { self.entry(vcpu_index).write(value) }
+    }
+
+    /// Iterate over copies of the entry for every vCPU, with the vCPU index
+    pub fn iter(&self) -> impl Iterator<Item = (VCPUIndex, T)> + '_ {
+        (0..num_vcpus()).map(|vcpu_index| (vcpu_index, self.get(vcpu_index)))
+    }
+
+    /// Returns a handle to the `u64` field of each entry selected by `field`, which must
+    /// return a reference to a `u64` inside the entry it is given, for example
+    /// `scoreboard.field(|entry| &entry.count)`. For a scoreboard of plain `u64`s, use
+    /// `scoreboard.field(|entry| entry)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `field` returns a reference to a `u64` outside of the entry it is given.
+    pub fn field<F>(&self, field: F) -> ScoreboardU64<'_, T>
+    where
+        F: FnOnce(&T) -> &u64,
+    {
+        // NOTE: All-zero bytes are a valid `T`, so a zeroed entry can be projected to find
+        // the offset of the field without touching the scoreboard
+        let entry = unsafe { MaybeUninit::<T>::zeroed().assume_init() };
+        let base = &entry as *const T as usize;
+        let offset = (field(&entry) as *const u64 as usize).wrapping_sub(base);
+
+        assert!(
+            offset
+                .checked_add(std::mem::size_of::<u64>())
+                .is_some_and(|end| end <= std::mem::size_of::<T>()),
+            "Scoreboard field must be a u64 inside the entry"
+        );
+
+        unsafe { self.field_at(offset) }
+    }
+
+    /// Returns a handle to the `u64` at `offset` bytes into each entry
+    ///
+    /// # Safety
+    ///
+    /// There must be a `u64` field at `offset` bytes into `T`, for example as computed by
+    /// `std::mem::offset_of!`.
+    pub unsafe fn field_at(&self, offset: usize) -> ScoreboardU64<'_, T> {
+        ScoreboardU64 {
+            entry: PluginU64 {
+                score: self.handle as *mut qemu_plugin_scoreboard,
+                offset,
+            },
+            marker: PhantomData,
+        }
+    }
+}
+
 #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
 impl<'a, T> Default for Scoreboard<'a, T> {
     fn default() -> Self {
@@ -63,3 +247,114 @@ impl<'a, T> Drop for Scoreboard<'a, T> {
         }
     }
 }
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+#[derive(Debug)]
+/// A handle to one `u64` field of every entry of a [`Scoreboard`], obtained with
+/// [`Scoreboard::field`]. The handle borrows the scoreboard, so it can not outlive it.
+/// It converts into a [`PluginU64`] for use with the inline operation APIs, in which case
+/// the scoreboard must be kept alive as long as the instrumentation using it.
+pub struct ScoreboardU64<'b, T> {
+    entry: PluginU64,
+    marker: PhantomData<&'b T>,
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl<'b, T> Clone for ScoreboardU64<'b, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl<'b, T> Copy for ScoreboardU64<'b, T> {}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+// SAFETY: The handle only identifies a scoreboard, and all access goes through QEMU
+unsafe impl<'b, T> Send for ScoreboardU64<'b, T> where T: Sync {}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+// SAFETY: The handle only identifies a scoreboard, and all access goes through QEMU
+unsafe impl<'b, T> Sync for ScoreboardU64<'b, T> where T: Sync {}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl<'b, T> ScoreboardU64<'b, T> {
+    /// The raw `PluginU64` for this field
+    pub fn entry(&self) -> PluginU64 {
+        self.entry
+    }
+
+    /// Get the value of this field for a given vCPU
+    ///
+    /// # Panics
+    ///
+    /// Panics if no vCPU with the given index has been created
+    pub fn get(&self, vcpu_index: VCPUIndex) -> u64 {
+        check_vcpu_index(vcpu_index);
+        unsafe { crate::sys::qemu_plugin_u64_get(self.entry, vcpu_index) }
+    }
+
+    /// Set the value of this field for a given vCPU
+    ///
+    /// # Panics
+    ///
+    /// Panics if no vCPU with the given index has been created
+    pub fn set(&self, vcpu_index: VCPUIndex, value: u64) {
+        check_vcpu_index(vcpu_index);
+        unsafe { crate::sys::qemu_plugin_u64_set(self.entry, vcpu_index, value) }
+    }
+
+    /// Add to the value of this field for a given vCPU
+    ///
+    /// # Panics
+    ///
+    /// Panics if no vCPU with the given index has been created
+    pub fn add(&self, vcpu_index: VCPUIndex, added: u64) {
+        check_vcpu_index(vcpu_index);
+        unsafe { crate::sys::qemu_plugin_u64_add(self.entry, vcpu_index, added) }
+    }
+
+    /// Get the sum of this field across all vCPUs
+    pub fn sum(&self) -> u64 {
+        unsafe { crate::sys::qemu_plugin_u64_sum(self.entry) }
+    }
+
+    /// Iterate over the value of this field for every vCPU, with the vCPU index
+    pub fn iter(&self) -> impl Iterator<Item = (VCPUIndex, u64)> + '_ {
+        (0..num_vcpus()).map(|vcpu_index| (vcpu_index, self.get(vcpu_index)))
+    }
+
+    /// The same field, as a field of a scoreboard of entries of type `U`. The entry type
+    /// only decides whether the handle can be shared between threads.
+    pub(crate) fn cast<U>(self) -> ScoreboardU64<'b, U> {
+        ScoreboardU64 {
+            entry: self.entry,
+            marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+impl<'b, T> From<ScoreboardU64<'b, T>> for PluginU64 {
+    fn from(value: ScoreboardU64<'b, T>) -> Self {
+        value.entry
+    }
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// The number of vCPUs with scoreboard entries
+fn num_vcpus() -> VCPUIndex {
+    qemu_plugin_num_vcpus()
+        .and_then(|n| VCPUIndex::try_from(n).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+/// Check that QEMU has allocated scoreboard entries for `vcpu_index`. QEMU does not check
+/// indices itself, so reading past the last entry would read out of bounds.
+fn check_vcpu_index(vcpu_index: VCPUIndex) {
+    assert!(
+        vcpu_index < num_vcpus(),
+        "No scoreboard entry for vCPU {vcpu_index}"
+    );
+}
@@ -0,0 +1,776 @@
+//! Buffered delivery of events from callbacks to a background writer
+//!
+//! Writing events out directly from execution callbacks, for example by serializing them
+//! to a socket, makes every vCPU wait for the writer, and for each other if the writer is
+//! shared. An [`EventSink`] instead gives each vCPU its own bounded single-producer,
+//! single-consumer ring buffer. Callbacks only move the event into the ring, and a
+//! background thread drains the rings in batches and passes them to the writer.
+//!
+//! When a vCPU produces events faster than they can be written, its ring fills up and
+//! the sink's [`Backpressure`] policy decides whether the vCPU waits for space or an event
+//! is dropped. Dropped events are counted in the sink's [`SinkStats`].
+//!
+//! Events still buffered when QEMU exits are lost unless the sink is closed first, which
+//! [`EventSink::close_at_exit`] arranges.
+//!
+//! # Example
+//!
+//! ```
+//! use qemu_plugin::{Backpressure, EventSink, VCPUIndex};
+//! use std::io::Write;
+//!
+//! let mut log = Vec::new();
+//! let sink = EventSink::<u64>::builder()
+//!     .backpressure(Backpressure::DropOldest)
+//!     .build(move |vcpu: VCPUIndex, batch: &[u64]| {
+//!         for vaddr in batch {
+//!             writeln!(log, "{vcpu}: {vaddr:#x}")?;
+//!         }
+//!         Ok(())
+//!     })
+//!     .unwrap();
+//!
+//! // In an execution callback
+//! sink.push(0, 0x401000);
+//!
+//! sink.close().unwrap();
+//! assert_eq!(sink.stats().written, 1);
+//! ```
+
+use crate::{
+    Error, PluginId, Result, VCPUIndex, policy::panic_message, qemu_plugin_register_atexit_cb,
+};
+use std::{
+    cell::UnsafeCell,
+    fmt, hint, iter,
+    mem::MaybeUninit,
+    ops::Deref,
+    panic::{AssertUnwindSafe, catch_unwind},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError,
+        atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle, Thread},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// What a vCPU does when its ring buffer is full
+pub enum Backpressure {
+    #[default]
+    /// Wait until the writer has made space for the event. No events are lost, but vCPUs
+    /// are slowed down to the speed of the writer.
+    Block,
+    /// Drop the oldest event in the ring to make space for the new one
+    DropOldest,
+    /// Drop the new event
+    DropNewest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Counters of what happened to the events pushed to an [`EventSink`]
+pub struct SinkStats {
+    /// The number of events added to a ring buffer
+    pub enqueued: u64,
+    /// The number of events passed to the writer
+    pub written: u64,
+    /// The number of events dropped to make space for newer events
+    pub dropped_oldest: u64,
+    /// The number of events dropped because their ring buffer was full, the sink was
+    /// closed, or the vCPU index was out of range
+    pub dropped_newest: u64,
+    /// The number of times a vCPU had to wait for space in its ring buffer
+    pub blocked: u64,
+}
+
+#[repr(align(64))]
+/// A value aligned to a cache line, so the producer and consumer indices of a ring do
+/// not share one
+struct CachePadded<T>(T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// A slot of a [`Ring`]
+struct Slot<T> {
+    /// The position the slot is ready to be pushed to or popped from next. It is the
+    /// position of the next push while the slot is empty, and one past the position of
+    /// the event it holds while it is full.
+    stamp: AtomicUsize,
+    /// The event, initialized while the slot is full
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A bounded lock-free ring buffer of events from a single vCPU, following the design of
+/// crossbeam's `ArrayQueue`. Events are pushed by the vCPU's thread and popped by the
+/// writer thread, which each claim a slot by advancing `tail` or `head`. Because the slot
+/// stamps say whether a slot is full, the ring is still sound when a vCPU index is shared
+/// between threads, and when a producer claims the oldest event to drop it.
+///
+/// Positions hold the index of a slot in their low bits and a lap count in their high
+/// bits, so a position is never reused while a thread may still hold it.
+struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    /// Added to a position to move it to the same slot in the next lap
+    one_lap: usize,
+    /// The position of the next event to pop
+    head: CachePadded<AtomicUsize>,
+    /// The position of the next event to push
+    tail: CachePadded<AtomicUsize>,
+}
+
+// NOTE: A slot's value is only accessed by the thread which claimed its position, and
+// events are moved between threads
+unsafe impl<T> Sync for Ring<T> where T: Send {}
+
+impl<T> Ring<T> {
+    /// Create an empty ring holding up to `capacity` events
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity)
+                .map(|index| Slot {
+                    stamp: AtomicUsize::new(index),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            one_lap: (capacity + 1).next_power_of_two(),
+            head: CachePadded(AtomicUsize::new(0)),
+            tail: CachePadded(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The slot index of `position`
+    fn index(&self, position: usize) -> usize {
+        position & (self.one_lap - 1)
+    }
+
+    /// The position after `position`
+    fn next(&self, position: usize) -> usize {
+        if self.index(position) + 1 < self.slots.len() {
+            position + 1
+        } else {
+            (position & !(self.one_lap - 1)).wrapping_add(self.one_lap)
+        }
+    }
+
+    /// The number of events in the ring
+    fn len(&self) -> usize {
+        loop {
+            let tail = self.tail.load(Ordering::SeqCst);
+            let head = self.head.load(Ordering::SeqCst);
+
+            // NOTE: `tail` and `head` are only consistent if `tail` did not change
+            if self.tail.load(Ordering::SeqCst) == tail {
+                let (head_index, tail_index) = (self.index(head), self.index(tail));
+
+                return if head_index < tail_index {
+                    tail_index - head_index
+                } else if head_index > tail_index {
+                    self.slots.len() - head_index + tail_index
+                } else if tail == head {
+                    0
+                } else {
+                    self.slots.len()
+                };
+            }
+        }
+    }
+
+    /// Push `event`, calling `full` with the position being pushed to, and its slot, if
+    /// the ring is full. `full` either returns the event to push it again, or returns
+    /// an error which is passed on.
+    fn push_or_else<F>(&self, mut event: T, mut full: F) -> std::result::Result<(), T>
+    where
+        F: FnMut(T, usize, &Slot<T>) -> std::result::Result<T, T>,
+    {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[self.index(tail)];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if tail == stamp {
+                match self.tail.compare_exchange_weak(
+                    tail,
+                    self.next(tail),
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: The slot is empty and the position was claimed
+                        unsafe { (*slot.value.get()).write(event) };
+                        slot.stamp.store(tail.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => {
+                        tail = current;
+                        hint::spin_loop();
+                    }
+                }
+            } else if stamp.wrapping_add(self.one_lap) == tail.wrapping_add(1) {
+                // NOTE: The slot still holds the event from the previous lap
+                atomic::fence(Ordering::SeqCst);
+                event = full(event, tail, slot)?;
+                hint::spin_loop();
+                tail = self.tail.load(Ordering::Relaxed);
+            } else {
+                // NOTE: Another thread is popping from or pushing to the slot
+                thread::yield_now();
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Push `event`, or return it if the ring is full
+    fn push(&self, event: T) -> std::result::Result<(), T> {
+        self.push_or_else(event, |event, tail, _| {
+            let head = self.head.load(Ordering::Relaxed);
+
+            if head.wrapping_add(self.one_lap) == tail {
+                Err(event)
+            } else {
+                Ok(event)
+            }
+        })
+    }
+
+    /// Push `event`, replacing and returning the oldest event if the ring is full
+    fn force_push(&self, event: T) -> Option<T> {
+        self.push_or_else(event, |event, tail, slot| {
+            let head = tail.wrapping_sub(self.one_lap);
+            let new_tail = self.next(tail);
+
+            // NOTE: Claim the oldest event as a pop would, then push to its slot
+            if self
+                .head
+                .compare_exchange_weak(
+                    head,
+                    new_tail.wrapping_sub(self.one_lap),
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                self.tail.store(new_tail, Ordering::SeqCst);
+                // SAFETY: Both the event in the slot and the position were claimed
+                let oldest = unsafe { slot.value.get().replace(MaybeUninit::new(event)) };
+                slot.stamp.store(tail.wrapping_add(1), Ordering::Release);
+                Err(unsafe { oldest.assume_init() })
+            } else {
+                Ok(event)
+            }
+        })
+        .err()
+    }
+
+    /// Pop the oldest event, if there is one
+    fn pop(&self) -> Option<T> {
+        let mut head = self.head.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[self.index(head)];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if head.wrapping_add(1) == stamp {
+                match self.head.compare_exchange_weak(
+                    head,
+                    self.next(head),
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: The slot is full and the position was claimed
+                        let event = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.stamp
+                            .store(head.wrapping_add(self.one_lap), Ordering::Release);
+                        return Some(event);
+                    }
+                    Err(current) => {
+                        head = current;
+                        hint::spin_loop();
+                    }
+                }
+            } else if stamp == head {
+                // NOTE: The slot is empty, which means the ring is empty unless a push to it
+                // is in progress
+                atomic::fence(Ordering::SeqCst);
+
+                if self.tail.load(Ordering::Relaxed) == head {
+                    return None;
+                }
+
+                hint::spin_loop();
+                head = self.head.load(Ordering::Relaxed);
+            } else {
+                // NOTE: Another thread is pushing to or popping from the slot
+                thread::yield_now();
+                head = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let head = *self.head.0.get_mut();
+        let head = self.index(head);
+
+        for offset in 0..self.len() {
+            let index = (head + offset) % self.slots.len();
+            unsafe { self.slots[index].value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// Requests to the writer thread to flush, and their completion
+#[derive(Default)]
+struct FlushState {
+    /// The number of flushes requested
+    requested: u64,
+    /// The number of the last flush request the writer completed, or `u64::MAX` once it
+    /// has exited
+    completed: u64,
+}
+
+/// The state shared between the handles of a sink and its writer thread
+struct Shared<T> {
+    /// The ring of each vCPU, created when the vCPU first pushes an event
+    rings: Box<[OnceLock<Ring<T>>]>,
+    /// The capacity of each ring
+    capacity: usize,
+    /// The maximum number of events passed to the writer at once
+    batch_size: usize,
+    /// What to do when a ring is full
+    backpressure: Backpressure,
+    /// How long the writer waits for events before checking the rings again
+    interval: Duration,
+    /// Whether the sink has been closed
+    closed: AtomicBool,
+    enqueued: AtomicU64,
+    written: AtomicU64,
+    dropped_oldest: AtomicU64,
+    dropped_newest: AtomicU64,
+    blocked: AtomicU64,
+    /// The writer thread, used to wake it
+    thread: OnceLock<Thread>,
+    /// The writer thread's handle, taken when the sink is closed
+    handle: Mutex<Option<JoinHandle<()>>>,
+    /// Flush requests to the writer thread
+    flush: Mutex<FlushState>,
+    /// Notified when the writer completes a flush
+    flushed: Condvar,
+    /// The first error returned by the writer, reported by the next flush or close
+    error: Mutex<Option<Error>>,
+}
+
+/// Lock `mutex`. The state behind the sink's mutexes is consistent between statements,
+/// so a poisoned lock is recovered.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<T> Shared<T> {
+    /// Wake the writer thread
+    fn wake(&self) {
+        if let Some(thread) = self.thread.get() {
+            thread.unpark();
+        }
+    }
+
+    /// Pass every event pushed before this call to `writer`, in batches
+    fn drain<W>(&self, writer: &mut W, batch: &mut Vec<T>)
+    where
+        W: FnMut(VCPUIndex, &[T]) -> Result<()>,
+    {
+        for (vcpu, ring) in self.rings.iter().enumerate() {
+            let Some(ring) = ring.get() else {
+                continue;
+            };
+
+            // NOTE: Events pushed during the drain are left for the next one, so it ends
+            let mut pending = ring.len();
+
+            while pending > 0 {
+                batch.extend(iter::from_fn(|| ring.pop()).take(pending.min(self.batch_size)));
+
+                if batch.is_empty() {
+                    break;
+                }
+
+                pending -= batch.len();
+                self.write(writer, vcpu as VCPUIndex, batch);
+                batch.clear();
+            }
+        }
+    }
+
+    /// Pass `batch` to `writer`, recording the first error or panic. Once the writer has
+    /// failed, batches are discarded so producers are not blocked forever.
+    fn write<W>(&self, writer: &mut W, vcpu: VCPUIndex, batch: &[T])
+    where
+        W: FnMut(VCPUIndex, &[T]) -> Result<()>,
+    {
+        let mut error = lock(&self.error);
+
+        if error.is_some() {
+            return;
+        }
+
+        match catch_unwind(AssertUnwindSafe(|| writer(vcpu, batch))) {
+            Ok(Ok(())) => {
+                self.written
+                    .fetch_add(batch.len() as u64, Ordering::Relaxed);
+            }
+            Ok(Err(e)) => *error = Some(e),
+            Err(payload) => {
+                *error = Some(Error::EventSinkWriterPanic {
+                    message: panic_message(&*payload).to_string(),
+                })
+            }
+        }
+    }
+
+    /// The body of the writer thread
+    fn run<W>(&self, mut writer: W)
+    where
+        W: FnMut(VCPUIndex, &[T]) -> Result<()>,
+    {
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        loop {
+            let requested = lock(&self.flush).requested;
+            let closed = self.closed.load(Ordering::Acquire);
+
+            self.drain(&mut writer, &mut batch);
+
+            {
+                let mut flush = lock(&self.flush);
+                // NOTE: Once closed, every event has been drained, so every flush
+                // requested now or later is complete
+                flush.completed = if closed {
+                    u64::MAX
+                } else {
+                    flush.completed.max(requested)
+                };
+            }
+            self.flushed.notify_all();
+
+            if closed {
+                break;
+            }
+
+            if lock(&self.flush).requested == requested {
+                thread::park_timeout(self.interval);
+            }
+        }
+    }
+
+    /// Close the sink and wait for the writer thread to pass every buffered event to the
+    /// writer and exit
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.wake();
+
+        if let Some(handle) = lock(&self.handle).take() {
+            // NOTE: Panics in the writer are caught, so the thread can not panic
+            let _ = handle.join();
+        }
+    }
+
+    /// Take the writer's error, if it failed
+    fn take_error(&self) -> Result<()> {
+        match lock(&self.error).take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A builder for an [`EventSink`]
+pub struct EventSinkBuilder<T> {
+    capacity: usize,
+    batch_size: usize,
+    max_vcpus: usize,
+    backpressure: Backpressure,
+    interval: Duration,
+    _events: std::marker::PhantomData<fn(T)>,
+}
+
+impl<T> EventSinkBuilder<T>
+where
+    T: Send + 'static,
+{
+    /// Set the number of events each vCPU's ring buffer can hold, which is rounded up to
+    /// a power of two. Defaults to 4096.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1).next_power_of_two();
+        self
+    }
+
+    /// Set the maximum number of events passed to the writer at once. Defaults to 256.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Set the number of vCPUs which can push events. Events pushed with a higher vCPU
+    /// index are dropped. Defaults to 256.
+    pub fn max_vcpus(mut self, max_vcpus: usize) -> Self {
+        self.max_vcpus = max_vcpus;
+        self
+    }
+
+    /// Set what vCPUs do when their ring buffer is full. Defaults to
+    /// [`Backpressure::Block`].
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Set how long the writer waits for events before checking the ring buffers again.
+    /// The writer is also woken when a ring buffer is half full. Defaults to 1ms.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Build the sink, starting a writer thread which passes batches of events from each
+    /// vCPU to `writer`, in the order that vCPU pushed them
+    ///
+    /// # Arguments
+    ///
+    /// - `writer`: Called with the index of a vCPU and a batch of its events
+    pub fn build<W>(self, writer: W) -> Result<EventSink<T>>
+    where
+        W: FnMut(VCPUIndex, &[T]) -> Result<()> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            rings: (0..self.max_vcpus).map(|_| OnceLock::new()).collect(),
+            capacity: self.capacity,
+            batch_size: self.batch_size,
+            backpressure: self.backpressure,
+            interval: self.interval,
+            closed: AtomicBool::new(false),
+            enqueued: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            dropped_oldest: AtomicU64::new(0),
+            dropped_newest: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            thread: OnceLock::new(),
+            handle: Mutex::new(None),
+            flush: Mutex::new(FlushState::default()),
+            flushed: Condvar::new(),
+            error: Mutex::new(None),
+        });
+
+        let handle = thread::Builder::new()
+            .name("qemu-plugin-sink".to_string())
+            .spawn({
+                let shared = shared.clone();
+                move || shared.run(writer)
+            })?;
+
+        let _ = shared.thread.set(handle.thread().clone());
+        *lock(&shared.handle) = Some(handle);
+
+        Ok(EventSink {
+            handles: Arc::new(Handles { shared }),
+        })
+    }
+}
+
+/// The state shared by the handles of a sink, but not by its writer thread. When the
+/// last handle is dropped, the sink is closed so the writer thread exits.
+struct Handles<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Drop for Handles<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// A sink which callbacks push events to, and which passes them to a writer on a
+/// background thread. Handles are cheap to clone and share the same sink, which is
+/// closed when the last handle is dropped.
+pub struct EventSink<T> {
+    handles: Arc<Handles<T>>,
+}
+
+impl<T> Clone for EventSink<T> {
+    fn clone(&self) -> Self {
+        Self {
+            handles: self.handles.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for EventSink<T>
+where
+    T: Send + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSink")
+            .field("backpressure", &self.handles.shared.backpressure)
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> EventSink<T>
+where
+    T: Send + 'static,
+{
+    /// A builder for a sink with the default settings
+    pub fn builder() -> EventSinkBuilder<T> {
+        EventSinkBuilder {
+            capacity: 4096,
+            batch_size: 256,
+            max_vcpus: 256,
+            backpressure: Backpressure::default(),
+            interval: Duration::from_millis(1),
+            _events: std::marker::PhantomData,
+        }
+    }
+
+    /// Push an event from the vCPU `vcpu`. Returns whether the event was enqueued, which
+    /// it is not if it was dropped because of the backpressure policy or because the sink
+    /// has been closed.
+    ///
+    /// # Arguments
+    ///
+    /// - `vcpu`: The index of the vCPU the event is from, whose ring it is pushed to
+    /// - `event`: The event
+    pub fn push(&self, vcpu: VCPUIndex, event: T) -> bool {
+        let shared = &*self.handles.shared;
+
+        let Some(ring) = shared.rings.get(vcpu as usize) else {
+            shared.dropped_newest.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+
+        let ring = ring.get_or_init(|| Ring::new(shared.capacity));
+        let mut event = event;
+        let mut blocked = false;
+
+        loop {
+            if shared.closed.load(Ordering::Acquire) {
+                shared.dropped_newest.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+
+            match shared.backpressure {
+                Backpressure::Block => match ring.push(event) {
+                    Ok(()) => break,
+                    Err(full) => {
+                        event = full;
+
+                        if !blocked {
+                            blocked = true;
+                            shared.blocked.fetch_add(1, Ordering::Relaxed);
+                        }
+
+                        shared.wake();
+                        thread::yield_now();
+                    }
+                },
+                Backpressure::DropOldest => {
+                    if ring.force_push(event).is_some() {
+                        shared.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                    }
+                    break;
+                }
+                Backpressure::DropNewest => {
+                    if ring.push(event).is_ok() {
+                        break;
+                    }
+
+                    shared.dropped_newest.fetch_add(1, Ordering::Relaxed);
+                    shared.wake();
+                    return false;
+                }
+            }
+        }
+
+        shared.enqueued.fetch_add(1, Ordering::Relaxed);
+
+        // NOTE: The writer also wakes up on its own every interval, so it is only woken
+        // early once the ring is at least half full
+        if ring.len() >= shared.capacity.div_ceil(2) {
+            shared.wake();
+        }
+
+        true
+    }
+
+    /// Wait until every event pushed before this call has been passed to the writer.
+    /// Returns the first error returned by the writer since the last flush, if any.
+    pub fn flush(&self) -> Result<()> {
+        let shared = &*self.handles.shared;
+
+        if lock(&shared.handle).is_none() {
+            return shared.take_error();
+        }
+
+        let mut flush = lock(&shared.flush);
+        flush.requested += 1;
+        let requested = flush.requested;
+        shared.wake();
+
+        while flush.completed < requested {
+            flush = shared
+                .flushed
+                .wait(flush)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+
+        drop(flush);
+        shared.take_error()
+    }
+
+    /// Close the sink, passing every buffered event to the writer and stopping the
+    /// writer thread. Events pushed afterwards are dropped. Closing an already closed
+    /// sink does nothing. Returns the first error returned by the writer since the last
+    /// flush, if any.
+    pub fn close(&self) -> Result<()> {
+        let shared = &*self.handles.shared;
+
+        shared.close();
+        shared.take_error()
+    }
+
+    /// Close the sink when QEMU exits, so buffered events are not lost. Errors from the
+    /// writer are reported with `qemu_plugin_outs`.
+    ///
+    /// # Arguments
+    ///
+    /// - `id`: The plugin ID
+    pub fn close_at_exit(&self, id: PluginId) -> Result<()> {
+        let sink = self.clone();
+
+        qemu_plugin_register_atexit_cb(id, move |_| {
+            if let Err(e) = sink.close() {
+                let _ = crate::qemu_plugin_outs(format!("Failed to write events: {e}\n"));
+            }
+        })
+    }
+
+    /// The counters of what happened to the events pushed to the sink
+    pub fn stats(&self) -> SinkStats {
+        let shared = &*self.handles.shared;
+
+        SinkStats {
+            enqueued: shared.enqueued.load(Ordering::Relaxed),
+            written: shared.written.load(Ordering::Relaxed),
+            dropped_oldest: shared.dropped_oldest.load(Ordering::Relaxed),
+            dropped_newest: shared.dropped_newest.load(Ordering::Relaxed),
+            blocked: shared.blocked.load(Ordering::Relaxed),
+        }
+    }
+}
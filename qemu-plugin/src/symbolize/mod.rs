@@ -0,0 +1,806 @@
+//! Symbols and source lines of guest addresses
+//!
+//! A [`Symbolizer`] resolves guest addresses to the function containing them, the offset
+//! into it, and the source file and line the code was compiled from. Unlike
+//! [`crate::Instruction::symbol`], which only knows the symbols QEMU loaded from the
+//! binary being emulated, a symbolizer reads the symbol tables and DWARF debug
+//! information of any number of object files, including the separate debug files of
+//! stripped objects installed under `/usr/lib/debug`.
+//!
+//! In user mode, the binary being emulated is located with
+//! [`crate::qemu_plugin_path_to_binary`] and relocated to where its code was loaded, as
+//! reported by [`crate::qemu_plugin_start_code`]. Shared objects, including those loaded
+//! at run time with `dlopen`, are located by passing syscalls to `Symbolizer::on_syscall`
+//! and `Symbolizer::on_syscall_return`, which watch the dynamic loader open and map them.
+//! Other objects are added with [`Symbolizer::add_object`]. In system mode, the symbols
+//! of a kernel are read from its `System.map` or from a copy of `/proc/kallsyms` with
+//! [`Symbolizer::add_symbol_map`], or from its `vmlinux` with [`Symbolizer::add_object`].
+//!
+//! Addresses are resolved once, and the result is cached, so symbolizing the same
+//! addresses repeatedly from execution callbacks is cheap.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use qemu_plugin::Result;
+//! use qemu_plugin::{
+//!     Args, HasCallbacks, Info, PluginId, Register, Symbolizer, TranslationBlock,
+//! };
+//! use std::sync::Arc;
+//!
+//! #[derive(Default)]
+//! struct Symbols {
+//!     symbolizer: Option<Arc<Symbolizer>>,
+//! }
+//!
+//! impl Register for Symbols {
+//!     fn register(&mut self, _id: PluginId, args: &Args, info: &Info) -> Result<()> {
+//!         // Accepts `object=<path>[@<address>]`, `system_map=<path>` and `sysroot=<path>`
+//!         self.symbolizer = Some(Arc::new(Symbolizer::from_args(info, args)?));
+//!         Ok(())
+//!     }
+//! }
+//!
+//! impl HasCallbacks for Symbols {
+//!     fn on_translation_block_translate(
+//!         &mut self,
+//!         _id: PluginId,
+//!         tb: TranslationBlock,
+//!     ) -> Result<()> {
+//!         let Some(symbolizer) = self.symbolizer.clone() else {
+//!             return Ok(());
+//!         };
+//!
+//!         let vaddr = tb.vaddr();
+//!         tb.register_execute_callback(move |_| {
+//!             println!("{}", symbolizer.symbolize(vaddr));
+//!         });
+//!
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+#[cfg(all(
+    feature = "unwind",
+    not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    ))
+))]
+use crate::unwind::UnwindTables;
+use crate::{
+    Args, Error, FromArgValue, Info, ObjectLayout, Result,
+    objects::{self, GuestObjects, LoadObject, lock},
+};
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+use crate::{Syscall, VCPUIndex};
+#[cfg(not(feature = "plugin-api-v0"))]
+use crate::{qemu_plugin_path_to_binary, qemu_plugin_start_code};
+use addr2line::Context;
+use gimli::{Dwarf, EndianArcSlice, RunTimeEndian, SectionId};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+};
+
+/// The directory separate debug files are installed in
+const DEBUG_DIRECTORY: &str = "/usr/lib/debug";
+
+/// The number of resolved addresses cached unless set with [`Symbolizer::with_cache_size`]
+const DEFAULT_CACHE_SIZE: usize = 1 << 16;
+
+/// The reader DWARF sections are parsed with, which owns the section data so it can be
+/// kept with the object file
+type Reader = EndianArcSlice<RunTimeEndian>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A position in a source file
+pub struct SourceLocation {
+    /// The path of the source file, as recorded when the object was compiled
+    pub file: PathBuf,
+    /// The line in the file, counting from 1, if known
+    pub line: Option<u32>,
+    /// The column in the line, counting from 1, if known
+    pub column: Option<u32>,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file.display())?;
+
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+
+            if let Some(column) = self.column {
+                write!(f, ":{column}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What a guest address was resolved to
+pub struct SymbolInfo {
+    /// The address which was resolved
+    pub address: u64,
+    /// The demangled name of the function containing the address, if known
+    pub symbol: Option<String>,
+    /// The offset of the address from the start of `symbol`, or from the start of
+    /// `object` if the function is not known
+    pub offset: u64,
+    /// The path of the object file containing the address, or the name of the kernel
+    /// module containing it, if known
+    pub object: Option<PathBuf>,
+    /// The source line the code at the address was compiled from, if the object file
+    /// has debug information
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for SymbolInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some(symbol) => write!(f, "{symbol}+{:#x}", self.offset)?,
+            None => write!(f, "{:#x}", self.address)?,
+        }
+
+        if let Some(location) = &self.location {
+            write!(f, " at {location}")?;
+        }
+
+        if let Some(object) = &self.object {
+            write!(f, " ({})", object.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An object file given as a plugin argument, as `<path>` for an object loaded at the
+/// addresses it was linked at, or as `<path>@<address>` for an object whose first
+/// loadable segment is mapped at `<address>`
+///
+/// # Example
+///
+/// ```
+/// use qemu_plugin::{FromArgValue, ObjectArg};
+///
+/// let object = ObjectArg::from_arg_value("object", "/lib/libc.so.6@0x7f0000000000")?;
+/// assert_eq!(object.path.to_str(), Some("/lib/libc.so.6"));
+/// assert_eq!(object.base, Some(0x7f0000000000));
+///
+/// let object = ObjectArg::from_arg_value("object", "vmlinux")?;
+/// assert_eq!(object.base, None);
+/// # Ok::<(), qemu_plugin::Error>(())
+/// ```
+pub struct ObjectArg {
+    /// The guest path of the object file
+    pub path: PathBuf,
+    /// Where the first loadable segment of the object is mapped, if it is not loaded at
+    /// the addresses it was linked at
+    pub base: Option<u64>,
+}
+
+impl FromArgValue for ObjectArg {
+    fn from_arg_value(key: &str, value: &str) -> Result<Self> {
+        let Some((path, base)) = value.rsplit_once('@') else {
+            return Ok(Self {
+                path: PathBuf::from(value),
+                base: None,
+            });
+        };
+
+        let base = u64::from_arg_value(key, base).map_err(|_| Error::InvalidArgValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            expected: Self::expected(),
+        })?;
+
+        Ok(Self {
+            path: PathBuf::from(path),
+            base: Some(base),
+        })
+    }
+
+    fn expected() -> String {
+        "<path>[@<address>]".to_string()
+    }
+}
+
+/// A function symbol, either of an object file or of a symbol map
+struct Symbol {
+    address: u64,
+    /// The size of the function, or 0 if it is not known
+    size: u64,
+    /// The demangled name of the function
+    name: String,
+    /// The kernel module the function belongs to, for symbols of a symbol map
+    module: Option<String>,
+}
+
+/// The symbol at or before `address` in `symbols`, which are sorted by address
+fn preceding(symbols: &[Symbol], address: u64) -> Option<(usize, &Symbol)> {
+    let index = symbols
+        .partition_point(|symbol| symbol.address <= address)
+        .checked_sub(1)?;
+
+    Some((index, &symbols[index]))
+}
+
+/// Sort `symbols` by address, keeping the first of symbols with the same address
+fn sort(symbols: &mut Vec<Symbol>) {
+    symbols.sort_by_key(|symbol| symbol.address);
+    symbols.dedup_by_key(|symbol| symbol.address);
+}
+
+/// Demangle the C++ or Rust symbol name `name`. Other names are returned unchanged,
+/// because short C names like `b` are also valid mangled C++ types.
+fn demangle(name: &str) -> String {
+    if name.starts_with("_Z") || name.starts_with("_R") {
+        addr2line::demangle_auto(Cow::from(name), None).into_owned()
+    } else {
+        name.to_string()
+    }
+}
+
+/// The defined function symbols of `file`
+fn function_symbols(file: &object::File) -> Vec<Symbol> {
+    file.symbols()
+        .chain(file.dynamic_symbols())
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.is_definition())
+        .filter_map(|symbol| {
+            let name = symbol.name().ok().filter(|name| !name.is_empty())?;
+
+            Some(Symbol {
+                address: symbol.address(),
+                size: symbol.size(),
+                name: demangle(name),
+                module: None,
+            })
+        })
+        .collect()
+}
+
+/// The line tables of `file`, or `None` if it has no DWARF debug information or it can
+/// not be parsed
+fn line_tables(file: &object::File) -> Option<Context<Reader>> {
+    file.section_by_name(".debug_info")?;
+
+    let endian = if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let dwarf = Dwarf::load(|id: SectionId| {
+        let data = file
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or_default();
+
+        Ok::<_, gimli::Error>(Reader::new(Arc::from(&*data), endian))
+    })
+    .ok()?;
+
+    Context::from_dwarf(dwarf).ok()
+}
+
+/// The guest paths a separate debug file for the object file `file` at `path` may be
+/// installed at, by its build ID and by its `.gnu_debuglink` section, in the order GDB
+/// searches them
+fn debug_file_paths(file: &object::File, path: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    if let Ok(Some([first, rest @ ..])) = file.build_id()
+        && !rest.is_empty()
+    {
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        paths.push(
+            Path::new(DEBUG_DIRECTORY)
+                .join(".build-id")
+                .join(hex(&[*first]))
+                .join(format!("{}.debug", hex(rest))),
+        );
+    }
+
+    if let Ok(Some((name, _))) = file.gnu_debuglink()
+        && let Ok(name) = std::str::from_utf8(name)
+    {
+        let directory = path.parent().unwrap_or(Path::new("/"));
+        paths.push(directory.join(name));
+        paths.push(directory.join(".debug").join(name));
+        paths.push(
+            Path::new(DEBUG_DIRECTORY)
+                .join(directory.strip_prefix("/").unwrap_or(directory))
+                .join(name),
+        );
+    }
+
+    paths
+}
+
+/// The function symbols and line tables of an object file, in the addresses of the file
+/// rather than where it is loaded
+pub(crate) struct ObjectFile {
+    pub(crate) path: PathBuf,
+    layout: ObjectLayout,
+    /// The function symbols, sorted by address
+    symbols: Vec<Symbol>,
+    /// The line tables, which are parsed lazily as they are searched
+    lines: Option<Mutex<Context<Reader>>>,
+    #[cfg(all(
+        feature = "unwind",
+        not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2",
+            feature = "plugin-api-v3"
+        ))
+    ))]
+    /// The call frame information, which the unwinder reads
+    pub(crate) unwind: UnwindTables,
+}
+
+impl LoadObject for ObjectFile {
+    /// Read and parse the object file at the guest path `path`, and its separate debug
+    /// file if it has one
+    fn load(path: &Path, host_path: &dyn Fn(&Path) -> PathBuf) -> Result<Self> {
+        let data = objects::read(&host_path(path))?;
+        let file = object::File::parse(&*data).map_err(|e| Error::ObjectFileError {
+            path: path.display().to_string(),
+            message: e.to_string(),
+        })?;
+
+        let mut symbols = function_symbols(&file);
+        let mut lines = line_tables(&file);
+
+        // NOTE: Stripped objects keep their full symbol table and debug information in a
+        // separate file, whose addresses are those of the stripped object
+        if lines.is_none() {
+            for debug_path in debug_file_paths(&file, path) {
+                let Ok(debug_data) = objects::read(&host_path(&debug_path)) else {
+                    continue;
+                };
+                let Ok(debug_file) = object::File::parse(&*debug_data) else {
+                    continue;
+                };
+
+                symbols.extend(function_symbols(&debug_file));
+                lines = line_tables(&debug_file);
+                break;
+            }
+        }
+
+        sort(&mut symbols);
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            layout: ObjectLayout::from_file(&file),
+            symbols,
+            lines: lines.map(Mutex::new),
+            #[cfg(all(
+                feature = "unwind",
+                not(any(
+                    feature = "plugin-api-v0",
+                    feature = "plugin-api-v1",
+                    feature = "plugin-api-v2",
+                    feature = "plugin-api-v3"
+                ))
+            ))]
+            unwind: UnwindTables::parse(&file),
+        })
+    }
+
+    fn layout(&self) -> &ObjectLayout {
+        &self.layout
+    }
+}
+
+impl ObjectFile {
+    /// The function symbol containing `address`, and the offset of `address` in it
+    fn symbol(&self, address: u64) -> Option<(&Symbol, u64)> {
+        let (_, symbol) = preceding(&self.symbols, address)?;
+        let offset = address - symbol.address;
+
+        (symbol.size == 0 || offset < symbol.size).then_some((symbol, offset))
+    }
+
+    /// The source lines the code at the file addresses `range` was compiled from, as the
+    /// file addresses and the line of each run of code from the same line
+    fn lines(&self, range: Range<u64>) -> Vec<(Range<u64>, SourceLocation)> {
+        let Some(lines) = &self.lines else {
+            return Vec::new();
+        };
+        let lines = lock(lines);
+        let Ok(locations) = lines.find_location_range(range.start, range.end) else {
+            return Vec::new();
+        };
+
+        locations
+            .filter_map(|(address, len, location)| {
+                Some((
+                    address..address.saturating_add(len),
+                    SourceLocation {
+                        file: PathBuf::from(location.file?),
+                        line: location.line,
+                        column: location.column,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// The source line the code at `address` was compiled from
+    fn location(&self, address: u64) -> Option<SourceLocation> {
+        let lines = lock(self.lines.as_ref()?);
+        let location = lines.find_location(address).ok()??;
+
+        Some(SourceLocation {
+            file: PathBuf::from(location.file?),
+            line: location.line,
+            column: location.column,
+        })
+    }
+}
+
+/// Resolves guest addresses to symbols and source lines
+///
+/// A symbolizer can be shared between vCPUs, and object files and symbol maps can be
+/// added while other vCPUs are symbolizing. Adding either clears the cache of resolved
+/// addresses. Object files are parsed once, when they are first added.
+pub struct Symbolizer {
+    /// The number of resolved addresses cached before the cache is cleared
+    cache_size: usize,
+    /// The object files loaded into the guest
+    objects: GuestObjects<ObjectFile>,
+    /// The symbols of every symbol map, sorted by address
+    kernel_symbols: RwLock<Vec<Symbol>>,
+    /// The resolved addresses
+    cache: RwLock<HashMap<u64, Arc<SymbolInfo>>>,
+}
+
+impl Default for Symbolizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Symbolizer {
+    /// Create a symbolizer with no object files or symbol maps
+    pub fn new() -> Self {
+        Self {
+            cache_size: DEFAULT_CACHE_SIZE,
+            objects: GuestObjects::new(),
+            kernel_symbols: RwLock::new(Vec::new()),
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Create a symbolizer for the guest described by `info`. In user mode, the binary
+    /// being emulated is added, located by the start of its code.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::ObjectFileError`] if the binary being emulated can not be read.
+    pub fn from_info(info: &Info) -> Result<Self> {
+        Self::new().with_binary(info)
+    }
+
+    /// Create a symbolizer for the guest described by `info` from the plugin arguments
+    /// `args`. In user mode, the binary being emulated is added, located by the start of
+    /// its code. The following arguments are read:
+    ///
+    /// - `sysroot=<path>`: Look guest paths up in this directory first, see
+    ///   [`Symbolizer::with_sysroot`]
+    /// - `object=<path>[@<address>]`: Add an object file, see [`ObjectArg`]. May be
+    ///   repeated.
+    /// - `system_map=<path>`: Add a `System.map` or a copy of `/proc/kallsyms`. May be
+    ///   repeated.
+    ///
+    /// Plugins which parse their arguments with [`crate::PluginArgs`] should declare these
+    /// arguments themselves and add them with [`Symbolizer::add_object`] and
+    /// [`Symbolizer::add_symbol_map`] instead, because unknown arguments are rejected.
+    ///
+    /// # Errors
+    ///
+    /// Fails if an argument is not valid, with [`Error::ObjectFileError`] if an object
+    /// file can not be read, and with [`Error::IoError`] if a symbol map can not be read.
+    pub fn from_args(info: &Info, args: &Args) -> Result<Self> {
+        let mut symbolizer = Self::new();
+
+        if let Some(sysroot) = args.get_path("sysroot")? {
+            symbolizer = symbolizer.with_sysroot(sysroot);
+        }
+
+        let symbolizer = symbolizer.with_binary(info)?;
+
+        for object in args.get_all_as::<ObjectArg>("object")? {
+            symbolizer.add_object(&object.path, object.base)?;
+        }
+
+        for path in args.get_all_as::<PathBuf>("system_map")? {
+            symbolizer.add_symbol_map(path)?;
+        }
+
+        Ok(symbolizer)
+    }
+
+    #[cfg(not(feature = "plugin-api-v0"))]
+    /// Add the binary being emulated, if the guest described by `info` runs in user mode
+    fn with_binary(self, info: &Info) -> Result<Self> {
+        if info.system.is_none()
+            && let (Some(path), Some(start)) =
+                (qemu_plugin_path_to_binary()?, qemu_plugin_start_code())
+        {
+            let object = self.objects.object(&path)?;
+            // NOTE: QEMU reports where the executable segments are loaded, so the rest of
+            // the binary is located relative to them
+            let bias = object.layout.bias_from_code(start);
+            self.insert(object, bias);
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(feature = "plugin-api-v0")]
+    /// The binary being emulated can not be located with version 0 of the plugin API, so
+    /// it must be added with [`Symbolizer::add_object`]
+    fn with_binary(self, _info: &Info) -> Result<Self> {
+        Ok(self)
+    }
+
+    /// Look guest paths up in `sysroot` before the host file system, for guests run with
+    /// the `-L` option of QEMU user mode
+    pub fn with_sysroot(mut self, sysroot: impl Into<PathBuf>) -> Self {
+        self.objects = self.objects.with_sysroot(sysroot.into());
+        self
+    }
+
+    /// Cache up to `cache_size` resolved addresses. When the cache is full, it is cleared.
+    pub fn with_cache_size(mut self, cache_size: usize) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// Add `object` loaded with `bias`, replacing any object it overlaps
+    fn insert(&self, object: Arc<ObjectFile>, bias: u64) {
+        self.objects.insert(object, bias);
+        self.clear_cache();
+    }
+
+    #[cfg(all(
+        feature = "unwind",
+        not(any(
+            feature = "plugin-api-v0",
+            feature = "plugin-api-v1",
+            feature = "plugin-api-v2",
+            feature = "plugin-api-v3"
+        ))
+    ))]
+    /// The object files loaded into the guest, sorted by address
+    pub(crate) fn mappings(
+        &self,
+    ) -> std::sync::RwLockReadGuard<'_, Vec<objects::Mapping<ObjectFile>>> {
+        self.objects.mappings()
+    }
+
+    /// Add the object file at the guest path `path`. If `base` is given, the first
+    /// loadable segment of the object is mapped at `base`, as the start of its mapping is
+    /// listed in `/proc/<pid>/maps`. Otherwise, the object is loaded at the addresses it
+    /// was linked at, like a kernel's `vmlinux` or an executable which is not position
+    /// independent.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::ObjectFileError`] if the file can not be read or parsed.
+    pub fn add_object(&self, path: impl AsRef<Path>, base: Option<u64>) -> Result<()> {
+        let object = self.objects.object(path.as_ref())?;
+        let bias = base.map_or(0, |base| object.layout.bias_from_base(base));
+        self.insert(object, bias);
+        Ok(())
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// Watch a syscall made by `vcpu_index` for object files being opened and mapped.
+    /// Pass every syscall to this, and their returns to `Symbolizer::on_syscall_return`.
+    pub fn on_syscall(&self, vcpu_index: VCPUIndex, syscall: &Syscall) {
+        self.objects.on_syscall(vcpu_index, syscall);
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// Complete a syscall passed to `Symbolizer::on_syscall`, adding an object file if it
+    /// was mapped
+    pub fn on_syscall_return(&self, vcpu_index: VCPUIndex, _num: i64, ret: i64) {
+        if self.objects.on_syscall_return(vcpu_index, ret) {
+            self.clear_cache();
+        }
+    }
+
+    /// Add the symbols of the symbol map at the host path `path`, in the format of a
+    /// kernel's `System.map` and of `/proc/kallsyms`. Each line holds the address, the
+    /// type and the name of a symbol, and in `/proc/kallsyms`, the module it belongs to.
+    /// Symbols with an address of zero, which `/proc/kallsyms` lists when it is read
+    /// without privileges, are skipped.
+    ///
+    /// Symbol maps are searched for addresses which are not in any object file.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::IoError`] if the file can not be read.
+    pub fn add_symbol_map(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut symbols = Vec::new();
+
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+
+            let (Some(address), Some(kind), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+
+            // Absolute symbols are not addresses, and undefined symbols have none
+            let Ok(address) = u64::from_str_radix(address, 16) else {
+                continue;
+            };
+
+            if address == 0 || matches!(kind, "a" | "A" | "U") {
+                continue;
+            }
+
+            symbols.push(Symbol {
+                address,
+                size: 0,
+                name: name.to_string(),
+                module: fields
+                    .next()
+                    .map(|module| module.trim_matches(['[', ']']).to_string()),
+            });
+        }
+
+        let mut kernel_symbols = self
+            .kernel_symbols
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        kernel_symbols.append(&mut symbols);
+        sort(&mut kernel_symbols);
+        drop(kernel_symbols);
+
+        self.clear_cache();
+
+        Ok(())
+    }
+
+    /// Forget every resolved address
+    pub fn clear_cache(&self) {
+        self.cache
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Resolve `address` to the function containing it and the source line it was
+    /// compiled from. Addresses which can not be resolved are returned with no symbol.
+    pub fn symbolize(&self, address: u64) -> Arc<SymbolInfo> {
+        if let Some(info) = self
+            .cache
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&address)
+        {
+            return info.clone();
+        }
+
+        let info = Arc::new(self.resolve(address));
+        let mut cache = self.cache.write().unwrap_or_else(PoisonError::into_inner);
+
+        if cache.len() >= self.cache_size {
+            cache.clear();
+        }
+
+        cache.insert(address, info.clone());
+
+        info
+    }
+
+    /// The source lines the code loaded at `range` was compiled from, as the loaded
+    /// addresses and the line of each run of code from the same line. Only the object
+    /// files with line tables are searched, so symbol maps contribute nothing.
+    pub fn lines(&self, range: Range<u64>) -> Vec<(Range<u64>, SourceLocation)> {
+        let mappings = self.objects.mappings();
+        let first = mappings.partition_point(|mapping| mapping.range.end <= range.start);
+
+        mappings[first..]
+            .iter()
+            .take_while(|mapping| mapping.range.start < range.end)
+            .flat_map(|mapping| {
+                let start = range.start.max(mapping.range.start);
+                let end = range.end.min(mapping.range.end);
+
+                mapping
+                    .object
+                    .lines(start.wrapping_sub(mapping.bias)..end.wrapping_sub(mapping.bias))
+                    .into_iter()
+                    .map(|(lines, location)| {
+                        (
+                            lines.start.wrapping_add(mapping.bias)
+                                ..lines.end.wrapping_add(mapping.bias),
+                            location,
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Resolve `address`, first in the object files and then in the symbol maps
+    fn resolve(&self, address: u64) -> SymbolInfo {
+        let mappings = self.objects.mappings();
+
+        if let Some(mapping) = objects::mapping(&mappings, address) {
+            let file_address = address.wrapping_sub(mapping.bias);
+            let symbol = mapping.object.symbol(file_address);
+
+            return SymbolInfo {
+                address,
+                symbol: symbol.map(|(symbol, _)| symbol.name.clone()),
+                offset: symbol.map_or(address - mapping.range.start, |(_, offset)| offset),
+                object: Some(mapping.object.path.clone()),
+                location: mapping.object.location(file_address),
+            };
+        }
+
+        drop(mappings);
+
+        let kernel_symbols = self
+            .kernel_symbols
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // NOTE: Symbol maps have no sizes, so each symbol ends where the next begins, and
+        // the last symbol, which usually marks the end of the kernel image, contains
+        // nothing
+        match preceding(&kernel_symbols, address) {
+            Some((index, symbol)) if index + 1 < kernel_symbols.len() => SymbolInfo {
+                address,
+                symbol: Some(symbol.name.clone()),
+                offset: address - symbol.address,
+                object: symbol.module.as_ref().map(PathBuf::from),
+                location: None,
+            },
+            _ => SymbolInfo {
+                address,
+                symbol: None,
+                offset: 0,
+                object: None,
+                location: None,
+            },
+        }
+    }
+}
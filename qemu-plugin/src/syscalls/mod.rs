@@ -0,0 +1,826 @@
+//! Linux syscall tables and typed syscall arguments for user-mode targets
+//!
+//! QEMU reports syscalls made by user-mode processes as a number and eight raw argument
+//! registers. A [`SyscallTable`] names the syscalls, error numbers and flags of a target's
+//! Linux ABI, and a [`SyscallSchema`] describes what each argument of a syscall holds: a
+//! file descriptor, a pointer to a string, a buffer and its length, a set of flags or a
+//! pointer to a struct. A [`Syscall`] combines the two to render syscalls the way
+//! `strace` does, reading strings and buffers from guest memory with a [`GuestMemory`].
+//!
+//! The tables are generated from the Linux UAPI headers by `generate-syscalls.rs`.
+//!
+//! # Example
+//!
+//! ```
+//! use qemu_plugin::{Syscall, Target};
+//!
+//! let args = [-100i64 as u64, 0x1000, 0o2000000, 0, 0, 0, 0, 0];
+//! let syscall = Syscall::with_target(Some(Target::X86_64), 257, args);
+//! assert_eq!(syscall.name(), Some("openat"));
+//! assert_eq!(syscall.render(), "openat(AT_FDCWD, 0x1000, O_RDONLY|O_CLOEXEC)");
+//! assert_eq!(syscall.render_return(-2), "-1 ENOENT");
+//! ```
+//!
+//! [`GuestMemory`]: crate::GuestMemory
+
+#[cfg(not(any(
+    feature = "plugin-api-v0",
+    feature = "plugin-api-v1",
+    feature = "plugin-api-v2",
+    feature = "plugin-api-v3"
+)))]
+use crate::GuestMemory;
+use crate::Target;
+use std::{
+    fmt::{self, Write},
+    hash::{Hash, Hasher},
+    sync::OnceLock,
+};
+
+include!("tables.rs");
+
+/// The largest error number Linux returns from a syscall, as a negative return value
+const MAX_ERRNO: i64 = 4095;
+
+/// The value of a directory file descriptor which refers to the current directory
+const AT_FDCWD: i64 = -100;
+
+/// The names of the `mmap` mapping types
+const MMAP_TYPES: &[(u64, &str)] = &[
+    (0x1, "MAP_SHARED"),
+    (0x2, "MAP_PRIVATE"),
+    (0x3, "MAP_SHARED_VALIDATE"),
+    (0x8, "MAP_DROPPABLE"),
+];
+
+/// The names of the memory protection flags
+const PROT_FLAGS: &[(u64, &str)] = &[
+    (0x1, "PROT_READ"),
+    (0x2, "PROT_WRITE"),
+    (0x4, "PROT_EXEC"),
+    (0x1000000, "PROT_GROWSDOWN"),
+    (0x2000000, "PROT_GROWSUP"),
+];
+
+/// The names of the flags of the `*at` syscalls
+const AT_FLAGS: &[(u64, &str)] = &[
+    (0x100, "AT_SYMLINK_NOFOLLOW"),
+    (0x200, "AT_REMOVEDIR"),
+    (0x400, "AT_SYMLINK_FOLLOW"),
+    (0x800, "AT_NO_AUTOMOUNT"),
+    (0x1000, "AT_EMPTY_PATH"),
+];
+
+/// The names of the `clone` flags. The low byte holds the signal sent to the parent
+/// when the child exits.
+const CLONE_FLAGS: &[(u64, &str)] = &[
+    (0x100, "CLONE_VM"),
+    (0x200, "CLONE_FS"),
+    (0x400, "CLONE_FILES"),
+    (0x800, "CLONE_SIGHAND"),
+    (0x1000, "CLONE_PIDFD"),
+    (0x2000, "CLONE_PTRACE"),
+    (0x4000, "CLONE_VFORK"),
+    (0x8000, "CLONE_PARENT"),
+    (0x10000, "CLONE_THREAD"),
+    (0x20000, "CLONE_NEWNS"),
+    (0x40000, "CLONE_SYSVSEM"),
+    (0x80000, "CLONE_SETTLS"),
+    (0x100000, "CLONE_PARENT_SETTID"),
+    (0x200000, "CLONE_CHILD_CLEARTID"),
+    (0x400000, "CLONE_DETACHED"),
+    (0x800000, "CLONE_UNTRACED"),
+    (0x1000000, "CLONE_CHILD_SETTID"),
+    (0x2000000, "CLONE_NEWCGROUP"),
+    (0x4000000, "CLONE_NEWUTS"),
+    (0x8000000, "CLONE_NEWIPC"),
+    (0x10000000, "CLONE_NEWUSER"),
+    (0x20000000, "CLONE_NEWPID"),
+    (0x40000000, "CLONE_NEWNET"),
+    (0x80000000, "CLONE_IO"),
+];
+
+#[derive(Debug)]
+/// The Linux syscalls of an architecture, with the names of its error numbers and of
+/// the flags whose values differ between architectures
+pub struct SyscallTable {
+    arch: &'static str,
+    syscalls: &'static [(i64, &'static str)],
+    errnos: &'static [(i64, &'static str)],
+    open_flags: &'static [(u64, &'static str)],
+    mmap_flags: &'static [(u64, &'static str)],
+}
+
+impl SyscallTable {
+    /// The syscall table of a target, if one is generated for it. Tables are generated
+    /// for x86_64, i386, arm, aarch64, riscv64, mips and ppc64, in either byte order.
+    pub fn for_target(target: Target) -> Option<&'static Self> {
+        match target {
+            Target::X86_64 => Some(&X86_64),
+            Target::I386 => Some(&I386),
+            Target::Arm | Target::Armeb => Some(&ARM),
+            Target::Aarch64 | Target::Aarch64Be => Some(&AARCH64),
+            Target::Riscv64 => Some(&RISCV64),
+            Target::Mips | Target::Mipsel => Some(&MIPS),
+            Target::Ppc64 | Target::Ppc64le => Some(&PPC64),
+            _ => None,
+        }
+    }
+
+    /// The syscall table of the target the plugin was installed in, if one is generated
+    /// for it
+    pub fn current() -> Option<&'static Self> {
+        Target::current().and_then(Self::for_target)
+    }
+
+    /// The name of the architecture
+    pub fn arch(&self) -> &'static str {
+        self.arch
+    }
+
+    /// The name of the syscall with a number
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use qemu_plugin::{SyscallTable, Target};
+    ///
+    /// let table = SyscallTable::for_target(Target::Aarch64).unwrap();
+    /// assert_eq!(table.name(64), Some("write"));
+    /// assert_eq!(table.number("write"), Some(64));
+    /// ```
+    pub fn name(&self, number: i64) -> Option<&'static str> {
+        self.syscalls
+            .binary_search_by_key(&number, |(number, _)| *number)
+            .ok()
+            .map(|index| self.syscalls[index].1)
+    }
+
+    /// The number of the syscall with a name
+    pub fn number(&self, name: &str) -> Option<i64> {
+        self.syscalls
+            .iter()
+            .find(|(_, syscall)| *syscall == name)
+            .map(|(number, _)| *number)
+    }
+
+    /// The numbers and names of all syscalls, ordered by number
+    pub fn iter(&self) -> impl Iterator<Item = (i64, &'static str)> + '_ {
+        self.syscalls.iter().copied()
+    }
+
+    /// The name of an error number, like `ENOENT`
+    pub fn errno_name(&self, errno: i64) -> Option<&'static str> {
+        self.errnos
+            .binary_search_by_key(&errno, |(errno, _)| *errno)
+            .ok()
+            .map(|index| self.errnos[index].1)
+    }
+
+    /// Whether a set of `open` flags may create a file
+    fn creates(&self, flags: u64) -> bool {
+        self.open_flags
+            .iter()
+            .filter(|(_, name)| matches!(*name, "O_CREAT" | "O_TMPFILE"))
+            .any(|(flag, _)| flags & flag == *flag)
+    }
+
+    /// The schema of the arguments of the syscall with a number, if it is known
+    pub fn schema(&self, number: i64) -> Option<SyscallSchema> {
+        let name = self.name(number)?;
+
+        // NOTE: The `mmap` syscall of i386 predates `mmap2` and takes its arguments in
+        // a struct
+        if self.arch == "i386" && name == "mmap" {
+            return Some(SyscallSchema {
+                args: &[ArgKind::Struct("mmap_arg_struct")],
+                returns: ReturnKind::Ptr,
+            });
+        }
+
+        SyscallSchema::for_name(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A set of flags a syscall argument holds
+pub enum FlagSet {
+    /// The flags of `open`, including the access mode
+    Open,
+    /// The flags of `mmap`, including the mapping type
+    Mmap,
+    /// The memory protection flags of `mmap` and `mprotect`
+    Prot,
+    /// The flags of the `*at` syscalls, like `AT_SYMLINK_NOFOLLOW`
+    At,
+    /// The flags of `clone`
+    Clone,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// What a syscall argument holds
+pub enum ArgKind {
+    /// A C `int`
+    Int,
+    /// A C `long`, the size of a pointer
+    Long,
+    /// An unsigned size or count, the size of a pointer
+    Uint,
+    /// A value best shown in hexadecimal, like an address or a request code
+    Hex,
+    /// File mode bits, shown in octal
+    Mode,
+    /// A file descriptor
+    Fd,
+    /// A directory file descriptor, which may be `AT_FDCWD`
+    DirFd,
+    /// A pointer to a NUL-terminated string
+    Str,
+    /// A pointer to a buffer whose length is held by the argument with index `len`.
+    /// Output buffers are filled by the kernel, so they are only read once the syscall
+    /// returns, up to the length it returned.
+    Buf {
+        /// The index of the argument holding the length of the buffer
+        len: usize,
+        /// Whether the kernel writes the buffer rather than reading it
+        output: bool,
+    },
+    /// A set of flags
+    Flags(FlagSet),
+    /// A pointer to a struct, with the name of its C type
+    Struct(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// What a syscall returns when it succeeds
+pub enum ReturnKind {
+    /// An integer, like a file descriptor or a count of bytes
+    Int,
+    /// An address
+    Ptr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The arguments of a syscall and what it returns
+pub struct SyscallSchema {
+    /// What each argument holds, in order
+    pub args: &'static [ArgKind],
+    /// What the syscall returns when it succeeds
+    pub returns: ReturnKind,
+}
+
+impl SyscallSchema {
+    /// The schema of the syscall with a name, if it is known. Syscalls have the same
+    /// arguments on every architecture, with the exception of the legacy i386 `mmap`,
+    /// which [`SyscallTable::schema`] accounts for.
+    pub fn for_name(name: &str) -> Option<Self> {
+        use ArgKind::*;
+
+        // NOTE: Named so the argument slices below are promoted to statics
+        const OUT_1: ArgKind = Buf {
+            len: 1,
+            output: true,
+        };
+        const OUT_2: ArgKind = Buf {
+            len: 2,
+            output: true,
+        };
+        const OUT_3: ArgKind = Buf {
+            len: 3,
+            output: true,
+        };
+        const IN_2: ArgKind = Buf {
+            len: 2,
+            output: false,
+        };
+
+        let int = |args| Some(Self::new(args, ReturnKind::Int));
+
+        match name {
+            "read" => int(&[Fd, OUT_2, Uint]),
+            "write" => int(&[Fd, IN_2, Uint]),
+            "pread64" => int(&[Fd, OUT_2, Uint, Long]),
+            "pwrite64" => int(&[Fd, IN_2, Uint, Long]),
+            "readv" | "writev" => int(&[Fd, Struct("iovec"), Int]),
+            "open" => int(&[Str, Flags(FlagSet::Open), Mode]),
+            "openat" => int(&[DirFd, Str, Flags(FlagSet::Open), Mode]),
+            "creat" => int(&[Str, Mode]),
+            "close" | "fchdir" | "fsync" | "fdatasync" | "dup" => int(&[Fd]),
+            "dup2" => int(&[Fd, Fd]),
+            "dup3" => int(&[Fd, Fd, Flags(FlagSet::Open)]),
+            "pipe" => int(&[Struct("int[2]")]),
+            "pipe2" => int(&[Struct("int[2]"), Flags(FlagSet::Open)]),
+            "stat" | "lstat" | "stat64" | "lstat64" => int(&[Str, Struct("stat")]),
+            "fstat" | "fstat64" => int(&[Fd, Struct("stat")]),
+            "newfstatat" | "fstatat64" => int(&[DirFd, Str, Struct("stat"), Flags(FlagSet::At)]),
+            "statx" => int(&[DirFd, Str, Flags(FlagSet::At), Hex, Struct("statx")]),
+            "lseek" => int(&[Fd, Long, Int]),
+            "mmap" | "mmap2" => Some(Self::new(
+                &[
+                    Hex,
+                    Uint,
+                    Flags(FlagSet::Prot),
+                    Flags(FlagSet::Mmap),
+                    Fd,
+                    Hex,
+                ],
+                ReturnKind::Ptr,
+            )),
+            "mprotect" => int(&[Hex, Uint, Flags(FlagSet::Prot)]),
+            "munmap" => int(&[Hex, Uint]),
+            "madvise" => int(&[Hex, Uint, Int]),
+            "brk" => Some(Self::new(&[Hex], ReturnKind::Ptr)),
+            "ioctl" => int(&[Fd, Hex, Hex]),
+            "fcntl" | "fcntl64" => int(&[Fd, Int, Hex]),
+            "access" => int(&[Str, Int]),
+            "faccessat" => int(&[DirFd, Str, Int]),
+            "faccessat2" => int(&[DirFd, Str, Int, Flags(FlagSet::At)]),
+            "execve" => int(&[Str, Hex, Hex]),
+            "execveat" => int(&[DirFd, Str, Hex, Hex, Flags(FlagSet::At)]),
+            "exit" | "exit_group" => int(&[Int]),
+            "getpid" | "getppid" | "gettid" | "getuid" | "geteuid" | "getgid" | "getegid"
+            | "getpgrp" | "setsid" | "sched_yield" | "fork" | "vfork" | "rt_sigreturn"
+            | "sigreturn" => int(&[]),
+            "kill" | "tkill" => int(&[Int, Int]),
+            "tgkill" => int(&[Int, Int, Int]),
+            "chdir" | "rmdir" | "unlink" | "chroot" => int(&[Str]),
+            "getcwd" => int(&[OUT_1, Uint]),
+            "mkdir" | "chmod" => int(&[Str, Mode]),
+            "mkdirat" | "fchmodat" => int(&[DirFd, Str, Mode]),
+            "fchmod" => int(&[Fd, Mode]),
+            "umask" => int(&[Mode]),
+            "unlinkat" => int(&[DirFd, Str, Flags(FlagSet::At)]),
+            "rename" | "link" | "symlink" => int(&[Str, Str]),
+            "renameat" => int(&[DirFd, Str, DirFd, Str]),
+            "renameat2" => int(&[DirFd, Str, DirFd, Str, Hex]),
+            "linkat" => int(&[DirFd, Str, DirFd, Str, Flags(FlagSet::At)]),
+            "symlinkat" => int(&[Str, DirFd, Str]),
+            "readlink" => int(&[Str, OUT_2, Uint]),
+            "readlinkat" => int(&[DirFd, Str, OUT_3, Uint]),
+            "chown" | "lchown" => int(&[Str, Int, Int]),
+            "fchown" => int(&[Fd, Int, Int]),
+            "fchownat" => int(&[DirFd, Str, Int, Int, Flags(FlagSet::At)]),
+            "truncate" => int(&[Str, Long]),
+            "ftruncate" => int(&[Fd, Long]),
+            "getdents" | "getdents64" => int(&[Fd, Struct("linux_dirent"), Uint]),
+            "socket" => int(&[Int, Int, Int]),
+            "connect" | "bind" => int(&[Fd, Struct("sockaddr"), Uint]),
+            "accept" | "getsockname" | "getpeername" => {
+                int(&[Fd, Struct("sockaddr"), Struct("socklen_t")])
+            }
+            "accept4" => int(&[Fd, Struct("sockaddr"), Struct("socklen_t"), Hex]),
+            "listen" | "shutdown" => int(&[Fd, Int]),
+            "sendto" => int(&[Fd, IN_2, Uint, Hex, Struct("sockaddr"), Uint]),
+            "recvfrom" => int(&[
+                Fd,
+                OUT_2,
+                Uint,
+                Hex,
+                Struct("sockaddr"),
+                Struct("socklen_t"),
+            ]),
+            "sendmsg" | "recvmsg" => int(&[Fd, Struct("msghdr"), Hex]),
+            "poll" => int(&[Struct("pollfd"), Uint, Int]),
+            "ppoll" => int(&[
+                Struct("pollfd"),
+                Uint,
+                Struct("timespec"),
+                Struct("sigset_t"),
+                Uint,
+            ]),
+            "epoll_create1" => int(&[Flags(FlagSet::Open)]),
+            "epoll_ctl" => int(&[Fd, Int, Fd, Struct("epoll_event")]),
+            "epoll_wait" => int(&[Fd, Struct("epoll_event"), Int, Int]),
+            "epoll_pwait" => int(&[
+                Fd,
+                Struct("epoll_event"),
+                Int,
+                Int,
+                Struct("sigset_t"),
+                Uint,
+            ]),
+            "eventfd2" => int(&[Uint, Flags(FlagSet::Open)]),
+            "memfd_create" => int(&[Str, Hex]),
+            "nanosleep" => int(&[Struct("timespec"), Struct("timespec")]),
+            "clock_gettime" | "clock_getres" => int(&[Int, Struct("timespec")]),
+            "clock_nanosleep" => int(&[Int, Hex, Struct("timespec"), Struct("timespec")]),
+            "gettimeofday" => int(&[Struct("timeval"), Struct("timezone")]),
+            "rt_sigaction" => int(&[Int, Struct("sigaction"), Struct("sigaction"), Uint]),
+            "rt_sigprocmask" => int(&[Int, Struct("sigset_t"), Struct("sigset_t"), Uint]),
+            "sigaltstack" => int(&[Struct("stack_t"), Struct("stack_t")]),
+            "set_tid_address" => int(&[Hex]),
+            "set_robust_list" => int(&[Struct("robust_list_head"), Uint]),
+            "rseq" => int(&[Struct("rseq"), Uint, Int, Hex]),
+            "futex" => int(&[Hex, Int, Int, Struct("timespec"), Hex, Int]),
+            "clone" => int(&[Flags(FlagSet::Clone), Hex, Hex, Hex, Hex]),
+            "clone3" => int(&[Struct("clone_args"), Uint]),
+            "wait4" => int(&[Int, Struct("int"), Hex, Struct("rusage")]),
+            "uname" => int(&[Struct("utsname")]),
+            "sysinfo" => int(&[Struct("sysinfo")]),
+            "arch_prctl" => int(&[Int, Hex]),
+            "prctl" => int(&[Int, Hex, Hex, Hex, Hex]),
+            "getrlimit" | "setrlimit" => int(&[Int, Struct("rlimit")]),
+            "prlimit64" => int(&[Int, Int, Struct("rlimit64"), Struct("rlimit64")]),
+            "getrandom" => int(&[OUT_1, Uint, Hex]),
+            "sched_getaffinity" | "sched_setaffinity" => int(&[Int, Uint, Hex]),
+            _ => None,
+        }
+    }
+
+    /// A schema with arguments and a return value
+    const fn new(args: &'static [ArgKind], returns: ReturnKind) -> Self {
+        Self { args, returns }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A typed view of a syscall made by a user-mode process, from the number and argument
+/// registers QEMU reports
+///
+/// The syscall is looked up in the table of its target the first time it is named or
+/// rendered, so a syscall which is never inspected costs nothing to create.
+pub struct Syscall {
+    target: Option<Target>,
+    number: i64,
+    args: [u64; 8],
+    /// The name and schema of the syscall, looked up when either is first needed
+    decoded: OnceLock<(Option<&'static str>, Option<SyscallSchema>)>,
+}
+
+impl PartialEq for Syscall {
+    fn eq(&self, other: &Self) -> bool {
+        (self.target, self.number, self.args) == (other.target, other.number, other.args)
+    }
+}
+
+impl Eq for Syscall {}
+
+impl Hash for Syscall {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.target, self.number, self.args).hash(state);
+    }
+}
+
+impl Syscall {
+    /// A syscall made by a process of the target the plugin was installed in
+    pub fn new(number: i64, args: [u64; 8]) -> Self {
+        Self::with_target(Target::current(), number, args)
+    }
+
+    /// A syscall made by a process of a target
+    pub fn with_target(target: Option<Target>, number: i64, args: [u64; 8]) -> Self {
+        Self {
+            target,
+            number,
+            args,
+            decoded: OnceLock::new(),
+        }
+    }
+
+    /// The target the syscall was made on, if it is known
+    pub fn target(&self) -> Option<Target> {
+        self.target
+    }
+
+    /// The syscall table of the target, if one is generated for it
+    pub fn table(&self) -> Option<&'static SyscallTable> {
+        self.target.and_then(SyscallTable::for_target)
+    }
+
+    /// The syscall number
+    pub fn number(&self) -> i64 {
+        self.number
+    }
+
+    /// The name and schema of the syscall, looked up the first time they are needed
+    fn decoded(&self) -> &(Option<&'static str>, Option<SyscallSchema>) {
+        self.decoded.get_or_init(|| {
+            let table = self.table();
+            (
+                table.and_then(|table| table.name(self.number)),
+                table.and_then(|table| table.schema(self.number)),
+            )
+        })
+    }
+
+    /// The name of the syscall, if it is known
+    pub fn name(&self) -> Option<&'static str> {
+        self.decoded().0
+    }
+
+    /// The raw values of the argument registers
+    pub fn args(&self) -> &[u64; 8] {
+        &self.args
+    }
+
+    /// The raw value of the argument with an index, counting from zero
+    pub fn arg(&self, index: usize) -> Option<u64> {
+        self.args.get(index).copied()
+    }
+
+    /// The schema of the syscall's arguments, if it is known
+    pub fn schema(&self) -> Option<SyscallSchema> {
+        self.decoded().1
+    }
+
+    /// The arguments of the syscall with what they hold. Syscalls without a known schema
+    /// are shown with six arguments in hexadecimal.
+    pub fn arguments(&self) -> impl Iterator<Item = (ArgKind, u64)> + '_ {
+        let kinds = self
+            .schema()
+            .map_or(&[ArgKind::Hex; 6][..], |schema| schema.args);
+
+        kinds.iter().copied().zip(self.args)
+    }
+
+    /// Render the syscall the way `strace` does, like `openat(AT_FDCWD, 0x7ffc1000,
+    /// O_RDONLY)`, without reading guest memory. Strings and buffers are shown as their
+    /// addresses.
+    pub fn render(&self) -> String {
+        self.format(&self.render_args())
+    }
+
+    /// Render each argument of the syscall the way [`Self::render`] does
+    pub fn render_args(&self) -> Vec<String> {
+        self.format_args(None, 0, |_, _| Vec::new())
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// Render the syscall the way `strace` does, like `openat(AT_FDCWD, "/etc/passwd",
+    /// O_RDONLY)`, reading strings and buffers from guest memory. At most `string_limit`
+    /// bytes of each string and buffer are shown. Output buffers are only read once the
+    /// syscall has returned `ret`, so decoding a syscall in its return callback shows
+    /// the data a `read` returned.
+    pub fn decode(&self, memory: &GuestMemory, string_limit: usize, ret: Option<i64>) -> String {
+        self.format(&self.decode_args(memory, string_limit, ret))
+    }
+
+    #[cfg(not(any(
+        feature = "plugin-api-v0",
+        feature = "plugin-api-v1",
+        feature = "plugin-api-v2",
+        feature = "plugin-api-v3"
+    )))]
+    /// Render each argument of the syscall the way [`Self::decode`] does
+    pub fn decode_args(
+        &self,
+        memory: &GuestMemory,
+        string_limit: usize,
+        ret: Option<i64>,
+    ) -> Vec<String> {
+        self.format_args(ret, string_limit, |addr, len| {
+            let mut data = vec![0; len];
+            let read = memory.read_partial(addr, &mut data);
+            data.truncate(read);
+            data
+        })
+    }
+
+    /// The error number a return value of the syscall holds, if it is an error
+    pub fn errno(&self, ret: i64) -> Option<i64> {
+        errno(ret)
+    }
+
+    /// Render a return value of the syscall the way `strace` does, as `-1` and the name of
+    /// the error number for errors, like `-1 ENOENT`
+    pub fn render_return(&self, ret: i64) -> String {
+        if let Some(errno) = self.errno(ret) {
+            return match self.table().and_then(|table| table.errno_name(errno)) {
+                Some(name) => format!("-1 {name}"),
+                None => format!("-1 errno {errno}"),
+            };
+        }
+
+        match self.schema().map(|schema| schema.returns) {
+            Some(ReturnKind::Ptr) => format!("{:#x}", self.unsigned(ret as u64)),
+            _ => ret.to_string(),
+        }
+    }
+
+    /// The size of a pointer on the target in bits
+    fn pointer_bits(&self) -> u32 {
+        self.target
+            .map_or(64, |target| target.pointer_width() as u32 * 8)
+    }
+
+    /// A value truncated to the size of a pointer
+    fn unsigned(&self, value: u64) -> u64 {
+        value & (u64::MAX >> (64 - self.pointer_bits()))
+    }
+
+    /// Render the syscall with its rendered arguments
+    fn format(&self, args: &[String]) -> String {
+        match self.name() {
+            Some(name) => format!("{name}({})", args.join(", ")),
+            None => format!("syscall_{}({})", self.number, args.join(", ")),
+        }
+    }
+
+    /// Render each argument, reading strings and buffers with `read`, which returns the
+    /// accessible bytes of a range of guest memory
+    fn format_args(
+        &self,
+        ret: Option<i64>,
+        string_limit: usize,
+        read: impl Fn(u64, usize) -> Vec<u8>,
+    ) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut previous = None;
+
+        for (kind, value) in self.arguments() {
+            // NOTE: Like `strace`, the mode of `open` is only shown if the file may be
+            // created, because it is ignored otherwise
+            if let (ArgKind::Mode, Some((ArgKind::Flags(FlagSet::Open), flags))) = (kind, previous)
+                && !self.table().is_some_and(|table| table.creates(flags))
+            {
+                break;
+            }
+
+            previous = Some((kind, value));
+
+            let mut out = String::new();
+            self.format_arg(&mut out, kind, value, ret, string_limit, &read);
+            args.push(out);
+        }
+
+        args
+    }
+
+    /// Render one argument
+    fn format_arg(
+        &self,
+        out: &mut String,
+        kind: ArgKind,
+        value: u64,
+        ret: Option<i64>,
+        string_limit: usize,
+        read: &impl Fn(u64, usize) -> Vec<u8>,
+    ) {
+        let pointer = self.unsigned(value);
+
+        let _ = match kind {
+            ArgKind::Int | ArgKind::Fd => write!(out, "{}", sign_extend(value, 32)),
+            ArgKind::Long => write!(out, "{}", sign_extend(value, self.pointer_bits())),
+            ArgKind::Uint => write!(out, "{pointer}"),
+            ArgKind::Hex => write!(out, "{pointer:#x}"),
+            ArgKind::Mode => write!(out, "0{:03o}", value & 0o7777),
+            ArgKind::DirFd => match sign_extend(value, 32) {
+                AT_FDCWD => write!(out, "AT_FDCWD"),
+                fd => write!(out, "{fd}"),
+            },
+            ArgKind::Str if pointer != 0 => {
+                let data = read(pointer, string_limit.saturating_add(1));
+
+                match data.iter().position(|byte| *byte == 0) {
+                    Some(end) => write_escaped(out, &data[..end], false),
+                    None if data.is_empty() => write!(out, "{pointer:#x}"),
+                    None => {
+                        let end = data.len().min(string_limit);
+                        write_escaped(out, &data[..end], true)
+                    }
+                }
+            }
+            ArgKind::Buf { len, output } if pointer != 0 => {
+                let len = match (output, ret) {
+                    (false, _) => Some(self.unsigned(self.args[len])),
+                    (true, Some(ret)) if ret >= 0 => Some(ret as u64),
+                    (true, _) => None,
+                };
+                let data = len.map(|len| {
+                    let shown = (len as usize).min(string_limit);
+                    (read(pointer, shown), len as usize > shown)
+                });
+
+                match data {
+                    Some((data, truncated)) if !data.is_empty() || len == Some(0) => {
+                        write_escaped(out, &data, truncated)
+                    }
+                    _ => write!(out, "{pointer:#x}"),
+                }
+            }
+            ArgKind::Flags(flags) => write_flags(out, flags, value & 0xffffffff, self.table()),
+            ArgKind::Str | ArgKind::Buf { .. } | ArgKind::Struct(_) if pointer == 0 => {
+                write!(out, "NULL")
+            }
+            ArgKind::Str | ArgKind::Buf { .. } | ArgKind::Struct(_) => {
+                write!(out, "{pointer:#x}")
+            }
+        };
+    }
+}
+
+impl fmt::Display for Syscall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render())
+    }
+}
+
+/// The error number a syscall's return value holds, if it is an error. Errors are the
+/// same for every syscall and target.
+pub(crate) fn errno(ret: i64) -> Option<i64> {
+    (-MAX_ERRNO..0).contains(&ret).then_some(-ret)
+}
+
+/// Sign extend the low `bits` bits of a value
+pub(crate) fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+/// Write bytes as a quoted C string, followed by `...` if they were truncated
+fn write_escaped(out: &mut String, data: &[u8], truncated: bool) -> fmt::Result {
+    out.push('"');
+
+    for (index, byte) in data.iter().enumerate() {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x0b => out.push_str("\\v"),
+            0x0c => out.push_str("\\f"),
+            0x20..=0x7e => out.push(*byte as char),
+            // NOTE: Octal escapes followed by a digit are padded so the digit is not
+            // read as part of the escape
+            _ if matches!(data.get(index + 1), Some(b'0'..=b'7')) => write!(out, "\\{byte:03o}")?,
+            _ => write!(out, "\\{byte:o}")?,
+        }
+    }
+
+    out.push('"');
+
+    if truncated {
+        out.push_str("...");
+    }
+
+    Ok(())
+}
+
+/// Write a set of flags as their names joined by `|`, followed by any bits without a name
+/// in hexadecimal. The names of flags whose values differ between architectures are
+/// taken from `table`.
+fn write_flags(
+    out: &mut String,
+    flags: FlagSet,
+    mut value: u64,
+    table: Option<&SyscallTable>,
+) -> fmt::Result {
+    // NOTE: Flags which contain other flags come first, so they are matched before the
+    // flags they contain
+    let names = match flags {
+        FlagSet::Open => table.map_or(&[][..], |table| table.open_flags),
+        FlagSet::Mmap => table.map_or(&[][..], |table| table.mmap_flags),
+        FlagSet::Prot => PROT_FLAGS,
+        FlagSet::At => AT_FLAGS,
+        FlagSet::Clone => CLONE_FLAGS,
+    };
+    let mut parts = Vec::new();
+
+    match flags {
+        FlagSet::Open => {
+            parts.push(match value & 0x3 {
+                0 => "O_RDONLY",
+                1 => "O_WRONLY",
+                2 => "O_RDWR",
+                _ => "O_ACCMODE",
+            });
+            value &= !0x3;
+        }
+        FlagSet::Mmap => {
+            if let Some((kind, name)) = MMAP_TYPES.iter().find(|(kind, _)| value & 0xf == *kind) {
+                parts.push(*name);
+                value &= !kind;
+            }
+        }
+        FlagSet::Prot if value == 0 => parts.push("PROT_NONE"),
+        _ => {}
+    }
+
+    for (flag, name) in names {
+        if value & flag == *flag {
+            parts.push(name);
+            value &= !flag;
+        }
+    }
+
+    let rest = match value {
+        0 if parts.is_empty() => Some("0".to_string()),
+        0 => None,
+        _ => Some(format!("{value:#x}")),
+    };
+
+    for (index, part) in parts.iter().copied().chain(rest.as_deref()).enumerate() {
+        if index > 0 {
+            out.push('|');
+        }
+        out.push_str(part);
+    }
+
+    Ok(())
+}
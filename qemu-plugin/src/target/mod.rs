@@ -0,0 +1,377 @@
+//! Descriptions of the targets QEMU emulates
+//!
+//! Every QEMU binary emulates a single target, which plugins learn by name from
+//! [`crate::Info::target_name`]. A [`Target`] parses that name once, at install time, into
+//! a description of the target's pointer width, byte order and instruction alignment,
+//! and of the convention its Linux user-mode emulation uses to make system calls.
+
+use crate::{Arch, Endian};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The index into [`Target::ALL`] of the target the plugin was installed in, or
+/// `usize::MAX` if it is not known
+static CURRENT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Whether QEMU is emulating a single user-mode process or a full system
+pub enum Mode {
+    /// User-mode emulation of a single process, e.g. `qemu-x86_64`
+    User,
+    /// Full system emulation, e.g. `qemu-system-x86_64`
+    System,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The registers used to make a system call from user mode, named as QEMU names them
+pub struct SyscallConvention {
+    /// The register holding the system call number
+    pub number: &'static str,
+    /// The registers holding the arguments of the system call, in order
+    pub arguments: &'static [&'static str],
+    /// The register holding the return value of the system call
+    pub return_value: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A target QEMU can be built for, named after the QEMU binary which emulates it
+///
+/// # Example
+///
+/// ```
+/// use qemu_plugin::{Endian, Target};
+///
+/// let target = Target::from_target_name("ppc64le").unwrap();
+/// assert_eq!(target.pointer_width(), 8);
+/// assert_eq!(target.endian(), Endian::Little);
+/// assert_eq!(target.syscall_convention().unwrap().number, "r0");
+/// ```
+pub enum Target {
+    /// 64-bit ARM, little-endian
+    Aarch64,
+    /// 64-bit ARM, big-endian
+    Aarch64Be,
+    /// DEC Alpha
+    Alpha,
+    /// 32-bit ARM, little-endian
+    Arm,
+    /// 32-bit ARM, big-endian
+    Armeb,
+    /// AVR
+    Avr,
+    /// Qualcomm Hexagon
+    Hexagon,
+    /// PA-RISC
+    Hppa,
+    /// 32-bit x86
+    I386,
+    /// 64-bit LoongArch
+    Loongarch64,
+    /// Motorola 68000
+    M68k,
+    /// MicroBlaze, big-endian
+    Microblaze,
+    /// MicroBlaze, little-endian
+    Microblazeel,
+    /// 32-bit MIPS, big-endian
+    Mips,
+    /// 32-bit MIPS, little-endian
+    Mipsel,
+    /// 64-bit MIPS, big-endian
+    Mips64,
+    /// 64-bit MIPS, little-endian
+    Mips64el,
+    /// 64-bit MIPS with the N32 ABI, big-endian
+    Mipsn32,
+    /// 64-bit MIPS with the N32 ABI, little-endian
+    Mipsn32el,
+    /// OpenRISC 1000
+    Or1k,
+    /// 32-bit PowerPC
+    Ppc,
+    /// 64-bit PowerPC, big-endian
+    Ppc64,
+    /// 64-bit PowerPC, little-endian
+    Ppc64le,
+    /// 32-bit RISC-V
+    Riscv32,
+    /// 64-bit RISC-V
+    Riscv64,
+    /// Renesas RX
+    Rx,
+    /// IBM z/Architecture
+    S390x,
+    /// SuperH SH-4, little-endian
+    Sh4,
+    /// SuperH SH-4, big-endian
+    Sh4eb,
+    /// 32-bit SPARC
+    Sparc,
+    /// 64-bit SPARC with 32-bit user-mode processes
+    Sparc32plus,
+    /// 64-bit SPARC
+    Sparc64,
+    /// Infineon TriCore
+    Tricore,
+    /// 64-bit x86
+    X86_64,
+    /// Xtensa, little-endian
+    Xtensa,
+    /// Xtensa, big-endian
+    Xtensaeb,
+}
+
+impl Target {
+    /// Every target
+    pub const ALL: &'static [Self] = &[
+        Self::Aarch64,
+        Self::Aarch64Be,
+        Self::Alpha,
+        Self::Arm,
+        Self::Armeb,
+        Self::Avr,
+        Self::Hexagon,
+        Self::Hppa,
+        Self::I386,
+        Self::Loongarch64,
+        Self::M68k,
+        Self::Microblaze,
+        Self::Microblazeel,
+        Self::Mips,
+        Self::Mipsel,
+        Self::Mips64,
+        Self::Mips64el,
+        Self::Mipsn32,
+        Self::Mipsn32el,
+        Self::Or1k,
+        Self::Ppc,
+        Self::Ppc64,
+        Self::Ppc64le,
+        Self::Riscv32,
+        Self::Riscv64,
+        Self::Rx,
+        Self::S390x,
+        Self::Sh4,
+        Self::Sh4eb,
+        Self::Sparc,
+        Self::Sparc32plus,
+        Self::Sparc64,
+        Self::Tricore,
+        Self::X86_64,
+        Self::Xtensa,
+        Self::Xtensaeb,
+    ];
+
+    /// The target QEMU emulates, from its name as given in [`crate::Info::target_name`],
+    /// for example `x86_64` or `aarch64-softmmu`
+    pub fn from_target_name(target_name: &str) -> Option<Self> {
+        let name = target_name
+            .split_once('-')
+            .map_or(target_name, |(name, _)| name);
+
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|target| target.name() == name)
+    }
+
+    /// The target of the QEMU the plugin was installed in, if its name names a known
+    /// target. This is the same as [`crate::Info::target`], for code which does not have
+    /// the `Info` at hand, like syscall callbacks.
+    pub fn current() -> Option<Self> {
+        Self::ALL.get(CURRENT.load(Ordering::Relaxed)).copied()
+    }
+
+    /// Record the target of the QEMU the plugin was installed in
+    pub(crate) fn set_current(target: Option<Self>) {
+        let index = target
+            .and_then(|target| Self::ALL.iter().position(|t| *t == target))
+            .unwrap_or(usize::MAX);
+        CURRENT.store(index, Ordering::Relaxed);
+    }
+
+    /// The name of the target, as QEMU names it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Aarch64 => "aarch64",
+            Self::Aarch64Be => "aarch64_be",
+            Self::Alpha => "alpha",
+            Self::Arm => "arm",
+            Self::Armeb => "armeb",
+            Self::Avr => "avr",
+            Self::Hexagon => "hexagon",
+            Self::Hppa => "hppa",
+            Self::I386 => "i386",
+            Self::Loongarch64 => "loongarch64",
+            Self::M68k => "m68k",
+            Self::Microblaze => "microblaze",
+            Self::Microblazeel => "microblazeel",
+            Self::Mips => "mips",
+            Self::Mipsel => "mipsel",
+            Self::Mips64 => "mips64",
+            Self::Mips64el => "mips64el",
+            Self::Mipsn32 => "mipsn32",
+            Self::Mipsn32el => "mipsn32el",
+            Self::Or1k => "or1k",
+            Self::Ppc => "ppc",
+            Self::Ppc64 => "ppc64",
+            Self::Ppc64le => "ppc64le",
+            Self::Riscv32 => "riscv32",
+            Self::Riscv64 => "riscv64",
+            Self::Rx => "rx",
+            Self::S390x => "s390x",
+            Self::Sh4 => "sh4",
+            Self::Sh4eb => "sh4eb",
+            Self::Sparc => "sparc",
+            Self::Sparc32plus => "sparc32plus",
+            Self::Sparc64 => "sparc64",
+            Self::Tricore => "tricore",
+            Self::X86_64 => "x86_64",
+            Self::Xtensa => "xtensa",
+            Self::Xtensaeb => "xtensaeb",
+        }
+    }
+
+    /// The architecture profile of the target's registers, if it has one
+    pub fn arch(&self) -> Option<Arch> {
+        match self {
+            Self::X86_64 => Some(Arch::X86_64),
+            Self::I386 => Some(Arch::I386),
+            Self::Aarch64 | Self::Aarch64Be => Some(Arch::Aarch64),
+            Self::Arm | Self::Armeb => Some(Arch::Arm),
+            Self::Riscv64 => Some(Arch::Riscv64),
+            Self::Ppc64 | Self::Ppc64le => Some(Arch::Ppc64),
+            _ => None,
+        }
+    }
+
+    /// The size of a pointer of a user-mode process in bytes
+    pub fn pointer_width(&self) -> usize {
+        match self {
+            Self::Aarch64
+            | Self::Aarch64Be
+            | Self::Alpha
+            | Self::Loongarch64
+            | Self::Mips64
+            | Self::Mips64el
+            | Self::Ppc64
+            | Self::Ppc64le
+            | Self::Riscv64
+            | Self::S390x
+            | Self::Sparc64
+            | Self::X86_64 => 8,
+            Self::Avr => 2,
+            _ => 4,
+        }
+    }
+
+    /// The default byte order of the target
+    pub fn endian(&self) -> Endian {
+        match self {
+            Self::Aarch64Be
+            | Self::Armeb
+            | Self::Hppa
+            | Self::M68k
+            | Self::Microblaze
+            | Self::Mips
+            | Self::Mips64
+            | Self::Mipsn32
+            | Self::Or1k
+            | Self::Ppc
+            | Self::Ppc64
+            | Self::S390x
+            | Self::Sh4eb
+            | Self::Sparc
+            | Self::Sparc32plus
+            | Self::Sparc64
+            | Self::Xtensaeb => Endian::Big,
+            _ => Endian::Little,
+        }
+    }
+
+    /// The alignment in bytes every instruction of the target is guaranteed to have,
+    /// accounting for compressed instruction sets like Thumb and RVC
+    pub fn instruction_alignment(&self) -> usize {
+        match self {
+            Self::I386 | Self::X86_64 | Self::Rx | Self::Xtensa | Self::Xtensaeb => 1,
+            Self::Arm
+            | Self::Armeb
+            | Self::Avr
+            | Self::M68k
+            | Self::Mips
+            | Self::Mipsel
+            | Self::Mips64
+            | Self::Mips64el
+            | Self::Mipsn32
+            | Self::Mipsn32el
+            | Self::Riscv32
+            | Self::Riscv64
+            | Self::S390x
+            | Self::Sh4
+            | Self::Sh4eb
+            | Self::Tricore => 2,
+            _ => 4,
+        }
+    }
+
+    /// The convention Linux user-mode processes on the target use to make system calls,
+    /// if QEMU can emulate Linux processes for the target. System calls with more
+    /// arguments than there are argument registers pass the rest on the stack.
+    pub fn syscall_convention(&self) -> Option<SyscallConvention> {
+        let (number, arguments, return_value): (_, &'static [&'static str], _) = match self {
+            Self::Aarch64 | Self::Aarch64Be => ("x8", &["x0", "x1", "x2", "x3", "x4", "x5"], "x0"),
+            Self::Alpha => ("r0", &["r16", "r17", "r18", "r19", "r20", "r21"], "r0"),
+            Self::Arm | Self::Armeb => ("r7", &["r0", "r1", "r2", "r3", "r4", "r5", "r6"], "r0"),
+            Self::Hexagon => ("r06", &["r00", "r01", "r02", "r03", "r04", "r05"], "r00"),
+            Self::Hppa => ("r20", &["r26", "r25", "r24", "r23", "r22", "r21"], "r28"),
+            Self::I386 => ("eax", &["ebx", "ecx", "edx", "esi", "edi", "ebp"], "eax"),
+            Self::Loongarch64 => ("r11", &["r4", "r5", "r6", "r7", "r8", "r9", "r10"], "r4"),
+            Self::M68k => ("d0", &["d1", "d2", "d3", "d4", "d5", "a0"], "d0"),
+            Self::Microblaze | Self::Microblazeel => {
+                ("r12", &["r5", "r6", "r7", "r8", "r9", "r10"], "r3")
+            }
+            Self::Mips | Self::Mipsel => ("r2", &["r4", "r5", "r6", "r7"], "r2"),
+            Self::Mips64 | Self::Mips64el | Self::Mipsn32 | Self::Mipsn32el => (
+                "r2",
+                &["r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11"],
+                "r2",
+            ),
+            Self::Or1k => ("r11", &["r3", "r4", "r5", "r6", "r7", "r8"], "r11"),
+            Self::Ppc | Self::Ppc64 | Self::Ppc64le => {
+                ("r0", &["r3", "r4", "r5", "r6", "r7", "r8"], "r3")
+            }
+            Self::Riscv32 | Self::Riscv64 => ("a7", &["a0", "a1", "a2", "a3", "a4", "a5"], "a0"),
+            Self::S390x => ("r1", &["r2", "r3", "r4", "r5", "r6", "r7"], "r2"),
+            Self::Sh4 | Self::Sh4eb => ("r3", &["r4", "r5", "r6", "r7", "r0", "r1"], "r0"),
+            Self::Sparc | Self::Sparc32plus | Self::Sparc64 => {
+                ("g1", &["o0", "o1", "o2", "o3", "o4", "o5"], "o0")
+            }
+            Self::X86_64 => ("rax", &["rdi", "rsi", "rdx", "r10", "r8", "r9"], "rax"),
+            Self::Xtensa | Self::Xtensaeb => ("a2", &["a6", "a3", "a4", "a5", "a8", "a9"], "a2"),
+            Self::Avr | Self::Rx | Self::Tricore => return None,
+        };
+
+        Some(SyscallConvention {
+            number,
+            arguments,
+            return_value,
+        })
+    }
+}
+
+impl FromStr for Target {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_target_name(s).ok_or(())
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
@@ -0,0 +1,131 @@
+//! Time control functionality for QEMU plugins
+//!
+//! In system mode, a single plugin may take control of the guest's virtual clock and
+//! move it forward itself, for example to build a deterministic clock driven by the
+//! number of instructions executed. In user mode, guest time always comes from the host
+//! kernel, and updates are ignored.
+
+use crate::{Error, Result};
+use std::{
+    ffi::c_void,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+/// The latest virtual time QEMU accepts, which takes the time as a signed value
+const MAX_TIME_NS: u64 = i64::MAX as u64;
+
+#[derive(Debug)]
+/// A wrapper structure for the opaque handle returned by
+/// `qemu_plugin_request_time_control`. Holding a `TimeControl` means this plugin, and no
+/// other, controls virtual time. QEMU grants control at most once, and there is no way
+/// to give it back, so dropping a `TimeControl` leaves time frozen at the last value set.
+///
+/// # Example
+///
+/// ```
+/// # use qemu_plugin::{Result, time::TimeControl};
+/// use qemu_plugin::{HasCallbacks, Info, PluginId, Register, TranslationBlock, install::Args};
+/// use std::sync::Arc;
+///
+/// #[derive(Default)]
+/// struct InstructionClock {
+///     time: Option<Arc<TimeControl>>,
+/// }
+///
+/// impl Register for InstructionClock {
+///     fn register(&mut self, _: PluginId, _: &Args, _: &Info) -> Result<()> {
+///         self.time = Some(Arc::new(TimeControl::request()?));
+///         Ok(())
+///     }
+/// }
+///
+/// impl HasCallbacks for InstructionClock {
+///     fn on_translation_block_translate(
+///         &mut self,
+///         _id: PluginId,
+///         tb: TranslationBlock,
+///     ) -> Result<()> {
+///         if let Some(time) = self.time.clone() {
+///             // Advance the clock by one nanosecond per instruction executed
+///             let insns = tb.size() as u64;
+///             tb.register_execute_callback(move |_| {
+///                 time.advance_ns(insns);
+///             });
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+pub struct TimeControl {
+    handle: usize,
+    // NOTE: Updates hold the lock across the call into QEMU, so that concurrent updates
+    // from several vCPUs reach QEMU in the order they are stored here
+    now: Mutex<u64>,
+}
+
+impl TimeControl {
+    /// Request control of virtual time. Only one plugin can control time, so this
+    /// returns an error if control has already been granted, whether to this plugin or
+    /// to another.
+    pub fn request() -> Result<Self> {
+        let handle = unsafe { crate::sys::qemu_plugin_request_time_control() };
+
+        if handle.is_null() {
+            Err(Error::TimeControlUnavailable)
+        } else {
+            Ok(Self {
+                handle: handle as usize,
+                now: Mutex::new(0),
+            })
+        }
+    }
+
+    /// The virtual time in nanoseconds, as last set through this handle. Virtual time
+    /// starts at zero.
+    pub fn now_ns(&self) -> u64 {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The virtual time, as last set through this handle
+    pub fn now(&self) -> Duration {
+        Duration::from_nanos(self.now_ns())
+    }
+
+    /// Move virtual time forward to `time_ns` nanoseconds. QEMU never moves time
+    /// backward, so a time earlier than the current time is ignored. Returns the virtual
+    /// time after the update.
+    pub fn update_ns(&self, time_ns: u64) -> u64 {
+        self.set_with(|now| now.max(time_ns))
+    }
+
+    /// Move virtual time forward to `time`. See [`TimeControl::update_ns`].
+    pub fn update(&self, time: Duration) -> Duration {
+        Duration::from_nanos(self.update_ns(duration_ns(time)))
+    }
+
+    /// Move virtual time forward by `delta_ns` nanoseconds. Returns the virtual time after
+    /// the update.
+    pub fn advance_ns(&self, delta_ns: u64) -> u64 {
+        self.set_with(|now| now.saturating_add(delta_ns))
+    }
+
+    /// Move virtual time forward by `delta`. See [`TimeControl::advance_ns`].
+    pub fn advance(&self, delta: Duration) -> Duration {
+        Duration::from_nanos(self.advance_ns(duration_ns(delta)))
+    }
+
+    /// Replace the virtual time with `update(now)`, saturating at the latest time QEMU
+    /// accepts, and pass the new time to QEMU
+    fn set_with(&self, update: impl FnOnce(u64) -> u64) -> u64 {
+        let mut now = self.now.lock().unwrap_or_else(PoisonError::into_inner);
+        *now = update(*now).min(MAX_TIME_NS);
+        unsafe { crate::sys::qemu_plugin_update_ns(self.handle as *const c_void, *now as i64) };
+        *now
+    }
+}
+
+/// Convert a duration to nanoseconds, saturating at `u64::MAX`
+fn duration_ns(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
@@ -1,7 +1,7 @@
 //! Translation Block-related functionality for QEMU plugins
 
 use crate::{
-    CallbackFlags, Error, Instruction, Result, VCPUIndex,
+    CallbackFlags, Error, InlineOp, Instruction, Result, VCPUIndex, arena,
     handle_qemu_plugin_register_vcpu_tb_exec_cb, sys::qemu_plugin_tb,
 };
 #[cfg(not(any(
@@ -10,7 +10,7 @@ use crate::{
     feature = "plugin-api-v2"
 )))]
 use crate::{PluginCondition, PluginU64};
-use std::{ffi::c_void, marker::PhantomData};
+use std::marker::PhantomData;
 
 #[derive(Debug, Clone)]
 /// Wrapper structure for a `qemu_plugin_tb *`
@@ -23,6 +23,7 @@ use std::{ffi::c_void, marker::PhantomData};
 /// # Example
 ///
 /// ```
+/// # use qemu_plugin::Result;
 /// struct MyPlugin;
 ///
 /// impl qemu_plugin::plugin::Register for MyPlugin {}
@@ -112,9 +113,7 @@ impl<'a> TranslationBlock<'a> {
     where
         F: FnMut(VCPUIndex) + Send + Sync + 'static,
     {
-        let callback = Box::new(cb);
-        let callback_box = Box::new(callback);
-        let userdata = Box::into_raw(callback_box) as *mut c_void;
+        let userdata = arena::alloc(cb);
 
         unsafe {
             crate::sys::qemu_plugin_register_vcpu_tb_exec_cb(
@@ -126,6 +125,33 @@ impl<'a> TranslationBlock<'a> {
         };
     }
 
+    /// Register an inline operation to be run on execution of this translation block
+    ///
+    /// # Arguments
+    ///
+    /// - `op`: The operation to be run
+    pub fn register_inline(&self, op: InlineOp) {
+        #[cfg(any(feature = "plugin-api-v0", feature = "plugin-api-v1"))]
+        unsafe {
+            crate::sys::qemu_plugin_register_vcpu_tb_exec_inline(
+                self.translation_block as *mut qemu_plugin_tb,
+                op.op,
+                op.ptr(),
+                op.imm,
+            )
+        };
+
+        #[cfg(not(any(feature = "plugin-api-v0", feature = "plugin-api-v1")))]
+        unsafe {
+            crate::sys::qemu_plugin_register_vcpu_tb_exec_inline_per_vcpu(
+                self.translation_block as *mut qemu_plugin_tb,
+                op.op,
+                op.entry(),
+                op.imm,
+            )
+        };
+    }
+
     #[cfg(not(any(
         feature = "plugin-api-v0",
         feature = "plugin-api-v1",
@@ -183,9 +209,7 @@ impl<'a> TranslationBlock<'a> {
     ) where
         F: FnMut(VCPUIndex) + Send + Sync + 'static,
     {
-        let callback = Box::new(cb);
-        let callback_box = Box::new(callback);
-        let userdata = Box::into_raw(callback_box) as *mut c_void;
+        let userdata = arena::alloc(cb);
 
         unsafe {
             crate::sys::qemu_plugin_register_vcpu_tb_exec_cond_cb(
@@ -0,0 +1,1041 @@
+//! Backtraces of the guest
+//!
+//! An [`Unwinder`] walks the guest stack from the registers of the current vCPU to
+//! produce a backtrace of [`StackFrame`]s, each resolved by a [`Symbolizer`] to the
+//! function, source line and object file containing it. Frames are unwound with the call
+//! frame information in the `.eh_frame` and `.debug_frame` sections of the binary being
+//! emulated and of the shared objects it loads. Code without call frame information, like hand-written assembly or
+//! code loaded from an unknown file, is unwound by following the chain of saved frame
+//! pointers instead.
+//!
+//! The unwinder reads the object files the symbolizer has located, so objects added to
+//! either are used by both. In user mode, the binary being emulated is located with
+//! [`crate::qemu_plugin_path_to_binary`], and shared objects are located by passing
+//! syscalls to [`Unwinder::on_syscall`] and [`Unwinder::on_syscall_return`], which watch
+//! the dynamic loader open and map them. Other objects can be added with
+//! [`Unwinder::add_object`]. x86_64, aarch64 and riscv64 guests are supported.
+//!
+//! Reading the registers and stack of a vCPU requires a callback registered with
+//! `CallbackFlags::QEMU_PLUGIN_CB_R_REGS`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use qemu_plugin::Result;
+//! use qemu_plugin::{
+//!     Args, CallbackFlags, HasCallbacks, Info, PluginId, Register, RegisterFile, Syscall,
+//!     Target, TranslationBlock, Unwinder, VCPUIndex,
+//! };
+//! use std::sync::Arc;
+//!
+//! #[derive(Default)]
+//! struct Backtraces {
+//!     target: Option<Target>,
+//!     unwinder: Option<Arc<Unwinder>>,
+//! }
+//!
+//! impl Register for Backtraces {
+//!     fn register(&mut self, _id: PluginId, _args: &Args, info: &Info) -> Result<()> {
+//!         self.target = info.target;
+//!         self.unwinder = Some(Arc::new(Unwinder::from_info(info)?));
+//!         Ok(())
+//!     }
+//! }
+//!
+//! impl HasCallbacks for Backtraces {
+//!     fn on_translation_block_translate(
+//!         &mut self,
+//!         _id: PluginId,
+//!         tb: TranslationBlock,
+//!     ) -> Result<()> {
+//!         let (Some(unwinder), Some(target)) = (self.unwinder.clone(), self.target) else {
+//!             return Ok(());
+//!         };
+//!
+//!         if tb.vaddr() == 0x401000 {
+//!             tb.register_execute_callback_flags(
+//!                 move |_| {
+//!                     if let Ok(registers) = RegisterFile::current(target)
+//!                         && let Ok(frames) = unwinder.unwind(&registers)
+//!                     {
+//!                         for (index, frame) in frames.iter().enumerate() {
+//!                             println!("#{index} {frame}");
+//!                         }
+//!                     }
+//!                 },
+//!                 CallbackFlags::QEMU_PLUGIN_CB_R_REGS,
+//!             );
+//!         }
+//!
+//!         Ok(())
+//!     }
+//!
+//!     fn on_syscall_typed(
+//!         &mut self,
+//!         _id: PluginId,
+//!         vcpu_index: VCPUIndex,
+//!         syscall: Syscall,
+//!     ) -> Result<()> {
+//!         if let Some(unwinder) = &self.unwinder {
+//!             unwinder.on_syscall(vcpu_index, &syscall);
+//!         }
+//!         Ok(())
+//!     }
+//!
+//!     fn on_syscall_return(
+//!         &mut self,
+//!         _id: PluginId,
+//!         vcpu_index: VCPUIndex,
+//!         num: i64,
+//!         ret: i64,
+//!     ) -> Result<()> {
+//!         if let Some(unwinder) = &self.unwinder {
+//!             unwinder.on_syscall_return(vcpu_index, num, ret);
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+use crate::{
+    Error, GuestMemory, Info, RegisterFile, Result, SourceLocation, Symbolizer, Syscall, Target,
+    VCPUIndex, callstack::RISCV_REGISTERS, objects,
+};
+use gimli::{
+    BaseAddresses, CallFrameInstruction, CfaRule, CieOrFde, DebugFrame, EhFrame, EndianSlice,
+    FrameDescriptionEntry, Register, RegisterRule, RunTimeEndian, UnwindContext, UnwindSection,
+    UnwindTableRow, Vendor,
+};
+use object::{Object, ObjectSection};
+use std::{fmt, ops::Range, path::Path, path::PathBuf, sync::Arc};
+
+/// The number of frames a backtrace is cut off at unless set with
+/// [`Unwinder::with_max_frames`]
+const DEFAULT_MAX_FRAMES: usize = 128;
+
+/// The number of registers unwinding tracks, by DWARF register number
+const REGISTERS: usize = 32;
+
+/// The values of the registers of a frame, by DWARF register number, or `None` where the
+/// value is not known
+type Registers = [Option<u64>; REGISTERS];
+
+/// Where a frame record saves the caller's frame pointer and return address, relative
+/// to the frame pointer, and where the caller's stack pointer is
+struct FrameRecord {
+    fp: i64,
+    ra: i64,
+    sp: i64,
+}
+
+/// The DWARF register numbering and frame layout of an architecture
+struct Abi {
+    /// The names QEMU gives the registers unwinding tracks, by DWARF register number
+    registers: &'static [&'static str],
+    /// The name QEMU gives the program counter
+    pc: &'static str,
+    /// The DWARF register number of the stack pointer
+    sp: usize,
+    /// The DWARF register number of the frame pointer
+    fp: usize,
+    /// The frame record the frame pointer points to
+    frame_record: FrameRecord,
+    /// The bits of a return address which hold an address, rather than a pointer
+    /// authentication code or tag
+    address_mask: u64,
+    /// The vendor extensions of the architecture's call frame information
+    vendor: Vendor,
+}
+
+impl Abi {
+    /// Find the caller of the frame at the file address `address` from the call frame
+    /// information `tables`, reading saved registers with `read_pointer`, or `None` if
+    /// there is none or it can not be evaluated
+    fn step_cfi(
+        &self,
+        tables: &UnwindTables,
+        address: u64,
+        registers: &Registers,
+        read_pointer: impl Fn(u64) -> Option<u64>,
+    ) -> Option<Step> {
+        tables
+            .with_row(address, self.vendor, |row, return_address, undefined| {
+                // The outermost frame, like the entry point of the binary or a thread,
+                // makes its return address undefined. The return address column of
+                // x86_64 is not a register, so every other frame has a rule for it.
+                if undefined
+                    || ((return_address.0 as usize) >= self.registers.len()
+                        && matches!(row.register(return_address), RegisterRule::Undefined))
+                {
+                    return Some(Step::End);
+                }
+
+                let cfa = match row.cfa() {
+                    CfaRule::RegisterAndOffset { register, offset } => registers
+                        .get(register.0 as usize)
+                        .copied()
+                        .flatten()?
+                        .wrapping_add_signed(*offset),
+                    CfaRule::Expression(_) => return None,
+                };
+
+                // Registers without a rule keep their value in the caller
+                let recover = |register: Register| match row.register(register) {
+                    RegisterRule::Undefined | RegisterRule::SameValue => {
+                        registers.get(register.0 as usize).copied().flatten()
+                    }
+                    RegisterRule::Offset(offset) => read_pointer(cfa.wrapping_add_signed(offset)),
+                    RegisterRule::ValOffset(offset) => Some(cfa.wrapping_add_signed(offset)),
+                    RegisterRule::Register(other) => {
+                        registers.get(other.0 as usize).copied().flatten()
+                    }
+                    _ => None,
+                };
+
+                let pc = recover(return_address)?;
+                let mut caller = [None; REGISTERS];
+
+                for (number, value) in caller.iter_mut().enumerate().take(self.registers.len()) {
+                    *value = recover(Register(number as u16));
+                }
+
+                caller[self.sp] = Some(cfa);
+
+                Some(Step::Caller(pc, Box::new(caller)))
+            })
+            .flatten()
+    }
+}
+
+/// The System V x86_64 ABI, which saves the frame pointer and return address above it
+const X86_64: Abi = Abi {
+    registers: &[
+        "rax", "rdx", "rcx", "rbx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ],
+    pc: "rip",
+    sp: 7,
+    fp: 6,
+    frame_record: FrameRecord {
+        fp: 0,
+        ra: 8,
+        sp: 16,
+    },
+    address_mask: u64::MAX,
+    vendor: Vendor::Default,
+};
+
+/// The AAPCS64 ABI. User-mode addresses are 48 bits, so the top bits of return addresses
+/// signed with pointer authentication are cleared.
+const AARCH64: Abi = Abi {
+    registers: &[
+        "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+        "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26",
+        "x27", "x28", "x29", "x30", "sp",
+    ],
+    pc: "pc",
+    sp: 31,
+    fp: 29,
+    frame_record: FrameRecord {
+        fp: 0,
+        ra: 8,
+        sp: 16,
+    },
+    address_mask: 0x0000_ffff_ffff_ffff,
+    vendor: Vendor::AArch64,
+};
+
+/// The RISC-V ABI, which saves the frame pointer and return address below it
+const RISCV64: Abi = Abi {
+    registers: &RISCV_REGISTERS,
+    pc: "pc",
+    sp: 2,
+    fp: 8,
+    frame_record: FrameRecord {
+        fp: -16,
+        ra: -8,
+        sp: 0,
+    },
+    address_mask: u64::MAX,
+    vendor: Vendor::Default,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// How the caller of a frame was found
+pub enum UnwindMethod {
+    /// The frame is the innermost, read from the vCPU's registers
+    Registers,
+    /// The frame was unwound with call frame information
+    CallFrameInfo,
+    /// The frame was unwound by following the saved frame pointer
+    FramePointer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A frame of a guest backtrace
+pub struct StackFrame {
+    /// The program counter. For every frame but the innermost, this is the return address
+    /// of the call the frame is executing.
+    pub pc: u64,
+    /// The stack pointer
+    pub sp: u64,
+    /// How the frame was found
+    pub method: UnwindMethod,
+    /// The object file containing the program counter, if it is known
+    pub object: Option<PathBuf>,
+    /// The demangled name of the function containing the program counter, if it is
+    /// known
+    pub symbol: Option<String>,
+    /// The offset of the program counter from the start of the function, or from the
+    /// start of the object file if the function is not known
+    pub offset: u64,
+    /// The source line of the call the frame is executing, or of the program counter in
+    /// the innermost frame, if the object file has debug information
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.pc)?;
+
+        match (&self.symbol, &self.object) {
+            (Some(symbol), _) => write!(f, " in {symbol}+{:#x}", self.offset)?,
+            (None, Some(_)) => write!(f, " at +{:#x}", self.offset)?,
+            (None, None) => {}
+        }
+
+        if let Some(location) = &self.location {
+            write!(f, " at {location}")?;
+        }
+
+        if let Some(object) = &self.object {
+            write!(f, " ({})", object.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The contents of a section of an object file, and its address in the file
+struct Section {
+    address: u64,
+    data: Vec<u8>,
+}
+
+/// A frame description entry, which describes how to unwind a range of code
+struct Fde {
+    /// The code the entry describes, in file addresses
+    range: Range<u64>,
+    /// The offset of the entry in its section
+    offset: usize,
+    /// Whether the entry is in `.debug_frame`, rather than `.eh_frame`
+    debug_frame: bool,
+}
+
+/// The call frame information of an object file, in the addresses of the file rather
+/// than where it is loaded
+pub(crate) struct UnwindTables {
+    endian: RunTimeEndian,
+    /// The size of a pointer on the target, which is the size of addresses in the sections
+    address_size: u8,
+    eh_frame: Option<Section>,
+    debug_frame: Option<Section>,
+    /// The base addresses `.eh_frame` pointers are relative to
+    bases: BaseAddresses,
+    /// The frame description entries of both sections, sorted by address
+    fdes: Vec<Fde>,
+}
+
+/// Add the frame description entries of `section` to `fdes`. Entries which can not be
+/// parsed are skipped.
+fn index_fdes<'a, S>(section: &S, bases: &BaseAddresses, debug_frame: bool, fdes: &mut Vec<Fde>)
+where
+    S: UnwindSection<EndianSlice<'a, RunTimeEndian>>,
+{
+    let mut entries = section.entries(bases);
+
+    while let Ok(Some(entry)) = entries.next() {
+        if let CieOrFde::Fde(partial) = entry
+            && let Ok(fde) = partial.parse(S::cie_from_offset)
+        {
+            fdes.push(Fde {
+                range: fde.initial_address()..fde.initial_address().wrapping_add(fde.len()),
+                offset: fde.offset(),
+                debug_frame,
+            });
+        }
+    }
+}
+
+/// The rule `instruction` gives `register`: `Some(true)` if it makes it undefined,
+/// `Some(false)` if it gives it any other rule, and `None` if it does not change it
+fn undefines(instruction: &CallFrameInstruction<usize>, register: Register) -> Option<bool> {
+    match *instruction {
+        CallFrameInstruction::Undefined { register: other } => Some(other == register),
+        CallFrameInstruction::SameValue { register: other }
+        | CallFrameInstruction::Offset {
+            register: other, ..
+        }
+        | CallFrameInstruction::OffsetExtendedSf {
+            register: other, ..
+        }
+        | CallFrameInstruction::ValOffset {
+            register: other, ..
+        }
+        | CallFrameInstruction::ValOffsetSf {
+            register: other, ..
+        }
+        | CallFrameInstruction::Register {
+            dest_register: other,
+            ..
+        }
+        | CallFrameInstruction::Expression {
+            register: other, ..
+        }
+        | CallFrameInstruction::ValExpression {
+            register: other, ..
+        } => (other == register).then_some(false),
+        _ => None,
+    }
+}
+
+/// Whether the instructions of `fde` explicitly make `register` undefined at `address`,
+/// as `.cfi_undefined` does for the return address of the outermost frame. The unwind
+/// table drops undefined rules, so it can not tell them from registers without a rule.
+fn undefined_at<'a, S>(
+    section: &S,
+    bases: &BaseAddresses,
+    fde: &FrameDescriptionEntry<EndianSlice<'a, RunTimeEndian>>,
+    address: u64,
+    register: Register,
+) -> bool
+where
+    S: UnwindSection<EndianSlice<'a, RunTimeEndian>>,
+{
+    let cie = fde.cie();
+    let mut initial = false;
+    let mut instructions = cie.instructions(section, bases);
+
+    while let Ok(Some(instruction)) = instructions.next() {
+        initial = undefines(&instruction, register).unwrap_or(initial);
+    }
+
+    let mut undefined = initial;
+    let mut remembered = Vec::new();
+    let mut location = fde.initial_address();
+    let mut instructions = fde.instructions(section, bases);
+
+    while let Ok(Some(instruction)) = instructions.next() {
+        match instruction {
+            CallFrameInstruction::SetLoc { address } => location = address,
+            CallFrameInstruction::AdvanceLoc { delta } => {
+                location = location
+                    .wrapping_add(u64::from(delta).wrapping_mul(cie.code_alignment_factor()));
+            }
+            CallFrameInstruction::RememberState => remembered.push(undefined),
+            CallFrameInstruction::RestoreState => {
+                undefined = remembered.pop().unwrap_or(initial);
+            }
+            CallFrameInstruction::Restore { register: other } if other == register => {
+                undefined = initial;
+            }
+            _ => undefined = undefines(&instruction, register).unwrap_or(undefined),
+        }
+
+        // Instructions after a location apply to the code from that location on
+        if location > address {
+            break;
+        }
+    }
+
+    undefined
+}
+
+/// Call `f` with the row of the unwind table of the entry at `offset` in `section` which
+/// covers `address`, the register holding the return address, and whether the entry
+/// makes the return address undefined there
+fn with_row<'a, S, T>(
+    section: &S,
+    bases: &BaseAddresses,
+    offset: usize,
+    address: u64,
+    f: impl FnOnce(&UnwindTableRow<usize>, Register, bool) -> T,
+) -> Option<T>
+where
+    S: UnwindSection<EndianSlice<'a, RunTimeEndian>>,
+{
+    let fde = section
+        .fde_from_offset(bases, S::Offset::from(offset), S::cie_from_offset)
+        .ok()?;
+    let mut ctx = UnwindContext::new();
+    let row = fde
+        .unwind_info_for_address(section, bases, &mut ctx, address)
+        .ok()?;
+    let return_address = fde.cie().return_address_register();
+    let undefined = undefined_at(section, bases, &fde, address, return_address);
+
+    Some(f(row, return_address, undefined))
+}
+
+impl UnwindTables {
+    /// Index the call frame information of the parsed object file `file`. Entries which
+    /// can not be parsed are skipped.
+    pub(crate) fn parse(file: &object::File) -> Self {
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let address_size = if file.is_64() { 8 } else { 4 };
+
+        let section = |name: &str| {
+            let section = file.section_by_name(name)?;
+            Some(Section {
+                address: section.address(),
+                data: section.uncompressed_data().ok()?.into_owned(),
+            })
+        };
+        let address = |name: &str| file.section_by_name(name).map(|section| section.address());
+
+        let eh_frame = section(".eh_frame");
+        let debug_frame = section(".debug_frame");
+
+        let mut bases = BaseAddresses::default();
+
+        if let Some(eh_frame) = &eh_frame {
+            bases = bases.set_eh_frame(eh_frame.address);
+        }
+
+        if let Some(address) = address(".eh_frame_hdr") {
+            bases = bases.set_eh_frame_hdr(address);
+        }
+
+        if let Some(address) = address(".text") {
+            bases = bases.set_text(address);
+        }
+
+        if let Some(address) = address(".got") {
+            bases = bases.set_got(address);
+        }
+
+        Self::new(endian, address_size, eh_frame, debug_frame, bases)
+    }
+
+    /// Index the frame description entries of `eh_frame` and `debug_frame`
+    fn new(
+        endian: RunTimeEndian,
+        address_size: u8,
+        eh_frame: Option<Section>,
+        debug_frame: Option<Section>,
+        bases: BaseAddresses,
+    ) -> Self {
+        let mut fdes = Vec::new();
+
+        if let Some(eh_frame) = &eh_frame {
+            let mut section = EhFrame::new(&eh_frame.data, endian);
+            section.set_address_size(address_size);
+            index_fdes(&section, &bases, false, &mut fdes);
+        }
+
+        if let Some(debug_frame) = &debug_frame {
+            let mut section = DebugFrame::new(&debug_frame.data, endian);
+            section.set_address_size(address_size);
+            index_fdes(&section, &BaseAddresses::default(), true, &mut fdes);
+        }
+
+        fdes.sort_by_key(|fde| fde.range.start);
+
+        Self {
+            endian,
+            address_size,
+            eh_frame,
+            debug_frame,
+            bases,
+            fdes,
+        }
+    }
+
+    /// The frame description entry covering `address`
+    fn fde(&self, address: u64) -> Option<&Fde> {
+        let index = self
+            .fdes
+            .partition_point(|fde| fde.range.start <= address)
+            .checked_sub(1)?;
+        let fde = &self.fdes[index];
+
+        fde.range.contains(&address).then_some(fde)
+    }
+
+    /// Call `f` with the row of the unwind table covering `address`, the register holding
+    /// the return address, and whether the return address is undefined there
+    fn with_row<T>(
+        &self,
+        address: u64,
+        vendor: Vendor,
+        f: impl FnOnce(&UnwindTableRow<usize>, Register, bool) -> T,
+    ) -> Option<T> {
+        let fde = self.fde(address)?;
+
+        if fde.debug_frame {
+            let mut section = DebugFrame::new(&self.debug_frame.as_ref()?.data, self.endian);
+            section.set_address_size(self.address_size);
+            section.set_vendor(vendor);
+            with_row(&section, &BaseAddresses::default(), fde.offset, address, f)
+        } else {
+            let mut section = EhFrame::new(&self.eh_frame.as_ref()?.data, self.endian);
+            section.set_address_size(self.address_size);
+            section.set_vendor(vendor);
+            with_row(&section, &self.bases, fde.offset, address, f)
+        }
+    }
+}
+
+/// The step from a frame to its caller
+enum Step {
+    /// The caller's program counter and registers
+    Caller(u64, Box<Registers>),
+    /// The frame is the outermost
+    End,
+}
+
+/// Unwinds the guest stack into backtraces
+///
+/// An unwinder can be shared between vCPUs, and object files can be added while other
+/// vCPUs are unwinding. Object files are parsed once, when they are first added to its
+/// symbolizer.
+pub struct Unwinder {
+    target: Target,
+    abi: &'static Abi,
+    memory: GuestMemory,
+    max_frames: usize,
+    /// Locates the object files loaded into the guest, and symbolizes frames
+    symbolizer: Arc<Symbolizer>,
+}
+
+impl Unwinder {
+    /// Unwind the stacks of `target`, which must be an x86_64, aarch64 or riscv64 target
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnsupportedUnwindTarget`] if stacks can not be unwound on
+    /// `target`.
+    pub fn new(target: Target) -> Result<Self> {
+        let abi = match target {
+            Target::X86_64 => &X86_64,
+            Target::Aarch64 => &AARCH64,
+            Target::Riscv64 => &RISCV64,
+            _ => {
+                return Err(Error::UnsupportedUnwindTarget {
+                    target: target.name().to_string(),
+                });
+            }
+        };
+
+        Ok(Self {
+            target,
+            abi,
+            memory: GuestMemory::new(target.endian()),
+            max_frames: DEFAULT_MAX_FRAMES,
+            symbolizer: Arc::new(Symbolizer::new()),
+        })
+    }
+
+    /// Unwind the stacks of the target described by `info`. In user mode, the binary
+    /// being emulated is added, located by the start of its code.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnsupportedUnwindTarget`] if the target is not known or stacks
+    /// can not be unwound on it, and with [`Error::ObjectFileError`] if the binary being
+    /// emulated can not be read.
+    pub fn from_info(info: &Info) -> Result<Self> {
+        let unwinder = match info.target {
+            Some(target) => Self::new(target)?,
+            None => {
+                return Err(Error::UnsupportedUnwindTarget {
+                    target: info.target_name.clone(),
+                });
+            }
+        };
+
+        Ok(unwinder.with_symbolizer(Arc::new(Symbolizer::from_info(info)?)))
+    }
+
+    /// Locate object files and symbolize frames with `symbolizer`, which may be shared
+    /// with the rest of the plugin. Object files already added to the unwinder are
+    /// dropped, and those added to either afterwards are used by both. A symbolizer
+    /// created with [`Symbolizer::with_sysroot`] looks guest paths up in its sysroot.
+    pub fn with_symbolizer(mut self, symbolizer: Arc<Symbolizer>) -> Self {
+        self.symbolizer = symbolizer;
+        self
+    }
+
+    /// Cut backtraces off after `max_frames` frames
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// The target whose stacks are unwound
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// The symbolizer which locates object files and symbolizes frames
+    pub fn symbolizer(&self) -> &Arc<Symbolizer> {
+        &self.symbolizer
+    }
+
+    /// Add the object file at the guest path `path`, whose first loadable segment is
+    /// mapped at `base`, as the start of its mapping is listed in `/proc/<pid>/maps`
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::ObjectFileError`] if the file can not be read or parsed.
+    pub fn add_object(&self, path: impl AsRef<Path>, base: u64) -> Result<()> {
+        self.symbolizer.add_object(path, Some(base))
+    }
+
+    /// Watch a syscall made by `vcpu_index` for object files being opened and mapped.
+    /// Pass every syscall to this, and their returns to [`Unwinder::on_syscall_return`].
+    /// Syscalls must not also be passed to the unwinder's symbolizer.
+    pub fn on_syscall(&self, vcpu_index: VCPUIndex, syscall: &Syscall) {
+        self.symbolizer.on_syscall(vcpu_index, syscall);
+    }
+
+    /// Complete a syscall passed to [`Unwinder::on_syscall`], adding an object file if it
+    /// was mapped
+    pub fn on_syscall_return(&self, vcpu_index: VCPUIndex, num: i64, ret: i64) {
+        self.symbolizer.on_syscall_return(vcpu_index, num, ret);
+    }
+
+    /// Unwind the stack of the current vCPU from its registers, innermost frame first
+    ///
+    /// This must only be called in a callback which has been registered with
+    /// `CallbackFlags::QEMU_PLUGIN_CB_R_REGS` or
+    /// `CallbackFlags::QEMU_PLUGIN_CB_RW_REGS`, otherwise it will fail.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the program counter or stack pointer can not be read. Frames which can
+    /// not be unwound end the backtrace rather than failing.
+    pub fn unwind(&self, registers: &RegisterFile) -> Result<Vec<StackFrame>> {
+        let mut values = [None; REGISTERS];
+
+        for (value, name) in values.iter_mut().zip(self.abi.registers) {
+            *value = registers.read_u64(name).ok();
+        }
+
+        let pc = registers.read_u64(self.abi.pc)?;
+        values[self.abi.sp] = Some(registers.read_u64(self.abi.registers[self.abi.sp])?);
+
+        Ok(self.unwind_from(pc, values))
+    }
+
+    /// Unwind the stack from a frame at `pc` with the register values `registers`
+    fn unwind_from(&self, mut pc: u64, mut registers: Registers) -> Vec<StackFrame> {
+        let mut frames = Vec::new();
+        let mut method = UnwindMethod::Registers;
+
+        while frames.len() < self.max_frames {
+            let Some(sp) = registers[self.abi.sp] else {
+                break;
+            };
+
+            // The return address of a caller is past its call, which may be the last
+            // instruction of the function, so the call itself is looked up instead
+            let address = if frames.is_empty() {
+                pc
+            } else {
+                pc.wrapping_sub(1)
+            };
+            // NOTE: The mappings are not locked while the frame is symbolized, which
+            // locks them again
+            let mapping = objects::mapping(&self.symbolizer.mappings(), address)
+                .map(|mapping| (mapping.object.clone(), mapping.bias));
+
+            frames.push(self.frame(pc, sp, method, address));
+
+            let step = mapping
+                .and_then(|(object, bias)| {
+                    self.abi.step_cfi(
+                        &object.unwind,
+                        address.wrapping_sub(bias),
+                        &registers,
+                        |address| self.read_pointer(address),
+                    )
+                })
+                .map(|step| (step, UnwindMethod::CallFrameInfo))
+                .or_else(|| {
+                    self.step_frame_pointer(&registers)
+                        .map(|step| (step, UnwindMethod::FramePointer))
+                });
+
+            let Some((Step::Caller(caller_pc, caller_registers), caller_method)) = step else {
+                break;
+            };
+            let caller_pc = caller_pc & self.abi.address_mask;
+            let caller_sp = caller_registers[self.abi.sp];
+
+            // The stack grows down, so callers are at or above their callees. A frame
+            // which does not move up the stack would repeat forever.
+            if caller_pc == 0
+                || caller_sp
+                    .is_none_or(|caller_sp| caller_sp < sp || (caller_sp == sp && caller_pc == pc))
+            {
+                break;
+            }
+
+            pc = caller_pc;
+            registers = *caller_registers;
+            method = caller_method;
+        }
+
+        frames
+    }
+
+    /// Describe the frame at `pc`, symbolized by the function containing `address`
+    fn frame(&self, pc: u64, sp: u64, method: UnwindMethod, address: u64) -> StackFrame {
+        let info = self.symbolizer.symbolize(address);
+        let offset = if info.symbol.is_some() || info.object.is_some() {
+            info.offset + (pc - address)
+        } else {
+            0
+        };
+
+        StackFrame {
+            pc,
+            sp,
+            method,
+            object: info.object.clone(),
+            symbol: info.symbol.clone(),
+            offset,
+            location: info.location.clone(),
+        }
+    }
+
+    /// Read the pointer at `address`
+    fn read_pointer(&self, address: u64) -> Option<u64> {
+        self.memory.read_u64(address).ok()
+    }
+
+    /// Find the caller of a frame by following its frame pointer to the frame record
+    /// saved by the function's prologue, or `None` if there is no frame pointer
+    fn step_frame_pointer(&self, registers: &Registers) -> Option<Step> {
+        let fp = registers[self.abi.fp]?;
+        let sp = registers[self.abi.sp]?;
+
+        if fp == 0 || fp % 8 != 0 || fp < sp {
+            return None;
+        }
+
+        let record = &self.abi.frame_record;
+        let caller_fp = self.read_pointer(fp.wrapping_add_signed(record.fp))?;
+        let pc = self.read_pointer(fp.wrapping_add_signed(record.ra))?;
+        let mut caller = [None; REGISTERS];
+
+        caller[self.abi.fp] = Some(caller_fp);
+        caller[self.abi.sp] = Some(fp.wrapping_add_signed(record.sp));
+
+        Some(Step::Caller(pc, Box::new(caller)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+    const DW_CFA_OFFSET: u8 = 0x80;
+    const DW_CFA_UNDEFINED: u8 = 0x07;
+    const DW_CFA_DEF_CFA: u8 = 0x0c;
+    const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+
+    /// Append `entry` to `section`, prefixed with its length and padded with
+    /// `DW_CFA_nop` to a multiple of the address size
+    fn push_entry(section: &mut Vec<u8>, mut entry: Vec<u8>) {
+        while !(entry.len() + 4).is_multiple_of(8) {
+            entry.push(0);
+        }
+
+        section.extend((entry.len() as u32).to_le_bytes());
+        section.extend(entry);
+    }
+
+    /// Call frame information in `.debug_frame` with a single entry covering `range`,
+    /// whose CIE has the initial instructions `cie` and whose FDE has the instructions
+    /// `fde`. Data is aligned to 8 bytes, and code to `code_alignment` bytes.
+    fn debug_frame(
+        code_alignment: u8,
+        return_address: u8,
+        cie: &[u8],
+        range: Range<u64>,
+        fde: &[u8],
+    ) -> UnwindTables {
+        let mut data = Vec::new();
+
+        let mut entry = u32::MAX.to_le_bytes().to_vec();
+        // Version 4, no augmentation, 8 byte addresses and no segment selectors
+        entry.extend([4, 0, 8, 0, code_alignment, 0x78, return_address]);
+        entry.extend(cie);
+        push_entry(&mut data, entry);
+
+        let mut entry = 0u32.to_le_bytes().to_vec();
+        entry.extend(range.start.to_le_bytes());
+        entry.extend((range.end - range.start).to_le_bytes());
+        entry.extend(fde);
+        push_entry(&mut data, entry);
+
+        UnwindTables::new(
+            RunTimeEndian::Little,
+            8,
+            None,
+            Some(Section { address: 0, data }),
+            BaseAddresses::default(),
+        )
+    }
+
+    /// Step from the frame at `address` with `registers`, reading the stack from `stack`
+    fn step(
+        abi: &Abi,
+        tables: &UnwindTables,
+        address: u64,
+        registers: &[(usize, u64)],
+        stack: &[(u64, u64)],
+    ) -> Option<Step> {
+        let mut values = [None; REGISTERS];
+
+        for &(number, value) in registers {
+            values[number] = Some(value);
+        }
+
+        let stack = stack.iter().copied().collect::<HashMap<_, _>>();
+
+        abi.step_cfi(tables, address, &values, |address| {
+            stack.get(&address).copied()
+        })
+    }
+
+    /// The caller's program counter and registers, failing if the frame is the outermost
+    fn caller(step: Option<Step>) -> (u64, Box<Registers>) {
+        match step {
+            Some(Step::Caller(pc, registers)) => (pc, registers),
+            Some(Step::End) => panic!("Expected a caller, found the end of the stack"),
+            None => panic!("Expected a caller, found no call frame information"),
+        }
+    }
+
+    #[test]
+    fn x86_64_frames_are_unwound_through_the_saved_frame_pointer() {
+        // push rbp at 0x1000, then the body from 0x1001
+        let tables = debug_frame(
+            1,
+            16,
+            &[DW_CFA_DEF_CFA, 7, 8, DW_CFA_OFFSET | 16, 1],
+            0x1000..0x1010,
+            &[
+                DW_CFA_ADVANCE_LOC | 1,
+                DW_CFA_DEF_CFA_OFFSET,
+                16,
+                DW_CFA_OFFSET | 6,
+                2,
+            ],
+        );
+
+        let (pc, registers) = caller(step(
+            &X86_64,
+            &tables,
+            0x1000,
+            &[(7, 0x7ff8), (6, 0x7f00), (3, 0x33)],
+            &[(0x7ff8, 0x2005)],
+        ));
+        assert_eq!(pc, 0x2005);
+        assert_eq!(registers[7], Some(0x8000));
+        assert_eq!(registers[6], Some(0x7f00));
+        assert_eq!(registers[3], Some(0x33));
+
+        let (pc, registers) = caller(step(
+            &X86_64,
+            &tables,
+            0x1004,
+            &[(7, 0x7ff0), (6, 0x1234)],
+            &[(0x7ff8, 0x2005), (0x7ff0, 0x7f00)],
+        ));
+        assert_eq!(pc, 0x2005);
+        assert_eq!(registers[7], Some(0x8000));
+        assert_eq!(registers[6], Some(0x7f00));
+    }
+
+    #[test]
+    fn aarch64_frames_are_unwound_through_the_saved_link_register() {
+        // stp x29, x30, [sp, #-16]! at 0x1000, then the body from 0x1004
+        let tables = debug_frame(
+            4,
+            30,
+            &[DW_CFA_DEF_CFA, 31, 0],
+            0x1000..0x1020,
+            &[
+                DW_CFA_ADVANCE_LOC | 1,
+                DW_CFA_DEF_CFA_OFFSET,
+                16,
+                DW_CFA_OFFSET | 29,
+                2,
+                DW_CFA_OFFSET | 30,
+                1,
+            ],
+        );
+
+        // Before the link register is saved, it holds the return address
+        let (pc, registers) = caller(step(
+            &AARCH64,
+            &tables,
+            0x1000,
+            &[(31, 0x8000), (29, 0x7f00), (30, 0x2004)],
+            &[],
+        ));
+        assert_eq!(pc, 0x2004);
+        assert_eq!(registers[31], Some(0x8000));
+        assert_eq!(registers[29], Some(0x7f00));
+
+        let (pc, registers) = caller(step(
+            &AARCH64,
+            &tables,
+            0x1008,
+            &[(31, 0x7ff0), (29, 0x7ff0), (30, 0x1008)],
+            &[(0x7ff0, 0x7f00), (0x7ff8, 0x2004)],
+        ));
+        assert_eq!(pc, 0x2004);
+        assert_eq!(registers[31], Some(0x8000));
+        assert_eq!(registers[29], Some(0x7f00));
+        assert_eq!(registers[30], Some(0x2004));
+    }
+
+    #[test]
+    fn undefined_return_addresses_end_the_stack() {
+        // An entry point which marks the link register undefined from 0x1008
+        let tables = debug_frame(
+            4,
+            30,
+            &[DW_CFA_DEF_CFA, 31, 0],
+            0x1000..0x1020,
+            &[DW_CFA_ADVANCE_LOC | 2, DW_CFA_UNDEFINED, 30],
+        );
+        let registers = [(31, 0x8000), (30, 0x2004)];
+
+        let (pc, _) = caller(step(&AARCH64, &tables, 0x1004, &registers, &[]));
+        assert_eq!(pc, 0x2004);
+        assert!(matches!(
+            step(&AARCH64, &tables, 0x1008, &registers, &[]),
+            Some(Step::End)
+        ));
+
+        // The return address column of x86_64 is not a register, so no rule ends the
+        // stack too
+        let tables = debug_frame(1, 16, &[DW_CFA_DEF_CFA, 7, 8], 0x1000..0x1010, &[]);
+        assert!(matches!(
+            step(&X86_64, &tables, 0x1000, &[(7, 0x8000)], &[]),
+            Some(Step::End)
+        ));
+    }
+}
@@ -8,7 +8,12 @@ REPO_ROOT=$(realpath "$SCRIPT_DIR/..")
 CHECK_PATHS=(
     "$REPO_ROOT/qemu-plugin"
     "$REPO_ROOT/qemu-plugin-sys"
+    "$REPO_ROOT/qemu-plugin-mock"
     "$REPO_ROOT/plugins/icount"
+    "$REPO_ROOT/plugins/strace"
+    "$REPO_ROOT/plugins/coverage"
+    "$REPO_ROOT/plugins/afl-cov"
+    "$REPO_ROOT/plugins/profile"
     "$REPO_ROOT/plugins/tiny"
     "$REPO_ROOT/plugins/tiny-system"
     "$REPO_ROOT/plugins/tracer"
@@ -17,6 +22,8 @@ CHECK_PATHS=(
 
 cargo +nightly check --manifest-path "$REPO_ROOT/plugins/tracer-events/Cargo.toml"
 cargo +nightly clippy --manifest-path "$REPO_ROOT/plugins/tracer-events/Cargo.toml"
+cargo +nightly check --manifest-path "$REPO_ROOT/qemu-plugin-derive/Cargo.toml"
+cargo +nightly clippy --manifest-path "$REPO_ROOT/qemu-plugin-derive/Cargo.toml"
 
 FEATURES="plugin-api-v0,plugin-api-v1,plugin-api-v2,plugin-api-v3,plugin-api-v4,plugin-api-v5"
 